@@ -0,0 +1,51 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use std::{fs, path::Path};
+use xbe::Xbe;
+use xbld::config::Configuration;
+
+/// Number of patch sites applied by each benchmark
+const SITES: u32 = 16;
+
+/// A configuration applying the framehook patch at `SITES` addresses `spacing` bytes apart. The
+/// patch is 5 bytes, so smaller spacings overlap and are applied sequentially.
+fn config(spacing: u32) -> Configuration {
+    let sites = (0..SITES)
+        .map(|i| {
+            format!(
+                r#"{{ start_symbol = "_framehook_patch", end_symbol = "_framehook_patch_end", virtual_address = {} }}"#,
+                396158 + i * spacing
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",\n");
+    let toml = format!(
+        r#"
+        modfiles = ["loader_stub.o"]
+
+        [[patch]]
+        patchfile = "framehook_patch.o"
+        sites = [
+            {sites}
+        ]"#
+    );
+    Configuration::from_toml(&toml, Path::new("test/bin/fakefile.toml")).unwrap()
+}
+
+fn apply_patches(c: &mut Criterion) {
+    let xbe = fs::read("test/bin/default.xbe").unwrap();
+
+    let mut group = c.benchmark_group("apply_patches");
+    for (name, spacing) in [("independent", 0x10), ("overlapping", 2)] {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || (config(spacing), Xbe::new(&xbe).unwrap()),
+                |(config, xbe)| xbld::inject(config, xbe).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, apply_patches);
+criterion_main!(benches);
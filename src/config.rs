@@ -1,13 +1,72 @@
-use std::path::Path;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+use xbe::SectionFlags;
 
-use crate::{obj::ObjectFile, patch::Patch};
-use anyhow::{Context, Result};
+use crate::{
+    detour::{Detour, DetourTarget, HookKind},
+    header::{HeaderAddress, ImageKind},
+    library::{library_name, LibraryChange},
+    memory::RETAIL_MEMORY_BUDGET,
+    obj::ObjectFile,
+    patch::{address_from_symbol_name, Patch, PatchSite, RawPatch},
+    reloc::{DataSection, COMBINED_SECTION_NAMES},
+    signature::{Signature, SignatureMatch},
+};
+use anyhow::{bail, Context, Result};
+use itertools::Itertools;
 use log::warn;
 
 #[derive(Debug)]
 pub struct Configuration {
-    pub(crate) patches: Vec<Patch>,
-    pub(crate) modfiles: Vec<ObjectFile>,
+    pub patches: Vec<Patch>,
+    pub(crate) raw_patches: Vec<RawPatch>,
+    pub modfiles: Vec<ObjectFile>,
+    pub(crate) detours: Vec<Detour>,
+    /// Names of patch sites to apply before all others, in order
+    pub(crate) patch_order: Vec<String>,
+    /// Treat suspicious-but-valid configurations as errors
+    pub(crate) deny_warnings: bool,
+    /// Strip sections from a previous injection instead of refusing to inject
+    pub(crate) allow_repatch: bool,
+    /// Allow patches to write to the XBE header, which isn't part of any section
+    pub(crate) allow_header_patches: bool,
+    /// Only inject the sections listed in `extra_sections`, instead of also combining `.text`,
+    /// `.data`, `.bss`, and `.rdata` into the default sections
+    pub(crate) no_default_sections: bool,
+    /// Names of custom COFF sections to inject, mapped to the name of the section they are
+    /// combined into
+    pub(crate) extra_sections: HashMap<String, String>,
+    /// Flags of injected sections, overriding the defaults chosen from the section name
+    pub(crate) section_flags: HashMap<String, SectionFlags>,
+    /// Bytes of zero-initialized space to reserve in `.mbss` beyond what the modfiles require
+    pub(crate) bss_size: usize,
+    /// Virtual sizes of injected sections, padding them with zero-initialized space beyond their
+    /// data
+    pub(crate) section_sizes: HashMap<String, u32>,
+    /// Where execution of the XBE starts, replacing the entry point of the input XBE
+    pub(crate) entry_point: Option<HeaderAddress>,
+    /// Address of the TLS directory, replacing the TLS address of the input XBE
+    pub(crate) tls_address: Option<HeaderAddress>,
+    /// Library version records to add to or remove from the XBE, in order. XBOXKRNL and XAPILIB
+    /// can't be removed, as the header points to their records.
+    pub(crate) libraries: Vec<LibraryChange>,
+    /// Files injected as sections without being parsed as object files
+    pub(crate) data_sections: Vec<DataSection>,
+    /// Share identical runs of read-only data, such as constants, instead of injecting each copy
+    pub(crate) deduplicate_constants: bool,
+    /// Store the data of added sections in gaps between the sections of the input XBE that are
+    /// large enough, instead of after all of them, keeping the output file smaller
+    pub(crate) fill_raw_gaps: bool,
+    /// Bytes of RAM the injected image must fit in, including the kernel's reservations
+    pub(crate) memory_budget: u32,
+    /// Warn instead of failing when the image doesn't fit in `memory_budget`
+    pub(crate) allow_over_budget: bool,
+    /// Kind of XBE this config is written for, if it only applies to one
+    pub(crate) image_kind: Option<ImageKind>,
+    /// Warn instead of failing when the input XBE isn't of `image_kind`
+    pub(crate) allow_image_kind_mismatch: bool,
 }
 
 impl Configuration {
@@ -26,20 +85,175 @@ impl Configuration {
         #[derive(serde::Deserialize)]
         struct ConfToml {
             patch: Option<Vec<PatchToml>>,
+            multi_patch: Option<Vec<MultiPatchToml>>,
+            raw_patch: Option<Vec<RawPatchToml>>,
             modfiles: Option<Vec<String>>,
+            detour: Option<Vec<DetourToml>>,
+            deny_warnings: Option<bool>,
+            patch_order: Option<Vec<String>>,
+            allow_repatch: Option<bool>,
+            allow_header_patches: Option<bool>,
+            no_default_sections: Option<bool>,
+            section_addresses: Option<SectionAddressesToml>,
+            extra_sections: Option<HashMap<String, String>>,
+            section_flags: Option<HashMap<String, String>>,
+            section_sizes: Option<HashMap<String, u32>>,
+            entry_point_symbol: Option<String>,
+            entry_point_address: Option<u32>,
+            tls_address_symbol: Option<String>,
+            tls_address: Option<u32>,
+            library: Option<Vec<LibraryToml>>,
+            data_section: Option<Vec<DataSectionToml>>,
+            optimizations: Option<OptimizationsToml>,
+            memory_budget: Option<u32>,
+            allow_over_budget: Option<bool>,
+            image_kind: Option<ImageKind>,
+            allow_image_kind_mismatch: Option<bool>,
         }
         #[derive(serde::Deserialize)]
         struct PatchToml {
             patchfile: String,
+            name: Option<String>,
+            #[serde(alias = "begin_symbol", alias = "hook_start")]
+            start_symbol: Option<String>,
+            #[serde(alias = "end_symbol_name", alias = "hook_end")]
+            end_symbol: Option<String>,
+            virtual_address: Option<u32>,
+            auto_detect_address: Option<bool>,
+            signature: Option<String>,
+            signature_offset: Option<i32>,
+            #[serde(rename = "match")]
+            signature_match: Option<SignatureMatch>,
+            max_matches: Option<usize>,
+            replaces_length: Option<u32>,
+            nop_pad: Option<bool>,
+            expected_bytes: Option<String>,
+            sites: Option<Vec<SiteToml>>,
+        }
+        #[derive(serde::Deserialize)]
+        struct MultiPatchToml {
+            patchfile: String,
+            hooks: Vec<SiteToml>,
+        }
+        #[derive(serde::Deserialize)]
+        struct SiteToml {
+            name: Option<String>,
+            #[serde(alias = "begin_symbol", alias = "hook_start")]
             start_symbol: String,
+            #[serde(alias = "end_symbol_name", alias = "hook_end")]
             end_symbol: String,
+            virtual_address: Option<u32>,
+            auto_detect_address: Option<bool>,
+            signature: Option<String>,
+            signature_offset: Option<i32>,
+            #[serde(rename = "match")]
+            signature_match: Option<SignatureMatch>,
+            max_matches: Option<usize>,
+            replaces_length: Option<u32>,
+            nop_pad: Option<bool>,
+            expected_bytes: Option<String>,
+        }
+        #[derive(serde::Deserialize)]
+        struct OptimizationsToml {
+            deduplicate_constants: Option<bool>,
+            fill_raw_gaps: Option<bool>,
+        }
+        #[derive(serde::Deserialize)]
+        struct SectionAddressesToml {
+            bss_size: Option<usize>,
+        }
+        #[derive(serde::Deserialize)]
+        struct RawPatchToml {
             virtual_address: u32,
+            bytes: String,
+            expected_bytes: Option<String>,
+        }
+        #[derive(serde::Deserialize)]
+        struct LibraryToml {
+            name: String,
+            major: Option<u16>,
+            minor: Option<u16>,
+            build: Option<u16>,
+            flags: Option<u16>,
+            remove: Option<bool>,
+        }
+        #[derive(serde::Deserialize)]
+        struct DataSectionToml {
+            name: String,
+            file: String,
+            virtual_address: Option<u32>,
+            flags: Option<String>,
+        }
+        #[derive(serde::Deserialize)]
+        struct DetourToml {
+            target: DetourTarget,
+            symbol: String,
+            with_original: Option<bool>,
+            kind: Option<HookKind>,
+            prologue_length: Option<u32>,
         }
 
         let conf: ConfToml = toml::from_str(conf)?;
 
         // Create patches from configuration data
-        let patches: Vec<_> = conf
+        let make_site = |site: SiteToml| -> Result<PatchSite> {
+            let expected_bytes = site
+                .expected_bytes
+                .as_deref()
+                .map(parse_hex)
+                .transpose()
+                .with_context(|| format!("Invalid expected_bytes for '{}'", site.start_symbol))?;
+
+            // Sites are located either by a fixed address or by scanning for a signature
+            let virtual_address = if site.auto_detect_address.unwrap_or_default() {
+                if site.virtual_address.is_some() {
+                    bail!(
+                        "Patch site '{}' specifies both virtual_address and \
+                        auto_detect_address",
+                        site.start_symbol
+                    );
+                }
+                Some(address_from_symbol_name(&site.start_symbol)?)
+            } else {
+                site.virtual_address
+            };
+            let (virtual_address, signature) = match (virtual_address, site.signature) {
+                (Some(va), None) => (va, None),
+                (None, Some(signature)) => {
+                    let signature: Signature = signature.parse().with_context(|| {
+                        format!("Invalid signature for '{}'", site.start_symbol)
+                    })?;
+                    (0, Some(signature))
+                }
+                _ => bail!(
+                    "Patch site '{}' must specify exactly one of virtual_address or \
+                    signature",
+                    site.start_symbol
+                ),
+            };
+            if signature.is_none() && (site.signature_match.is_some() || site.max_matches.is_some())
+            {
+                bail!(
+                    "Patch site '{}' specifies match or max_matches without a signature",
+                    site.start_symbol
+                );
+            }
+
+            let mut s = PatchSite::new(site.start_symbol, site.end_symbol, virtual_address);
+            s.signature = signature;
+            s.signature_offset = site.signature_offset.unwrap_or_default();
+            s.signature_match = site.signature_match.unwrap_or_default();
+            s.max_matches = site.max_matches;
+            s.replaces_length = site
+                .replaces_length
+                .or_else(|| expected_bytes.as_ref().map(|b| b.len() as u32));
+            s.expected_bytes = expected_bytes;
+            s.nop_pad = site.nop_pad.unwrap_or(true);
+            s.name = site.name;
+            Ok(s)
+        };
+
+        let patch_sites = conf
             .patch
             .unwrap_or_default()
             .into_iter()
@@ -48,12 +262,100 @@ impl Configuration {
                 buf.pop();
                 buf.push(Path::new(&patch.patchfile));
 
-                Patch::new(
-                    buf,
-                    patch.start_symbol,
-                    patch.end_symbol,
-                    patch.virtual_address,
-                )
+                // A patch may define a single site inline, a list of sites, or both
+                let mut sites = match (patch.start_symbol, patch.end_symbol) {
+                    (Some(start_symbol), Some(end_symbol)) => {
+                        vec![make_site(SiteToml {
+                            name: patch.name,
+                            start_symbol,
+                            end_symbol,
+                            virtual_address: patch.virtual_address,
+                            auto_detect_address: patch.auto_detect_address,
+                            signature: patch.signature,
+                            signature_offset: patch.signature_offset,
+                            signature_match: patch.signature_match,
+                            max_matches: patch.max_matches,
+                            replaces_length: patch.replaces_length,
+                            nop_pad: patch.nop_pad,
+                            expected_bytes: patch.expected_bytes,
+                        })?]
+                    }
+                    (None, None)
+                        if patch.virtual_address.is_none()
+                            && patch.auto_detect_address.is_none()
+                            && patch.signature.is_none() =>
+                    {
+                        Vec::new()
+                    }
+                    _ => bail!(
+                        "Patch '{}' must specify all of start_symbol, end_symbol, and \
+                        virtual_address, auto_detect_address, or signature, or none of them",
+                        patch.patchfile
+                    ),
+                };
+                for site in patch.sites.unwrap_or_default() {
+                    sites.push(make_site(site)?);
+                }
+                if sites.is_empty() {
+                    bail!("Patch '{}' does not define any sites", patch.patchfile);
+                }
+                Ok((buf, sites))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let multi_patch_sites = conf
+            .multi_patch
+            .unwrap_or_default()
+            .into_iter()
+            .map(|patch| {
+                let mut buf = path.to_path_buf();
+                buf.pop();
+                buf.push(Path::new(&patch.patchfile));
+
+                if patch.hooks.is_empty() {
+                    bail!(
+                        "Multi patch '{}' does not define any hooks",
+                        patch.patchfile
+                    );
+                }
+                let sites = patch
+                    .hooks
+                    .into_iter()
+                    .map(make_site)
+                    .collect::<Result<Vec<_>>>()?;
+                Ok((buf, sites))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Adjacent entries sharing a patchfile become a single patch, so the file is only parsed
+        // once. Entries separated by another patchfile stay separate to keep the declared order.
+        let mut grouped: Vec<(PathBuf, Vec<PatchSite>)> = Vec::new();
+        for (patchfile, sites) in patch_sites.into_iter().chain(multi_patch_sites) {
+            match grouped.last_mut().filter(|(p, _)| *p == patchfile) {
+                Some((_, existing)) => existing.extend(sites),
+                None => grouped.push((patchfile, sites)),
+            }
+        }
+        let patches = grouped
+            .into_iter()
+            .map(|(patchfile, sites)| Patch::new(patchfile, sites))
+            .collect::<Result<_>>()?;
+
+        let raw_patches = conf
+            .raw_patch
+            .unwrap_or_default()
+            .into_iter()
+            .map(|patch| -> Result<RawPatch> {
+                let context = || format!("Invalid raw patch at {:#x}", patch.virtual_address);
+                Ok(RawPatch {
+                    virtual_address: patch.virtual_address,
+                    bytes: parse_hex(&patch.bytes).with_context(context)?,
+                    expected_bytes: patch
+                        .expected_bytes
+                        .as_deref()
+                        .map(parse_hex)
+                        .transpose()
+                        .with_context(context)?,
+                })
             })
             .collect::<Result<_>>()?;
 
@@ -70,17 +372,241 @@ impl Configuration {
             })
             .collect::<Result<_>>()?;
 
+        let detours = conf
+            .detour
+            .unwrap_or_default()
+            .into_iter()
+            .map(|detour| {
+                let kind = detour.kind.unwrap_or_default();
+                Detour {
+                    target: detour.target,
+                    symbol_name: detour.symbol,
+                    kind,
+                    with_original: detour.with_original.unwrap_or_default(),
+                    prologue_length: detour.prologue_length.unwrap_or(kind.jmp_len()),
+                }
+            })
+            .collect();
+
+        let extra_sections = conf
+            .extra_sections
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(coff_name, combined_name)| {
+                (section_name(coff_name), section_name(combined_name))
+            })
+            .collect();
+
+        let section_flags = conf
+            .section_flags
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, flags)| {
+                let flags = parse_section_flags(&flags)
+                    .with_context(|| format!("Invalid section_flags for '{name}'"))?;
+                Ok((section_name(name), flags))
+            })
+            .collect::<Result<_>>()?;
+
+        let entry_point = header_address(conf.entry_point_symbol, conf.entry_point_address)
+            .context("Only one of entry_point_symbol and entry_point_address may be specified")?;
+        let tls_address = header_address(conf.tls_address_symbol, conf.tls_address)
+            .context("Only one of tls_address_symbol and tls_address may be specified")?;
+
+        let libraries = conf
+            .library
+            .unwrap_or_default()
+            .into_iter()
+            .map(|lib| {
+                let version = (lib.major, lib.minor, lib.build, lib.flags);
+                match (lib.remove.unwrap_or_default(), version) {
+                    (true, (None, None, None, None)) => Ok(LibraryChange::Remove(lib.name)),
+                    (true, _) => bail!(
+                        "Library '{}' is removed, so it can't specify a version or flags",
+                        lib.name
+                    ),
+                    (false, (Some(major), Some(minor), Some(build), flags)) => {
+                        Ok(LibraryChange::Add {
+                            name: library_name(&lib.name)?,
+                            major,
+                            minor,
+                            build,
+                            flags: flags.unwrap_or_default(),
+                        })
+                    }
+                    (false, _) => bail!(
+                        "Library '{}' must specify major, minor, and build versions",
+                        lib.name
+                    ),
+                }
+            })
+            .collect::<Result<_>>()?;
+
+        let data_sections = conf
+            .data_section
+            .unwrap_or_default()
+            .into_iter()
+            .map(|sec| {
+                let mut buf = path.to_path_buf();
+                buf.pop();
+                buf.push(Path::new(&sec.file));
+
+                let name = section_name(sec.name);
+                let flags = parse_section_flags(sec.flags.as_deref().unwrap_or("PRELOAD"))
+                    .with_context(|| format!("Invalid flags for data section '{name}'"))?;
+                let data = std::fs::read(&buf)
+                    .with_context(|| format!("Failed to read file '{buf:?}'"))?;
+                Ok(DataSection {
+                    name,
+                    path: buf,
+                    data,
+                    virtual_address: sec.virtual_address,
+                    flags,
+                })
+            })
+            .collect::<Result<_>>()?;
+
         if patches.is_empty() {
             warn!("Config file contains 0 patches. Any mod code will be unaccessible.");
         }
-        Ok(Self { patches, modfiles })
+        Ok(Self {
+            patches,
+            raw_patches,
+            modfiles,
+            detours,
+            patch_order: conf.patch_order.unwrap_or_default(),
+            deny_warnings: conf.deny_warnings.unwrap_or_default(),
+            allow_repatch: conf.allow_repatch.unwrap_or_default(),
+            allow_header_patches: conf.allow_header_patches.unwrap_or_default(),
+            no_default_sections: conf.no_default_sections.unwrap_or_default(),
+            extra_sections,
+            section_flags,
+            bss_size: conf
+                .section_addresses
+                .and_then(|s| s.bss_size)
+                .unwrap_or_default(),
+            section_sizes: conf
+                .section_sizes
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(name, size)| (section_name(name), size))
+                .collect(),
+            entry_point,
+            tls_address,
+            libraries,
+            data_sections,
+            deduplicate_constants: conf
+                .optimizations
+                .as_ref()
+                .and_then(|o| o.deduplicate_constants)
+                .unwrap_or_default(),
+            fill_raw_gaps: conf
+                .optimizations
+                .and_then(|o| o.fill_raw_gaps)
+                .unwrap_or_default(),
+            memory_budget: conf.memory_budget.unwrap_or(RETAIL_MEMORY_BUDGET),
+            allow_over_budget: conf.allow_over_budget.unwrap_or_default(),
+            image_kind: conf.image_kind,
+            allow_image_kind_mismatch: conf.allow_image_kind_mismatch.unwrap_or_default(),
+        })
+    }
+
+    /// Allow injecting into an XBE that already contains injected sections by stripping them
+    pub fn set_allow_repatch(&mut self, allow_repatch: bool) {
+        self.allow_repatch = allow_repatch;
+    }
+
+    /// Only inject the sections listed in `extra_sections`
+    pub fn set_no_default_sections(&mut self, no_default_sections: bool) {
+        self.no_default_sections = no_default_sections;
+    }
+
+    /// Names of patch sites to apply first, in order, replacing any `patch_order` from the config
+    pub fn set_patch_order(&mut self, patch_order: Vec<String>) {
+        self.patch_order = patch_order;
+    }
+
+    /// Warn instead of failing when the injected image doesn't fit in its memory budget
+    pub fn set_allow_over_budget(&mut self, allow_over_budget: bool) {
+        self.allow_over_budget = allow_over_budget;
+    }
+
+    /// Names of the sections an injection with this config may add to the XBE: the combined
+    /// sections, the targets of `extra_sections`, and the data sections
+    pub(crate) fn injected_section_names(&self) -> Vec<&str> {
+        COMBINED_SECTION_NAMES
+            .into_iter()
+            .chain(self.extra_sections.values().map(String::as_str))
+            .chain(self.data_sections.iter().map(|s| s.name.as_str()))
+            .unique()
+            .collect()
+    }
+
+    /// Object files written over the XBE at fixed sites
+    pub fn patches(&self) -> &[Patch] {
+        &self.patches
+    }
+
+    /// Object files injected into the XBE as new sections
+    pub fn modfiles(&self) -> &[ObjectFile] {
+        &self.modfiles
+    }
+
+    /// Paths of every object file this configuration reads, mods first and then patches
+    pub fn object_paths(&self) -> impl Iterator<Item = &Path> {
+        self.modfiles
+            .iter()
+            .chain(self.patches.iter().map(|p| &p.patchfile))
+            .map(|obj| obj.path.as_path())
+    }
+}
+
+/// Adds the leading '.' to a section name if it was omitted
+fn section_name(name: String) -> String {
+    if name.starts_with('.') {
+        name
+    } else {
+        format!(".{name}")
     }
 }
 
+/// Replacement header address given by either a symbol or an address, if any
+fn header_address(symbol: Option<String>, address: Option<u32>) -> Result<Option<HeaderAddress>> {
+    match (symbol, address) {
+        (Some(symbol), None) => Ok(Some(HeaderAddress::Symbol(symbol))),
+        (None, Some(address)) => Ok(Some(HeaderAddress::Address(address))),
+        (None, None) => Ok(None),
+        (Some(symbol), Some(address)) => {
+            bail!("Both symbol '{symbol}' and address {address:#x} are specified")
+        }
+    }
+}
+
+/// Parses `|` separated section flag names, such as `"PRELOAD|EXECUTABLE"`
+fn parse_section_flags(s: &str) -> Result<SectionFlags> {
+    s.split('|')
+        .map(|flag| match flag.trim() {
+            "PRELOAD" => Ok(SectionFlags::PRELOAD),
+            "EXECUTABLE" => Ok(SectionFlags::EXECUTABLE),
+            "WRITABLE" => Ok(SectionFlags::WRITABLE),
+            flag => bail!("Unknown section flag '{flag}'"),
+        })
+        .fold_ok(SectionFlags::empty(), |flags, flag| flags | flag)
+}
+
+/// Parses a string of whitespace separated hex bytes, such as `"8B 44 24 08"`
+fn parse_hex(s: &str) -> Result<Vec<u8>> {
+    s.split_whitespace()
+        .map(|b| u8::from_str_radix(b, 16).with_context(|| format!("Invalid hex byte '{b}'")))
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use std::path::PathBuf;
 
+    use crate::patch::PatchError;
+
     use super::*;
     type TestError = std::result::Result<(), Box<dyn std::error::Error>>;
 
@@ -104,6 +630,8 @@ mod tests {
             patch.patchfile.path,
             PathBuf::from("test/bin/framehook_patch.o")
         );
+        assert_eq!(patch.sites.len(), 1);
+        let patch = &patch.sites[0];
         assert_eq!(patch.start_symbol_name, "_framehook_patch".to_string());
         assert_eq!(patch.end_symbol_name, "_framehook_patch_end".to_string());
         assert_eq!(patch.virtual_address, 396158);
@@ -114,6 +642,20 @@ mod tests {
         assert_eq!(modfile.path, PathBuf::from("test/bin/loader.o"));
         let modfile = &config.modfiles[1];
         assert_eq!(modfile.path, PathBuf::from("test/bin/mod.o"));
+
+        // The parsed config is available to library users
+        let site = &config.patches()[0].sites[0];
+        assert_eq!(site.name(), "_framehook_patch");
+        assert_eq!(site.virtual_address, 396158);
+        assert_eq!(config.modfiles()[1].path, PathBuf::from("test/bin/mod.o"));
+        assert_eq!(
+            config.object_paths().collect::<Vec<_>>(),
+            [
+                Path::new("test/bin/loader.o"),
+                Path::new("test/bin/mod.o"),
+                Path::new("test/bin/framehook_patch.o")
+            ]
+        );
         Ok(())
     }
 
@@ -143,11 +685,15 @@ mod tests {
             patch.patchfile.path,
             PathBuf::from("test/bin/framehook_patch.o")
         );
+        assert_eq!(patch.sites.len(), 1);
+        let patch = &patch.sites[0];
         assert_eq!(patch.start_symbol_name, "_framehook_patch".to_string());
         assert_eq!(patch.end_symbol_name, "_framehook_patch_end".to_string());
         assert_eq!(patch.virtual_address, 396158);
         let patch = &config.patches[1];
         assert_eq!(patch.patchfile.path, PathBuf::from("test/bin/mod.o"));
+        assert_eq!(patch.sites.len(), 1);
+        let patch = &patch.sites[0];
         assert_eq!(patch.start_symbol_name, "start".to_string());
         assert_eq!(patch.end_symbol_name, "end".to_string());
         assert_eq!(patch.virtual_address, 1234);
@@ -156,4 +702,578 @@ mod tests {
         assert_eq!(config.modfiles.len(), 0);
         Ok(())
     }
+
+    #[test]
+    fn config_parse_patch_sites() -> TestError {
+        let toml = r#"
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            sites = [
+                { start_symbol = "_framehook_patch", end_symbol = "_framehook_patch_end", virtual_address = 396158 },
+                { start_symbol = "start", end_symbol = "end", virtual_address = 1234, replaces_length = 8 },
+            ]"#;
+
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+
+        assert_eq!(config.patches.len(), 1);
+        let sites = &config.patches[0].sites;
+        assert_eq!(sites.len(), 2);
+        assert_eq!(sites[0].start_symbol_name, "_framehook_patch");
+        assert_eq!(sites[0].end_symbol_name, "_framehook_patch_end");
+        assert_eq!(sites[0].virtual_address, 396158);
+        assert_eq!(sites[0].replaces_length, None);
+        assert_eq!(sites[1].start_symbol_name, "start");
+        assert_eq!(sites[1].end_symbol_name, "end");
+        assert_eq!(sites[1].virtual_address, 1234);
+        assert_eq!(sites[1].replaces_length, Some(8));
+        Ok(())
+    }
+
+    #[test]
+    fn config_parse_multi_patch_hooks() -> TestError {
+        let toml = r#"
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396158
+
+            [[multi_patch]]
+            patchfile = "framehook_patch.o"
+            hooks = [
+                { start_symbol = "_framehook_patch", end_symbol = "_framehook_patch_end", virtual_address = 0x1000 },
+                { name = "second", start_symbol = "start", end_symbol = "end", virtual_address = 1234 },
+            ]
+
+            [[multi_patch]]
+            patchfile = "mod.o"
+            hooks = [{ start_symbol = "_test", end_symbol = "_test2", virtual_address = 0x2000 }]"#;
+
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+
+        // Hooks are added to the adjacent patch already using the same file
+        assert_eq!(config.patches.len(), 2);
+        let patch = &config.patches[0];
+        assert_eq!(
+            patch.patchfile.path,
+            PathBuf::from("test/bin/framehook_patch.o")
+        );
+        let addresses: Vec<_> = patch.sites.iter().map(|s| s.virtual_address).collect();
+        assert_eq!(addresses, [396158, 0x1000, 1234]);
+        assert_eq!(patch.sites[2].name(), "second");
+        assert_eq!(
+            config.patches[1].patchfile.path,
+            PathBuf::from("test/bin/mod.o")
+        );
+        assert_eq!(config.patches[1].sites[0].start_symbol_name, "_test");
+
+        let toml = r#"
+            [[multi_patch]]
+            patchfile = "framehook_patch.o"
+            hooks = []"#;
+        assert!(Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn config_parse_non_adjacent_patchfiles() -> TestError {
+        let toml = r#"
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396158
+
+            [[patch]]
+            patchfile = "mod.o"
+            start_symbol = "start"
+            end_symbol = "end"
+            virtual_address = 1234
+
+            [[multi_patch]]
+            patchfile = "framehook_patch.o"
+            hooks = [{ start_symbol = "_test", end_symbol = "_test2", virtual_address = 0x1000 }]"#;
+
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+
+        // The same patchfile separated by another one is kept as two patches, in declared order
+        let paths: Vec<_> = config
+            .patches
+            .iter()
+            .map(|p| p.patchfile.path.clone())
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                PathBuf::from("test/bin/framehook_patch.o"),
+                PathBuf::from("test/bin/mod.o"),
+                PathBuf::from("test/bin/framehook_patch.o"),
+            ]
+        );
+        assert_eq!(config.patches[0].sites[0].virtual_address, 396158);
+        assert_eq!(config.patches[2].sites[0].virtual_address, 0x1000);
+        Ok(())
+    }
+
+    #[test]
+    fn config_parse_expected_bytes() -> TestError {
+        let toml = r#"
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396158
+            expected_bytes = "A1 c0 2A 37 00""#;
+
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+
+        let site = &config.patches[0].sites[0];
+        assert_eq!(
+            site.expected_bytes,
+            Some(vec![0xA1, 0xC0, 0x2A, 0x37, 0x00])
+        );
+        assert_eq!(site.replaces_length, Some(5));
+        Ok(())
+    }
+
+    #[test]
+    fn config_parse_signature() -> TestError {
+        let toml = r#"
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            sites = [
+                { start_symbol = "_framehook_patch", end_symbol = "_framehook_patch_end", signature = "A1 ?? ?? ?? 00", signature_offset = -2 },
+                { start_symbol = "_framehook_patch", end_symbol = "_framehook_patch_end", virtual_address = 396158 },
+            ]"#;
+
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+
+        let sites = &config.patches[0].sites;
+        assert_eq!(
+            sites[0].signature,
+            Some("A1 ?? ?? ?? 00".parse::<Signature>()?)
+        );
+        assert_eq!(sites[0].signature_offset, -2);
+        assert_eq!(sites[1].signature, None);
+
+        // A site must have exactly one of virtual_address and signature
+        let toml = r#"
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396158
+            signature = "A1 ?? ?? ?? 00""#;
+        assert!(Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn config_parse_signature_match() -> TestError {
+        let toml = r#"
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            signature = "A1 ?? ?? ?? 00"
+            match = "all"
+            max_matches = 8"#;
+
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+
+        let site = &config.patches[0].sites[0];
+        assert_eq!(site.signature_match, SignatureMatch::All);
+        assert_eq!(site.max_matches, Some(8));
+
+        // Matching only makes sense for sites located by signature
+        let toml = r#"
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396158
+            match = "all""#;
+        assert!(Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn config_parse_raw_patch() -> TestError {
+        let toml = r#"
+            [[raw_patch]]
+            virtual_address = 396158
+            bytes = "90 90 90 90 90"
+            expected_bytes = "A1 C0 2A 37 00"
+
+            [[raw_patch]]
+            virtual_address = 0x1000
+            bytes = "C3""#;
+
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+
+        assert_eq!(config.raw_patches.len(), 2);
+        let patch = &config.raw_patches[0];
+        assert_eq!(patch.virtual_address, 396158);
+        assert_eq!(patch.bytes, vec![0x90; 5]);
+        assert_eq!(
+            patch.expected_bytes,
+            Some(vec![0xA1, 0xC0, 0x2A, 0x37, 0x00])
+        );
+        let patch = &config.raw_patches[1];
+        assert_eq!(patch.bytes, vec![0xC3]);
+        assert_eq!(patch.expected_bytes, None);
+
+        let toml = r#"
+            [[raw_patch]]
+            virtual_address = 396158
+            bytes = "90 9""#;
+        assert!(Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn config_parse_symbol_aliases() -> TestError {
+        for (start_key, end_key) in [
+            ("start_symbol", "end_symbol"),
+            ("begin_symbol", "end_symbol_name"),
+            ("hook_start", "hook_end"),
+        ] {
+            let toml = format!(
+                r#"
+                [[patch]]
+                patchfile = "framehook_patch.o"
+                {start_key} = "_framehook_patch"
+                {end_key} = "_framehook_patch_end"
+                virtual_address = 396158
+
+                [[patch]]
+                patchfile = "framehook_patch.o"
+                sites = [{{ {start_key} = "start", {end_key} = "end", virtual_address = 1234 }}]"#
+            );
+            let config = Configuration::from_toml(&toml, Path::new("test/bin/fakefile.toml"))?;
+
+            let sites: Vec<_> = config.patches.iter().flat_map(|p| p.sites.iter()).collect();
+            assert_eq!(sites.len(), 2, "{start_key}/{end_key}");
+            assert_eq!(sites[0].start_symbol_name, "_framehook_patch");
+            assert_eq!(sites[0].end_symbol_name, "_framehook_patch_end");
+            assert_eq!(sites[1].start_symbol_name, "start");
+            assert_eq!(sites[1].end_symbol_name, "end");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn config_parse_memory_budget() -> TestError {
+        let config = Configuration::from_toml("", Path::new("test/bin/fakefile.toml"))?;
+        assert_eq!(config.memory_budget, RETAIL_MEMORY_BUDGET);
+        assert!(!config.allow_over_budget);
+
+        let toml = r#"
+            memory_budget = 0x8000000
+            allow_over_budget = true"#;
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        assert_eq!(config.memory_budget, crate::memory::DEVKIT_MEMORY_BUDGET);
+        assert!(config.allow_over_budget);
+        Ok(())
+    }
+
+    #[test]
+    fn config_parse_image_kind() -> TestError {
+        let config = Configuration::from_toml("", Path::new("test/bin/fakefile.toml"))?;
+        assert_eq!(config.image_kind, None);
+
+        let toml = r#"image_kind = "retail""#;
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        assert_eq!(config.image_kind, Some(ImageKind::Retail));
+        assert!(Configuration::from_toml(
+            r#"image_kind = "prototype""#,
+            Path::new("test/bin/fakefile.toml")
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn config_parse_auto_detect_address() -> TestError {
+        let toml = r#"
+            [[patch]]
+            patchfile = "patch_site.o"
+            start_symbol = "_patch_site_00060B7E_start"
+            end_symbol = "_framehook_patch_end"
+            auto_detect_address = true"#;
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        assert_eq!(config.patches[0].sites[0].virtual_address, 0x60B7E);
+
+        let err = Configuration::from_toml(
+            &toml.replace("_patch_site_00060B7E_start", "_framehook_patch"),
+            Path::new("test/bin/fakefile.toml"),
+        )
+        .expect_err("Detected an address from an unconventional name");
+        assert!(matches!(
+            err.downcast_ref::<PatchError>(),
+            Some(PatchError::NoAddressInSymbol(_))
+        ));
+
+        assert!(Configuration::from_toml(
+            &format!("{toml}\nvirtual_address = 396158"),
+            Path::new("test/bin/fakefile.toml"),
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn config_parse_optimizations() -> TestError {
+        let config = Configuration::from_toml("", Path::new("test/bin/fakefile.toml"))?;
+        assert!(!config.deduplicate_constants);
+        assert!(!config.fill_raw_gaps);
+
+        let toml = r#"
+            [optimizations]
+            deduplicate_constants = true
+            fill_raw_gaps = true"#;
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        assert!(config.deduplicate_constants);
+        assert!(config.fill_raw_gaps);
+        Ok(())
+    }
+
+    #[test]
+    fn config_parse_bss_size() -> TestError {
+        let config = Configuration::from_toml("", Path::new("test/bin/fakefile.toml"))?;
+        assert_eq!(config.bss_size, 0);
+
+        let toml = r#"
+            [section_addresses]
+            bss_size = 1024"#;
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        assert_eq!(config.bss_size, 1024);
+        Ok(())
+    }
+
+    #[test]
+    fn config_parse_extra_sections() -> TestError {
+        let toml = r#"
+            [extra_sections]
+            xbox = ".mxbox"
+            ".hook" = "mhook""#;
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+
+        assert_eq!(
+            config.extra_sections,
+            HashMap::from([
+                (".xbox".to_string(), ".mxbox".to_string()),
+                (".hook".to_string(), ".mhook".to_string())
+            ])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn config_parse_section_flags() -> TestError {
+        let toml = r#"
+            [section_flags]
+            mtext = "PRELOAD|EXECUTABLE"
+            ".mdata" = " PRELOAD ""#;
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+
+        assert_eq!(
+            config.section_flags,
+            HashMap::from([
+                (
+                    ".mtext".to_string(),
+                    SectionFlags::PRELOAD | SectionFlags::EXECUTABLE
+                ),
+                (".mdata".to_string(), SectionFlags::PRELOAD)
+            ])
+        );
+
+        let toml = r#"
+            [section_flags]
+            mtext = "PRELOAD|READABLE""#;
+        assert!(Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn config_parse_section_sizes() -> TestError {
+        let toml = r#"
+            [section_sizes]
+            mdata = 8192
+            ".mbss" = 0x100"#;
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+
+        assert_eq!(
+            config.section_sizes,
+            HashMap::from([(".mdata".to_string(), 8192), (".mbss".to_string(), 0x100)])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn config_parse_header_addresses() -> TestError {
+        let config = Configuration::from_toml(
+            r#"entry_point_symbol = "_main""#,
+            Path::new("test/bin/fakefile.toml"),
+        )?;
+        assert_eq!(
+            config.entry_point,
+            Some(HeaderAddress::Symbol("_main".to_string()))
+        );
+
+        let config = Configuration::from_toml(
+            "entry_point_address = 0x12345",
+            Path::new("test/bin/fakefile.toml"),
+        )?;
+        assert_eq!(config.entry_point, Some(HeaderAddress::Address(0x12345)));
+
+        let toml = r#"
+            entry_point_symbol = "_main"
+            entry_point_address = 0x12345"#;
+        assert!(Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml")).is_err());
+
+        let toml = r#"
+            tls_address_symbol = "_mod_tls_data"
+            entry_point_address = 0x12345"#;
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        assert_eq!(
+            config.tls_address,
+            Some(HeaderAddress::Symbol("_mod_tls_data".to_string()))
+        );
+        assert_eq!(config.entry_point, Some(HeaderAddress::Address(0x12345)));
+        Ok(())
+    }
+
+    #[test]
+    fn config_parse_libraries() -> TestError {
+        let toml = r#"
+            [[library]]
+            name = "MYLIB"
+            major = 1
+            minor = 0
+            build = 5849
+            flags = 0x4000
+
+            [[library]]
+            name = "XACTENG"
+            remove = true"#;
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        assert_eq!(
+            config.libraries,
+            [
+                LibraryChange::Add {
+                    name: *b"MYLIB\0\0\0",
+                    major: 1,
+                    minor: 0,
+                    build: 5849,
+                    flags: 0x4000,
+                },
+                LibraryChange::Remove("XACTENG".to_string()),
+            ]
+        );
+
+        for toml in [
+            r#"library = [{ name = "MYLIB", major = 1 }]"#,
+            r#"library = [{ name = "MYLIB", major = 1, minor = 0, build = 1, remove = true }]"#,
+            r#"library = [{ name = "LONGNAME1", major = 1, minor = 0, build = 1 }]"#,
+        ] {
+            assert!(Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml")).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn config_parse_data_sections() -> TestError {
+        let toml = r#"
+            [[data_section]]
+            name = "blob"
+            file = "blob.bin"
+
+            [[data_section]]
+            name = ".mfont"
+            file = "blob.bin"
+            virtual_address = 0x800000
+            flags = "PRELOAD|WRITABLE""#;
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+
+        assert_eq!(config.data_sections.len(), 2);
+        let blob = &config.data_sections[0];
+        assert_eq!(blob.name, ".blob");
+        assert_eq!(blob.path, PathBuf::from("test/bin/blob.bin"));
+        assert_eq!(blob.data, std::fs::read("test/bin/blob.bin")?);
+        assert_eq!(blob.virtual_address, None);
+        assert_eq!(blob.flags, SectionFlags::PRELOAD);
+        let font = &config.data_sections[1];
+        assert_eq!(font.virtual_address, Some(0x800000));
+        assert_eq!(font.flags, SectionFlags::PRELOAD | SectionFlags::WRITABLE);
+
+        let toml = r#"data_section = [{ name = "missing", file = "missing.bin" }]"#;
+        assert!(Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn hex_parse() {
+        assert_eq!(parse_hex("").unwrap(), Vec::<u8>::new());
+        assert_eq!(parse_hex("90").unwrap(), vec![0x90]);
+        assert_eq!(
+            parse_hex(" 8b 44\t24  08 ").unwrap(),
+            vec![0x8B, 0x44, 0x24, 0x08]
+        );
+        assert!(parse_hex("8B4").is_err());
+        assert!(parse_hex("8B 4G").is_err());
+        assert!(parse_hex("8B44").is_err());
+    }
+
+    #[test]
+    fn config_parse_incomplete_site() {
+        let toml = r#"
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            virtual_address = 396158"#;
+
+        assert!(Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml")).is_err());
+    }
+
+    #[test]
+    fn config_parse_detour() -> TestError {
+        let toml = r#"
+            [[detour]]
+            target = 396158
+            symbol = "_my_func"
+
+            [[detour]]
+            target = "_framehook_patch"
+            symbol = "_my_other_func"
+            with_original = true
+            prologue_length = 6
+
+            [[detour]]
+            target = 396158
+            symbol = "_my_cave"
+            kind = "short_jmp""#;
+
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+
+        assert_eq!(config.detours.len(), 3);
+        let detour = &config.detours[0];
+        assert_eq!(detour.target, DetourTarget::Address(396158));
+        assert_eq!(detour.symbol_name, "_my_func");
+        assert!(!detour.with_original);
+        assert_eq!(detour.prologue_length, 5);
+        let detour = &config.detours[1];
+        assert_eq!(
+            detour.target,
+            DetourTarget::Symbol("_framehook_patch".to_string())
+        );
+        assert_eq!(detour.original_symbol_name(), "_my_other_func_original");
+        assert!(detour.with_original);
+        assert_eq!(detour.prologue_length, 6);
+        assert_eq!(detour.kind, HookKind::Jmp);
+        let detour = &config.detours[2];
+        assert_eq!(detour.kind, HookKind::ShortJmp);
+        assert_eq!(detour.prologue_length, 2);
+        Ok(())
+    }
 }
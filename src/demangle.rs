@@ -0,0 +1,93 @@
+use itertools::Itertools;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum DemangleError {
+    #[error("Symbol '{0}' is ambiguous, it matches the mangled symbols [{1}]")]
+    Ambiguous(String, String),
+}
+
+/// Best-effort demangling of an MSVC decorated name to its qualified name, such as
+/// `?framehook@ns@@YAXXZ` to `ns::framehook`. Special members (constructors, operators, ...) and
+/// templates are not supported and return `None`, as do names that aren't MSVC decorated.
+pub(crate) fn qualified_name(mangled: &str) -> Option<String> {
+    let (name, _signature) = mangled.strip_prefix('?')?.split_once("@@")?;
+    if name.is_empty() || name.starts_with('?') || name.contains('$') {
+        return None;
+    }
+    Some(name.split('@').rev().join("::"))
+}
+
+/// Resolves a configured symbol `name` to one of `candidates`. An exact match is preferred,
+/// otherwise `name` is compared with the demangled qualified name and unqualified base name of
+/// each MSVC decorated candidate. Returns `None` if nothing matches.
+pub(crate) fn resolve_name<'a>(
+    name: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> Result<Option<&'a str>, DemangleError> {
+    let mut matches = Vec::new();
+    for candidate in candidates {
+        if candidate == name {
+            return Ok(Some(candidate));
+        }
+        let matched = qualified_name(candidate).is_some_and(|qualified| {
+            qualified == name || qualified.rsplit("::").next() == Some(name)
+        });
+        if matched && !matches.contains(&candidate) {
+            matches.push(candidate);
+        }
+    }
+
+    match matches.as_slice() {
+        [] => Ok(None),
+        [mangled] => Ok(Some(mangled)),
+        _ => Err(DemangleError::Ambiguous(
+            name.to_string(),
+            matches.join(", "),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn demangle() {
+        assert_eq!(
+            qualified_name("?framehook@@YAXXZ").as_deref(),
+            Some("framehook")
+        );
+        assert_eq!(
+            qualified_name("?update@Player@game@@QAEXM@Z").as_deref(),
+            Some("game::Player::update")
+        );
+        assert_eq!(qualified_name("??0Player@@QAE@XZ"), None);
+        assert_eq!(qualified_name("?$vector@H@std@@"), None);
+        assert_eq!(qualified_name("_framehook"), None);
+    }
+
+    #[test]
+    fn resolve() {
+        let names = [
+            "_framehook_patch",
+            "?framehook@@YAXXZ",
+            "?update@Player@@QAEXM@Z",
+            "?update@Enemy@@QAEXM@Z",
+        ];
+        assert_eq!(
+            resolve_name("_framehook_patch", names).unwrap(),
+            Some("_framehook_patch")
+        );
+        assert_eq!(
+            resolve_name("framehook", names).unwrap(),
+            Some("?framehook@@YAXXZ")
+        );
+        assert_eq!(
+            resolve_name("Player::update", names).unwrap(),
+            Some("?update@Player@@QAEXM@Z")
+        );
+        assert!(resolve_name("update", names).is_err());
+        assert_eq!(resolve_name("missing", names).unwrap(), None);
+    }
+}
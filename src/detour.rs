@@ -0,0 +1,266 @@
+use crate::{
+    demangle, header,
+    patch::{overwrite, HeaderWrites, NOP},
+    reloc::{strip_null, SectionMap, SymbolTable},
+    section::{SectionExt, XbeExt},
+};
+use anyhow::{bail, Result};
+use log::info;
+use thiserror::Error;
+use xbe::Xbe;
+
+/// x86 `jmp rel32` opcode
+const JMP_REL32: u8 = 0xE9;
+/// Length in bytes of a `jmp rel32` instruction
+const JMP_LEN: u32 = 5;
+/// x86 `jmp rel8` opcode
+const JMP_REL8: u8 = 0xEB;
+/// Length in bytes of a `jmp rel8` instruction
+const SHORT_JMP_LEN: u32 = 2;
+
+#[derive(Debug, Error)]
+pub enum DetourError {
+    #[error("Detour target {0:#x} is not within an executable section")]
+    NonExecutableTarget(u32),
+    #[error("Prologue length {0} is too short to hold a jump (minimum {1} bytes)")]
+    PrologueTooShort(u32, u32),
+    #[error("Short jump from {0:#x} to {1:#x} has displacement {2}, outside the range -128..=127")]
+    ShortJmpOutOfRange(u32, u32, i64),
+    #[error("Symbol '{0}' at {1:#x} lies within the {2} bytes overwritten at {3:#x}")]
+    SymbolOverwritten(String, u32, u32, u32),
+    #[error("The {1} bytes overwritten at {0:#x} run past the end of section '{2}' at {3:#x}")]
+    PrologueCrossesSection(u32, u32, String, u32),
+}
+
+/// The location a detour redirects from
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(untagged)]
+pub(crate) enum DetourTarget {
+    Address(u32),
+    Symbol(String),
+}
+
+/// The instruction written at a detour's target
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum HookKind {
+    /// 5-byte `jmp rel32`, able to reach any address
+    #[default]
+    Jmp,
+    /// 2-byte `jmp rel8`, for targets with too little room for a full jump. The destination must
+    /// be within 127 bytes, such as a code cave next to the target.
+    ShortJmp,
+}
+
+impl HookKind {
+    /// Length in bytes of the jump instruction
+    pub(crate) fn jmp_len(self) -> u32 {
+        match self {
+            HookKind::Jmp => JMP_LEN,
+            HookKind::ShortJmp => SHORT_JMP_LEN,
+        }
+    }
+}
+
+/// Redirects execution of a base game function to a function defined by a mod
+#[derive(Debug)]
+pub(crate) struct Detour {
+    pub(crate) target: DetourTarget,
+    pub(crate) symbol_name: String,
+    pub(crate) kind: HookKind,
+    /// Build a trampoline so the mod can still call the original function
+    pub(crate) with_original: bool,
+    /// Number of bytes of whole instructions at the start of the target function. These are
+    /// overwritten by the jump and copied to the trampoline as-is, so relative branches within
+    /// the prologue are not supported.
+    pub(crate) prologue_length: u32,
+}
+
+impl Detour {
+    /// Replaces the destination and target symbol names with the MSVC mangled symbols they refer
+    /// to, when they only match a symbol once demangled.
+    pub(crate) fn resolve_symbol_names(&mut self, symbol_table: &SymbolTable) -> Result<()> {
+        let names = std::iter::once(&mut self.symbol_name).chain(match &mut self.target {
+            DetourTarget::Symbol(name) => Some(name),
+            DetourTarget::Address(_) => None,
+        });
+        for name in names {
+            if let Some(mangled) =
+                demangle::resolve_name(name.as_str(), symbol_table.iter().map(|(name, _)| name))?
+            {
+                if mangled != name.as_str() {
+                    info!("Resolved symbol '{name}' to '{mangled}'");
+                    *name = mangled.to_string();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Name of the synthetic symbol pointing at this detour's trampoline
+    pub(crate) fn original_symbol_name(&self) -> String {
+        format!("{}_original", self.symbol_name)
+    }
+
+    /// Length of the trampoline: the relocated prologue followed by a jump back
+    fn trampoline_len(&self) -> u32 {
+        self.prologue_length + JMP_LEN
+    }
+
+    /// Writes the jump to the mod function at the target address and, if requested, fills the
+    /// trampoline reserved at `trampoline_offset` within `.mtext` and defines its symbol. Returns
+    /// the target address and the bytes overwritten there.
+    ///
+    /// This must run after addresses are assigned but before relocations are processed so mods
+    /// can reference the trampoline symbol.
+    pub(crate) fn apply(
+        &self,
+        xbe: &mut Xbe,
+        symbol_table: &mut SymbolTable,
+        section_map: &mut SectionMap<'_>,
+        trampoline_offset: Option<u32>,
+    ) -> Result<(u32, Vec<u8>)> {
+        if self.prologue_length < self.kind.jmp_len() {
+            bail!(DetourError::PrologueTooShort(
+                self.prologue_length,
+                self.kind.jmp_len()
+            ));
+        }
+
+        let target = match &self.target {
+            DetourTarget::Address(address) => *address,
+            DetourTarget::Symbol(name) => symbol_table
+                .get(name)
+                .ok_or_else(|| symbol_table.undefined_symbol(name))?,
+        };
+        let destination = symbol_table
+            .get(&self.symbol_name)
+            .ok_or_else(|| symbol_table.undefined_symbol(&self.symbol_name))?;
+
+        // Validate the target region
+        let section = match xbe.section_containing(target) {
+            Some(s) if s.flags.contains(xbe::SectionFlags::EXECUTABLE) => s,
+            _ => bail!(DetourError::NonExecutableTarget(target)),
+        };
+        let section_end = section.virtual_range().end;
+        if target.saturating_add(self.prologue_length) > section_end {
+            bail!(DetourError::PrologueCrossesSection(
+                target,
+                self.prologue_length,
+                strip_null(&section.name).to_string(),
+                section_end
+            ));
+        }
+        // The entry point is the one function start the XBE itself records
+        let xbe_symbols = header::entry_point(xbe)
+            .ok()
+            .map(|address| ("entry point", address));
+        if let Some((name, address)) = symbol_table
+            .iter()
+            .chain(xbe_symbols)
+            .find(|(_, address)| (target + 1..target + self.prologue_length).contains(address))
+        {
+            bail!(DetourError::SymbolOverwritten(
+                name.to_string(),
+                address,
+                self.prologue_length,
+                target
+            ));
+        }
+
+        let mut bytes = vec![NOP; self.prologue_length as usize];
+        let jmp = &mut bytes[..self.kind.jmp_len() as usize];
+        match self.kind {
+            HookKind::Jmp => write_jmp(jmp, target, destination),
+            HookKind::ShortJmp => write_short_jmp(jmp, target, destination)?,
+        }
+        let original = overwrite(xbe, &mut HeaderWrites::new(false), target, &bytes)?;
+
+        if let Some(offset) = trampoline_offset {
+            let mtext = section_map
+                .get_mut(".text")
+                .expect("Trampoline space was not reserved");
+            let address = mtext.virtual_address + offset;
+            let trampoline =
+                &mut mtext.bytes[offset as usize..(offset + self.trampoline_len()) as usize];
+
+            let (prologue, jmp) = trampoline.split_at_mut(original.len());
+            prologue.copy_from_slice(&original);
+            write_jmp(
+                jmp,
+                address + self.prologue_length,
+                target + self.prologue_length,
+            );
+
+            info!(
+                "Defining trampoline '{}' at {address:#x}",
+                self.original_symbol_name()
+            );
+            symbol_table.insert(self.original_symbol_name(), address);
+        }
+
+        Ok((target, original))
+    }
+}
+
+/// Reserves space in `.mtext` for the trampolines of detours that keep the original function
+/// callable. Returns the offset of each detour's trampoline within `.mtext`.
+pub(crate) fn reserve_trampolines(
+    detours: &[Detour],
+    section_map: &mut SectionMap<'_>,
+) -> Vec<Option<u32>> {
+    detours
+        .iter()
+        .map(|d| {
+            d.with_original
+                .then(|| section_map.reserve(".mtext", d.trampoline_len()))
+        })
+        .collect()
+}
+
+/// Writes a `jmp rel32` located at virtual address `from` targeting virtual address `to`
+fn write_jmp(buf: &mut [u8], from: u32, to: u32) {
+    buf[0] = JMP_REL32;
+    buf[1..JMP_LEN as usize].copy_from_slice(&to.wrapping_sub(from + JMP_LEN).to_le_bytes());
+}
+
+/// Writes a `jmp rel8` located at virtual address `from` targeting virtual address `to`, failing if
+/// `to` is out of range
+fn write_short_jmp(buf: &mut [u8], from: u32, to: u32) -> Result<()> {
+    let displacement = i64::from(to) - i64::from(from + SHORT_JMP_LEN);
+    let displacement = i8::try_from(displacement)
+        .map_err(|_| DetourError::ShortJmpOutOfRange(from, to, displacement))?;
+    buf[0] = JMP_REL8;
+    buf[1] = displacement as u8;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jmp_displacement() {
+        let mut buf = [0; 5];
+        write_jmp(&mut buf, 0x1000, 0x2000);
+        assert_eq!(buf, [0xE9, 0xFB, 0x0F, 0x00, 0x00]);
+
+        write_jmp(&mut buf, 0x2000, 0x1000);
+        assert_eq!(buf, [0xE9, 0xFB, 0xEF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn short_jmp_displacement() {
+        let mut buf = [0; 2];
+        write_short_jmp(&mut buf, 0x1000, 0x1002 + 127).unwrap();
+        assert_eq!(buf, [0xEB, 0x7F]);
+
+        write_short_jmp(&mut buf, 0x1000, 0x1002 - 128).unwrap();
+        assert_eq!(buf, [0xEB, 0x80]);
+
+        let err = write_short_jmp(&mut buf, 0x1000, 0x1002 + 128).unwrap_err();
+        assert!(err.to_string().contains("displacement 128"));
+        let err = write_short_jmp(&mut buf, 0x1000, 0x1002 - 129).unwrap_err();
+        assert!(err.to_string().contains("displacement -129"));
+    }
+}
@@ -0,0 +1,223 @@
+//! Errors produced while injecting into an XBE.
+//!
+//! [`inject`](crate::inject) fails with an [`InjectError`]. Its [`kind`](InjectError::kind)
+//! classifies the failure, and its [`step`](InjectError::step) is the [`InjectStep`] identifying
+//! the patch, detour, or file involved. The innermost cause is one of the typed errors re-exported
+//! here, and can be recovered without parsing the message:
+//!
+//! ```ignore
+//! match (err.kind(), err.step()) {
+//!     (InjectErrorKind::SymbolResolution, Some(InjectStep::Patch { name, .. })) => {
+//!         match err.root_cause().downcast_ref::<RelocationError>() {
+//!             Some(RelocationError::SymbolAddress(symbol)) => { /* `name` uses undefined `symbol` */ }
+//!             _ => {}
+//!         }
+//!     }
+//!     _ => {}
+//! }
+//! ```
+use std::{fmt::Display, path::PathBuf};
+use thiserror::Error;
+
+pub use crate::{
+    demangle::DemangleError,
+    detour::DetourError,
+    header::HeaderError,
+    library::LibraryError,
+    logo::LogoError,
+    memory::BudgetError,
+    obj::ObjectError,
+    patch::{AddressPosition, NearestSection, PatchError},
+    reloc::RelocationError,
+    section::SectionError,
+    signature::SignatureError,
+};
+
+/// Why an injection failed, see [`InjectError::kind`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InjectErrorKind {
+    /// A file couldn't be read or written
+    Io,
+    /// A symbol used by a patch, detour, or modfile isn't defined by any of them
+    SymbolResolution,
+    /// A relocation in a modfile or patch couldn't be processed
+    Relocation,
+    /// A patch couldn't be applied to the XBE
+    Patch,
+    /// The injected XBE has problems that may stop it from loading, and `deny_warnings` is set
+    Validation,
+    /// An object file or the XBE uses a feature that isn't supported
+    Unsupported,
+    /// Any other failure, such as an invalid signature or library change
+    Other,
+}
+
+/// How an injection handles patches that fail to apply
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ErrorMode {
+    /// Stop at the first failure, instead of applying the remaining patches to report every
+    /// failure
+    pub fail_fast: bool,
+}
+
+/// Failures collected from the patches of an injection, reported once every patch is applied
+#[derive(Debug, Error)]
+#[error("{} patches failed to apply", .0.len())]
+pub(crate) struct CollectedErrors(pub(crate) Vec<anyhow::Error>);
+
+/// An injection that failed, with the cause of the failure and the step it happened in
+#[derive(Debug)]
+pub struct InjectError {
+    kind: InjectErrorKind,
+    step: Option<InjectStep>,
+    source: anyhow::Error,
+    /// Failures after this one, collected unless [`ErrorMode::fail_fast`] is set
+    others: Vec<InjectError>,
+}
+
+impl InjectError {
+    pub fn kind(&self) -> InjectErrorKind {
+        self.kind
+    }
+
+    /// The step of the injection that failed, if the failure is specific to one
+    pub fn step(&self) -> Option<&InjectStep> {
+        self.step.as_ref()
+    }
+
+    /// The innermost cause of the failure
+    pub fn root_cause(&self) -> &(dyn std::error::Error + 'static) {
+        self.source.root_cause()
+    }
+
+    /// Every failure of the injection, starting with this one
+    pub fn errors(&self) -> impl Iterator<Item = &InjectError> {
+        std::iter::once(self).chain(self.others.iter())
+    }
+
+    /// The first cause of the failure of type `E`, such as a [`PatchError`]
+    pub fn downcast_ref<E>(&self) -> Option<&E>
+    where
+        E: Display + std::fmt::Debug + Send + Sync + 'static,
+    {
+        self.source.downcast_ref()
+    }
+}
+
+impl Display for InjectError {
+    /// The alternate form includes each cause of the failure, like [`anyhow::Error`]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if f.alternate() {
+            write!(f, "{:#}", self.source)?;
+        } else {
+            write!(f, "{}", self.source)?;
+        }
+        if !self.others.is_empty() {
+            write!(f, " (and {} more errors)", self.others.len())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for InjectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source.chain().nth(1)
+    }
+}
+
+impl From<anyhow::Error> for InjectError {
+    fn from(source: anyhow::Error) -> Self {
+        let source = match source.downcast::<CollectedErrors>() {
+            Ok(CollectedErrors(errors)) => {
+                let mut errors = errors.into_iter().map(Self::from);
+                let mut first = errors.next().expect("Collected no errors");
+                first.others = errors.collect();
+                return first;
+            }
+            Err(source) => source,
+        };
+
+        let step = source.downcast_ref::<InjectStep>().cloned();
+        let root = source.root_cause();
+        let kind = if matches!(step, Some(InjectStep::Validation(_))) {
+            InjectErrorKind::Validation
+        } else if source.chain().any(|e| e.is::<std::io::Error>()) {
+            InjectErrorKind::Io
+        } else if matches!(
+            root.downcast_ref::<RelocationError>(),
+            Some(RelocationError::SymbolAddress(_))
+        ) || matches!(
+            root.downcast_ref::<PatchError>(),
+            Some(PatchError::UndefinedSymbol(..))
+        ) {
+            InjectErrorKind::SymbolResolution
+        } else if matches!(
+            root.downcast_ref::<ObjectError>(),
+            Some(ObjectError::UnsupportedMachine(_))
+        ) || matches!(
+            root.downcast_ref::<RelocationError>(),
+            Some(RelocationError::UnsupportedStorageClass(_))
+        ) || matches!(
+            root.downcast_ref::<HeaderError>(),
+            Some(HeaderError::UnknownEntryKey)
+        ) {
+            InjectErrorKind::Unsupported
+        } else if root.is::<RelocationError>() {
+            InjectErrorKind::Relocation
+        } else if root.is::<PatchError>() {
+            InjectErrorKind::Patch
+        } else {
+            InjectErrorKind::Other
+        };
+
+        Self {
+            kind,
+            step,
+            source,
+            others: Vec::new(),
+        }
+    }
+}
+
+/// The step of an injection that failed
+#[derive(Debug, Clone, Error)]
+pub enum InjectStep {
+    #[error("Failed to locate patch site '{name}'")]
+    Signature { name: String },
+    #[error("Failed to install detour to '{symbol}'")]
+    Detour { symbol: String },
+    #[error("Failed to process relocations in section '{section}' of '{file:?}'")]
+    Relocation { file: PathBuf, section: String },
+    #[error("Failed to apply patch '{name}' at {virtual_address:#x} from '{file:?}'")]
+    Patch {
+        name: String,
+        start_symbol: String,
+        virtual_address: u32,
+        file: PathBuf,
+    },
+    #[error("Failed to apply raw patch at {virtual_address:#x}")]
+    RawPatch { virtual_address: u32 },
+    #[error("Failed to set the entry point")]
+    EntryPoint,
+    #[error("Failed to set the TLS address")]
+    TlsAddress,
+    #[error("Failed to change the library version of '{name}'")]
+    Library { name: String },
+    #[error("Failed to add data section '{name}' from '{file:?}'")]
+    DataSection { name: String, file: PathBuf },
+    #[error("Injected XBE has {0} validation issues")]
+    Validation(usize),
+}
+
+/// Reasons an XBE can't be restored from an injection manifest
+#[derive(Debug, Error)]
+pub enum RestoreError {
+    #[error("Region of '{0}' at {1:#x} is not mapped by the XBE")]
+    Unmapped(String, u32),
+    #[error("Region of '{0}' at {1:#x} matches neither the patched nor the original bytes")]
+    RegionModified(String, u32),
+    #[error("Injected section '{0}' at {1:#x} is missing")]
+    MissingSection(String, u32),
+    #[error("Restored XBE has checksum {1:#010x} but the original was {0:#010x}")]
+    ChecksumMismatch(u32, u32),
+}
@@ -0,0 +1,919 @@
+use crate::{
+    library::{self, missing_required_libraries},
+    reloc::SymbolTable,
+    section::{
+        read_u32, write_u32, RawLayout, RawSectionHeader, SectionError, XbeExt, RAW_ALIGNMENT,
+    },
+};
+use anyhow::{bail, Result};
+use itertools::Itertools;
+use log::{debug, warn};
+use std::{collections::HashMap, ops::Range};
+use thiserror::Error;
+use xbe::{SectionFlags, Xbe};
+
+/// Key the entry point of a retail XBE is XOR encoded with
+const RETAIL_ENTRY_KEY: u32 = 0xA8FC_57AB;
+/// Key the entry point of a debug XBE is XOR encoded with
+const DEBUG_ENTRY_KEY: u32 = 0x9485_9D4B;
+/// Key the kernel thunk address of a retail XBE is XOR encoded with
+const RETAIL_THUNK_KEY: u32 = 0x5B6D_40B6;
+/// Key the kernel thunk address of a debug XBE is XOR encoded with
+const DEBUG_THUNK_KEY: u32 = 0xEFB1_F152;
+/// Most UTF-16 code units a title name can have, leaving room for its null terminator
+pub const MAX_TITLE_NAME_LEN: usize = 0x27;
+/// Most title IDs the certificate's alternate title ID list holds
+pub const MAX_ALTERNATE_TITLE_IDS: usize = 16;
+/// File offset of the PE checksum in a serialized XBE
+pub const PE_CHECKSUM_OFFSET: usize = 0x144;
+/// File offset of the debug pathname address in a serialized XBE
+const DEBUG_PATHNAME_ADDRESS_OFFSET: usize = 0x14C;
+/// File offset of the debug file name address in a serialized XBE
+const DEBUG_FILENAME_ADDRESS_OFFSET: usize = 0x150;
+/// Size of the pages sections are loaded into
+const PAGE_SIZE: u32 = 0x1000;
+
+#[derive(Debug, Error)]
+pub enum HeaderError {
+    #[error("Entry point {0:#x} is not within an executable section")]
+    NonExecutableEntryPoint(u32),
+    #[error("Entry point of the input XBE can't be decoded with the retail or debug key")]
+    UnknownEntryKey,
+    #[error("Config is for {0:?} XBEs, but the input XBE is {1:?}")]
+    ImageKindMismatch(ImageKind, ImageKind),
+    #[error("TLS address {0:#x} is not mapped by any section")]
+    UnmappedTlsAddress(u32),
+    #[error("Title name '{0}' is {1} UTF-16 characters, but at most {MAX_TITLE_NAME_LEN} fit")]
+    TitleNameTooLong(String, usize),
+    #[error("{0} alternate title IDs given, but at most {MAX_ALTERNATE_TITLE_IDS} fit")]
+    TooManyAlternateTitleIds(usize),
+    #[error("Certificate is outside of the serialized XBE")]
+    MissingCertificate,
+    #[error(
+        "Debug pathname '{0}' has no backslash, so the xbe crate can't serialize it. Load it with \
+         XbeImage to keep the pathname as it was."
+    )]
+    DebugPathnameWithoutBackslash(String),
+    #[error(
+        "Headers end at {0:#x}, past the start of section data at {1:#x}. Too many sections were \
+         added for the xbe crate to fit their headers before the first section. Add them with \
+         XbeExt::try_add_section to move the section data out of the way."
+    )]
+    HeadersOverlapSections(u32, u32),
+    #[error(
+        "XBE has no {0} library version, which the xbe crate needs to serialize it. Load it with \
+         XbeImage to keep the header's pointer to the missing library."
+    )]
+    MissingLibrary(String),
+}
+
+/// A replacement for an address in the XBE header, given directly or as a symbol
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum HeaderAddress {
+    Address(u32),
+    Symbol(String),
+}
+
+impl HeaderAddress {
+    /// Finds the virtual address of this replacement
+    pub(crate) fn resolve(&self, symbol_table: &SymbolTable) -> Result<u32> {
+        match self {
+            HeaderAddress::Address(address) => Ok(*address),
+            HeaderAddress::Symbol(name) => Ok(symbol_table
+                .get(name)
+                .ok_or_else(|| symbol_table.undefined_symbol(name))?),
+        }
+    }
+}
+
+/// Whether an XBE is built for retail consoles or debug kits, which encode header addresses with
+/// different keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageKind {
+    Retail,
+    Debug,
+    /// The entry point decodes to an address within the XBE with neither key
+    Unknown,
+}
+
+impl ImageKind {
+    /// Keys the entry point and kernel thunk address of this kind of XBE are encoded with
+    fn keys(self) -> Option<(u32, u32)> {
+        match self {
+            ImageKind::Retail => Some((RETAIL_ENTRY_KEY, RETAIL_THUNK_KEY)),
+            ImageKind::Debug => Some((DEBUG_ENTRY_KEY, DEBUG_THUNK_KEY)),
+            ImageKind::Unknown => None,
+        }
+    }
+
+    /// Decodes an entry point as stored in the header of this kind of XBE
+    pub fn decode_entry_point(self, encoded: u32) -> Option<u32> {
+        self.keys().map(|(key, _)| encoded ^ key)
+    }
+
+    /// Encodes `address` as an entry point for the header of this kind of XBE
+    pub fn encode_entry_point(self, address: u32) -> Option<u32> {
+        self.keys().map(|(key, _)| address ^ key)
+    }
+
+    /// Decodes a kernel thunk address as stored in the header of this kind of XBE
+    pub fn decode_kernel_thunk_address(self, encoded: u32) -> Option<u32> {
+        self.keys().map(|(_, key)| encoded ^ key)
+    }
+
+    /// Encodes `address` as a kernel thunk address for the header of this kind of XBE
+    pub fn encode_kernel_thunk_address(self, address: u32) -> Option<u32> {
+        self.keys().map(|(_, key)| address ^ key)
+    }
+}
+
+/// Kind of `xbe`, determined by which key decodes its entry point to an address within the XBE
+pub fn image_kind(xbe: &Xbe) -> ImageKind {
+    [
+        (RETAIL_ENTRY_KEY, ImageKind::Retail),
+        (DEBUG_ENTRY_KEY, ImageKind::Debug),
+    ]
+    .into_iter()
+    .find(|&(key, _)| {
+        xbe.section_containing(xbe.header.entry_point ^ key)
+            .is_some()
+    })
+    .map_or(ImageKind::Unknown, |(_, kind)| kind)
+}
+
+/// Kind of `xbe`, failing if its entry point can't be decoded with any known key
+fn known_image_kind(xbe: &Xbe) -> Result<ImageKind> {
+    match image_kind(xbe) {
+        ImageKind::Unknown => bail!(HeaderError::UnknownEntryKey),
+        kind => Ok(kind),
+    }
+}
+
+/// Decoded virtual address execution of `xbe` starts at
+pub fn entry_point(xbe: &Xbe) -> Result<u32> {
+    Ok(known_image_kind(xbe)?
+        .decode_entry_point(xbe.header.entry_point)
+        .expect("Known image kind has keys"))
+}
+
+/// Decoded virtual address of the table of kernel imports of `xbe`
+pub fn kernel_thunk_address(xbe: &Xbe) -> Result<u32> {
+    Ok(known_image_kind(xbe)?
+        .decode_kernel_thunk_address(xbe.header.kernel_image_thunk_address)
+        .expect("Known image kind has keys"))
+}
+
+/// Points the kernel imports of `xbe` at the table at `address`, encoded with the key of the
+/// detected [`ImageKind`]. Returns the encoded address it replaced.
+pub fn set_kernel_thunk_address(xbe: &mut Xbe, address: u32) -> Result<u32> {
+    let encoded = known_image_kind(xbe)?
+        .encode_kernel_thunk_address(address)
+        .expect("Known image kind has keys");
+    Ok(std::mem::replace(
+        &mut xbe.header.kernel_image_thunk_address,
+        encoded,
+    ))
+}
+
+/// Starts execution of `xbe` at `address`, which must be within an executable section. The
+/// entry point is encoded with the same key as the original. Returns the encoded entry point it
+/// replaced.
+pub fn set_entry_point(xbe: &mut Xbe, address: u32) -> Result<u32> {
+    let executable = xbe
+        .section_containing(address)
+        .is_some_and(|s| s.flags.contains(xbe::SectionFlags::EXECUTABLE));
+    if !executable {
+        bail!(HeaderError::NonExecutableEntryPoint(address));
+    }
+
+    let encoded = known_image_kind(xbe)?
+        .encode_entry_point(address)
+        .expect("Known image kind has keys");
+    Ok(std::mem::replace(&mut xbe.header.entry_point, encoded))
+}
+
+/// Points the TLS directory of `xbe` at `address`, which must be mapped by a section. Returns
+/// the TLS address it replaced.
+pub(crate) fn set_tls_address(xbe: &mut Xbe, address: u32) -> Result<u32> {
+    if xbe.section_containing(address).is_none() {
+        bail!(HeaderError::UnmappedTlsAddress(address));
+    }
+    Ok(std::mem::replace(&mut xbe.header.tls_address, address))
+}
+
+/// Size of the TLS directory
+const TLS_DIRECTORY_SIZE: u32 = 0x18;
+
+/// The TLS directory at the TLS address, describing the data each thread's TLS is initialized from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TlsDirectory {
+    /// Start of the data each thread's TLS is copied from
+    pub raw_data_start: u32,
+    /// End of the data each thread's TLS is copied from
+    pub raw_data_end: u32,
+    /// Address the loader writes the TLS index to
+    pub index_address: u32,
+    /// Address of the null terminated list of TLS callbacks
+    pub callbacks_address: u32,
+    /// Bytes of zeros following the copied data in each thread's TLS
+    pub zero_fill_size: u32,
+    pub characteristics: u32,
+}
+
+impl TlsDirectory {
+    fn decode(raw: &[u8]) -> Self {
+        let field = |i: usize| u32::from_le_bytes([raw[i], raw[i + 1], raw[i + 2], raw[i + 3]]);
+        Self {
+            raw_data_start: field(0x0),
+            raw_data_end: field(0x4),
+            index_address: field(0x8),
+            callbacks_address: field(0xC),
+            zero_fill_size: field(0x10),
+            characteristics: field(0x14),
+        }
+    }
+
+    fn encode(&self) -> [u8; TLS_DIRECTORY_SIZE as usize] {
+        let mut raw = [0; TLS_DIRECTORY_SIZE as usize];
+        let fields = [
+            self.raw_data_start,
+            self.raw_data_end,
+            self.index_address,
+            self.callbacks_address,
+            self.zero_fill_size,
+            self.characteristics,
+        ];
+        for (bytes, field) in raw.chunks_exact_mut(4).zip(fields) {
+            bytes.copy_from_slice(&field.to_le_bytes());
+        }
+        raw
+    }
+
+    /// Moves the addresses pointing into `from` to the same offset from `to`, for when the data
+    /// there is moved. The end of the TLS data moves with it if it's the end of `from`.
+    pub fn relocate(&mut self, from: Range<u32>, to: u32) {
+        let moved = |address: u32| address.wrapping_sub(from.start).wrapping_add(to);
+        for address in [
+            &mut self.raw_data_start,
+            &mut self.index_address,
+            &mut self.callbacks_address,
+        ] {
+            if from.contains(address) {
+                *address = moved(*address);
+            }
+        }
+        if from.start < self.raw_data_end && self.raw_data_end <= from.end {
+            self.raw_data_end = moved(self.raw_data_end);
+        }
+    }
+}
+
+/// Reads the TLS directory `xbe` points to, which must be within the data of a section
+pub fn tls_directory(xbe: &Xbe) -> Result<TlsDirectory> {
+    let address = xbe.header.tls_address;
+    match xbe.bytes_at(address..address.saturating_add(TLS_DIRECTORY_SIZE)) {
+        Some(raw) => Ok(TlsDirectory::decode(raw)),
+        None => bail!(HeaderError::UnmappedTlsAddress(address)),
+    }
+}
+
+/// Replaces the TLS directory `xbe` points to, which must be within the data of a section
+pub fn set_tls_directory(xbe: &mut Xbe, directory: &TlsDirectory) -> Result<()> {
+    let address = xbe.header.tls_address;
+    match xbe.write_virtual(address, &directory.encode()) {
+        Some(_) => Ok(()),
+        None => bail!(HeaderError::UnmappedTlsAddress(address)),
+    }
+}
+
+/// Checks the debug pathname of `xbe` has the backslash [`serialize`] needs
+pub fn check_debug_pathname(xbe: &Xbe) -> Result<()> {
+    if !xbe.header.debug_pathname.contains('\\') {
+        bail!(HeaderError::DebugPathnameWithoutBackslash(
+            xbe.header.debug_pathname.clone()
+        ));
+    }
+    Ok(())
+}
+
+/// Gives a debug pathname of `xbe` without a backslash a leading one, so [`serialize`] writes
+/// the whole pathname as the debug file name. Returns whether it was added.
+/// [`remove_debug_backslash`] leaves it out of the serialized XBE again.
+pub fn add_debug_backslash(xbe: &mut Xbe) -> bool {
+    if xbe.header.debug_pathname.contains('\\') {
+        return false;
+    }
+    warn!(
+        "Debug pathname '{}' has no backslash, using the whole pathname as the debug file name",
+        xbe.header.debug_pathname
+    );
+    xbe.header.debug_pathname.insert(0, '\\');
+    true
+}
+
+/// Runs `f` on `xbe` with what [`serialize`] needs: a debug pathname without a backslash is given one, see [`add_debug_backslash`], and a missing XBOXKRNL or XAPILIB library
+/// version is given a placeholder record. Both are taken back out of the XBE `f` returns, so it
+/// keeps the pathname and libraries `xbe` had. A placeholder `f` replaced with a version is kept.
+pub(crate) fn with_serializable<T>(
+    mut xbe: Xbe,
+    f: impl FnOnce(Xbe) -> Result<(Xbe, T)>,
+) -> Result<(Xbe, T)> {
+    let debug_backslash = add_debug_backslash(&mut xbe);
+    let missing = missing_required_libraries(&xbe);
+    for name in missing.iter() {
+        warn!("XBE has no {name} library version, adding a placeholder until it's returned");
+        xbe.library_versions
+            .push(library::placeholder_library(name.as_bytes()));
+    }
+
+    let (mut xbe, value) = f(xbe)?;
+    if debug_backslash {
+        if let Some(pathname) = xbe.header.debug_pathname.strip_prefix('\\') {
+            xbe.header.debug_pathname = pathname.to_string();
+        }
+    }
+    for name in missing.iter() {
+        if let Some(i) = xbe.library_versions.iter().position(|lib| {
+            library::is_placeholder(lib) && library::same_name(&lib.library_name, name.as_bytes())
+        }) {
+            xbe.library_versions.remove(i);
+        }
+    }
+    Ok((xbe, value))
+}
+
+/// Points the debug pathname of the serialized XBE `image` at its debug file name, which leaves
+/// out a leading backslash added by [`add_debug_backslash`]
+pub fn remove_debug_backslash(image: &mut [u8]) -> Result<()> {
+    let filename = read_u32(image, DEBUG_FILENAME_ADDRESS_OFFSET)?;
+    write_u32(image, DEBUG_PATHNAME_ADDRESS_OFFSET, filename)
+}
+
+/// Checks `xbe` has the XBOXKRNL and XAPILIB library versions [`serialize`] needs
+pub fn check_required_libraries(xbe: &Xbe) -> Result<()> {
+    if let Some(name) = missing_required_libraries(xbe).into_iter().next() {
+        bail!(HeaderError::MissingLibrary(name));
+    }
+    Ok(())
+}
+
+/// Serializes `xbe` with the xbe crate, making up for where its output is wrong for an XBE that
+/// was read from a file. Every such serialization goes through this, and the workarounds
+/// elsewhere in xbld are for the same behavior of the xbe crate:
+///
+/// - It panics without a backslash in the debug pathname or without XBOXKRNL and XAPILIB library
+///   versions. Those fail with [`HeaderError::DebugPathnameWithoutBackslash`] and
+///   [`HeaderError::MissingLibrary`] instead, and [`with_serializable`] stands in for them.
+/// - It keeps the raw addresses of loaded sections. Data overlapped by a section that grew is
+///   moved apart, and headers grown over the data of the first section fail with
+///   [`HeaderError::HeadersOverlapSections`].
+/// - It gives each section the page reference counts after the previous one's, so sections
+///   sharing a page are given the same count.
+/// - It counts inserted files in the size of image, so they're left out after the last loaded
+///   section.
+/// - It only keeps the header fields [`Xbe`] models. [`XbeImage`](crate::image::XbeImage) writes
+///   the others back as they were loaded.
+pub fn serialize(xbe: &Xbe) -> Result<Vec<u8>> {
+    check_debug_pathname(xbe)?;
+    check_required_libraries(xbe)?;
+    let image = xbe.serialize()?;
+    check_header_space(&image)?;
+    let mut image = if RawLayout::parse(&image)?.raw_overlap() {
+        debug!("Moving section data that overlaps after a section grew");
+        separate_raw_data(&image, xbe)?
+    } else {
+        image
+    };
+    share_page_ref_counts(&mut image)?;
+    exclude_inserted_files(&mut image)?;
+    Ok(image)
+}
+
+/// Points the head and tail page reference counts of sections of the serialized XBE `image` that
+/// start or end on the same page at the same count, the one of the first section by virtual
+/// address to use the page. Counts given
+/// in storage order only agree with the pages shared when sections are stored in address order.
+///
+/// The counts of pages that are already shared consistently are left as they are.
+fn share_page_ref_counts(image: &mut [u8]) -> Result<()> {
+    let layout = RawLayout::parse(image)?;
+    let mut counts: HashMap<u32, u32> = HashMap::new();
+    for section in layout
+        .sections
+        .iter()
+        .filter(|s| s.virtual_size > 0)
+        .sorted_by_key(|s| s.virtual_address)
+    {
+        let head_page = section.virtual_address / PAGE_SIZE;
+        let tail_page = section
+            .virtual_address
+            .saturating_add(section.virtual_size - 1)
+            / PAGE_SIZE;
+        let head = *counts
+            .entry(head_page)
+            .or_insert(section.head_page_ref_address);
+        let tail = *counts
+            .entry(tail_page)
+            .or_insert(section.tail_page_ref_address);
+        write_u32(image, section.offset + 0x1C, head)?;
+        write_u32(image, section.offset + 0x20, tail)?;
+    }
+    Ok(())
+}
+
+/// Shrinks the size of image of the serialized XBE `image` to the end of its last loaded section,
+/// rounded up to a page, when sections flagged as inserted files reach past it. The kernel
+/// doesn't load inserted files.
+fn exclude_inserted_files(image: &mut [u8]) -> Result<()> {
+    let layout = RawLayout::parse(image)?;
+    let inserted = SectionFlags::INSERTED_FILE.bits();
+    let (files, loaded): (Vec<_>, Vec<_>) = layout
+        .sections
+        .iter()
+        .partition(|s| s.flags & inserted != 0);
+    let end = |sections: &[&RawSectionHeader]| {
+        sections
+            .iter()
+            .map(|s| s.virtual_address.saturating_add(s.virtual_size))
+            .fold(
+                layout.base_address.saturating_add(layout.size_of_headers),
+                u32::max,
+            )
+    };
+    let loaded_end = end(&loaded);
+    if end(&files) <= loaded_end {
+        return Ok(());
+    }
+    let size_of_image = (loaded_end - layout.base_address)
+        .checked_next_multiple_of(PAGE_SIZE)
+        .unwrap_or(u32::MAX);
+    write_u32(image, 0x10C, size_of_image.min(layout.size_of_image))
+}
+
+/// Moves the data of each section of the serialized XBE `image` that overlaps the data before it
+/// up past that data, page aligned, keeping the order of the sections' data and the raw addresses
+/// of the others. The data is taken from `xbe`, as the serialized data of overlapping sections is
+/// clobbered.
+pub(crate) fn separate_raw_data(image: &[u8], xbe: &Xbe) -> Result<Vec<u8>> {
+    let layout = RawLayout::parse(image)?;
+    let mut headers: Vec<_> = layout.sections.iter().filter(|s| s.raw_size > 0).collect();
+    headers.sort_by_key(|s| s.raw_address);
+
+    let start = headers
+        .first()
+        .map_or(image.len(), |s| s.raw_address as usize)
+        .min(image.len());
+    let mut separated = image[..start].to_vec();
+    for header in headers {
+        let Some(section) = xbe
+            .sections
+            .iter()
+            .find(|s| s.virtual_address == header.virtual_address)
+        else {
+            bail!(SectionError::UnmatchedHeader(header.virtual_address));
+        };
+
+        let end = separated.len();
+        let raw_address = match header.raw_address as usize {
+            raw_address if raw_address >= end => raw_address,
+            _ => end.next_multiple_of(RAW_ALIGNMENT),
+        };
+        // Keep whatever was between the sections
+        separated.extend_from_slice(image.get(end..raw_address).unwrap_or_default());
+        separated.resize(raw_address, 0);
+        separated.extend_from_slice(&section.data);
+        write_u32(&mut separated, header.offset + 0xC, raw_address as u32)?;
+        write_u32(
+            &mut separated,
+            header.offset + 0x10,
+            section.data.len() as u32,
+        )?;
+    }
+    separated.resize(separated.len().next_multiple_of(RAW_ALIGNMENT), 0);
+    Ok(separated)
+}
+
+/// Checks the headers of the serialized XBE `image` end before the data of its first section,
+/// which headers grown by sections added with [`Xbe::add_section`] can reach, see [`serialize`].
+/// [`XbeExt::try_add_section`] moves the data out of the way first.
+pub fn check_header_space(image: &[u8]) -> Result<()> {
+    let layout = RawLayout::parse(image)?;
+    let first = layout
+        .sections
+        .iter()
+        .filter(|s| s.raw_size > 0)
+        .map(|s| s.raw_address)
+        .min();
+    match first {
+        Some(raw_address) if raw_address < layout.size_of_headers => bail!(
+            HeaderError::HeadersOverlapSections(layout.size_of_headers, raw_address)
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Name of the title `xbe` contains, decoded from UTF-16 up to the first null. Invalid UTF-16 is
+/// replaced rather than rejected.
+pub fn title_name(xbe: &Xbe) -> String {
+    decode_title_name(&xbe.header.title_name)
+}
+
+/// Replaces the title name of `xbe`, which must fit in [`MAX_TITLE_NAME_LEN`] UTF-16 code units
+pub fn set_title_name(xbe: &mut Xbe, name: &str) -> Result<()> {
+    xbe.header.title_name = encode_title_name(name)?;
+    Ok(())
+}
+
+fn decode_title_name(raw: &[u8; 0x50]) -> String {
+    let units: Vec<u16> = raw
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .take_while(|&unit| unit != 0)
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+/// Encodes `name` as null terminated UTF-16LE, zero filling the rest of the field
+fn encode_title_name(name: &str) -> Result<[u8; 0x50]> {
+    let units: Vec<u16> = name.encode_utf16().collect();
+    if units.len() > MAX_TITLE_NAME_LEN {
+        bail!(HeaderError::TitleNameTooLong(name.to_string(), units.len()));
+    }
+
+    let mut raw = [0; 0x50];
+    for (bytes, unit) in raw.chunks_exact_mut(2).zip(units) {
+        bytes.copy_from_slice(&unit.to_le_bytes());
+    }
+    Ok(raw)
+}
+
+/// Decodes the certificate's raw alternate title IDs, the IDs of other releases of the title
+/// whose saves can be loaded. IDs are little-endian and the list ends at the first zero.
+pub fn decode_alternate_title_ids(raw: &[u8; 0x40]) -> Vec<u32> {
+    raw.chunks_exact(4)
+        .map(|id| u32::from_le_bytes([id[0], id[1], id[2], id[3]]))
+        .take_while(|&id| id != 0)
+        .collect()
+}
+
+/// Encodes `ids` as the certificate's raw alternate title IDs, zero filling unused entries.
+///
+/// A mod that changes the title ID keeps existing saves loadable by listing the original title ID
+/// here.
+pub fn encode_alternate_title_ids(ids: &[u32]) -> Result<[u8; 0x40]> {
+    if ids.len() > MAX_ALTERNATE_TITLE_IDS {
+        bail!(HeaderError::TooManyAlternateTitleIds(ids.len()));
+    }
+
+    let mut raw = [0; 0x40];
+    for (bytes, id) in raw.chunks_exact_mut(4).zip(ids) {
+        bytes.copy_from_slice(&id.to_le_bytes());
+    }
+    Ok(raw)
+}
+
+/// Computes the PE checksum of `bytes`: the sum of its 16-bit words with carries folded back in,
+/// plus its length. The checksum field at `checksum_offset` is excluded from the sum.
+pub fn compute_pe_checksum(bytes: &[u8], checksum_offset: usize) -> u32 {
+    let checksum_field = checksum_offset..checksum_offset + 4;
+    let mut sum = bytes
+        .chunks(2)
+        .enumerate()
+        .filter(|(i, _)| !checksum_field.contains(&(i * 2)))
+        .fold(0u32, |sum, (_, word)| {
+            let sum = sum + u16::from_le_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32;
+            (sum & 0xFFFF) + (sum >> 16)
+        });
+    sum = (sum & 0xFFFF) + (sum >> 16);
+    sum.wrapping_add(bytes.len() as u32)
+}
+
+/// Replaces the PE checksum of the serialized XBE `bytes` with one computed from its contents,
+/// returning the checksum it replaced
+pub fn update_pe_checksum(bytes: &mut [u8]) -> Option<u32> {
+    let checksum = compute_pe_checksum(bytes, PE_CHECKSUM_OFFSET);
+    let field = bytes.get_mut(PE_CHECKSUM_OFFSET..PE_CHECKSUM_OFFSET + 4)?;
+    let original = u32::from_le_bytes(field.try_into().ok()?);
+    field.copy_from_slice(&checksum.to_le_bytes());
+    Some(original)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    type TestError = std::result::Result<(), Box<dyn std::error::Error>>;
+
+    #[test]
+    fn set_entry() -> TestError {
+        let mut xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let original = xbe.header.entry_point;
+        let entry = entry_point(&xbe)?;
+
+        set_entry_point(&mut xbe, 396158)?;
+        assert_eq!(entry_point(&xbe)?, 396158);
+        assert_eq!(xbe.header.entry_point ^ 396158, original ^ entry);
+
+        // The XBE header isn't executable
+        let err = set_entry_point(&mut xbe, 0x10000).expect_err("Entered the header");
+        assert!(matches!(
+            err.downcast_ref::<HeaderError>(),
+            Some(HeaderError::NonExecutableEntryPoint(0x10000))
+        ));
+        assert_eq!(entry_point(&xbe)?, 396158);
+        Ok(())
+    }
+
+    #[test]
+    fn decode_entry() -> TestError {
+        let xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let kind = image_kind(&xbe);
+        let entry = kind
+            .decode_entry_point(xbe.header.entry_point)
+            .ok_or("No keys for the image kind")?;
+        assert_eq!(entry, entry_point(&xbe)?);
+        assert_eq!(kind.encode_entry_point(entry), Some(xbe.header.entry_point));
+
+        // The decoded entry point is in the game's code
+        let section = xbe
+            .section_containing(entry)
+            .ok_or("Entry point is unmapped")?;
+        assert_eq!(crate::reloc::strip_null(&section.name), ".text");
+        assert!(section.flags.contains(xbe::SectionFlags::EXECUTABLE));
+
+        let thunk = kind
+            .decode_kernel_thunk_address(xbe.header.kernel_image_thunk_address)
+            .ok_or("No keys for the image kind")?;
+        assert_eq!(thunk, kernel_thunk_address(&xbe)?);
+        assert_eq!(
+            kind.encode_kernel_thunk_address(thunk),
+            Some(xbe.header.kernel_image_thunk_address)
+        );
+        assert_eq!(ImageKind::Unknown.decode_entry_point(entry), None);
+        Ok(())
+    }
+
+    #[test]
+    fn image_kinds() -> TestError {
+        let mut xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        assert_eq!(image_kind(&xbe), ImageKind::Retail);
+        let entry = entry_point(&xbe)?;
+        let thunk = kernel_thunk_address(&xbe)?;
+
+        // Re-encode the header as a debug XBE would be
+        xbe.header.entry_point ^= RETAIL_ENTRY_KEY ^ DEBUG_ENTRY_KEY;
+        xbe.header.kernel_image_thunk_address ^= RETAIL_THUNK_KEY ^ DEBUG_THUNK_KEY;
+        assert_eq!(image_kind(&xbe), ImageKind::Debug);
+        assert_eq!(entry_point(&xbe)?, entry);
+        assert_eq!(kernel_thunk_address(&xbe)?, thunk);
+
+        // Setters keep the debug keys
+        set_entry_point(&mut xbe, 396158)?;
+        set_kernel_thunk_address(&mut xbe, thunk + 4)?;
+        assert_eq!(xbe.header.entry_point, 396158 ^ DEBUG_ENTRY_KEY);
+        assert_eq!(
+            xbe.header.kernel_image_thunk_address,
+            (thunk + 4) ^ DEBUG_THUNK_KEY
+        );
+        assert_eq!(image_kind(&xbe), ImageKind::Debug);
+
+        xbe.header.entry_point = 0;
+        assert_eq!(image_kind(&xbe), ImageKind::Unknown);
+        let err = kernel_thunk_address(&xbe).expect_err("Decoded with an unknown key");
+        assert!(matches!(
+            err.downcast_ref::<HeaderError>(),
+            Some(HeaderError::UnknownEntryKey)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn title_names() -> TestError {
+        let raw = encode_title_name("Battle for Bikini Bottom")?;
+        assert_eq!(raw[..6], [b'B', 0, b'a', 0, b't', 0]);
+        assert!(raw[48..].iter().all(|&b| b == 0));
+        assert_eq!(decode_title_name(&raw), "Battle for Bikini Bottom");
+
+        // Characters outside the BMP take two code units
+        let name = "スポンジ・ボブ 🍍";
+        let raw = encode_title_name(name)?;
+        assert_eq!(raw[..2], 0x30B9u16.to_le_bytes());
+        assert_eq!(decode_title_name(&raw), name);
+
+        let longest = "x".repeat(MAX_TITLE_NAME_LEN);
+        assert_eq!(decode_title_name(&encode_title_name(&longest)?), longest);
+        let err = encode_title_name(&format!("{longest}🍍")).expect_err("Overlong title name");
+        assert!(matches!(
+            err.downcast_ref::<HeaderError>(),
+            Some(HeaderError::TitleNameTooLong(_, 0x29))
+        ));
+
+        // Anything after the first null is ignored
+        let mut raw = encode_title_name("Bob")?;
+        raw[8] = b'!';
+        assert_eq!(decode_title_name(&raw), "Bob");
+
+        let mut xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        set_title_name(&mut xbe, "Patched")?;
+        let xbe = Xbe::new(&xbe.serialize()?)?;
+        assert_eq!(title_name(&xbe), "Patched");
+        Ok(())
+    }
+
+    #[test]
+    fn alternate_title_ids() -> TestError {
+        let raw = encode_alternate_title_ids(&[0x5454_0003, 0x5454_0010])?;
+        assert_eq!(raw[..8], [0x03, 0x00, 0x54, 0x54, 0x10, 0x00, 0x54, 0x54]);
+        assert!(raw[8..].iter().all(|&b| b == 0));
+        assert_eq!(decode_alternate_title_ids(&raw), [0x5454_0003, 0x5454_0010]);
+        assert_eq!(decode_alternate_title_ids(&[0; 0x40]), []);
+
+        let full: Vec<u32> = (1..=MAX_ALTERNATE_TITLE_IDS as u32).collect();
+        assert_eq!(
+            decode_alternate_title_ids(&encode_alternate_title_ids(&full)?),
+            full
+        );
+        let err = encode_alternate_title_ids(&[1; MAX_ALTERNATE_TITLE_IDS + 1])
+            .expect_err("Too many alternate title IDs");
+        assert!(matches!(
+            err.downcast_ref::<HeaderError>(),
+            Some(HeaderError::TooManyAlternateTitleIds(17))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn pe_checksum() {
+        assert_eq!(compute_pe_checksum(&[0x01, 0x00, 0x02, 0x00], 0x144), 3 + 4);
+        // Carries are folded back into the sum, and an odd trailing byte is zero extended
+        assert_eq!(
+            compute_pe_checksum(&[0xFF, 0xFF, 0x02, 0x00, 0x05], 0x144),
+            (0x0002 + 0x0005) + 5
+        );
+
+        // The checksum field doesn't contribute to the checksum
+        let mut bytes = vec![0; 0x148];
+        bytes[PE_CHECKSUM_OFFSET..].copy_from_slice(&[0xFF; 4]);
+        assert_eq!(compute_pe_checksum(&bytes, PE_CHECKSUM_OFFSET), 0x148);
+        assert_eq!(update_pe_checksum(&mut bytes), Some(0xFFFF_FFFF));
+        assert_eq!(bytes[PE_CHECKSUM_OFFSET..], 0x148u32.to_le_bytes());
+        assert_eq!(update_pe_checksum(&mut bytes), Some(0x148));
+        assert_eq!(update_pe_checksum(&mut [0; 4]), None);
+    }
+
+    #[test]
+    fn set_tls() -> TestError {
+        let mut xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let original = xbe.header.tls_address;
+
+        assert_eq!(set_tls_address(&mut xbe, 396158)?, original);
+        assert_eq!(xbe.header.tls_address, 396158);
+
+        let err = set_tls_address(&mut xbe, 0x10).expect_err("Unmapped TLS address");
+        assert!(matches!(
+            err.downcast_ref::<HeaderError>(),
+            Some(HeaderError::UnmappedTlsAddress(0x10))
+        ));
+        assert_eq!(xbe.header.tls_address, 396158);
+        Ok(())
+    }
+
+    #[test]
+    fn debug_pathname() -> TestError {
+        let mut xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        assert_eq!(serialize(&xbe)?, xbe.serialize()?);
+
+        assert!(!add_debug_backslash(&mut xbe));
+
+        for path in ["", "D:/build/default.exe"] {
+            xbe.header.debug_pathname = path.to_string();
+            let err = serialize(&xbe).expect_err("Serialized a path without a backslash");
+            assert!(matches!(
+                err.downcast_ref::<HeaderError>(),
+                Some(HeaderError::DebugPathnameWithoutBackslash(p)) if p == path
+            ));
+
+            // The whole pathname is the file name, and the pathname points at it
+            assert!(add_debug_backslash(&mut xbe));
+            assert_eq!(xbe.header.debug_pathname, format!("\\{path}"));
+            let mut image = serialize(&xbe)?;
+            remove_debug_backslash(&mut image)?;
+            assert_eq!(Xbe::new(&image)?.header.debug_pathname, path);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn header_space() -> TestError {
+        let mut xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let mut image = serialize(&xbe)?;
+        let layout = RawLayout::parse(&image)?;
+        let first = layout
+            .sections
+            .iter()
+            .filter(|s| s.raw_size > 0)
+            .map(|s| s.raw_address)
+            .min()
+            .ok_or("XBE has no sections")?;
+        image[0x108..0x10C].copy_from_slice(&(first + 1).to_le_bytes());
+        let err = check_header_space(&image).expect_err("Headers overlap section data");
+        assert!(matches!(
+            err.downcast_ref::<HeaderError>(),
+            Some(&HeaderError::HeadersOverlapSections(end, start)) if end == first + 1 && start == first
+        ));
+
+        // Many tiny sections move the data of the others up once the headers reach it
+        for i in 0..30u8 {
+            let address = xbe.get_next_virtual_address();
+            let flags = xbe::SectionFlags::PRELOAD;
+            xbe.try_add_section(&format!(".m{i}"), flags, vec![i; 4], address, 4)?;
+        }
+        let image = serialize(&xbe)?;
+        check_header_space(&image)?;
+        let layout = RawLayout::parse(&image)?;
+        assert!(!layout.raw_overlap());
+        let last = layout.sections.last().ok_or("XBE has no sections")?;
+        assert!(last.offset + 0x38 <= layout.size_of_headers as usize);
+
+        let reloaded = Xbe::new(&image)?;
+        assert_eq!(reloaded.sections.len(), xbe.sections.len());
+        for (reloaded, section) in reloaded.sections.iter().zip(xbe.sections.iter()) {
+            assert_eq!(reloaded.virtual_address, section.virtual_address);
+            assert_eq!(reloaded.data, section.data);
+        }
+        for i in 0..30u8 {
+            let section = reloaded
+                .section_by_name(&format!(".m{i}"))
+                .ok_or("Added section is missing")?;
+            assert_eq!(section.data, [i; 4]);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn tls_directories() -> TestError {
+        let mut xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let directory = tls_directory(&xbe)?;
+        assert!(directory.raw_data_start <= directory.raw_data_end);
+
+        let mut moved = directory;
+        moved.relocate(directory.raw_data_start..directory.raw_data_end, 0x10_0000);
+        assert_eq!(moved.raw_data_start, 0x10_0000);
+        assert_eq!(
+            moved.raw_data_end - moved.raw_data_start,
+            directory.raw_data_end - directory.raw_data_start
+        );
+        assert_eq!(moved.zero_fill_size, directory.zero_fill_size);
+
+        set_tls_directory(&mut xbe, &moved)?;
+        let reloaded = Xbe::new(&serialize(&xbe)?)?;
+        assert_eq!(tls_directory(&reloaded)?, moved);
+        set_tls_directory(&mut xbe, &directory)?;
+
+        // The directory is kept when section data is moved to make room for a grown section
+        let layout = RawLayout::parse(&serialize(&xbe)?)?;
+        let last_raw = layout.sections.iter().map(|s| s.raw_address).max();
+        let grown = layout
+            .sections
+            .iter()
+            .find(|s| Some(s.raw_address) != last_raw && s.virtual_size >= s.raw_size + 0x1000)
+            .ok_or("No section with room to grow")?;
+        let section = xbe
+            .sections
+            .iter()
+            .find(|s| s.virtual_address == grown.virtual_address)
+            .ok_or("Section header without a section")?;
+        let (name, mut data) = (section.name.clone(), section.data.clone());
+        data.resize(data.len() + 0x1000, 0);
+        xbe.replace_section_data(&name, data, None)?;
+        let reloaded = Xbe::new(&serialize(&xbe)?)?;
+        assert_eq!(tls_directory(&reloaded)?, directory);
+
+        xbe.header.tls_address = 0x10;
+        assert!(matches!(
+            tls_directory(&xbe)
+                .expect_err("Unmapped TLS directory")
+                .downcast_ref::<HeaderError>(),
+            Some(HeaderError::UnmappedTlsAddress(0x10))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn required_libraries() -> TestError {
+        let mut xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        check_required_libraries(&xbe)?;
+
+        xbe.library_versions
+            .retain(|lib| !lib.library_name.starts_with(b"XAPILIB"));
+        let err = check_required_libraries(&xbe).expect_err("Passed without XAPILIB");
+        assert!(matches!(
+            err.downcast_ref::<HeaderError>(),
+            Some(HeaderError::MissingLibrary(name)) if name == "XAPILIB"
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,1476 @@
+use crate::{
+    header::{self, separate_raw_data, HeaderError, MAX_ALTERNATE_TITLE_IDS},
+    library::{self, REQUIRED_LIBRARIES},
+    logo::{decode_logo, encode_logo, LogoError, LOGO_HEIGHT, LOGO_WIDTH},
+    reloc::strip_null,
+    section::{read_u32, write_u32, RawLayout, SectionError, XbeExt, RAW_ALIGNMENT},
+};
+use anyhow::{bail, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::{
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
+    ops::{BitOr, Range},
+};
+use xbe::Xbe;
+
+/// Size of the certificate fields xbld reads
+const CERTIFICATE_SIZE: usize = 0xB0;
+/// Offset of the alternate title IDs within the certificate
+const ALTERNATE_TITLE_IDS_OFFSET: usize = 0x5C;
+/// Offset of the allowed media bits within the certificate
+const ALLOWED_MEDIA_OFFSET: usize = 0x9C;
+/// Offset of the game region bits within the certificate
+const GAME_REGION_OFFSET: usize = 0xA0;
+/// Offset of the game ratings within the certificate
+const GAME_RATINGS_OFFSET: usize = 0xA4;
+/// Offset of the disk number within the certificate
+const DISK_NUMBER_OFFSET: usize = 0xA8;
+
+/// File offset of the logo's virtual address in a serialized XBE
+const LOGO_ADDRESS_OFFSET: usize = 0x170;
+/// File offset of the logo's size in a serialized XBE
+const LOGO_SIZE_OFFSET: usize = 0x174;
+/// A one byte logo entry for a run of no pixels, to pad a re-encoded logo to the original size
+const LOGO_PADDING: u8 = 0x1;
+
+/// File offset of the initialization flags in a serialized XBE
+const INIT_FLAGS_OFFSET: usize = 0x124;
+
+/// File offset of the size of the image once loaded in a serialized XBE
+const SIZE_OF_IMAGE_OFFSET: usize = 0x10C;
+/// File offset of the PE fields copied from the executable the XBE was built from
+const PE_FIELDS_OFFSET: usize = 0x130;
+/// File offset of the PE size of image in a serialized XBE
+const PE_SIZE_OF_IMAGE_OFFSET: usize = 0x140;
+/// File offset of the PE timestamp in a serialized XBE
+const PE_TIMEDATE_OFFSET: usize = 0x148;
+
+/// File offset of the number of library version records in a serialized XBE
+const LIBRARY_COUNT_OFFSET: usize = 0x160;
+/// File offset of the virtual address of the library version records in a serialized XBE
+const LIBRARY_VERSIONS_ADDRESS_OFFSET: usize = 0x164;
+/// File offsets of the virtual addresses of the XBOXKRNL and XAPILIB library version records in
+/// a serialized XBE, in the order of [`REQUIRED_LIBRARIES`]
+const REQUIRED_LIBRARY_ADDRESS_OFFSETS: [usize; 2] = [0x168, 0x16C];
+/// Size of a library version record
+const LIBRARY_VERSION_SIZE: usize = 0x10;
+
+/// Fields of the image header copied from the PE executable the XBE was built from, which the
+/// kernel doesn't use but debugging tools may. See [`XbeImage::pe_size_of_image`] for the PE size
+/// of image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeFields {
+    pub stack_commit: u32,
+    pub heap_reserve: u32,
+    pub heap_commit: u32,
+    pub base_address: u32,
+    pub timedate: u32,
+}
+
+/// Offset of the SHA-1 digest within a section header
+const SECTION_DIGEST_OFFSET: usize = 0x24;
+
+/// How [`XbeImage::serialize`] writes the SHA-1 digest of each section
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DigestMode {
+    /// Keep the digests the xbe crate writes: the loaded digests of the sections it loaded, even
+    /// if patches changed their data, and zeros for added sections
+    #[default]
+    Preserve,
+    /// Compute each section's digest from its serialized data
+    Recompute,
+}
+
+/// Whether the SHA-1 digest stored in a section header matches the section's data, see
+/// [`XbeExt::verify_digests`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestStatus {
+    Match,
+    Mismatch {
+        stored: [u8; 20],
+        computed: [u8; 20],
+    },
+    /// The stored digest is all zeros, as xbld writes for the sections it adds unless digests
+    /// are recomputed
+    Absent,
+}
+
+/// The [`DigestStatus`] of each section of the serialized XBE `image`, with the virtual address
+/// of the section, in the order the sections are stored
+pub(crate) fn digest_statuses(image: &[u8]) -> Result<Vec<(u32, DigestStatus)>> {
+    let layout = RawLayout::parse(image)?;
+    stored_digests(image)?
+        .into_iter()
+        .zip(layout.sections.iter())
+        .map(|((virtual_address, stored), section)| {
+            let computed = section_digest(image, section.raw_range())?;
+            Ok((virtual_address, DigestStatus::new(stored, computed)))
+        })
+        .collect()
+}
+
+/// The SHA-1 digest stored in each section header of the serialized XBE `image`, with the
+/// virtual address of the section, in the order the sections are stored
+fn stored_digests(image: &[u8]) -> Result<Vec<(u32, [u8; 20])>> {
+    RawLayout::parse(image)?
+        .sections
+        .iter()
+        .map(|section| {
+            let start = section.offset + SECTION_DIGEST_OFFSET;
+            let Some(stored) = image.get(start..start + 20) else {
+                bail!(SectionError::Truncated(start + 20));
+            };
+            Ok((section.virtual_address, stored.try_into()?))
+        })
+        .collect()
+}
+
+impl DigestStatus {
+    /// Status of the `stored` digest of a section whose data hashes to `computed`
+    fn new(stored: [u8; 20], computed: [u8; 20]) -> Self {
+        if stored == [0; 20] {
+            DigestStatus::Absent
+        } else if stored == computed {
+            DigestStatus::Match
+        } else {
+            DigestStatus::Mismatch { stored, computed }
+        }
+    }
+}
+
+/// How the kernel sets up the system before running the title, the image header's
+/// initialization flags. Bits without a name are kept as they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InitFlags(pub u32);
+
+impl InitFlags {
+    pub const MOUNT_UTILITY_DRIVE: Self = Self(0x1);
+    pub const FORMAT_UTILITY_DRIVE: Self = Self(0x2);
+    pub const LIMIT_64MB: Self = Self(0x4);
+    pub const DONT_SETUP_HARDDISK: Self = Self(0x8);
+
+    /// Whether every bit of `other` is set
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Sets or clears every bit of `other`
+    pub fn set(&mut self, other: Self, value: bool) {
+        if value {
+            self.0 |= other.0;
+        } else {
+            self.0 &= !other.0;
+        }
+    }
+}
+
+impl BitOr for InitFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Regions a title may be played in, the certificate's game region bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GameRegion(pub u32);
+
+impl GameRegion {
+    pub const NORTH_AMERICA: Self = Self(0x1);
+    pub const JAPAN: Self = Self(0x2);
+    pub const REST_OF_WORLD: Self = Self(0x4);
+    pub const MANUFACTURING: Self = Self(0x8000_0000);
+    /// Every retail region, for region free builds
+    pub const ALL: Self = Self(0x7);
+
+    /// Whether every bit of `other` is set
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for GameRegion {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Media a title may be run from, the certificate's allowed media bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllowedMedia(pub u32);
+
+impl AllowedMedia {
+    pub const HARD_DISK: Self = Self(0x1);
+    pub const DVD_X2: Self = Self(0x2);
+    pub const DVD_CD: Self = Self(0x4);
+    pub const CD: Self = Self(0x8);
+    pub const DVD_5_RO: Self = Self(0x10);
+    pub const DVD_9_RO: Self = Self(0x20);
+    pub const DVD_5_RW: Self = Self(0x40);
+    pub const DVD_9_RW: Self = Self(0x80);
+    pub const DONGLE: Self = Self(0x100);
+    pub const MEDIA_BOARD: Self = Self(0x200);
+    pub const NONSECURE_HARD_DISK: Self = Self(0x4000_0000);
+    pub const NONSECURE_MODE: Self = Self(0x8000_0000);
+
+    /// Whether every bit of `other` is set
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for AllowedMedia {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// The certificate's game ratings, an ESRB rating
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GameRatings {
+    RatingPending,
+    AdultsOnly,
+    Mature,
+    Teen,
+    Everyone,
+    KidsToAdults,
+    EarlyChildhood,
+    /// Any other value, such as the `0xFFFFFFFF` of unrated titles
+    Other(u32),
+}
+
+impl From<u32> for GameRatings {
+    fn from(raw: u32) -> Self {
+        match raw {
+            0 => GameRatings::RatingPending,
+            1 => GameRatings::AdultsOnly,
+            2 => GameRatings::Mature,
+            3 => GameRatings::Teen,
+            4 => GameRatings::Everyone,
+            5 => GameRatings::KidsToAdults,
+            6 => GameRatings::EarlyChildhood,
+            raw => GameRatings::Other(raw),
+        }
+    }
+}
+
+impl From<GameRatings> for u32 {
+    fn from(ratings: GameRatings) -> Self {
+        match ratings {
+            GameRatings::RatingPending => 0,
+            GameRatings::AdultsOnly => 1,
+            GameRatings::Mature => 2,
+            GameRatings::Teen => 3,
+            GameRatings::Everyone => 4,
+            GameRatings::KidsToAdults => 5,
+            GameRatings::EarlyChildhood => 6,
+            GameRatings::Other(raw) => raw,
+        }
+    }
+}
+
+/// Defines [`HeaderJson`] from one list of its fields, each with how [`XbeImage::header_json`]
+/// reads it, so a field can't be declared without being read or read in the wrong place. The
+/// closure-like head names the bindings the read expressions use: the [`XbeImage`], its serialized
+/// bytes, and functions reading a `u32` at an offset into the image header and the certificate.
+macro_rules! header_json {
+    (|$xbe:ident, $image:ident, $field:ident, $certificate_field:ident| {
+        $($(#[$attr:meta])* $name:ident: $ty:ty = $read:expr,)*
+    }) => {
+        /// The image header and certificate fields of a serialized XBE, in a form that can be
+        /// written as JSON for other tools. See [`XbeImage::header_json`].
+        #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+        pub struct HeaderJson {
+            $($(#[$attr])* pub $name: $ty,)*
+        }
+
+        impl HeaderJson {
+            fn read($xbe: &XbeImage) -> Result<Self> {
+                let $image = $xbe.serialize()?;
+                let $field = |offset: usize| read_u32(&$image, offset);
+                let certificate = certificate_offset(&$image)?;
+                let $certificate_field = |offset: usize| read_u32(&$image, certificate + offset);
+                Ok(Self {
+                    $($name: $read,)*
+                })
+            }
+        }
+    };
+}
+
+header_json!(|xbe, image, field, certificate_field| {
+    /// Hex encoded digital signature of the headers
+    digital_signature: String = image[0x4..0x104]
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect(),
+    base_address: u32 = field(0x104)?,
+    size_of_headers: u32 = field(0x108)?,
+    size_of_image: u32 = field(SIZE_OF_IMAGE_OFFSET)?,
+    size_of_image_header: u32 = field(0x110)?,
+    timedate: u32 = field(0x114)?,
+    certificate_address: u32 = field(0x118)?,
+    section_count: u32 = field(0x11C)?,
+    section_headers_address: u32 = field(0x120)?,
+    init_flags: u32 = field(INIT_FLAGS_OFFSET)?,
+    /// Entry point as stored, XOR encoded with the retail or debug key
+    entry_point: u32 = field(0x128)?,
+    tls_address: u32 = field(0x12C)?,
+    pe_stack_commit: u32 = field(PE_FIELDS_OFFSET)?,
+    pe_heap_reserve: u32 = field(PE_FIELDS_OFFSET + 0x4)?,
+    pe_heap_commit: u32 = field(PE_FIELDS_OFFSET + 0x8)?,
+    pe_base_address: u32 = field(PE_FIELDS_OFFSET + 0xC)?,
+    pe_size_of_image: u32 = field(PE_SIZE_OF_IMAGE_OFFSET)?,
+    pe_checksum: u32 = field(header::PE_CHECKSUM_OFFSET)?,
+    pe_timedate: u32 = field(PE_TIMEDATE_OFFSET)?,
+    debug_pathname: String = xbe.debug_pathname().to_string(),
+    /// Kernel thunk address as stored, XOR encoded with the retail or debug key
+    kernel_thunk_address: u32 = field(0x158)?,
+    title_id: u32 = certificate_field(0x8)?,
+    title_name: String = header::title_name(&xbe.xbe),
+    alternate_title_ids: [u32; MAX_ALTERNATE_TITLE_IDS] = xbe.alternate_title_ids,
+    allowed_media: u32 = certificate_field(ALLOWED_MEDIA_OFFSET)?,
+    game_region: u32 = certificate_field(GAME_REGION_OFFSET)?,
+    game_ratings: u32 = certificate_field(GAME_RATINGS_OFFSET)?,
+    disk_number: u32 = certificate_field(DISK_NUMBER_OFFSET)?,
+    version: u32 = certificate_field(0xAC)?,
+});
+
+/// An [`Xbe`] along with the header fields it doesn't model, see [`header::serialize`]. The
+/// fields are read from the image the XBE is loaded from and written back over its serialized image by
+/// [`XbeImage::serialize`], so an unchanged `XbeImage` serializes them as they were loaded. A
+/// field changed in the [`Xbe`] itself, such as by a header patch, is kept unless its setter
+/// changed it too.
+pub struct XbeImage {
+    pub xbe: Xbe,
+    init_flags: InitFlags,
+    pe_fields: PeFields,
+    /// PE size of image given by [`XbeImage::set_pe_size_of_image`]
+    pe_size_of_image: Option<u32>,
+    /// Size of image and PE size of image of the image the XBE was loaded from
+    loaded_sizes_of_image: (u32, u32),
+    alternate_title_ids: [u32; MAX_ALTERNATE_TITLE_IDS],
+    allowed_media: AllowedMedia,
+    game_region: GameRegion,
+    game_ratings: GameRatings,
+    disk_number: u32,
+    /// Bytes after the end of the last section of the image the XBE was loaded from
+    trailing_padding: Option<Vec<u8>>,
+    digest_mode: DigestMode,
+    /// Encoded logo given by [`XbeImage::set_logo_image`]
+    logo: Option<Vec<u8>>,
+    /// Header pointers to the XBOXKRNL and XAPILIB library versions of the image the XBE was
+    /// loaded from, kept for libraries it doesn't have
+    required_library_addresses: [u32; 2],
+    /// Number of placeholder library versions added for libraries the loaded image doesn't have
+    library_placeholders: usize,
+    /// Whether a backslash was added to a debug pathname that had none, see
+    /// [`header::add_debug_backslash`]
+    debug_backslash: bool,
+    /// Virtual addresses and file offsets of the sections whose data the loaded image embedded in
+    /// its headers
+    embedded_sections: Vec<(u32, u32)>,
+    /// Digests stored in the section headers of the image the XBE was loaded from, by the virtual
+    /// address of the section
+    loaded_digests: Vec<(u32, [u8; 20])>,
+    /// Values of the fields of [`XbeImage::header_fields`] in the image the XBE was loaded from
+    loaded_fields: Vec<u32>,
+    /// Values the xbe crate wrote for the fields of [`XbeImage::header_fields`] and the PE size of
+    /// image when the XBE was loaded. A field it writes differently later was changed in the
+    /// [`Xbe`].
+    crate_fields: (Vec<u32>, u32),
+}
+
+impl XbeImage {
+    /// Parses the serialized XBE `image`. The bytes after the end of its last section are kept,
+    /// so they can be written as they were. An image that ends before the data of a section does
+    /// is refused with [`SectionError::Truncated`].
+    ///
+    /// Images with section names that aren't UTF-8 are refused with [`SectionError::NonUtf8Name`].
+    /// Section data embedded in the headers is moved out of their way, and [`XbeImage::serialize`]
+    /// embeds it where it was again.
+    ///
+    /// Images without an XBOXKRNL or XAPILIB library version are given an empty placeholder so
+    /// they can be serialized. [`XbeImage::serialize`] leaves the placeholder out again and writes
+    /// the header's pointer to the library as it was loaded, which may be 0. Likewise a debug
+    /// pathname without a backslash is written as it was loaded, see
+    /// [`XbeImage::debug_pathname`].
+    pub fn new(image: &[u8]) -> Result<Self> {
+        let layout = RawLayout::parse(image)?;
+        if let Some(end) = layout
+            .sections
+            .iter()
+            .map(|s| s.raw_range().end as usize)
+            .find(|&end| end > image.len())
+        {
+            bail!(SectionError::Truncated(end));
+        }
+        for section in layout.sections.iter() {
+            let start = section.name_address.saturating_sub(layout.base_address) as usize;
+            let name = image.get(start..).unwrap_or_default();
+            let name = name.split(|&b| b == 0).next().unwrap_or_default();
+            if std::str::from_utf8(name).is_err() {
+                bail!(SectionError::NonUtf8Name(start));
+            }
+        }
+
+        let (moved, embedded_sections) = move_embedded_sections(image, &layout)?;
+        let mut xbe = Xbe::new(&moved)?;
+        let missing = library::missing_required_libraries(&xbe);
+        for name in missing.iter() {
+            warn!("XBE has no {name} library version, keeping the header's pointer to it");
+            xbe.library_versions
+                .push(library::placeholder_library(name.as_bytes()));
+        }
+
+        let debug_backslash = header::add_debug_backslash(&mut xbe);
+
+        let end = (layout.raw_end() as usize).min(image.len());
+        let mut xbe_image = Self::with_fields(xbe, image)?;
+        xbe_image.trailing_padding = Some(image[end..].to_vec());
+        xbe_image.library_placeholders = missing.len();
+        xbe_image.debug_backslash = debug_backslash;
+        xbe_image.embedded_sections = embedded_sections;
+        Ok(xbe_image)
+    }
+
+    /// Reads a serialized XBE from `reader` and parses it like [`XbeImage::new`]. Only the
+    /// headers, the data of each section and the bytes after the last section are read. The
+    /// padding between sections isn't kept, so it's skipped. An image that ends before the headers
+    /// or the data of a section does is refused with [`SectionError::Truncated`], giving the
+    /// offset the data was expected to reach.
+    pub fn read_from(mut reader: impl Read + Seek) -> Result<Self> {
+        let mut image = vec![0; 0x10C];
+        read_exact_at(&mut reader, 0, &mut image)?;
+        let size_of_headers = read_u32(&image, 0x108)? as usize;
+        if size_of_headers > image.len() {
+            image.resize(size_of_headers, 0);
+            read_exact_at(&mut reader, 0x10C, &mut image[0x10C..])?;
+        }
+
+        let layout = RawLayout::parse(&image)?;
+        let len = reader.seek(SeekFrom::End(0))? as usize;
+        if let Some(end) = layout
+            .sections
+            .iter()
+            .map(|s| s.raw_range().end as usize)
+            .find(|&end| end > len)
+        {
+            bail!(SectionError::Truncated(end));
+        }
+        image.resize(image.len().max(layout.raw_end() as usize), 0);
+        for section in layout.sections.iter().filter(|s| s.raw_size > 0) {
+            let range = section.raw_range().start as usize..section.raw_range().end as usize;
+            read_exact_at(&mut reader, range.start, &mut image[range])?;
+        }
+
+        // The bytes after the last section are written back as they were
+        reader.seek(SeekFrom::Start(image.len() as u64))?;
+        reader.read_to_end(&mut image)?;
+        Self::new(&image)
+    }
+
+    /// Wraps `xbe`, taking the fields it doesn't model from its serialized image. The padding of
+    /// that image is written after the last section. A debug pathname without a backslash is kept
+    /// like [`XbeImage::new`] keeps it.
+    pub fn from_xbe(mut xbe: Xbe) -> Result<Self> {
+        let debug_backslash = header::add_debug_backslash(&mut xbe);
+        let image = header::serialize(&xbe)?;
+        let mut xbe_image = Self::with_fields(xbe, &image)?;
+        xbe_image.debug_backslash = debug_backslash;
+        Ok(xbe_image)
+    }
+
+    fn with_fields(xbe: Xbe, image: &[u8]) -> Result<Self> {
+        let certificate = certificate_offset(image)?;
+        let mut alternate_title_ids = [0; MAX_ALTERNATE_TITLE_IDS];
+        for (i, id) in alternate_title_ids.iter_mut().enumerate() {
+            *id = read_u32(image, certificate + ALTERNATE_TITLE_IDS_OFFSET + i * 4)?;
+        }
+
+        let mut xbe_image = Self {
+            xbe,
+            init_flags: InitFlags(read_u32(image, INIT_FLAGS_OFFSET)?),
+            pe_fields: PeFields {
+                stack_commit: read_u32(image, PE_FIELDS_OFFSET)?,
+                heap_reserve: read_u32(image, PE_FIELDS_OFFSET + 0x4)?,
+                heap_commit: read_u32(image, PE_FIELDS_OFFSET + 0x8)?,
+                base_address: read_u32(image, PE_FIELDS_OFFSET + 0xC)?,
+                timedate: read_u32(image, PE_TIMEDATE_OFFSET)?,
+            },
+            pe_size_of_image: None,
+            loaded_sizes_of_image: (
+                read_u32(image, SIZE_OF_IMAGE_OFFSET)?,
+                read_u32(image, PE_SIZE_OF_IMAGE_OFFSET)?,
+            ),
+            alternate_title_ids,
+            allowed_media: AllowedMedia(read_u32(image, certificate + ALLOWED_MEDIA_OFFSET)?),
+            game_region: GameRegion(read_u32(image, certificate + GAME_REGION_OFFSET)?),
+            game_ratings: read_u32(image, certificate + GAME_RATINGS_OFFSET)?.into(),
+            disk_number: read_u32(image, certificate + DISK_NUMBER_OFFSET)?,
+            trailing_padding: None,
+            digest_mode: DigestMode::default(),
+            logo: None,
+            required_library_addresses: [
+                read_u32(image, REQUIRED_LIBRARY_ADDRESS_OFFSETS[0])?,
+                read_u32(image, REQUIRED_LIBRARY_ADDRESS_OFFSETS[1])?,
+            ],
+            library_placeholders: 0,
+            debug_backslash: false,
+            embedded_sections: Vec::new(),
+            loaded_digests: stored_digests(image)?,
+            loaded_fields: Vec::new(),
+            crate_fields: (Vec::new(), 0),
+        };
+        let serialized = header::serialize(&xbe_image.xbe)?;
+        xbe_image.loaded_fields = xbe_image
+            .header_fields(image)?
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect();
+        xbe_image.crate_fields = (
+            xbe_image
+                .header_fields(&serialized)?
+                .into_iter()
+                .map(|(offset, _)| read_u32(&serialized, offset))
+                .collect::<Result<_>>()?,
+            read_u32(&serialized, PE_SIZE_OF_IMAGE_OFFSET)?,
+        );
+        Ok(xbe_image)
+    }
+
+    pub fn init_flags(&self) -> InitFlags {
+        self.init_flags
+    }
+
+    pub fn set_init_flags(&mut self, flags: InitFlags) {
+        self.init_flags = flags;
+    }
+
+    pub fn pe_fields(&self) -> &PeFields {
+        &self.pe_fields
+    }
+
+    pub fn pe_fields_mut(&mut self) -> &mut PeFields {
+        &mut self.pe_fields
+    }
+
+    /// The PE size of image [`XbeImage::serialize`] writes for an image of `size_of_image`. Unless
+    /// one is set it's the size of image, or the loaded PE size of image if the size of image
+    /// hasn't changed since the XBE was loaded.
+    pub fn pe_size_of_image(&self, size_of_image: u32) -> u32 {
+        match (self.pe_size_of_image, self.loaded_sizes_of_image) {
+            (Some(size), _) => size,
+            (None, (loaded, pe_size)) if loaded == size_of_image => pe_size,
+            (None, _) => size_of_image,
+        }
+    }
+
+    /// Replaces the PE size of image, or lets it follow the size of image if `None`
+    pub fn set_pe_size_of_image(&mut self, size: Option<u32>) {
+        self.pe_size_of_image = size;
+    }
+
+    /// The certificate's alternate title IDs, the IDs of other releases of the title whose saves
+    /// can be loaded. Unused entries are zero.
+    pub fn alternate_title_ids(&self) -> &[u32; MAX_ALTERNATE_TITLE_IDS] {
+        &self.alternate_title_ids
+    }
+
+    /// Replaces the alternate title IDs with `ids`, zero filling unused entries.
+    ///
+    /// A mod that changes the title ID keeps saves of the original release loadable by listing
+    /// the original title ID here:
+    ///
+    /// ```ignore
+    /// let mut image = XbeImage::new(&fs::read("default.xbe")?)?;
+    /// // ...change the title ID...
+    /// image.set_alternate_title_ids(&[original_title_id])?;
+    /// let bytes = image.serialize()?;
+    /// ```
+    pub fn set_alternate_title_ids(&mut self, ids: &[u32]) -> Result<()> {
+        if ids.len() > MAX_ALTERNATE_TITLE_IDS {
+            bail!(HeaderError::TooManyAlternateTitleIds(ids.len()));
+        }
+
+        self.alternate_title_ids = [0; MAX_ALTERNATE_TITLE_IDS];
+        self.alternate_title_ids[..ids.len()].copy_from_slice(ids);
+        Ok(())
+    }
+
+    /// Media the title may be run from
+    pub fn allowed_media(&self) -> AllowedMedia {
+        self.allowed_media
+    }
+
+    pub fn set_allowed_media(&mut self, media: AllowedMedia) {
+        self.allowed_media = media;
+    }
+
+    /// Regions the title may be played in
+    pub fn game_region(&self) -> GameRegion {
+        self.game_region
+    }
+
+    /// Replaces the regions the title may be played in, [`GameRegion::ALL`] for a region free
+    /// build
+    pub fn set_game_region(&mut self, region: GameRegion) {
+        self.game_region = region;
+    }
+
+    pub fn game_ratings(&self) -> GameRatings {
+        self.game_ratings
+    }
+
+    pub fn set_game_ratings(&mut self, ratings: GameRatings) {
+        self.game_ratings = ratings;
+    }
+
+    /// Which disk of a multi-disk title this is, from 0
+    pub fn disk_number(&self) -> u32 {
+        self.disk_number
+    }
+
+    pub fn set_disk_number(&mut self, disk_number: u32) {
+        self.disk_number = disk_number;
+    }
+
+    pub fn digest_mode(&self) -> DigestMode {
+        self.digest_mode
+    }
+
+    pub fn set_digest_mode(&mut self, mode: DigestMode) {
+        self.digest_mode = mode;
+    }
+
+    /// The logo shown by the dashboard, as [`LOGO_WIDTH`] by [`LOGO_HEIGHT`] 8-bit grayscale
+    /// pixels, see [`decode_logo`]
+    pub fn logo_image(&self) -> Result<Vec<u8>> {
+        let image = self.serialize()?;
+        let range = logo_range(&image)?;
+        decode_logo(&image[range])
+    }
+
+    /// Replaces the logo shown by the dashboard with `pixels`, see [`encode_logo`]. The headers
+    /// are laid out by the xbe crate, so the logo can't grow: the encoded logo must fit in the
+    /// space of the current one, and is padded with empty runs to fill it.
+    pub fn set_logo_image(&mut self, pixels: &[u8], width: usize, height: usize) -> Result<()> {
+        let mut raw = encode_logo(pixels, width, height)?;
+        let size = logo_range(&self.serialize()?)?.len();
+        if raw.len() > size {
+            bail!(LogoError::TooLarge(raw.len(), size));
+        }
+        raw.resize(size, LOGO_PADDING);
+        self.logo = Some(raw);
+        Ok(())
+    }
+
+    /// Whether the SHA-1 digest each section had in the image the XBE was loaded from matches its
+    /// data now, by section name in the order they're stored, like [`XbeExt::verify_digests`].
+    /// The digests are computed from the loaded section data, without serializing the XBE.
+    /// Sections added since it was loaded have no digest.
+    pub fn verify_digests(&self) -> Vec<(String, DigestStatus)> {
+        self.xbe
+            .sections
+            .iter()
+            .map(|section| {
+                let stored = self
+                    .loaded_digests
+                    .iter()
+                    .find(|(address, _)| *address == section.virtual_address)
+                    .map_or([0; 20], |(_, digest)| *digest);
+                let status = DigestStatus::new(stored, data_digest(&section.data));
+                (strip_null(&section.name).to_string(), status)
+            })
+            .collect()
+    }
+
+    /// Debug pathname of the XBE as [`XbeImage::serialize`] writes it. The backslash added to a
+    /// loaded pathname without one is left out while it's the only one.
+    pub fn debug_pathname(&self) -> &str {
+        let pathname = &self.xbe.header.debug_pathname;
+        match pathname.strip_prefix('\\') {
+            Some(added) if self.debug_backslash && !added.contains('\\') => added,
+            _ => pathname,
+        }
+    }
+
+    /// The header fields of the XBE as [`XbeImage::serialize`] writes them
+    pub fn header_json(&self) -> Result<HeaderJson> {
+        HeaderJson::read(self)
+    }
+
+    /// Writes the serialized XBE to `writer`, with its PE checksum recomputed from the serialized
+    /// bytes. The headers are written first, then the data of each section in file order, straight
+    /// from the [`Xbe`], with whatever the image has between them. The xbe crate can only serialize
+    /// a whole XBE, so the headers and checksum still come from one image in memory, but only the
+    /// headers and the bytes between sections are kept from it while the data is written.
+    pub fn write_to(&self, mut writer: impl Write) -> Result<()> {
+        let mut image = self.serialize()?;
+        if header::update_pe_checksum(&mut image).is_none() {
+            bail!(SectionError::Truncated(header::PE_CHECKSUM_OFFSET + 4));
+        }
+        let layout = RawLayout::parse(&image)?;
+        let mut headers: Vec<_> = layout
+            .sections
+            .iter()
+            .filter(|s| s.raw_size > 0 && s.raw_address >= layout.size_of_headers)
+            .collect();
+        headers.sort_by_key(|s| s.raw_address);
+
+        // What's between the data of the sections, ending with what's after the last one
+        let mut end = layout.size_of_headers as usize;
+        let mut chunks = Vec::with_capacity(headers.len() + 1);
+        for header in headers.iter() {
+            let range = header.raw_range();
+            chunks.push(
+                image
+                    .get(end..range.start as usize)
+                    .unwrap_or_default()
+                    .to_vec(),
+            );
+            end = range.end as usize;
+        }
+        chunks.push(image.get(end..).unwrap_or_default().to_vec());
+        image.truncate(layout.size_of_headers as usize);
+        image.shrink_to_fit();
+
+        writer.write_all(&image)?;
+        for (header, chunk) in headers.iter().zip(chunks.iter()) {
+            let Some(section) = self
+                .xbe
+                .sections
+                .iter()
+                .find(|s| s.virtual_address == header.virtual_address)
+            else {
+                bail!(SectionError::UnmatchedHeader(header.virtual_address));
+            };
+            writer.write_all(chunk)?;
+            writer.write_all(&section.data)?;
+        }
+        writer.write_all(chunks.last().map_or(&[], Vec::as_slice))?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Serializes the XBE with [`header::serialize`], then writes the fields it doesn't model over
+    /// the serialized image. A field the xbe crate now writes differently than when the XBE was loaded was changed in the
+    /// [`Xbe`], such as by a header patch, so it's kept unless its setter changed it. The PE
+    /// checksum isn't updated, see [`header::update_pe_checksum`].
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        let mut image = header::serialize(&self.xbe)?;
+        for (i, (offset, value)) in self.header_fields(&image)?.into_iter().enumerate() {
+            let set = value != self.loaded_fields[i];
+            if set || read_u32(&image, offset)? == self.crate_fields.0[i] {
+                write_u32(&mut image, offset, value)?;
+            }
+        }
+        if self.pe_size_of_image.is_some()
+            || read_u32(&image, PE_SIZE_OF_IMAGE_OFFSET)? == self.crate_fields.1
+        {
+            let size_of_image = read_u32(&image, SIZE_OF_IMAGE_OFFSET)?;
+            write_u32(
+                &mut image,
+                PE_SIZE_OF_IMAGE_OFFSET,
+                self.pe_size_of_image(size_of_image),
+            )?;
+        }
+
+        self.remove_library_placeholders(&mut image)?;
+        if self.debug_pathname() != self.xbe.header.debug_pathname {
+            header::remove_debug_backslash(&mut image)?;
+        }
+        if !self.embedded_sections.is_empty() {
+            image = self.embed_sections(&image)?;
+        }
+
+        if let Some(padding) = &self.trailing_padding {
+            let end = RawLayout::parse(&image)?.raw_end() as usize;
+            image.resize(end, 0);
+            image.extend_from_slice(padding);
+        }
+
+        if let Some(logo) = &self.logo {
+            let range = logo_range(&image)?;
+            if range.len() != logo.len() {
+                bail!(LogoError::TooLarge(logo.len(), range.len()));
+            }
+            image[range].copy_from_slice(logo);
+        }
+
+        if self.digest_mode == DigestMode::Recompute {
+            for section in RawLayout::parse(&image)?.sections {
+                let digest = section_digest(&image, section.raw_range())?;
+                let start = section.offset + SECTION_DIGEST_OFFSET;
+                match image.get_mut(start..start + digest.len()) {
+                    Some(field) => field.copy_from_slice(&digest),
+                    None => bail!(SectionError::Truncated(start + digest.len())),
+                }
+            }
+        }
+        Ok(image)
+    }
+
+    /// The fields other than the PE size of image that [`XbeImage::serialize`] writes, as their
+    /// file offsets in the serialized XBE `image` and the values it writes
+    fn header_fields(&self, image: &[u8]) -> Result<Vec<(usize, u32)>> {
+        let certificate = certificate_offset(image)?;
+        let pe = &self.pe_fields;
+        let mut fields = vec![
+            (INIT_FLAGS_OFFSET, self.init_flags.0),
+            (PE_FIELDS_OFFSET, pe.stack_commit),
+            (PE_FIELDS_OFFSET + 0x4, pe.heap_reserve),
+            (PE_FIELDS_OFFSET + 0x8, pe.heap_commit),
+            (PE_FIELDS_OFFSET + 0xC, pe.base_address),
+            (PE_TIMEDATE_OFFSET, pe.timedate),
+            (certificate + ALLOWED_MEDIA_OFFSET, self.allowed_media.0),
+            (certificate + GAME_REGION_OFFSET, self.game_region.0),
+            (certificate + GAME_RATINGS_OFFSET, self.game_ratings.into()),
+            (certificate + DISK_NUMBER_OFFSET, self.disk_number),
+        ];
+        fields.extend(
+            self.alternate_title_ids
+                .iter()
+                .enumerate()
+                .map(|(i, &id)| (certificate + ALTERNATE_TITLE_IDS_OFFSET + i * 4, id)),
+        );
+        Ok(fields)
+    }
+
+    /// Moves the data of the sections the loaded image embedded in its headers back to where it
+    /// was in the serialized XBE `image`, growing the headers over it. Data that would overlap the
+    /// headers the xbe crate wrote is left after the other sections.
+    fn embed_sections(&self, image: &[u8]) -> Result<Vec<u8>> {
+        let layout = RawLayout::parse(image)?;
+        let mut embedded = image.to_vec();
+        let mut size_of_headers = layout.size_of_headers;
+        for &(virtual_address, raw_address) in self.embedded_sections.iter() {
+            let Some(section) = layout
+                .sections
+                .iter()
+                .find(|s| s.virtual_address == virtual_address && s.raw_size > 0)
+            else {
+                continue;
+            };
+            if raw_address < layout.size_of_headers {
+                warn!(
+                    "Headers now reach past file offset {raw_address:#x}, so the data of the \
+                     section at {virtual_address:#x} is written after the other sections instead"
+                );
+                continue;
+            }
+            write_u32(&mut embedded, section.offset + 0xC, raw_address)?;
+            size_of_headers = size_of_headers.max(raw_address.saturating_add(section.raw_size));
+        }
+        write_u32(&mut embedded, 0x108, size_of_headers)?;
+        // Sections the grown headers now overlap are moved after them
+        separate_raw_data(&embedded, &self.xbe)
+    }
+
+    /// Leaves the placeholder library versions added by [`XbeImage::new`] out of the serialized
+    /// `image`, zeroing their records and restoring the loaded header pointers to the libraries.
+    /// Placeholders that were replaced with a version are kept.
+    fn remove_library_placeholders(&self, image: &mut [u8]) -> Result<()> {
+        let placeholders: Vec<_> = self
+            .xbe
+            .library_versions
+            .iter()
+            .rev()
+            .take(self.library_placeholders)
+            .take_while(|lib| library::is_placeholder(lib))
+            .collect();
+        if placeholders.is_empty() {
+            return Ok(());
+        }
+
+        let count = read_u32(image, LIBRARY_COUNT_OFFSET)? as usize;
+        let kept = count.saturating_sub(placeholders.len());
+        let start = read_u32(image, LIBRARY_VERSIONS_ADDRESS_OFFSET)?
+            .saturating_sub(read_u32(image, 0x104)?) as usize;
+        let end = start + count * LIBRARY_VERSION_SIZE;
+        match image.get_mut(start + kept * LIBRARY_VERSION_SIZE..end) {
+            Some(records) => records.fill(0),
+            None => bail!(SectionError::Truncated(end)),
+        }
+        write_u32(image, LIBRARY_COUNT_OFFSET, kept as u32)?;
+
+        for ((name, offset), address) in REQUIRED_LIBRARIES
+            .iter()
+            .zip(REQUIRED_LIBRARY_ADDRESS_OFFSETS)
+            .zip(self.required_library_addresses)
+        {
+            if placeholders
+                .iter()
+                .any(|lib| library::same_name(&lib.library_name, name))
+            {
+                write_u32(image, offset, address)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Copies the serialized XBE `image`, moving the data of sections embedded in its headers after
+/// its last section, as the xbe crate would write the headers over it. Returns the copy along with
+/// the virtual addresses and file offsets of the moved sections.
+fn move_embedded_sections(image: &[u8], layout: &RawLayout) -> Result<(Vec<u8>, Vec<(u32, u32)>)> {
+    let mut moved = image[..(layout.raw_end() as usize).min(image.len())].to_vec();
+    let mut embedded = Vec::new();
+    for section in layout
+        .sections
+        .iter()
+        .filter(|s| s.raw_size > 0 && s.raw_address < layout.size_of_headers)
+    {
+        let range = section.raw_range();
+        let Some(data) = image.get(range.start as usize..range.end as usize) else {
+            bail!(SectionError::Truncated(range.end as usize));
+        };
+        moved.resize(moved.len().next_multiple_of(RAW_ALIGNMENT), 0);
+        write_u32(&mut moved, section.offset + 0xC, moved.len() as u32)?;
+        moved.extend_from_slice(data);
+        embedded.push((section.virtual_address, section.raw_address));
+    }
+    Ok((moved, embedded))
+}
+
+/// File offsets of the logo in the serialized XBE `image`, found through its virtual address at
+/// offset 0x170
+fn logo_range(image: &[u8]) -> Result<Range<usize>> {
+    let address = read_u32(image, LOGO_ADDRESS_OFFSET)?;
+    let start = address.saturating_sub(read_u32(image, 0x104)?) as usize;
+    let end = start.saturating_add(read_u32(image, LOGO_SIZE_OFFSET)? as usize);
+    if end > image.len() {
+        bail!(SectionError::Truncated(end));
+    }
+    Ok(start..end)
+}
+
+/// File offset of the certificate in the serialized XBE `image`, found through the certificate
+/// address at offset 0x118
+fn certificate_offset(image: &[u8]) -> Result<usize> {
+    let certificate = read_u32(image, 0x118)?.checked_sub(read_u32(image, 0x104)?);
+    match certificate.map(|c| c as usize) {
+        Some(offset) if image.len() >= offset.saturating_add(CERTIFICATE_SIZE) => Ok(offset),
+        _ => bail!(HeaderError::MissingCertificate),
+    }
+}
+
+/// Fills `buf` with the bytes of `reader` at the file offset `offset`, failing with
+/// [`SectionError::Truncated`] if the reader ends first
+fn read_exact_at(reader: &mut (impl Read + Seek), offset: usize, buf: &mut [u8]) -> Result<()> {
+    reader.seek(SeekFrom::Start(offset as u64))?;
+    match reader.read_exact(buf) {
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+            bail!(SectionError::Truncated(offset + buf.len()))
+        }
+        result => Ok(result?),
+    }
+}
+
+/// SHA-1 digest of the section whose data is at `range` of the serialized XBE `image`, taken over
+/// its little endian raw size followed by its data
+fn section_digest(image: &[u8], range: Range<u32>) -> Result<[u8; 20]> {
+    let Some(data) = image.get(range.start as usize..range.end as usize) else {
+        bail!(SectionError::Truncated(range.end as usize));
+    };
+    Ok(data_digest(data))
+}
+
+/// SHA-1 digest of a section with `data`, taken over its little endian length followed by the
+/// data itself
+fn data_digest(data: &[u8]) -> [u8; 20] {
+    let mut sha1 = Sha1::new();
+    sha1.update((data.len() as u32).to_le_bytes());
+    sha1.update(data);
+    sha1.finalize().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::header::decode_alternate_title_ids;
+    use std::{fs, io::Cursor};
+
+    type TestError = std::result::Result<(), Box<dyn std::error::Error>>;
+
+    #[test]
+    fn alternate_title_ids() -> TestError {
+        let bytes = fs::read("test/bin/default.xbe")?;
+        let mut image = XbeImage::new(&bytes)?;
+        let certificate = certificate_offset(&bytes)?;
+        let start = certificate + ALTERNATE_TITLE_IDS_OFFSET;
+        let raw: &[u8; 0x40] = bytes[start..start + 0x40].try_into()?;
+        assert!(image
+            .alternate_title_ids()
+            .starts_with(&decode_alternate_title_ids(raw)));
+
+        // The IDs are kept when the XBE is serialized and loaded again
+        let ids = [0x5454_0003, 0x5454_0010];
+        image.set_alternate_title_ids(&ids)?;
+        let reloaded = XbeImage::new(&image.serialize()?)?;
+        assert_eq!(reloaded.alternate_title_ids()[..2], ids);
+        assert!(reloaded.alternate_title_ids()[2..]
+            .iter()
+            .all(|&id| id == 0));
+
+        let err = image
+            .set_alternate_title_ids(&[1; MAX_ALTERNATE_TITLE_IDS + 1])
+            .expect_err("Too many alternate title IDs");
+        assert!(matches!(
+            err.downcast_ref::<HeaderError>(),
+            Some(HeaderError::TooManyAlternateTitleIds(17))
+        ));
+        assert_eq!(image.alternate_title_ids()[..2], ids);
+
+        assert!(certificate_offset(&bytes[..0x100]).is_err());
+        Ok(())
+    }
+    #[test]
+    fn section_digests() -> TestError {
+        let bytes = fs::read("test/bin/default.xbe")?;
+        let mut image = XbeImage::new(&bytes)?;
+        assert_eq!(image.serialize()?, XbeImage::new(&bytes)?.serialize()?);
+
+        let address = image.xbe.get_next_virtual_address();
+        image.xbe.add_section(
+            ".mtest\0".to_string(),
+            xbe::SectionFlags::PRELOAD,
+            vec![0xAB; 0x10],
+            address,
+            0x10,
+        );
+        image.set_digest_mode(DigestMode::Recompute);
+        let serialized = image.serialize()?;
+        let layout = RawLayout::parse(&serialized)?;
+        assert_eq!(layout.sections.len(), image.xbe.sections.len());
+        for section in layout.sections.iter() {
+            let data =
+                &serialized[section.raw_range().start as usize..][..section.raw_size as usize];
+            let mut sha1 = Sha1::new();
+            sha1.update(section.raw_size.to_le_bytes());
+            sha1.update(data);
+            let start = section.offset + SECTION_DIGEST_OFFSET;
+            assert_eq!(serialized[start..start + 20], sha1.finalize()[..]);
+        }
+
+        // The digests of unmodified vanilla sections are the ones they were loaded with
+        let vanilla = RawLayout::parse(&bytes)?;
+        let first = &vanilla.sections[0];
+        let start = first.offset + SECTION_DIGEST_OFFSET;
+        assert_eq!(
+            serialized[layout.sections[0].offset + SECTION_DIGEST_OFFSET..][..20],
+            bytes[start..start + 20]
+        );
+        Ok(())
+    }
+    #[test]
+    fn verify_digests() -> TestError {
+        let bytes = fs::read("test/bin/default.xbe")?;
+        let mut image = XbeImage::new(&bytes)?;
+        let statuses = image.verify_digests();
+        assert_eq!(statuses, Xbe::new(&bytes)?.verify_digests()?);
+        assert!(statuses.iter().all(|(_, s)| *s == DigestStatus::Match));
+
+        // Changed data mismatches the loaded digest, and added sections have none
+        let tampered = image
+            .xbe
+            .sections
+            .iter()
+            .position(|s| !s.data.is_empty())
+            .ok_or("No section with data")?;
+        image.xbe.sections[tampered].data[0] ^= 0xFF;
+        let address = image.xbe.get_next_virtual_address();
+        image.xbe.add_section(
+            ".mtest\0".to_string(),
+            xbe::SectionFlags::PRELOAD,
+            vec![0xAB; 0x10],
+            address,
+            0x10,
+        );
+        let statuses = image.verify_digests();
+        assert!(matches!(
+            statuses[tampered].1,
+            DigestStatus::Mismatch { stored, computed } if stored != computed
+        ));
+        assert_eq!(
+            statuses.last(),
+            Some(&(".mtest".to_string(), DigestStatus::Absent))
+        );
+        Ok(())
+    }
+    #[test]
+    fn embedded_section() -> TestError {
+        let mut xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let address = xbe.get_next_virtual_address();
+        let data: Vec<u8> = (0..0x10).collect();
+        let flags = xbe::SectionFlags::PRELOAD;
+        xbe.add_section(".memb\0".to_string(), flags, data.clone(), address, 0x10);
+        let mut bytes = header::serialize(&xbe)?;
+
+        // Move the added section's data to the end of the headers, and grow them over it
+        let layout = RawLayout::parse(&bytes)?;
+        let section = layout
+            .sections
+            .iter()
+            .find(|s| s.virtual_address == address)
+            .ok_or("Added section has no header")?;
+        let raw_address = layout.size_of_headers;
+        bytes.truncate(section.raw_address as usize);
+        bytes[raw_address as usize..][..data.len()].copy_from_slice(&data);
+        write_u32(&mut bytes, section.offset + 0xC, raw_address)?;
+        write_u32(&mut bytes, 0x108, raw_address + 0x10)?;
+
+        // The data is embedded where it was, not clobbered by the headers
+        let serialized = XbeImage::new(&bytes)?.serialize()?;
+        let layout = RawLayout::parse(&serialized)?;
+        let embedded = layout
+            .sections
+            .iter()
+            .find(|s| s.virtual_address == address)
+            .ok_or("Embedded section has no header")?;
+        assert_eq!(embedded.raw_address, raw_address);
+        assert!(embedded.raw_range().end <= layout.size_of_headers);
+        let reloaded = Xbe::new(&serialized)?;
+        assert_eq!(
+            reloaded
+                .sections
+                .iter()
+                .find(|s| s.virtual_address == address)
+                .map(|s| s.data.clone()),
+            Some(data)
+        );
+        assert_eq!(XbeImage::new(&serialized)?.serialize()?, serialized);
+        Ok(())
+    }
+    #[test]
+    fn debug_pathname_without_backslash() -> TestError {
+        for path in ["", "D:/build/default.exe"] {
+            let mut xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+            xbe.header.debug_pathname = path.to_string();
+
+            let image = XbeImage::from_xbe(xbe)?;
+            assert_eq!(image.debug_pathname(), path);
+            let serialized = image.serialize()?;
+            assert_eq!(Xbe::new(&serialized)?.header.debug_pathname, path);
+
+            // Loading the image keeps the pathname too
+            let reloaded = XbeImage::new(&serialized)?;
+            assert_eq!(reloaded.debug_pathname(), path);
+            assert_eq!(reloaded.serialize()?, serialized);
+        }
+        Ok(())
+    }
+    #[test]
+    fn write_to() -> TestError {
+        // Keeps every write apart, to check the image isn't written in one piece
+        struct Writes(Vec<Vec<u8>>);
+        impl Write for Writes {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.push(buf.to_vec());
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let image = XbeImage::new(&fs::read("test/bin/default.xbe")?)?;
+        let mut expected = image.serialize()?;
+        header::update_pe_checksum(&mut expected);
+
+        let mut writes = Writes(Vec::new());
+        image.write_to(&mut writes)?;
+        assert_eq!(writes.0.concat(), expected);
+        let size_of_headers = RawLayout::parse(&expected)?.size_of_headers as usize;
+        assert_eq!(writes.0[0], expected[..size_of_headers]);
+        assert!(writes.0.iter().all(|w| w.len() < expected.len()));
+        Ok(())
+    }
+    #[test]
+    fn truncated() -> TestError {
+        let bytes = fs::read("test/bin/default.xbe")?;
+        let image = XbeImage::read_from(Cursor::new(&bytes))?;
+        assert_eq!(image.serialize()?, XbeImage::new(&bytes)?.serialize()?);
+
+        let layout = RawLayout::parse(&bytes)?;
+        let last = layout
+            .sections
+            .iter()
+            .max_by_key(|s| s.raw_range().end)
+            .ok_or("XBE has no sections")?;
+        let end = last.raw_range().end as usize;
+        let truncated = &bytes[..end - 1];
+        let err = XbeImage::read_from(Cursor::new(truncated))
+            .err()
+            .ok_or("Read a truncated XBE")?;
+        assert!(matches!(
+            err.downcast_ref::<SectionError>(),
+            Some(&SectionError::Truncated(offset)) if offset == end
+        ));
+
+        let err = XbeImage::read_from(Cursor::new(&bytes[..0x110]))
+            .err()
+            .ok_or("Read a truncated XBE")?;
+        assert!(matches!(
+            err.downcast_ref::<SectionError>(),
+            Some(SectionError::Truncated(_))
+        ));
+        Ok(())
+    }
+    #[test]
+    fn read_from_skips_padding() -> TestError {
+        /// Reader that counts the bytes read through it
+        struct CountingReader<R> {
+            inner: R,
+            read: usize,
+        }
+        impl<R: Read> Read for CountingReader<R> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let read = self.inner.read(buf)?;
+                self.read += read;
+                Ok(read)
+            }
+        }
+        impl<R: Seek> Seek for CountingReader<R> {
+            fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+                self.inner.seek(pos)
+            }
+        }
+
+        let bytes = fs::read("test/bin/default.xbe")?;
+        let mut reader = CountingReader {
+            inner: Cursor::new(&bytes),
+            read: 0,
+        };
+        let image = XbeImage::read_from(&mut reader)?;
+        assert_eq!(image.serialize()?, XbeImage::new(&bytes)?.serialize()?);
+        assert!(
+            reader.read < bytes.len(),
+            "Read {:#x} of {:#x} bytes",
+            reader.read,
+            bytes.len()
+        );
+        Ok(())
+    }
+    #[test]
+    fn certificate_fields() -> TestError {
+        let bytes = fs::read("test/bin/default.xbe")?;
+        let mut image = XbeImage::new(&bytes)?;
+        let certificate = certificate_offset(&bytes)?;
+        let raw = |offset: usize| read_u32(&bytes, certificate + offset);
+        assert_eq!(image.allowed_media().0, raw(ALLOWED_MEDIA_OFFSET)?);
+        assert_eq!(image.game_region().0, raw(GAME_REGION_OFFSET)?);
+        assert_eq!(u32::from(image.game_ratings()), raw(GAME_RATINGS_OFFSET)?);
+        assert_eq!(image.disk_number(), raw(DISK_NUMBER_OFFSET)?);
+
+        // Unchanged fields are written back as they were loaded
+        let unchanged = image.serialize()?;
+        let range = certificate..certificate + CERTIFICATE_SIZE;
+        assert_eq!(unchanged[range.clone()], bytes[range]);
+
+        image.set_game_region(GameRegion::ALL);
+        image.set_allowed_media(AllowedMedia::HARD_DISK | AllowedMedia::DVD_X2);
+        image.set_game_ratings(GameRatings::Teen);
+        image.set_disk_number(1);
+        let reloaded = XbeImage::new(&image.serialize()?)?;
+        assert!(reloaded.game_region().contains(GameRegion::JAPAN));
+        assert!(!reloaded.game_region().contains(GameRegion::MANUFACTURING));
+        assert_eq!(
+            reloaded.allowed_media(),
+            AllowedMedia::HARD_DISK | AllowedMedia::DVD_X2
+        );
+        assert_eq!(reloaded.game_ratings(), GameRatings::Teen);
+        assert_eq!(reloaded.disk_number(), 1);
+
+        assert_eq!(
+            GameRatings::from(0xFFFF_FFFF),
+            GameRatings::Other(0xFFFF_FFFF)
+        );
+        assert_eq!(u32::from(GameRatings::from(0xFFFF_FFFF)), 0xFFFF_FFFF);
+        Ok(())
+    }
+    #[test]
+    fn init_flags() -> TestError {
+        let bytes = fs::read("test/bin/default.xbe")?;
+        let mut image = XbeImage::new(&bytes)?;
+        assert_eq!(image.init_flags().0, read_u32(&bytes, INIT_FLAGS_OFFSET)?);
+        assert_eq!(
+            image.serialize()?[INIT_FLAGS_OFFSET..INIT_FLAGS_OFFSET + 4],
+            bytes[INIT_FLAGS_OFFSET..INIT_FLAGS_OFFSET + 4]
+        );
+
+        // Unknown bits are kept alongside the named ones
+        let mut flags = InitFlags(0x8000_0000) | InitFlags::LIMIT_64MB;
+        flags.set(InitFlags::MOUNT_UTILITY_DRIVE, true);
+        flags.set(InitFlags::LIMIT_64MB, false);
+        image.set_init_flags(flags);
+        let reloaded = XbeImage::new(&image.serialize()?)?;
+        assert_eq!(reloaded.init_flags(), InitFlags(0x8000_0001));
+        assert!(reloaded
+            .init_flags()
+            .contains(InitFlags::MOUNT_UTILITY_DRIVE));
+        assert!(!reloaded.init_flags().contains(InitFlags::LIMIT_64MB));
+        Ok(())
+    }
+    #[test]
+    fn pe_size_of_image() -> TestError {
+        let bytes = fs::read("test/bin/default.xbe")?;
+        let mut image = XbeImage::new(&bytes)?;
+        let loaded = read_u32(&bytes, PE_SIZE_OF_IMAGE_OFFSET)?;
+        let unchanged = image.serialize()?;
+        assert_eq!(read_u32(&unchanged, PE_SIZE_OF_IMAGE_OFFSET)?, loaded);
+        assert_eq!(
+            unchanged[PE_FIELDS_OFFSET..0x14C],
+            bytes[PE_FIELDS_OFFSET..0x14C]
+        );
+
+        // Adding a section grows the PE size of image along with the size of image
+        let address = image.xbe.get_next_virtual_address();
+        image.xbe.add_section(
+            ".mtest\0".to_string(),
+            xbe::SectionFlags::PRELOAD,
+            vec![0; 0x2000],
+            address,
+            0x2000,
+        );
+        let grown = image.serialize()?;
+        let size_of_image = read_u32(&grown, SIZE_OF_IMAGE_OFFSET)?;
+        assert!(size_of_image > read_u32(&bytes, SIZE_OF_IMAGE_OFFSET)?);
+        assert_eq!(read_u32(&grown, PE_SIZE_OF_IMAGE_OFFSET)?, size_of_image);
+
+        image.set_pe_size_of_image(Some(0x1234_5000));
+        image.pe_fields_mut().heap_commit = 0x10_0000;
+        let reloaded = XbeImage::new(&image.serialize()?)?;
+        assert_eq!(reloaded.pe_size_of_image(size_of_image), 0x1234_5000);
+        assert_eq!(reloaded.pe_fields().heap_commit, 0x10_0000);
+        Ok(())
+    }
+    #[test]
+    fn trailing_padding() -> TestError {
+        let bytes = fs::read("test/bin/default.xbe")?;
+        let end = RawLayout::parse(&bytes)?.raw_end() as usize;
+        assert_eq!(XbeImage::new(&bytes)?.serialize()?.len(), bytes.len());
+
+        // Odd sized padding that isn't zero is kept as it was
+        let mut padded = bytes[..end].to_vec();
+        padded.extend([0xCC; 0x123]);
+        let serialized = XbeImage::new(&padded)?.serialize()?;
+        assert_eq!(serialized.len(), end + 0x123);
+        assert!(serialized[end..].iter().all(|&b| b == 0xCC));
+
+        // Including no padding at all
+        let serialized = XbeImage::new(&bytes[..end])?.serialize()?;
+        assert_eq!(serialized.len(), end);
+
+        // Without a loaded image the xbe crate pads the end
+        let xbe = Xbe::new(&bytes)?;
+        let serialized = XbeImage::from_xbe(xbe)?.serialize()?;
+        assert!(serialized.len() >= end);
+        Ok(())
+    }
+    #[test]
+    fn header_json() -> TestError {
+        let bytes = fs::read("test/bin/default.xbe")?;
+        let image = XbeImage::new(&bytes)?;
+        let header = image.header_json()?;
+        let xbe = Xbe::new(&bytes)?;
+        assert_eq!(header.digital_signature.len(), 0x200);
+        assert_eq!(header.tls_address, xbe.header.tls_address);
+        assert_eq!(header.debug_pathname, xbe.header.debug_pathname);
+        assert_eq!(header.section_count as usize, xbe.sections.len());
+        assert!(!header.title_name.is_empty());
+
+        let json = serde_json::to_string_pretty(&header)?;
+        assert_eq!(serde_json::from_str::<HeaderJson>(&json)?, header);
+        Ok(())
+    }
+    #[test]
+    fn logo() -> TestError {
+        let bytes = fs::read("test/bin/default.xbe")?;
+        let mut image = XbeImage::new(&bytes)?;
+        let pixels = image.logo_image()?;
+        assert_eq!(pixels.len(), LOGO_WIDTH * LOGO_HEIGHT);
+
+        // Re-encoding gives the same pixels, padded to the original size
+        image.set_logo_image(&pixels, LOGO_WIDTH, LOGO_HEIGHT)?;
+        assert_eq!(image.logo_image()?, pixels);
+        let serialized = image.serialize()?;
+        assert_eq!(serialized.len(), bytes.len());
+        assert_eq!(logo_range(&serialized)?, logo_range(&bytes)?);
+
+        let inverted: Vec<u8> = pixels.iter().map(|p| !p & 0xF0).collect();
+        image.set_logo_image(&inverted, LOGO_WIDTH, LOGO_HEIGHT)?;
+        assert_eq!(XbeImage::new(&image.serialize()?)?.logo_image()?, inverted);
+
+        // Alternating shades take a byte per pixel, more than any real logo
+        let noise: Vec<u8> = (0..LOGO_WIDTH * LOGO_HEIGHT)
+            .map(|i| (i as u8 % 2) << 4)
+            .collect();
+        let err = image
+            .set_logo_image(&noise, LOGO_WIDTH, LOGO_HEIGHT)
+            .expect_err("Logo larger than the original");
+        assert!(matches!(
+            err.downcast_ref::<LogoError>(),
+            Some(LogoError::TooLarge(1700, _))
+        ));
+        assert_eq!(image.logo_image()?, inverted);
+        Ok(())
+    }
+    #[test]
+    fn non_utf8_section_name() -> TestError {
+        let mut bytes = fs::read("test/bin/default.xbe")?;
+        let layout = RawLayout::parse(&bytes)?;
+        let section = layout.sections.first().ok_or("XBE has no sections")?;
+        let name = (section.name_address - layout.base_address) as usize;
+        bytes[name] = 0xFF;
+
+        let err = XbeImage::new(&bytes)
+            .err()
+            .ok_or("Loaded a non UTF-8 name")?;
+        assert!(matches!(
+            err.downcast_ref::<SectionError>(),
+            Some(&SectionError::NonUtf8Name(offset)) if offset == name
+        ));
+        Ok(())
+    }
+    #[test]
+    fn missing_library() -> TestError {
+        let mut bytes = fs::read("test/bin/default.xbe")?;
+        let base_address = read_u32(&bytes, 0x104)?;
+        let count = read_u32(&bytes, LIBRARY_COUNT_OFFSET)?;
+        let records = (read_u32(&bytes, LIBRARY_VERSIONS_ADDRESS_OFFSET)? - base_address) as usize;
+        let xapilib = (0..count as usize)
+            .map(|i| records + i * LIBRARY_VERSION_SIZE)
+            .find(|&offset| bytes[offset..offset + 8] == *b"XAPILIB\0")
+            .ok_or("No XAPILIB library version")?;
+        bytes[xapilib..xapilib + 8].copy_from_slice(b"XAPILIX\0");
+        write_u32(&mut bytes, REQUIRED_LIBRARY_ADDRESS_OFFSETS[1], 0)?;
+
+        let err = header::serialize(&Xbe::new(&bytes)?).expect_err("Serialized without XAPILIB");
+        assert!(matches!(
+            err.downcast_ref::<HeaderError>(),
+            Some(HeaderError::MissingLibrary(name)) if name == "XAPILIB"
+        ));
+
+        // The missing library's pointer is kept, and the kernel's still points to its record
+        let image = XbeImage::new(&bytes)?;
+        let serialized = image.serialize()?;
+        assert_eq!(read_u32(&serialized, LIBRARY_COUNT_OFFSET)?, count);
+        assert_eq!(
+            read_u32(&serialized, REQUIRED_LIBRARY_ADDRESS_OFFSETS[1])?,
+            0
+        );
+        let kernel =
+            (read_u32(&serialized, REQUIRED_LIBRARY_ADDRESS_OFFSETS[0])? - base_address) as usize;
+        assert_eq!(serialized[kernel..kernel + 8], *b"XBOXKRNL");
+        assert_eq!(XbeImage::new(&serialized)?.serialize()?, serialized);
+        Ok(())
+    }
+}
@@ -1,54 +1,805 @@
 #![warn(rust_2018_idioms)]
 pub mod config;
+pub(crate) mod demangle;
+pub(crate) mod detour;
+pub mod error;
+pub mod header;
+pub mod image;
+pub mod library;
+pub mod lint;
+pub mod logo;
+pub mod memory;
 pub mod obj;
+pub mod pack;
 pub(crate) mod patch;
 pub(crate) mod reloc;
+pub mod report;
+pub mod section;
+pub(crate) mod signature;
+pub mod util;
+pub mod validate;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use config::Configuration;
-use reloc::{SectionMap, SymbolTable};
+use detour::Detour;
+use error::{
+    CollectedErrors, ErrorMode, HeaderError, InjectError, InjectStep, PatchError, RestoreError,
+};
+use itertools::Itertools;
+use log::{debug, info, warn};
+use patch::HeaderWrites;
+pub use patch::{Patch, PatchSite};
+use rayon::prelude::*;
+use reloc::SectionMap;
+pub use reloc::SymbolTable;
+use report::{InjectionReport, PatchReport, SectionReport};
+use section::{StripSections, XbeExt};
+use signature::SignatureError;
+use std::collections::HashMap;
 use xbe::Xbe;
 
 /// How to inject
+/// - give a debug pathname without a backslash a leading one, and a missing XBOXKRNL or XAPILIB
+///   library version a placeholder, so the XBE can be serialized while injecting
+///     - Both are taken back out of the result, which keeps the input's pathname and libraries
+/// - check for sections left by a previous injection
+///     - These are the combined sections and any `extra_sections` or `[[data_section]]`s the
+///       config would add
+///     - They are stripped if `allow_repatch` is set, otherwise the injection is refused
 /// - separate patch files from other object files
 ///     - Symbols are shared between Patches and Mods
 ///     - Sections from patches are not combined into the '.m{text,data,bss,rdata}' sections.
-/// - combine .text, .data, .bss, .rdata of each non-patch file
+/// - check the input XBE is of the config's `image_kind`, if it has one
+///     - Only a warning is given if `allow_image_kind_mismatch` is set
+/// - match patch and detour symbol names that aren't found exactly against demangled C++ names
+/// - resolve patch sites located by signature
+/// - combine .text, .data, .bss, .rdata, and any configured `extra_sections` of each non-patch
+///   file
+///     - Only `extra_sections` are combined if `no_default_sections` is set
 ///     - have start offsets within the sections for each file
+///     - Identical runs of read-only data share one copy if `deduplicate_constants` is set.
+///       A file's whole .rdata contribution is merged or kept, not individual constants within it.
+/// - pad combined sections to the virtual sizes given in `section_sizes`
 /// - assign virtual address ranges to each combined section
 /// - build combined symbol table
 ///     - Most symbols are assigned a virtual address within a combined section
 ///     - Patch symbols are assigned a virtual address from a config file
+/// - install detours and build their trampolines
 /// - process relocations within each file
 /// - process base game patch files
+///     - Patch sites are applied in the order they are declared in the config, unless
+///       `patch_order` names sites to apply first. Later sites overwrite earlier ones where they
+///       overlap. The applied order is recorded in the [`InjectionReport`].
+///     - Symbols defined within each applied site are added to the symbol table, so later sites
+///       can refer to them
+///     - When no sites overlap or refer to each other they are relocated in parallel
+///     - Unless the [`InjectOptions`] are `fail_fast`, the remaining sites are still applied
+///       after one fails, so every failure is reported
+/// - apply raw byte patches, after all object file patches
+///     - Patches may only write to the XBE header if `allow_header_patches` is set
+///     - Writes to the header are applied together once every patch is, so the XBE is only
+///       parsed again once
+/// - add and remove library versions given by `[[library]]` entries, in order
 /// - insert sections into xbe
-pub fn inject(config: Configuration, mut xbe: Xbe) -> Result<Xbe> {
+/// - add each `[[data_section]]` file as a section, at its configured address or after all other
+///   sections
+/// - store the data of each added section in the first gap between the input XBE's sections that
+///   holds it, if `fill_raw_gaps` is set
+/// - check the image and the kernel's reservations fit in `memory_budget` bytes of RAM
+///     - Only a warning is given if `allow_over_budget` is set
+/// - replace the entry point with `entry_point_symbol` or `entry_point_address`, which must be
+///   within an executable section
+/// - replace the TLS address with `tls_address_symbol` or `tls_address`, which must be mapped by
+///   a section
+/// - check the sections and header of the result, warning about anything that may stop it from
+///   loading
+///     - The injection fails instead if `deny_warnings` is set
+pub fn inject(config: Configuration, xbe: Xbe) -> std::result::Result<Xbe, InjectError> {
+    inject_steps(config, InjectOptions::default(), xbe)
+        .map(|(xbe, _)| xbe)
+        .map_err(InjectError::from)
+}
+
+/// Performs the same injection as [`inject`], additionally returning a summary of the changes
+/// made to the XBE.
+///
+/// The summary includes a checksum of the input, so this serializes the XBE once more than
+/// [`inject`] does.
+pub fn inject_with_report(
+    config: Configuration,
+    xbe: Xbe,
+) -> std::result::Result<(Xbe, InjectionReport), InjectError> {
+    let options = InjectOptions {
+        checksum_input: true,
+        ..Default::default()
+    };
+    inject_with_options(config, xbe, options)
+}
+
+/// How an injection is carried out, independent of the config describing what to inject
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InjectOptions {
+    /// Whether injection stops at the first patch that fails. By default every patch is applied,
+    /// so each failure is reported.
+    pub error_mode: ErrorMode,
+    /// Whether to record a checksum of the input in the report, as manifests and patch packs
+    /// need. This serializes the input XBE once more, so it's off by default.
+    pub checksum_input: bool,
+}
+
+/// Performs the same injection as [`inject_with_report`], carried out according to `options`
+pub fn inject_with_options(
+    config: Configuration,
+    xbe: Xbe,
+    options: InjectOptions,
+) -> std::result::Result<(Xbe, InjectionReport), InjectError> {
+    Ok(inject_steps(config, options, xbe)?)
+}
+
+fn inject_steps(
+    config: Configuration,
+    options: InjectOptions,
+    xbe: Xbe,
+) -> Result<(Xbe, InjectionReport)> {
+    // the xbe crate can't serialize some XBEs it loads, so they're adjusted until injected
+    header::with_serializable(xbe, |xbe| inject_serializable(config, options, xbe))
+}
+
+fn inject_serializable(
+    mut config: Configuration,
+    options: InjectOptions,
+    mut xbe: Xbe,
+) -> Result<(Xbe, InjectionReport)> {
+    let mut report = InjectionReport::default();
+    if options.checksum_input {
+        report.original_checksum = reloc::crc32(&header::serialize(&xbe)?);
+    }
+
+    load(&mut config, &mut xbe)?;
+    // detours are resolved while the combined sections borrow the config
+    let mut detours = std::mem::take(&mut config.detours);
+    let (mut section_map, mut symbol_table, trampolines) = link(&config, &detours, &xbe)?;
+    install_detours(
+        &mut detours,
+        trampolines,
+        &mut xbe,
+        &mut section_map,
+        &mut symbol_table,
+        &mut report,
+    )?;
+
+    // process relocations for mods
+    section_map.process_relocations(&symbol_table, &config.modfiles)?;
+
+    #[cfg(debug_assertions)]
+    let checksums = section_map.checksums();
+
+    report.sections = section_map
+        .iter()
+        .map(|(name, sec)| SectionReport {
+            name: name.to_string(),
+            virtual_address: sec.virtual_address,
+            size: sec.virtual_size() as usize,
+        })
+        .sorted_by(|a, b| a.name.cmp(&b.name))
+        .collect();
+
+    apply_patches(&config, options, &mut xbe, &mut symbol_table, &mut report)?;
+    apply_libraries(&config, &mut xbe, &mut report)?;
+    let (entry_point, tls_address) = header_addresses(&config, &symbol_table)?;
+    add_sections(&config, section_map, &mut xbe, &mut report)?;
+    set_header_addresses(&mut xbe, entry_point, tls_address, &mut report)?;
+    validate_result(&config, &xbe)?;
+
+    // verify section data survived the copy into the XBE
+    #[cfg(debug_assertions)]
+    reloc::verify_checksums(&xbe, &checksums)?;
+
+    // return patched xbe
+    Ok((xbe, report))
+}
+
+/// Prepares `xbe` and `config` for linking: strips the sections of a previous injection, checks
+/// the config is for this kind of XBE, and resolves the symbol names and signatures of patches
+fn load(config: &mut Configuration, xbe: &mut Xbe) -> Result<()> {
+    // strip sections from previous injections
+    let injected_names = config.injected_section_names();
+    if xbe
+        .sections
+        .iter()
+        .any(|s| injected_names.contains(&reloc::strip_null(&s.name)))
+    {
+        if !config.allow_repatch {
+            bail!(
+                "Input XBE appears to have been modded previously, as it already contains \
+                injected sections. Use a vanilla XBE as input, or set allow_repatch to strip \
+                the previously injected sections."
+            );
+        }
+        let stripped: Vec<_> = xbe
+            .strip_sections(StripSections::Names(&injected_names))
+            .context("Failed to strip previously injected sections")?
+            .iter()
+            .map(|s| reloc::strip_null(&s.name).to_string())
+            .collect();
+        warn!(
+            "Stripped previously injected sections {stripped:?}. Bytes overwritten by previous \
+            patches are not restored."
+        );
+    }
+
+    // check the config is for this kind of XBE
+    let image_kind = header::image_kind(xbe);
+    debug!("Input XBE is a {image_kind:?} image");
+    if let Some(expected) = config.image_kind.filter(|&kind| kind != image_kind) {
+        let mismatch = HeaderError::ImageKindMismatch(expected, image_kind);
+        if !config.allow_image_kind_mismatch {
+            bail!(mismatch);
+        }
+        warn!("{mismatch}");
+    }
+
+    // match configured symbol names against C++ mangled names
+    for patch in config.patches.iter_mut() {
+        patch.resolve_symbol_names()?;
+    }
+
+    // locate patch sites addressed by signature
+    for patch in config.patches.iter_mut() {
+        patch.sites = std::mem::take(&mut patch.sites)
+            .into_iter()
+            .map(|site| {
+                let name = site.name().to_string();
+                site.resolve_signature(xbe)
+                    .with_context(|| InjectStep::Signature { name })
+            })
+            .flatten_ok()
+            .collect::<Result<_>>()?;
+    }
+    Ok(())
+}
+
+/// Combines the sections of the mod files, assigns them addresses after the sections of `xbe`
+/// and builds the symbol table. Returns them along with the trampoline offset reserved for each
+/// of `detours`.
+fn link<'a>(
+    config: &'a Configuration,
+    detours: &[Detour],
+    xbe: &Xbe,
+) -> Result<(SectionMap<'a>, SymbolTable, Vec<Option<u32>>)> {
     // combine sections
-    let mut section_map = SectionMap::from_data(&config.modfiles);
+    let mut section_map = SectionMap::from_data_with_extra_sections(
+        &config.modfiles,
+        config
+            .extra_sections
+            .iter()
+            .map(|(coff_name, combined_name)| (coff_name.as_str(), combined_name.as_str()))
+            .collect(),
+        !config.no_default_sections,
+    )?;
+    section_map.allocate_common_symbols(&config.modfiles)?;
+    if config.deduplicate_constants {
+        let saved = section_map.deduplicate_constants(&config.modfiles);
+        debug!("Merged {saved} bytes of duplicate read-only data");
+    }
+    if config.bss_size > 0 {
+        section_map
+            .get_or_insert(".mbss")
+            .reserve_bss(config.bss_size);
+    }
+    let trampolines = detour::reserve_trampolines(detours, &mut section_map);
+    for (name, size) in config.section_sizes.iter() {
+        section_map.set_virtual_size(name, *size)?;
+    }
+    debug!(
+        "Combined {} sections totaling {} bytes",
+        section_map.section_count(),
+        section_map.total_size()
+    );
+    for name in reloc::COMBINED_SECTION_NAMES {
+        if let Some(size) = section_map.size_of(name) {
+            debug!("{name}: {size} bytes");
+        }
+    }
 
     // Assign virtual addresses
-    section_map.assign_addresses(&xbe);
+    section_map.assign_addresses(xbe);
+    debug!("Section layout:\n{section_map}");
 
     // build symbol table
-    let symbol_table = SymbolTable::new(&section_map, &config)?;
+    let symbol_table = SymbolTable::new(&section_map, config)?;
+    log::trace!("{symbol_table}");
+    Ok((section_map, symbol_table, trampolines))
+}
 
-    // process relocations for mods
-    section_map.process_relocations(&symbol_table, &config.modfiles)?;
+/// Installs `detours` into `xbe`, defining their trampoline symbols before mods reference them,
+/// and records the bytes each one overwrote in `report`
+fn install_detours(
+    detours: &mut [Detour],
+    trampolines: Vec<Option<u32>>,
+    xbe: &mut Xbe,
+    section_map: &mut SectionMap<'_>,
+    symbol_table: &mut SymbolTable,
+    report: &mut InjectionReport,
+) -> Result<()> {
+    for (detour, trampoline) in detours.iter_mut().zip(trampolines) {
+        detour
+            .resolve_symbol_names(symbol_table)
+            .with_context(|| InjectStep::Detour {
+                symbol: detour.symbol_name.clone(),
+            })?;
+        let (target, original_bytes) = detour
+            .apply(xbe, symbol_table, section_map, trampoline)
+            .with_context(|| InjectStep::Detour {
+                symbol: detour.symbol_name.clone(),
+            })?;
 
-    // apply patches
-    for patch in config.patches.iter() {
-        patch.apply(&mut xbe, &symbol_table).with_context(|| {
-            format!(
-                "Failed to apply patch '{}'",
-                patch.start_symbol_name.clone()
-            )
+        let written_bytes =
+            patch::read(xbe, patch::byte_range(target, original_bytes.len())?)?.into_owned();
+        report.patches.push(PatchReport {
+            sequence: report.patches.len(),
+            name: format!("detour to {}", detour.symbol_name),
+            virtual_address: target,
+            length: original_bytes.len() as u32,
+            checksum: 0,
+            original_bytes,
+            written_bytes,
+        });
+    }
+    Ok(())
+}
+
+/// Applies the patch sites and then the raw patches of `config` to `xbe`, adding the symbols
+/// sites define to `symbol_table` and recording each patched region in `report`. Unless
+/// `options` are `fail_fast`, every patch is applied before the failures are returned together.
+fn apply_patches(
+    config: &Configuration,
+    options: InjectOptions,
+    xbe: &mut Xbe,
+    symbol_table: &mut SymbolTable,
+    report: &mut InjectionReport,
+) -> Result<()> {
+    let patch_maps = config
+        .patches
+        .iter()
+        .map(Patch::section_map)
+        .collect::<Result<Vec<_>>>()?;
+    let order = patch::application_order(&config.patches, &config.patch_order)?;
+    let patch_context = |i: usize, site: &PatchSite| InjectStep::Patch {
+        name: site.name().to_string(),
+        start_symbol: site.start_symbol_name.clone(),
+        virtual_address: site.virtual_address,
+        file: config.patches[i].patchfile.path.clone(),
+    };
+
+    // sites that don't overlap or refer to each other are relocated in parallel, then written
+    // in order
+    let applied: Vec<_> = report
+        .patches
+        .iter()
+        .map(|p| p.virtual_address..p.virtual_address + p.length)
+        .collect();
+    let fail_fast = options.error_mode.fail_fast;
+    let mut errors = Vec::new();
+    let mut header = HeaderWrites::new(config.allow_header_patches);
+    let mut built = if patch::independent_sites(&config.patches, &order, &applied) {
+        debug!(
+            "Building {} independent patch sites in parallel",
+            order.len()
+        );
+        let xbe = &*xbe;
+        let header = &header;
+        let symbol_table = &*symbol_table;
+        order
+            .par_iter()
+            .map(|&(i, site)| {
+                config.patches[i]
+                    .build_site(
+                        site,
+                        patch_maps[i].clone(),
+                        xbe,
+                        header,
+                        symbol_table,
+                        config.deny_warnings,
+                    )
+                    .with_context(|| patch_context(i, site))
+                    .map(Some)
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            // sites that fail to build are retried in order, collecting their errors there
+            .map(|built| built.or_else(|e| if fail_fast { Err(e) } else { Ok(None) }))
+            .collect::<Result<Vec<_>>>()?
+    } else {
+        vec![None; order.len()]
+    };
+
+    // (matches, applied) for each site applied at every occurrence of its signature
+    let mut signature_matches: HashMap<(usize, &str), (usize, usize)> = HashMap::new();
+    // index of the patch each symbol defined by a site belongs to
+    let mut symbol_patches: HashMap<String, usize> = HashMap::new();
+    for (&(i, site), bytes) in order.iter().zip(built.iter_mut()) {
+        let patch = &config.patches[i];
+        if let Some(name) = &site.matched_from {
+            signature_matches.entry((i, name)).or_default().0 += 1;
+        }
+        let bytes = match bytes.take() {
+            Some(bytes) => Ok(bytes),
+            None => patch
+                .build_site(
+                    site,
+                    patch_maps[i].clone(),
+                    xbe,
+                    &header,
+                    symbol_table,
+                    config.deny_warnings,
+                )
+                .with_context(|| patch_context(i, site)),
+        };
+        let Some(bytes) = collect_error(bytes, fail_fast, &mut errors)? else {
+            continue;
+        };
+
+        // sites applied at every signature match must not clobber other patches
+        let end = site.virtual_address.saturating_add(bytes.len() as u32);
+        let overlapped = report.patches.iter().find(|p| {
+            p.virtual_address < end && site.virtual_address < p.virtual_address + p.length
+        });
+        if let Some(other) = overlapped.filter(|_| site.skip_overlapping) {
+            warn!(
+                "Skipping patch site '{}' as it overlaps '{}' at {:#x}",
+                site.name(),
+                other.name,
+                other.virtual_address
+            );
+            continue;
+        }
+
+        let original_bytes = site
+            .write(xbe, &mut header, &bytes)
+            .with_context(|| patch_context(i, site));
+        let Some(original_bytes) = collect_error(original_bytes, fail_fast, &mut errors)? else {
+            continue;
+        };
+
+        if let Some(name) = &site.matched_from {
+            signature_matches.entry((i, name)).or_default().1 += 1;
+        }
+        // the written region may be longer than the built bytes, when padded with nops
+        let written_bytes = header
+            .read(
+                xbe,
+                patch::byte_range(site.virtual_address, original_bytes.len())?,
+            )?
+            .into_owned();
+        report.patches.push(PatchReport {
+            sequence: report.patches.len(),
+            name: site.name().to_string(),
+            virtual_address: site.virtual_address,
+            length: original_bytes.len() as u32,
+            checksum: 0,
+            original_bytes,
+            written_bytes,
+        });
+
+        for error in define_site_symbols(patch, i, site, symbol_table, &mut symbol_patches)? {
+            let error: Result<()> = Err(error.into());
+            collect_error(
+                error.with_context(|| patch_context(i, site)),
+                fail_fast,
+                &mut errors,
+            )?;
+        }
+    }
+
+    for ((_, name), (matches, applied)) in signature_matches.into_iter().sorted() {
+        info!("Applied patch site '{name}' at {applied} of {matches} signature matches");
+        if applied == 0 {
+            let error = Err(SignatureError::NoneApplied(name.to_string(), matches).into());
+            collect_error::<()>(error, fail_fast, &mut errors)?;
+        }
+    }
+
+    // apply raw patches after object file patches
+    for patch in config.raw_patches.iter() {
+        let original_bytes = patch
+            .apply(xbe, &mut header)
+            .with_context(|| InjectStep::RawPatch {
+                virtual_address: patch.virtual_address,
+            });
+        let Some(original_bytes) = collect_error(original_bytes, fail_fast, &mut errors)? else {
+            continue;
+        };
+
+        report.patches.push(PatchReport {
+            sequence: report.patches.len(),
+            name: format!("raw patch at {:#x}", patch.virtual_address),
+            virtual_address: patch.virtual_address,
+            length: patch.bytes.len() as u32,
+            checksum: 0,
+            original_bytes,
+            written_bytes: patch.bytes.clone(),
+        });
+    }
+
+    if !errors.is_empty() {
+        bail!(CollectedErrors(errors));
+    }
+    // header writes are only parsed once every patch is applied
+    header.apply(xbe)?;
+
+    // checksum patched regions once every patch is applied, so overlapping patches still verify
+    for patch in report.patches.iter_mut() {
+        let bytes = patch::read(
+            xbe,
+            patch::byte_range(patch.virtual_address, patch.length as usize)?,
+        )?;
+        patch.checksum = reloc::crc32(&bytes);
+    }
+    Ok(())
+}
+
+/// Adds the symbols the patch site `site` of `patch`, the `i`th patch, defines to
+/// `symbol_table`, returning the symbols another patch already defined elsewhere as errors.
+///
+/// Sites of one patch applied at several addresses define the same symbols at each of them,
+/// which keep the address they were first defined at.
+fn define_site_symbols(
+    patch: &Patch,
+    i: usize,
+    site: &PatchSite,
+    symbol_table: &mut SymbolTable,
+    symbol_patches: &mut HashMap<String, usize>,
+) -> Result<Vec<PatchError>> {
+    let mut duplicates = Vec::new();
+    for (name, address) in patch.site_symbols(site)? {
+        let existing = symbol_table.get(&name);
+        // start symbols are in the table before any site is applied, at their first site
+        let own_symbol = match symbol_patches.get(&name) {
+            Some(&patch_index) => patch_index == i,
+            None => patch
+                .sites
+                .iter()
+                .any(|s| s.start_symbol_name == name && Some(s.virtual_address) == existing),
+        };
+        match existing {
+            Some(existing) if existing != address && own_symbol => {
+                debug!(
+                    "Patch site '{}' redefines '{name}' at {address:#x}, keeping {existing:#x}",
+                    site.name()
+                );
+                continue;
+            }
+            Some(existing) if existing != address => {
+                duplicates.push(PatchError::DuplicateSymbol(name, existing, address));
+                continue;
+            }
+            _ => (),
+        }
+        debug!(
+            "Patch site '{}' defines '{name}' at {address:#x}",
+            site.name()
+        );
+        symbol_patches.insert(name.clone(), i);
+        symbol_table.insert(name, address);
+    }
+    Ok(duplicates)
+}
+
+/// Adds and removes the library versions of the `[[library]]` entries of `config`, recording the
+/// original library versions in `report` if there are any
+fn apply_libraries(
+    config: &Configuration,
+    xbe: &mut Xbe,
+    report: &mut InjectionReport,
+) -> Result<()> {
+    if !config.libraries.is_empty() {
+        report.original_library_versions =
+            Some(xbe.library_versions.iter().map(Into::into).collect());
+    }
+    for library in config.libraries.iter() {
+        library.apply(xbe).with_context(|| InjectStep::Library {
+            name: library.name(),
         })?;
     }
+    Ok(())
+}
 
+/// The configured entry point and TLS address, resolved once every patch site has added its
+/// symbols to `symbol_table`
+fn header_addresses(
+    config: &Configuration,
+    symbol_table: &SymbolTable,
+) -> Result<(Option<u32>, Option<u32>)> {
+    let entry_point = config
+        .entry_point
+        .as_ref()
+        .map(|entry_point| entry_point.resolve(symbol_table))
+        .transpose()
+        .context(InjectStep::EntryPoint)?;
+    let tls_address = config
+        .tls_address
+        .as_ref()
+        .map(|tls_address| tls_address.resolve(symbol_table))
+        .transpose()
+        .context(InjectStep::TlsAddress)?;
+    Ok((entry_point, tls_address))
+}
+
+/// Inserts the combined sections and the `[[data_section]]`s of `config` into `xbe`, recording
+/// them in `report`, then fills raw gaps if configured and checks the memory budget
+fn add_sections(
+    config: &Configuration,
+    section_map: SectionMap<'_>,
+    xbe: &mut Xbe,
+    report: &mut InjectionReport,
+) -> Result<()> {
     // insert sections into XBE
-    section_map.finalize(&mut xbe);
+    section_map.finalize(xbe, &config.section_flags)?;
 
-    // return patched xbe
+    for data_section in config.data_sections.iter() {
+        let virtual_address =
+            data_section
+                .add_to(xbe)
+                .with_context(|| InjectStep::DataSection {
+                    name: data_section.name.clone(),
+                    file: data_section.path.clone(),
+                })?;
+        report.sections.push(SectionReport {
+            name: data_section.name.clone(),
+            virtual_address,
+            size: data_section.data.len(),
+        });
+    }
+    report.sections.sort_by(|a, b| a.name.cmp(&b.name));
+
+    if config.fill_raw_gaps {
+        let names: Vec<_> = report
+            .sections
+            .iter()
+            .map(|s| s.name.as_str())
+            .filter(|&name| xbe.section_by_name(name).is_some())
+            .collect();
+        for (name, raw_address) in names.iter().zip(xbe.move_to_raw_gaps(&names)?) {
+            if let Some(raw_address) = raw_address {
+                debug!("Stored '{name}' in the gap at file offset {raw_address:#x}");
+            }
+        }
+    }
+
+    // check the image still fits in memory, now every section has been added
+    match memory::check_memory_budget(xbe, config.memory_budget) {
+        Ok(footprint) => debug!(
+            "Image uses {footprint:#x} of {:#x} bytes of memory",
+            config.memory_budget
+        ),
+        Err(e) if config.allow_over_budget => warn!("{e}"),
+        Err(e) => return Err(e.into()),
+    }
+    Ok(())
+}
+
+/// Replaces the entry point and TLS address of `xbe` with the resolved ones, if any, recording
+/// the replaced values in `report`
+fn set_header_addresses(
+    xbe: &mut Xbe,
+    entry_point: Option<u32>,
+    tls_address: Option<u32>,
+    report: &mut InjectionReport,
+) -> Result<()> {
+    if let Some(address) = entry_point {
+        debug!(
+            "Replacing entry point {:#x} with {address:#x}",
+            header::entry_point(xbe).context(InjectStep::EntryPoint)?
+        );
+        report.original_entry_point =
+            Some(header::set_entry_point(xbe, address).context(InjectStep::EntryPoint)?);
+    }
+    if let Some(address) = tls_address {
+        debug!("Setting TLS address to {address:#x}");
+        report.original_tls_address =
+            Some(header::set_tls_address(xbe, address).context(InjectStep::TlsAddress)?);
+    }
+    Ok(())
+}
+
+/// Checks the injected `xbe` can be loaded, warning about each issue, or failing if `config`
+/// denies warnings. Patched sections keep the digests they were loaded with, so their digests
+/// aren't checked.
+fn validate_result(config: &Configuration, xbe: &Xbe) -> Result<()> {
+    let issues = validate::validate_xbe(xbe);
+    for issue in issues.iter() {
+        warn!("{issue}");
+    }
+    if config.deny_warnings && !issues.is_empty() {
+        bail!(InjectStep::Validation(issues.len()));
+    }
+    Ok(())
+}
+
+/// Passes on the value of `result`, or if it failed, either returns its error when `fail_fast` is
+/// set or adds it to `errors`
+fn collect_error<T>(
+    result: Result<T>,
+    fail_fast: bool,
+    errors: &mut Vec<anyhow::Error>,
+) -> Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if fail_fast => Err(e),
+        Err(e) => {
+            errors.push(e);
+            Ok(None)
+        }
+    }
+}
+
+/// Reverts an injection using the manifest written by it, restoring the bytes overwritten by
+/// each patch, the replaced header fields and library versions, and removing the added sections.
+/// The restored XBE must be identical to the one the manifest was created from.
+///
+/// Nothing is changed if any patched region contains neither the patched nor the original bytes,
+/// as this indicates something else has modified the XBE since.
+pub fn restore(mut xbe: Xbe, manifest: &InjectionReport) -> Result<Xbe> {
+    // check every region before writing anything
+    let mut to_restore = Vec::new();
+    for patch in manifest.patches.iter() {
+        let bytes = patch::byte_range(patch.virtual_address, patch.length as usize)
+            .and_then(|range| patch::read(&xbe, range))
+            .with_context(|| RestoreError::Unmapped(patch.name.clone(), patch.virtual_address))?;
+        if reloc::crc32(&bytes) == patch.checksum {
+            to_restore.push(patch);
+        } else if *bytes != *patch.original_bytes {
+            bail!(RestoreError::RegionModified(
+                patch.name.clone(),
+                patch.virtual_address
+            ));
+        }
+    }
+
+    // undo patches in reverse so overlapping patches restore the bytes they replaced
+    let mut header = HeaderWrites::new(true);
+    for patch in to_restore.into_iter().rev() {
+        patch::overwrite(
+            &mut xbe,
+            &mut header,
+            patch.virtual_address,
+            &patch.original_bytes,
+        )?;
+    }
+    header.apply(&mut xbe)?;
+
+    if let Some(entry_point) = manifest.original_entry_point {
+        xbe.header.entry_point = entry_point;
+    }
+    if let Some(tls_address) = manifest.original_tls_address {
+        xbe.header.tls_address = tls_address;
+    }
+    if let Some(libraries) = manifest.original_library_versions.as_ref() {
+        xbe.library_versions = libraries.iter().map(Into::into).collect();
+    }
+
+    for section in manifest.sections.iter() {
+        if xbe
+            .section_by_name(&section.name)
+            .map(|s| s.virtual_address)
+            != Some(section.virtual_address)
+        {
+            bail!(RestoreError::MissingSection(
+                section.name.clone(),
+                section.virtual_address
+            ));
+        }
+        xbe.remove_section(&section.name)?;
+    }
+
+    let checksum = reloc::crc32(&header::serialize(&xbe)?);
+    if checksum != manifest.original_checksum {
+        bail!(RestoreError::ChecksumMismatch(
+            manifest.original_checksum,
+            checksum
+        ));
+    }
     Ok(xbe)
 }
 
@@ -56,7 +807,18 @@ pub fn inject(config: Configuration, mut xbe: Xbe) -> Result<Xbe> {
 mod tests {
     use std::{fs, path::Path};
 
-    use crate::{config::Configuration, inject};
+    use crate::{
+        config::Configuration,
+        error::{
+            BudgetError, DetourError, ErrorMode, HeaderError, InjectErrorKind, InjectStep,
+            PatchError, RelocationError, RestoreError, SignatureError,
+        },
+        header, inject, inject_with_options, inject_with_report, reloc,
+        report::InjectionReport,
+        restore,
+        section::{self, SectionError, SectionExt, StripSections, XbeExt},
+        InjectOptions,
+    };
 
     type TestError = std::result::Result<(), Box<dyn std::error::Error>>;
 
@@ -93,4 +855,1616 @@ mod tests {
         assert_eq!(target_hash, actual_hash);
         Ok(())
     }
+
+    #[test]
+    fn repatch() -> TestError {
+        let toml = |allow_repatch: bool| {
+            format!(
+                r#"
+                modfiles = ["loader_stub.o"]
+                allow_repatch = {allow_repatch}
+
+                [[patch]]
+                patchfile = "framehook_patch.o"
+                start_symbol = "_framehook_patch"
+                end_symbol = "_framehook_patch_end"
+                virtual_address = 396158"#
+            )
+        };
+        let expected = fs::read("test/bin/minimal_example.xbe")?;
+
+        // Refuse to patch an already patched XBE
+        let config = Configuration::from_toml(&toml(false), Path::new("test/bin/fakefile.toml"))?;
+        assert!(inject(config, xbe::Xbe::new(&expected)?).is_err());
+
+        // Repatching with the same config is equivalent to patching the vanilla XBE
+        let config = Configuration::from_toml(&toml(true), Path::new("test/bin/fakefile.toml"))?;
+        let output = inject(config, xbe::Xbe::new(&expected)?)?;
+        assert_eq!(output.serialize()?, expected);
+
+        // Allowing repatching doesn't affect a vanilla XBE
+        let config = Configuration::from_toml(&toml(true), Path::new("test/bin/fakefile.toml"))?;
+        let output = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)?;
+        assert_eq!(output.serialize()?, expected);
+        Ok(())
+    }
+
+    #[test]
+    // Sections from extra_sections and data sections are stripped along with the combined ones
+    fn repatch_extra_sections() -> TestError {
+        let toml = r#"
+            modfiles = ["loader_stub.o", "hook_section.o"]
+            allow_repatch = true
+
+            [extra_sections]
+            hook = ".mhook"
+
+            [[data_section]]
+            name = "blob"
+            file = "blob.bin"
+
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396158"#;
+
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let first = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)?;
+        let expected = first.serialize()?;
+
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let output = inject(config, first)?;
+        assert_eq!(
+            output
+                .sections
+                .iter()
+                .filter(|s| [".mhook", ".blob"].contains(&reloc::strip_null(&s.name)))
+                .count(),
+            2
+        );
+        assert_eq!(output.serialize()?, expected);
+        Ok(())
+    }
+
+    #[test]
+    // Previously injected sections the header points into can't be stripped for repatching
+    fn repatch_referenced_section() -> TestError {
+        let toml = r#"
+            modfiles = ["loader_stub.o"]
+            allow_repatch = true
+
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396158"#;
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let mut first = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)?;
+        let mtext = first
+            .section_by_name(".mtext")
+            .ok_or("No .mtext section in output")?
+            .virtual_address;
+        header::set_tls_address(&mut first, mtext)?;
+
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let err =
+            inject(config, first).expect_err("Stripped the section containing the TLS directory");
+        assert!(matches!(
+            err.root_cause().downcast_ref::<SectionError>(),
+            Some(SectionError::Referenced(name, "TLS address", _)) if name == ".mtext"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn strip_sections() -> TestError {
+        let toml = r#"
+            modfiles = ["loader_stub.o"]
+
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396158"#;
+        let vanilla = xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let mut output = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)?;
+        let mtext = output
+            .section_by_name(".mtext")
+            .ok_or("No .mtext section in output")?
+            .virtual_address;
+
+        // Sections the header points into are kept
+        let tls_address = header::set_tls_address(&mut output, mtext)?;
+        let err = output
+            .strip_sections(StripSections::Prefix(".m"))
+            .expect_err("Stripped the section containing the TLS directory");
+        assert!(matches!(
+            err.downcast_ref::<SectionError>(),
+            Some(SectionError::Referenced(name, "TLS address", address))
+                if name == ".mtext" && *address == mtext
+        ));
+        assert!(output.section_by_name(".mtext").is_some());
+        output.header.tls_address = tls_address;
+
+        let stripped = output.strip_sections(StripSections::Prefix(".m"))?;
+        assert_eq!(stripped.len(), 1);
+        assert_eq!(reloc::strip_null(&stripped[0].name), ".mtext");
+        assert!(output
+            .strip_sections(StripSections::Names(&[".mtext"]))?
+            .is_empty());
+
+        // Only the patched bytes differ from the vanilla XBE
+        let layout = |xbe: &xbe::Xbe| {
+            xbe.sections
+                .iter()
+                .map(|s| (s.name.clone(), s.virtual_address, s.virtual_size, s.flags))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(layout(&output), layout(&vanilla));
+        let output = xbe::Xbe::new(&output.serialize()?)?;
+        assert_eq!(layout(&output), layout(&vanilla));
+        assert_ne!(
+            output.get_bytes(396158..396163),
+            vanilla.get_bytes(396158..396163)
+        );
+        Ok(())
+    }
+
+    #[test]
+    // Defining the patch through a list of sites should be equivalent to defining it inline
+    fn patch_sites_example() -> TestError {
+        let toml = r#"
+            modfiles = ["loader_stub.o"]
+
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            sites = [
+                { start_symbol = "_framehook_patch", end_symbol = "_framehook_patch_end", virtual_address = 396158 },
+            ]"#;
+
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let output = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)?;
+
+        assert_eq!(
+            output.serialize()?,
+            fs::read("test/bin/minimal_example.xbe")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    // The patch file is a copy of the minimal example's with the start symbol renamed to its
+    // address, so it should be injected at the same address
+    fn auto_detect_address_example() -> TestError {
+        let toml = r#"
+            modfiles = ["loader_stub.o"]
+
+            [[patch]]
+            patchfile = "patch_site.o"
+            start_symbol = "_patch_site_00060B7E_start"
+            end_symbol = "_framehook_patch_end"
+            auto_detect_address = true"#;
+
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let output = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)?;
+
+        assert_eq!(
+            output.serialize()?,
+            fs::read("test/bin/minimal_example.xbe")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn expected_bytes() -> TestError {
+        // The framehook patch replaces `mov eax, [0x372ac0]`
+        let toml = |expected_bytes: &str| {
+            format!(
+                r#"
+                modfiles = ["loader_stub.o"]
+
+                [[patch]]
+                patchfile = "framehook_patch.o"
+                start_symbol = "_framehook_patch"
+                end_symbol = "_framehook_patch_end"
+                virtual_address = 396158
+                expected_bytes = "{expected_bytes}""#
+            )
+        };
+
+        let config =
+            Configuration::from_toml(&toml("A1 C0 2A 37 00"), Path::new("test/bin/fakefile.toml"))?;
+        let output = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)?;
+        assert_eq!(
+            output.serialize()?,
+            fs::read("test/bin/minimal_example.xbe")?
+        );
+
+        let config =
+            Configuration::from_toml(&toml("8B 44 24 08 89"), Path::new("test/bin/fakefile.toml"))?;
+        let err = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)
+            .expect_err("Patch applied despite unexpected bytes");
+        assert!(format!("{err:?}").contains("Expected bytes [8B 44 24 08 89]"));
+        Ok(())
+    }
+
+    #[test]
+    // bss_patch.o increments `_uninit`, an uninitialized global in mod.o's .bss
+    fn patch_references_bss() -> TestError {
+        let original = xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        // .mbss sorts first, so it is placed at the first free address
+        let mbss_address = original.get_next_virtual_address();
+
+        let toml = r#"
+            modfiles = ["mod.o"]
+
+            [[patch]]
+            patchfile = "bss_patch.o"
+            start_symbol = "_bss_patch"
+            end_symbol = "_bss_patch_end"
+            virtual_address = 396158"#;
+
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let (output, report) = inject_with_report(config, original)?;
+
+        assert!(report
+            .sections
+            .iter()
+            .any(|s| s.name == ".mbss" && s.virtual_address == mbss_address && s.size == 4));
+        let inc = output
+            .get_bytes(396158..396164)
+            .ok_or("Patch site unmapped")?;
+        assert_eq!(inc[..2], [0xFF, 0x05]);
+        assert_eq!(inc[2..], mbss_address.to_le_bytes());
+
+        // The zero-filled .bss occupies address space but no file data
+        let mbss = output
+            .section_by_name(".mbss")
+            .ok_or("No .mbss section in output")?;
+        assert!(mbss.data.is_empty());
+        assert_eq!(mbss.virtual_size, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn reserved_bss() -> TestError {
+        let toml = r#"
+            modfiles = ["loader_stub.o"]
+
+            [section_addresses]
+            bss_size = 1024
+
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396158"#;
+
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let (output, report) =
+            inject_with_report(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)?;
+
+        // loader_stub.o has no .bss, so the section consists only of the reserved space
+        assert!(report
+            .sections
+            .iter()
+            .any(|s| s.name == ".mbss" && s.size == 1024));
+        let mbss = output
+            .section_by_name(".mbss")
+            .ok_or("No .mbss section in output")?;
+        assert!(mbss.data.is_empty());
+        assert_eq!(mbss.virtual_size, 1024);
+        Ok(())
+    }
+
+    #[test]
+    fn entry_point() -> TestError {
+        let toml = |entry_point: &str| {
+            format!(
+                r#"
+                modfiles = ["loader_stub.o", "mod.o"]
+                {entry_point}
+
+                [[patch]]
+                patchfile = "framehook_patch.o"
+                start_symbol = "_framehook_patch"
+                end_symbol = "_framehook_patch_end"
+                virtual_address = 396158"#
+            )
+        };
+
+        let config = Configuration::from_toml(
+            &toml(r#"entry_point_symbol = "_test""#),
+            Path::new("test/bin/fakefile.toml"),
+        )?;
+        let input = xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let original = input.header.entry_point;
+        let (output, report) = inject_with_report(config, input)?;
+        assert_eq!(report.original_entry_point, Some(original));
+
+        // Execution starts in the injected code
+        let output = xbe::Xbe::new(&output.serialize()?)?;
+        let mtext = report
+            .sections
+            .iter()
+            .find(|s| s.name == ".mtext")
+            .ok_or("No .mtext section in report")?;
+        let address = header::entry_point(&output)?;
+        assert!(
+            (mtext.virtual_address..mtext.virtual_address + mtext.size as u32).contains(&address)
+        );
+
+        let config = Configuration::from_toml(
+            &toml("entry_point_address = 396158"),
+            Path::new("test/bin/fakefile.toml"),
+        )?;
+        let output = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)?;
+        assert_eq!(header::entry_point(&output)?, 396158);
+
+        // The entry point must be executable
+        let config = Configuration::from_toml(
+            &toml("entry_point_address = 0x10000"),
+            Path::new("test/bin/fakefile.toml"),
+        )?;
+        let err = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)
+            .expect_err("Entry point in the header");
+        assert!(matches!(
+            err.root_cause().downcast_ref::<HeaderError>(),
+            Some(HeaderError::NonExecutableEntryPoint(0x10000))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn tls_address() -> TestError {
+        let toml = |tls_address: &str| {
+            format!(
+                r#"
+                modfiles = ["loader_stub.o", "mod.o"]
+                {tls_address}
+
+                [[patch]]
+                patchfile = "framehook_patch.o"
+                start_symbol = "_framehook_patch"
+                end_symbol = "_framehook_patch_end"
+                virtual_address = 396158"#
+            )
+        };
+
+        let config = Configuration::from_toml(
+            &toml(r#"tls_address_symbol = "_test""#),
+            Path::new("test/bin/fakefile.toml"),
+        )?;
+        let input = xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let original = input.header.tls_address;
+        let (output, report) = inject_with_report(config, input)?;
+        assert_eq!(report.original_tls_address, Some(original));
+
+        let output = xbe::Xbe::new(&output.serialize()?)?;
+        let mtext = report
+            .sections
+            .iter()
+            .find(|s| s.name == ".mtext")
+            .ok_or("No .mtext section in report")?;
+        assert!(
+            (mtext.virtual_address..mtext.virtual_address + mtext.size as u32)
+                .contains(&output.header.tls_address)
+        );
+
+        let config = Configuration::from_toml(
+            &toml("tls_address = 0x10"),
+            Path::new("test/bin/fakefile.toml"),
+        )?;
+        let err = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)
+            .expect_err("Unmapped TLS address");
+        assert!(matches!(
+            err.root_cause().downcast_ref::<HeaderError>(),
+            Some(HeaderError::UnmappedTlsAddress(0x10))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn data_sections() -> TestError {
+        let input = xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let fixed_address = input.get_next_virtual_address() + 0x10_0000;
+        let toml = format!(
+            r#"
+            modfiles = ["loader_stub.o"]
+
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396158
+
+            [[data_section]]
+            name = "blob"
+            file = "blob.bin"
+
+            [[data_section]]
+            name = "fixed"
+            file = "blob.bin"
+            virtual_address = {fixed_address}"#
+        );
+        let config = Configuration::from_toml(&toml, Path::new("test/bin/fakefile.toml"))?;
+        let (output, report) = inject_with_report(config, input)?;
+        let output = xbe::Xbe::new(&output.serialize()?)?;
+
+        let blob = fs::read("test/bin/blob.bin")?;
+        let blob_address = report
+            .sections
+            .iter()
+            .find(|s| s.name == ".blob")
+            .ok_or("No .blob section in report")?
+            .virtual_address;
+        for address in [blob_address, fixed_address] {
+            assert_eq!(
+                output.get_bytes(address..address + blob.len() as u32),
+                Some(blob.as_slice())
+            );
+        }
+
+        // Data sections can't overlap the XBE's own sections
+        let toml =
+            r#"data_section = [{ name = "blob", file = "blob.bin", virtual_address = 396158 }]"#;
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let err = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)
+            .expect_err("Data section overlaps the game's code");
+        assert!(matches!(
+            err.root_cause().downcast_ref::<RelocationError>(),
+            Some(RelocationError::OverlappingSection(..))
+        ));
+
+        // Nor can they wrap around the end of the address space
+        let toml = r#"data_section = [{ name = "blob", file = "blob.bin", virtual_address = 0xFFFFFFF0 }]"#;
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let err = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)
+            .expect_err("Data section wrapped around the address space");
+        assert!(matches!(
+            err.root_cause().downcast_ref::<RelocationError>(),
+            Some(RelocationError::AddressOverflow(_, 0xFFFF_FFF0, _))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn memory_budget_data_sections() -> TestError {
+        // A data section placed near the top of retail memory leaves no room for the kernel
+        let toml = |allow: bool| {
+            format!(
+                r#"
+                allow_over_budget = {allow}
+                data_section = [{{ name = "blob", file = "blob.bin", virtual_address = 0x3F00000 }}]"#
+            )
+        };
+
+        let config = Configuration::from_toml(&toml(false), Path::new("test/bin/fakefile.toml"))?;
+        let err = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)
+            .expect_err("Data section pushed the image over budget");
+        assert!(matches!(
+            err.root_cause().downcast_ref::<BudgetError>(),
+            Some(BudgetError::OverBudget(..))
+        ));
+
+        let config = Configuration::from_toml(&toml(true), Path::new("test/bin/fakefile.toml"))?;
+        assert!(inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn section_sizes() -> TestError {
+        let toml = |size: u32| {
+            format!(
+                r#"
+                modfiles = ["loader_stub.o"]
+
+                [section_sizes]
+                mtext = {size}
+
+                [[patch]]
+                patchfile = "framehook_patch.o"
+                start_symbol = "_framehook_patch"
+                end_symbol = "_framehook_patch_end"
+                virtual_address = 396158"#
+            )
+        };
+
+        let config = Configuration::from_toml(&toml(0x1000), Path::new("test/bin/fakefile.toml"))?;
+        let output = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)?;
+        let output = xbe::Xbe::new(&output.serialize()?)?;
+
+        // The header has the overridden size but only the 0x14 bytes of code are stored
+        let mtext = output
+            .section_by_name(".mtext")
+            .ok_or("No .mtext section in output")?;
+        assert_eq!(mtext.virtual_size, 0x1000);
+        assert_eq!(mtext.data.len(), 0x14);
+
+        // The override can't cut off the section's contents
+        let config = Configuration::from_toml(&toml(4), Path::new("test/bin/fakefile.toml"))?;
+        let err = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)
+            .expect_err("Section smaller than its contents");
+        assert!(matches!(
+            err.root_cause().downcast_ref::<RelocationError>(),
+            Some(RelocationError::VirtualSizeTooSmall(_, 4, 0x14))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn short_jmp_detour_out_of_range() -> TestError {
+        // .mtext is placed after all of the XBE's sections, far out of range of a short jump
+        let toml = r#"
+            modfiles = ["loader_stub.o"]
+
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396158
+
+            [[detour]]
+            target = 396158
+            symbol = "_framehook_shim"
+            kind = "short_jmp""#;
+
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let err = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)
+            .expect_err("Short jump written to an out of range destination");
+        assert!(format!("{err:?}").contains("outside the range -128..=127"));
+        Ok(())
+    }
+
+    #[test]
+    fn extra_sections() -> TestError {
+        let toml = r#"
+            modfiles = ["loader_stub.o", "hook_section.o"]
+
+            [extra_sections]
+            hook = ".mhook"
+
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396158"#;
+
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let (output, report) =
+            inject_with_report(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)?;
+
+        assert!(report
+            .sections
+            .iter()
+            .any(|s| s.name == ".mhook" && s.size == 6));
+        let mhook = output
+            .section_by_name(".mhook")
+            .ok_or("No .mhook section in output")?;
+        // mov eax, 1; ret
+        assert_eq!(mhook.data, [0xB8, 0x01, 0x00, 0x00, 0x00, 0xC3]);
+        Ok(())
+    }
+
+    #[test]
+    fn typed_errors() -> TestError {
+        // Without loader_stub.o the patch's jump to `_framehook_shim` can't be resolved
+        let toml = r#"
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396158"#;
+
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let err = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)
+            .expect_err("Patch applied with an undefined symbol");
+
+        assert_eq!(err.kind(), InjectErrorKind::SymbolResolution);
+        match err.step() {
+            Some(InjectStep::Patch {
+                name,
+                virtual_address,
+                file,
+                ..
+            }) => {
+                assert_eq!(name, "_framehook_patch");
+                assert_eq!(*virtual_address, 396158);
+                assert_eq!(file, Path::new("test/bin/framehook_patch.o"));
+            }
+            _ => panic!("Missing patch context: {err:?}"),
+        }
+        match err.root_cause().downcast_ref::<RelocationError>() {
+            Some(RelocationError::SymbolAddress(symbol)) => assert_eq!(symbol, "_framehook_shim"),
+            _ => panic!("Unexpected root cause: {err:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn section_flags() -> TestError {
+        let toml = r#"
+            modfiles = ["mod.o"]
+
+            [section_flags]
+            mdata = "PRELOAD"
+            mbss = "WRITABLE"
+
+            [[patch]]
+            patchfile = "bss_patch.o"
+            start_symbol = "_bss_patch"
+            end_symbol = "_bss_patch_end"
+            virtual_address = 396158"#;
+
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let output = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)?;
+
+        // Read the flags back from the serialized section headers
+        let output = xbe::Xbe::new(&output.serialize()?)?;
+        let flags = |name: &str| output.section_by_name(name).map(|s| s.flags);
+        assert_eq!(flags(".mdata"), Some(xbe::SectionFlags::PRELOAD));
+        assert_eq!(flags(".mbss"), Some(xbe::SectionFlags::WRITABLE));
+        // Sections without configured flags keep their defaults
+        assert_eq!(
+            flags(".mtext"),
+            Some(xbe::SectionFlags::PRELOAD | xbe::SectionFlags::EXECUTABLE)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn verify_manifest() -> TestError {
+        use crate::report::{InjectionReport, PatchStatus};
+
+        let config = Configuration::from_file(Path::new("test/conf.toml"))?;
+        let (mut output, report) =
+            inject_with_report(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)?;
+        let manifest = InjectionReport::from_manifest(&report.to_manifest()?)?;
+
+        let statuses = manifest.verify(&output);
+        assert_eq!(statuses.len(), 1);
+        assert_eq!(statuses[0].1, PatchStatus::Intact);
+
+        // Undo the first byte of the patch
+        output
+            .get_bytes_mut(396158..396159)
+            .ok_or("Patch site unmapped")?[0] = 0xA1;
+        assert_eq!(manifest.verify(&output)[0].1, PatchStatus::Modified);
+
+        // The unpatched XBE is reported as modified, while an unmapped address is missing
+        let vanilla = xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        assert_eq!(manifest.verify(&vanilla)[0].1, PatchStatus::Modified);
+        let mut missing = manifest.patches[0].clone();
+        missing.virtual_address = 0xF000_0000;
+        assert_eq!(missing.status(&vanilla), PatchStatus::Missing);
+        Ok(())
+    }
+
+    #[test]
+    fn restore_round_trip() -> TestError {
+        use sha1::{Digest, Sha1};
+
+        let vanilla = fs::read("test/bin/default.xbe")?;
+        let toml = format!(
+            r#"
+            modfiles = ["loader_stub.o", "mod.o"]
+            entry_point_symbol = "_test"
+            tls_address_symbol = "_test"
+
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396158
+
+            [[raw_patch]]
+            virtual_address = 396160
+            bytes = "90 90 90 90 90"
+
+            [[detour]]
+            target = {}
+            symbol = "_test"
+            with_original = true
+
+            [[library]]
+            name = "MYLIB"
+            major = 1
+            minor = 0
+            build = 5849"#,
+            396158 + 0x100
+        );
+        let config = Configuration::from_toml(&toml, Path::new("test/bin/fakefile.toml"))?;
+        let (patched, report) = inject_with_report(config, xbe::Xbe::new(&vanilla)?)?;
+        let manifest = InjectionReport::from_manifest(&report.to_manifest()?)?;
+        assert_eq!(
+            manifest.original_library_versions.as_ref().map(Vec::len),
+            Some(xbe::Xbe::new(&vanilla)?.library_versions.len())
+        );
+
+        let patched = patched.serialize()?;
+        assert_ne!(Sha1::digest(&patched), Sha1::digest(&vanilla));
+        let restored = restore(xbe::Xbe::new(&patched)?, &manifest)?;
+        assert_eq!(Sha1::digest(restored.serialize()?), Sha1::digest(&vanilla));
+
+        // A region changed by something other than the injection is left alone
+        let mut tampered = xbe::Xbe::new(&patched)?;
+        tampered
+            .get_bytes_mut(396158..396159)
+            .ok_or("Patch site unmapped")?[0] = 0xCC;
+        let err = restore(tampered, &manifest).expect_err("Restored a tampered region");
+        assert!(matches!(
+            err.downcast_ref::<RestoreError>(),
+            Some(RestoreError::RegionModified(_, 396158))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn header_address() -> TestError {
+        let toml = r#"
+            [[raw_patch]]
+            virtual_address = 0x10104
+            bytes = "00""#;
+
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let err = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)
+            .expect_err("Patched the XBE header");
+        assert!(matches!(
+            err.root_cause().downcast_ref::<PatchError>(),
+            Some(PatchError::HeaderAddress(0x10104))
+        ));
+        assert!(err
+            .root_cause()
+            .to_string()
+            .contains("allow_header_patches"));
+        Ok(())
+    }
+
+    #[test]
+    fn header_patch() -> TestError {
+        // The time and date stamp, which the xbe crate keeps as is
+        let toml = r#"
+            allow_header_patches = true
+
+            [[raw_patch]]
+            virtual_address = 0x10114
+            bytes = "78 56 34 12""#;
+
+        let vanilla = fs::read("test/bin/default.xbe")?;
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let (output, report) = inject_with_report(config, xbe::Xbe::new(&vanilla)?)?;
+        let patched = output.serialize()?;
+        assert_eq!(patched[0x114..0x118], [0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(report.patches[0].original_bytes, vanilla[0x114..0x118]);
+        assert!(report
+            .verify(&xbe::Xbe::new(&patched)?)
+            .iter()
+            .all(|(_, status)| *status == crate::report::PatchStatus::Intact));
+
+        let restored = restore(xbe::Xbe::new(&patched)?, &report)?;
+        assert_eq!(restored.serialize()?, vanilla);
+        Ok(())
+    }
+
+    #[test]
+    fn spans_sections() -> TestError {
+        let xbe = xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let first = xbe
+            .sections
+            .iter()
+            .min_by_key(|s| s.virtual_address)
+            .ok_or("XBE has no sections")?;
+        let end = first.virtual_address + first.virtual_size;
+
+        let toml = format!(
+            r#"
+            [[raw_patch]]
+            virtual_address = {}
+            bytes = "00 00 00 00""#,
+            end - 2
+        );
+        let name = reloc::strip_null(&first.name).to_string();
+
+        let config = Configuration::from_toml(&toml, Path::new("test/bin/fakefile.toml"))?;
+        let err = inject(config, xbe).expect_err("Patched across the end of a section");
+        assert_eq!(err.kind(), InjectErrorKind::Patch);
+        assert!(matches!(
+            err.root_cause().downcast_ref::<PatchError>(),
+            Some(PatchError::SpansSections(start, _, section))
+                if *start == end - 2 && *section == name
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn common_symbols() -> TestError {
+        let original = xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        // .mbss sorts first, so it is placed at the first free address
+        let mbss_address = original.get_next_virtual_address();
+
+        let toml = r#"
+            modfiles = ["loader_stub.o", "common.o"]
+
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396158"#;
+
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let (output, report) = inject_with_report(config, original)?;
+
+        assert!(report
+            .sections
+            .iter()
+            .any(|s| s.name == ".mbss" && s.virtual_address == mbss_address && s.size == 16));
+
+        // common.o's code follows the 0x14 bytes of loader_stub.o in .mtext
+        let mtext = report
+            .sections
+            .iter()
+            .find(|s| s.name == ".mtext")
+            .ok_or("No .mtext section")?;
+        let code = output
+            .get_bytes(mtext.virtual_address + 0x14..mtext.virtual_address + 0x14 + 0xB)
+            .ok_or(".mtext unmapped")?;
+        // mov eax, [_counter]
+        assert_eq!(code[1..5], mbss_address.to_le_bytes());
+        // mov ecx, _buffer
+        assert_eq!(code[6..10], (mbss_address + 4).to_le_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn deduplicate_constants() -> TestError {
+        // rdata_a.o and rdata_b.o have identical .rdata tables, each loaded by a mov in .text
+        fn inject_rdata(
+            deduplicate: bool,
+        ) -> Result<(xbe::Xbe, InjectionReport), Box<dyn std::error::Error>> {
+            let toml = format!(
+                r#"
+                modfiles = ["loader_stub.o", "rdata_a.o", "rdata_b.o"]
+
+                [optimizations]
+                deduplicate_constants = {deduplicate}
+
+                [[patch]]
+                patchfile = "framehook_patch.o"
+                start_symbol = "_framehook_patch"
+                end_symbol = "_framehook_patch_end"
+                virtual_address = 396158"#
+            );
+            Ok(inject_with_report(
+                Configuration::from_toml(&toml, Path::new("test/bin/fakefile.toml"))?,
+                xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?,
+            )?)
+        }
+        let section = |report: &InjectionReport, name: &str| {
+            report
+                .sections
+                .iter()
+                .find(|s| s.name == name)
+                .map(|s| (s.virtual_address, s.size))
+                .ok_or(format!("No {name} section"))
+        };
+        // rdata_b.o's mov operand, after the 0x14 bytes of loader_stub.o and 6 of rdata_a.o
+        let table_b = |output: &xbe::Xbe, mtext: u32| {
+            output
+                .get_bytes(mtext + 0x1A + 1..mtext + 0x1A + 5)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+                .ok_or(".mtext unmapped")
+        };
+
+        let (output, report) = inject_rdata(false)?;
+        let (mrdata, size) = section(&report, ".mrdata")?;
+        assert_eq!(size, 32);
+        let (mtext, _) = section(&report, ".mtext")?;
+        assert_eq!(table_b(&output, mtext)?, mrdata + 16);
+
+        // The second copy is dropped and rdata_b.o's reference is relocated to the first
+        let (output, report) = inject_rdata(true)?;
+        let (mrdata, size) = section(&report, ".mrdata")?;
+        assert_eq!(size, 16);
+        let (mtext, _) = section(&report, ".mtext")?;
+        assert_eq!(table_b(&output, mtext)?, mrdata);
+        assert_eq!(
+            output.get_bytes(mrdata..mrdata + 16),
+            Some(&[1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0][..])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn no_default_sections() -> TestError {
+        let toml = r#"
+            modfiles = ["loader_stub.o"]
+            no_default_sections = true
+
+            [[raw_patch]]
+            virtual_address = 396158
+            bytes = "90 90 90 90 90""#;
+
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let (output, report) =
+            inject_with_report(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)?;
+
+        assert!(report.sections.is_empty());
+        assert!(!output
+            .sections
+            .iter()
+            .any(|s| reloc::COMBINED_SECTION_NAMES.contains(&reloc::strip_null(&s.name))));
+        assert_eq!(output.get_bytes(396158..396163), Some(&[0x90; 5][..]));
+        Ok(())
+    }
+
+    #[test]
+    fn raw_patch() -> TestError {
+        let toml = |expected_bytes: &str| {
+            format!(
+                r#"
+                [[raw_patch]]
+                virtual_address = 396158
+                bytes = "90 90 90 90 90"
+                expected_bytes = "{expected_bytes}""#
+            )
+        };
+
+        let config =
+            Configuration::from_toml(&toml("A1 C0 2A 37 00"), Path::new("test/bin/fakefile.toml"))?;
+        let (output, report) =
+            inject_with_report(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)?;
+        assert_eq!(output.get_bytes(396158..396163), Some(&[0x90; 5][..]));
+        assert_eq!(report.patches.len(), 1);
+        assert_eq!(report.patches[0].length, 5);
+
+        let config =
+            Configuration::from_toml(&toml("8B 44 24 08 89"), Path::new("test/bin/fakefile.toml"))?;
+        let err = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)
+            .expect_err("Raw patch applied despite unexpected bytes");
+        assert!(format!("{err:?}").contains("Expected bytes [8B 44 24 08 89]"));
+        Ok(())
+    }
+
+    #[test]
+    fn error_modes() -> TestError {
+        // The patch's `_framehook_shim` is undefined without loader_stub.o, and two of the three
+        // raw patches fail
+        let toml = r#"
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396158
+
+            [[raw_patch]]
+            virtual_address = 396158
+            bytes = "90"
+            expected_bytes = "90"
+
+            [[raw_patch]]
+            virtual_address = 396160
+            bytes = "90"
+
+            [[raw_patch]]
+            virtual_address = 0x10000
+            bytes = "90""#;
+        let inject_with = |error_mode| -> std::result::Result<_, Box<dyn std::error::Error>> {
+            let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+            let xbe = xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+            let options = InjectOptions {
+                error_mode,
+                ..Default::default()
+            };
+            Ok(inject_with_options(config, xbe, options)
+                .expect_err("Injected despite failing patches"))
+        };
+
+        let err = inject_with(ErrorMode::default())?;
+        let errors: Vec<_> = err.errors().collect();
+        assert_eq!(errors.len(), 3, "{err:?}");
+        assert_eq!(errors[0].kind(), InjectErrorKind::SymbolResolution);
+        assert!(matches!(
+            errors[1].step(),
+            Some(InjectStep::RawPatch {
+                virtual_address: 396158
+            })
+        ));
+        assert!(matches!(
+            errors[2].root_cause().downcast_ref::<PatchError>(),
+            Some(PatchError::HeaderAddress(0x10000))
+        ));
+        assert!(err.to_string().ends_with("(and 2 more errors)"));
+
+        let err = inject_with(ErrorMode { fail_fast: true })?;
+        assert_eq!(err.errors().count(), 1);
+        assert_eq!(err.kind(), InjectErrorKind::SymbolResolution);
+        Ok(())
+    }
+
+    #[test]
+    fn signature_site() -> TestError {
+        // Build a signature from the code following the framehook, with the patched instruction's
+        // operand wildcarded, starting 4 bytes before the patch site
+        let xbe = xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let bytes = xbe.get_bytes(396154..396174).ok_or("Bad address")?;
+        let signature = bytes
+            .iter()
+            .enumerate()
+            .map(|(i, b)| match i {
+                5..=8 => "??".to_string(),
+                _ => format!("{b:02X}"),
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let toml = format!(
+            r#"
+            modfiles = ["loader_stub.o"]
+
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            signature = "{signature}"
+            signature_offset = 4"#
+        );
+        let config = Configuration::from_toml(&toml, Path::new("test/bin/fakefile.toml"))?;
+        let (output, report) = inject_with_report(config, xbe)?;
+
+        assert_eq!(report.patches[0].virtual_address, 396158);
+        assert_eq!(
+            output.serialize()?,
+            fs::read("test/bin/minimal_example.xbe")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn signature_match_all() -> TestError {
+        let toml = |extra: &str| {
+            format!(
+                r#"
+                modfiles = ["loader_stub.o"]
+
+                [[patch]]
+                patchfile = "framehook_patch.o"
+                start_symbol = "_framehook_patch"
+                end_symbol = "_framehook_patch_end"
+                signature = "A1 C0 2A 37 00"
+                match = "all"
+                {extra}"#
+            )
+        };
+
+        // Each match is reported under the address it was applied at
+        let config = Configuration::from_toml(&toml(""), Path::new("test/bin/fakefile.toml"))?;
+        let (output, report) =
+            inject_with_report(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)?;
+        assert!(report
+            .patches
+            .iter()
+            .any(|p| p.name == "_framehook_patch@0x60b7e" && p.virtual_address == 396158));
+        assert_eq!(
+            output
+                .get_bytes(396158..396159)
+                .ok_or("Patched range unmapped")?,
+            [0xE9]
+        );
+
+        // Exceeding the sanity limit is an error
+        let config = Configuration::from_toml(
+            &toml("max_matches = 0"),
+            Path::new("test/bin/fakefile.toml"),
+        )?;
+        let err = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)
+            .expect_err("Applied more matches than max_matches");
+        assert!(matches!(
+            err.root_cause().downcast_ref::<SignatureError>(),
+            Some(SignatureError::TooManyMatches(_, _, 0))
+        ));
+
+        // Matches overlapping an earlier patch are skipped
+        let config = Configuration::from_toml(
+            &toml(
+                r#"
+                [[patch]]
+                patchfile = "framehook_patch.o"
+                name = "fixed"
+                start_symbol = "_framehook_patch"
+                end_symbol = "_framehook_patch_end"
+                virtual_address = 396158"#,
+            ),
+            Path::new("test/bin/fakefile.toml"),
+        )?;
+        let config = Configuration {
+            patch_order: vec!["fixed".to_string()],
+            ..config
+        };
+        let (_, report) =
+            inject_with_report(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)?;
+        assert_eq!(report.patches[0].name, "fixed");
+        assert!(report
+            .patches
+            .iter()
+            .all(|p| p.virtual_address != 396158 || p.name == "fixed"));
+
+        // A site whose every match was skipped is an error
+        let config = Configuration::from_toml(
+            &toml(
+                r#"
+                [[patch]]
+                patchfile = "framehook_patch.o"
+                name = "again"
+                start_symbol = "_framehook_patch"
+                end_symbol = "_framehook_patch_end"
+                signature = "A1 C0 2A 37 00"
+                match = "all""#,
+            ),
+            Path::new("test/bin/fakefile.toml"),
+        )?;
+        let err = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)
+            .expect_err("Applied a site whose matches all overlapped");
+        assert!(matches!(
+            err.root_cause().downcast_ref::<SignatureError>(),
+            Some(SignatureError::NoneApplied(name, _)) if name == "again"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn symbol_suggestions() -> TestError {
+        let toml = r#"
+            modfiles = ["loader_stub.o"]
+
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396158"#;
+
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let err = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)
+            .expect_err("Patch applied with a misspelled start symbol");
+        match err.root_cause().downcast_ref::<PatchError>() {
+            Some(PatchError::UndefinedSymbol(name, count, hint)) => {
+                assert_eq!(name, "framehook_patch");
+                assert!(*count > 0);
+                assert!(hint.contains("'_framehook_patch'"));
+            }
+            _ => panic!("Unexpected root cause: {err:?}"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn mangled_symbol_names() -> TestError {
+        let toml = r#"
+            modfiles = ["loader_stub.o"]
+
+            [[patch]]
+            patchfile = "mangled.o"
+            start_symbol = "hook"
+            end_symbol = "_hook_end"
+            virtual_address = 396158"#;
+
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let (output, report) =
+            inject_with_report(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)?;
+
+        // The report names the mangled symbol that was matched
+        assert_eq!(report.patches[0].name, "?hook@@YAXXZ");
+        assert_eq!(
+            output
+                .get_bytes(396158..396159)
+                .ok_or("Patched range unmapped")?,
+            [0xC3]
+        );
+        Ok(())
+    }
+
+    #[test]
+    // The second patch calls a function installed by the first, which is only addressable once the
+    // first patch has been applied
+    fn chained_patches() -> TestError {
+        let toml = |order: &str| {
+            format!(
+                r#"
+                modfiles = ["loader_stub.o"]
+                {order}
+
+                [[patch]]
+                patchfile = "chain_a.o"
+                start_symbol = "_dispatcher_patch"
+                end_symbol = "_dispatcher_patch_end"
+                virtual_address = 396158
+
+                [[patch]]
+                patchfile = "chain_b.o"
+                start_symbol = "_call_patch"
+                end_symbol = "_call_patch_end"
+                virtual_address = 396170"#
+            )
+        };
+
+        let config = Configuration::from_toml(&toml(""), Path::new("test/bin/fakefile.toml"))?;
+        let output = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)?;
+
+        // call _dispatcher at 396159 from 396170
+        let bytes = output
+            .get_bytes(396170..396175)
+            .ok_or("Patched range unmapped")?;
+        assert_eq!(bytes, [0xE8, 0xF0, 0xFF, 0xFF, 0xFF]);
+
+        // The dispatcher isn't defined until its patch is applied
+        let config = Configuration::from_toml(
+            &toml(r#"patch_order = ["_call_patch"]"#),
+            Path::new("test/bin/fakefile.toml"),
+        )?;
+        assert!(inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn duplicate_site_symbol() -> TestError {
+        // _framehook_shim is already defined by the modfile
+        let toml = r#"
+            modfiles = ["loader_stub.o"]
+
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396158
+
+            [[patch]]
+            patchfile = "loader.o"
+            start_symbol = "_framehook_shim"
+            end_symbol = "_framehook_c"
+            virtual_address = 396200"#;
+
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let err = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)
+            .expect_err("Redefined a modfile symbol");
+        assert!(matches!(
+            err.root_cause().downcast_ref::<PatchError>(),
+            Some(PatchError::DuplicateSymbol(name, _, 396200)) if name == "_framehook_shim"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn site_section_below_zero() -> TestError {
+        // _dispatcher is 1 byte into its section, which would have to start at -1
+        let toml = r#"
+            modfiles = ["loader_stub.o"]
+
+            [[patch]]
+            patchfile = "chain_a.o"
+            start_symbol = "_dispatcher"
+            end_symbol = "_dispatcher_patch_end"
+            virtual_address = 0"#;
+
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let err = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)
+            .expect_err("Patch section placed below address 0");
+        assert!(matches!(
+            err.root_cause().downcast_ref::<PatchError>(),
+            Some(PatchError::SectionBelowZero(name, 1, 0)) if name == "_dispatcher"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    // Two sites writing the same 5-byte jump 3 bytes apart; whichever is applied last wins the
+    // overlapping bytes.
+    fn patch_order() -> TestError {
+        let toml = |order: &str| {
+            format!(
+                r#"
+                modfiles = ["loader_stub.o"]
+                {order}
+
+                [[patch]]
+                patchfile = "framehook_patch.o"
+                sites = [
+                    {{ name = "a", start_symbol = "_framehook_patch", end_symbol = "_framehook_patch_end", virtual_address = 396158 }},
+                    {{ name = "b", start_symbol = "_framehook_patch", end_symbol = "_framehook_patch_end", virtual_address = 396161 }},
+                ]"#
+            )
+        };
+        let mtext_address =
+            xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?.get_next_virtual_address();
+
+        // Declaration order: "b" overwrites the tail of "a"
+        let config = Configuration::from_toml(&toml(""), Path::new("test/bin/fakefile.toml"))?;
+        let (output, report) =
+            inject_with_report(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)?;
+        let names: Vec<_> = report.patches.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["a", "b"]);
+        let bytes = output
+            .get_bytes(396158..396166)
+            .ok_or("Patched range unmapped")?;
+        assert_eq!(bytes[3], 0xE9);
+        // but the report keeps the bytes "a" wrote before they were overwritten
+        let written = &report.patches[0].written_bytes;
+        assert_eq!(written[0], 0xE9);
+        assert_eq!(written[1..5], (mtext_address - (396158 + 5)).to_le_bytes());
+
+        // Reordered: "a" is applied last and is left intact
+        let config = Configuration::from_toml(
+            &toml(r#"patch_order = ["b", "a"]"#),
+            Path::new("test/bin/fakefile.toml"),
+        )?;
+        let (output, report) =
+            inject_with_report(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)?;
+        let names: Vec<_> = report.patches.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["b", "a"]);
+        assert_eq!(report.patches[1].sequence, 1);
+        let bytes = output
+            .get_bytes(396158..396166)
+            .ok_or("Patched range unmapped")?;
+        assert_eq!(bytes[0], 0xE9);
+        assert_eq!(bytes[1..5], (mtext_address - (396158 + 5)).to_le_bytes());
+        Ok(())
+    }
+
+    #[test]
+    // The framehook patch is 5 bytes long, so replacing 8 bytes should leave 3 NOPs after it and
+    // leave everything past the replaced region untouched.
+    fn nop_pad_patch() -> TestError {
+        let toml = r#"
+            modfiles = ["loader_stub.o"]
+
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396158
+            replaces_length = 8"#;
+
+        let original = xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let output = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)?;
+
+        let patched = output
+            .get_bytes(396158..396158 + 12)
+            .ok_or("Patched range unmapped")?;
+        let vanilla = original
+            .get_bytes(396158..396158 + 12)
+            .ok_or("Patched range unmapped")?;
+        assert_eq!(patched[0], 0xE9);
+        assert_eq!(&patched[5..8], &[0x90; 3]);
+        assert_eq!(&patched[8..], &vanilla[8..]);
+        Ok(())
+    }
+
+    #[test]
+    // Detours the start of the base game's .text section to the mod's shim, keeping the original
+    // callable through a trampoline placed after the mod's code.
+    fn detour_with_original() -> TestError {
+        let original = xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let target = original
+            .section_by_name(".text")
+            .ok_or("No .text section in test XBE")?
+            .virtual_address;
+        let mtext_address = original.get_next_virtual_address();
+
+        let toml = format!(
+            r#"
+            modfiles = ["loader_stub.o"]
+
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396158
+
+            [[detour]]
+            target = {target}
+            symbol = "_framehook_shim"
+            with_original = true"#
+        );
+
+        let config = Configuration::from_toml(&toml, Path::new("test/bin/fakefile.toml"))?;
+        let output = inject(config, xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?)?;
+
+        // Jump from the target to the shim at the start of .mtext
+        let jmp = output
+            .get_bytes(target..target + 5)
+            .ok_or("Detour target unmapped")?;
+        assert_eq!(jmp[0], 0xE9);
+        assert_eq!(jmp[1..], (mtext_address - (target + 5)).to_le_bytes());
+
+        // Trampoline follows the 0x14 bytes of loader_stub.o
+        let trampoline = output
+            .get_bytes(mtext_address + 0x14..mtext_address + 0x14 + 10)
+            .ok_or("Trampoline unmapped")?;
+        let prologue = original
+            .get_bytes(target..target + 5)
+            .ok_or("Detour target unmapped")?;
+        assert_eq!(&trampoline[..5], prologue);
+        assert_eq!(trampoline[5], 0xE9);
+        assert_eq!(
+            trampoline[6..],
+            (target + 5)
+                .wrapping_sub(mtext_address + 0x14 + 10)
+                .to_le_bytes()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn detour_past_section_end() -> TestError {
+        let original = xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let text_end = original
+            .section_by_name(".text")
+            .ok_or("No .text section in test XBE")?
+            .virtual_range()
+            .end;
+
+        // Only 3 of the 5 bytes of the jump fit before the end of .text
+        let toml = format!(
+            r#"
+            modfiles = ["loader_stub.o"]
+
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396158
+
+            [[detour]]
+            target = {}
+            symbol = "_framehook_shim""#,
+            text_end - 3
+        );
+
+        let config = Configuration::from_toml(&toml, Path::new("test/bin/fakefile.toml"))?;
+        let err = inject(config, original).expect_err("Detour written past the end of .text");
+        assert!(matches!(
+            err.root_cause().downcast_ref::<DetourError>(),
+            Some(DetourError::PrologueCrossesSection(_, 5, name, end))
+                if name == ".text" && *end == text_end
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn code_patch_into_data_section() -> TestError {
+        let xbe = xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let data_address = xbe
+            .section_by_name(".data")
+            .ok_or("No .data section in test XBE")?
+            .virtual_address;
+
+        let toml = format!(
+            r#"
+            deny_warnings = true
+            modfiles = ["loader_stub.o"]
+
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = {data_address}"#
+        );
+
+        let config = Configuration::from_toml(&toml, Path::new("test/bin/fakefile.toml"))?;
+        assert!(inject(config, xbe).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn image_kind_mismatch() -> TestError {
+        let toml = r#"
+            image_kind = "debug"
+            modfiles = ["loader_stub.o"]
+
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396158"#;
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let xbe = xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let err = inject(config, xbe).expect_err("Injected a debug config into a retail XBE");
+        assert!(matches!(
+            err.downcast_ref::<HeaderError>(),
+            Some(HeaderError::ImageKindMismatch(
+                header::ImageKind::Debug,
+                header::ImageKind::Retail
+            ))
+        ));
+
+        let config = Configuration::from_toml(
+            &format!("allow_image_kind_mismatch = true\n{toml}"),
+            Path::new("test/bin/fakefile.toml"),
+        )?;
+        let xbe = xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        inject(config, xbe)?;
+        Ok(())
+    }
+
+    #[test]
+    fn empty_debug_pathname() -> TestError {
+        let toml = r#"
+            modfiles = ["loader_stub.o"]
+
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396158"#;
+        let mut xbe = xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        xbe.header.debug_pathname = String::new();
+
+        // The xbe crate would panic serializing the XBE without a backslash while injecting, but
+        // the result keeps the pathname it was given
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let (xbe, _) = inject_with_report(config, xbe)?;
+        assert_eq!(xbe.header.debug_pathname, "");
+        assert!(xbe.section_by_name(".mtext").is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn missing_library() -> TestError {
+        let toml = r#"
+            modfiles = ["loader_stub.o"]
+
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396158"#;
+        let mut xbe = xbe::Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        xbe.library_versions
+            .retain(|lib| !lib.library_name.starts_with(b"XAPILIB"));
+        let libraries = xbe.library_versions.len();
+
+        // The xbe crate would panic serializing the XBE without XAPILIB while injecting, but the
+        // result is left without it
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let xbe = inject(config, xbe)?;
+        assert_eq!(xbe.library_versions.len(), libraries);
+        assert!(xbe
+            .library_versions
+            .iter()
+            .all(|lib| !lib.library_name.starts_with(b"XAPILIB")));
+        assert!(xbe.section_by_name(".mtext").is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn compressed_section() -> TestError {
+        // Set the compressed flag in the raw header of .text, as a loader that compresses sections
+        // would leave it
+        let mut image = fs::read("test/bin/default.xbe")?;
+        let text = section::RawLayout::parse(&image)?
+            .sections
+            .into_iter()
+            .find(|s| (s.virtual_address..s.virtual_address + s.virtual_size).contains(&396158))
+            .ok_or("Patch address is unmapped")?;
+        let flags = text.flags | section::COMPRESSED_FLAG;
+        image[text.offset..text.offset + 4].copy_from_slice(&flags.to_le_bytes());
+        let xbe = xbe::Xbe::new(&image)?;
+
+        let toml = r#"
+            modfiles = ["loader_stub.o"]
+
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396158"#;
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let err = inject(config, xbe).expect_err("Patched a compressed section");
+        assert!(matches!(
+            err.root_cause().downcast_ref::<PatchError>(),
+            Some(PatchError::CompressedSection(name, 396158)) if name == ".text"
+        ));
+        Ok(())
+    }
 }
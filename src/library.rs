@@ -0,0 +1,284 @@
+use crate::reloc::strip_null;
+use anyhow::{bail, Result};
+use log::{info, warn};
+use thiserror::Error;
+use xbe::{raw::LibraryVersion, Xbe};
+
+#[derive(Debug, Error)]
+pub enum LibraryError {
+    #[error("XBE already has a library version for '{0}'")]
+    DuplicateLibrary(String),
+    #[error("Library name '{0}' is longer than 8 bytes")]
+    NameTooLong(String),
+    #[error("Can't remove library version '{0}' as the XBE header points to it")]
+    RequiredLibrary(String),
+}
+
+/// Libraries whose version records the XBE header points to directly
+pub(crate) const REQUIRED_LIBRARIES: [&[u8]; 2] = [b"XBOXKRNL", b"XAPILIB"];
+
+/// A change to the library versions of the XBE.
+///
+/// The XBE header holds pointers to the XBOXKRNL and XAPILIB records, which can't be recomputed
+/// here. Records are only edited in place or appended, so those pointers stay valid, and removing
+/// either library is rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum LibraryChange {
+    Add {
+        name: [u8; 8],
+        major: u16,
+        minor: u16,
+        build: u16,
+        flags: u16,
+    },
+    Remove(String),
+}
+
+impl LibraryChange {
+    /// Name of the library being changed
+    pub(crate) fn name(&self) -> String {
+        match self {
+            LibraryChange::Add { name, .. } => display_name(name),
+            LibraryChange::Remove(name) => name.clone(),
+        }
+    }
+
+    pub(crate) fn apply(&self, xbe: &mut Xbe) -> Result<()> {
+        match self {
+            LibraryChange::Add {
+                name,
+                major,
+                minor,
+                build,
+                flags,
+            } => {
+                info!(
+                    "Adding library version {} {major}.{minor}.{build}",
+                    display_name(name)
+                );
+                add_library_version(xbe, name, *major, *minor, *build, *flags)
+            }
+            LibraryChange::Remove(name) => {
+                if remove_library_version(xbe, name.as_bytes())?.is_none() {
+                    warn!("Can't remove library version '{name}' as the XBE doesn't have it");
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Pads `name` with nulls to the 8 bytes of a library version name
+pub(crate) fn library_name(name: &str) -> Result<[u8; 8]> {
+    let mut padded = [0; 8];
+    padded
+        .get_mut(..name.len())
+        .ok_or_else(|| LibraryError::NameTooLong(name.to_string()))?
+        .copy_from_slice(name.as_bytes());
+    Ok(padded)
+}
+
+/// `name` without its null padding, for messages
+fn display_name(name: &[u8]) -> String {
+    strip_null(&String::from_utf8_lossy(name)).to_string()
+}
+
+/// Whether the library version name `a` is the same as `b`, ignoring null padding
+pub(crate) fn same_name(a: &[u8], b: &[u8]) -> bool {
+    let trim = |name: &[u8]| {
+        let len = name.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+        &name[..len]
+    };
+    trim(a) == trim(b)
+}
+
+/// Adds a library version record to `xbe`. Each library may only have one record.
+pub fn add_library_version(
+    xbe: &mut Xbe,
+    name: &[u8; 8],
+    major: u16,
+    minor: u16,
+    build: u16,
+    flags: u16,
+) -> Result<()> {
+    if get_library_version(xbe, name).is_some() {
+        bail!(LibraryError::DuplicateLibrary(display_name(name)));
+    }
+    xbe.library_versions.push(LibraryVersion {
+        library_name: *name,
+        major_version: major,
+        minor_version: minor,
+        build_version: build,
+        library_flags: flags,
+    });
+    Ok(())
+}
+
+/// Sets the version of the library named `name` in `xbe`, editing its record in place if it has
+/// one or adding one otherwise. Returns the record it replaced.
+pub fn set_library_version(
+    xbe: &mut Xbe,
+    name: &str,
+    major: u16,
+    minor: u16,
+    build: u16,
+    flags: u16,
+) -> Result<Option<LibraryVersion>> {
+    let library_name = library_name(name)?;
+    let version = LibraryVersion {
+        library_name,
+        major_version: major,
+        minor_version: minor,
+        build_version: build,
+        library_flags: flags,
+    };
+    match xbe
+        .library_versions
+        .iter_mut()
+        .find(|lib| same_name(&lib.library_name, &library_name))
+    {
+        Some(lib) => Ok(Some(std::mem::replace(lib, version))),
+        None => {
+            xbe.library_versions.push(version);
+            Ok(None)
+        }
+    }
+}
+
+/// Removes the library version record named `name` from `xbe`, returning it. XBOXKRNL and
+/// XAPILIB can't be removed, as the header points to their records.
+pub fn remove_library_version(xbe: &mut Xbe, name: &[u8]) -> Result<Option<LibraryVersion>> {
+    if REQUIRED_LIBRARIES.iter().any(|lib| same_name(lib, name)) {
+        bail!(LibraryError::RequiredLibrary(display_name(name)));
+    }
+    let position = xbe
+        .library_versions
+        .iter()
+        .position(|lib| same_name(&lib.library_name, name));
+    Ok(position.map(|position| xbe.library_versions.remove(position)))
+}
+
+/// The library version record named `name` in `xbe`
+pub fn get_library_version<'a>(xbe: &'a Xbe, name: &[u8]) -> Option<&'a LibraryVersion> {
+    xbe.library_versions
+        .iter()
+        .find(|lib| same_name(&lib.library_name, name))
+}
+
+/// Names of the libraries the header points to that `xbe` has no version record for, such as
+/// in homebrew built with open toolchains. The xbe crate panics serializing an XBE without them.
+pub fn missing_required_libraries(xbe: &Xbe) -> Vec<String> {
+    REQUIRED_LIBRARIES
+        .iter()
+        .filter(|&&name| get_library_version(xbe, name).is_none())
+        .map(|name| display_name(name))
+        .collect()
+}
+
+/// An empty record for the required library `name`, version 0.0.0, standing in for a missing one
+/// so the xbe crate can serialize the XBE
+pub(crate) fn placeholder_library(name: &[u8]) -> LibraryVersion {
+    let mut library_name = [0; 8];
+    library_name[..name.len()].copy_from_slice(name);
+    LibraryVersion {
+        library_name,
+        major_version: 0,
+        minor_version: 0,
+        build_version: 0,
+        library_flags: 0,
+    }
+}
+
+/// Whether `library` is a record made by [`placeholder_library`]
+pub(crate) fn is_placeholder(library: &LibraryVersion) -> bool {
+    REQUIRED_LIBRARIES
+        .iter()
+        .any(|name| same_name(&library.library_name, name))
+        && library.major_version == 0
+        && library.minor_version == 0
+        && library.build_version == 0
+        && library.library_flags == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    type TestError = std::result::Result<(), Box<dyn std::error::Error>>;
+
+    #[test]
+    fn names() -> TestError {
+        assert_eq!(library_name("XAPILIB")?, *b"XAPILIB\0");
+        assert_eq!(library_name("D3D8LTCG")?, *b"D3D8LTCG");
+        assert!(library_name("TOOLONGNAME").is_err());
+
+        assert!(same_name(b"XAPILIB\0", b"XAPILIB"));
+        assert!(!same_name(b"XAPILIB\0", b"XAPI"));
+        Ok(())
+    }
+
+    #[test]
+    fn library_round_trip() -> TestError {
+        let mut xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let count = xbe.library_versions.len();
+
+        add_library_version(&mut xbe, &library_name("MYLIB")?, 1, 2, 3, 0)?;
+        let err = add_library_version(&mut xbe, &library_name("MYLIB")?, 1, 2, 3, 0)
+            .expect_err("Added a library twice");
+        assert!(matches!(
+            err.downcast_ref::<LibraryError>(),
+            Some(LibraryError::DuplicateLibrary(name)) if name == "MYLIB"
+        ));
+
+        let mut xbe = Xbe::new(&xbe.serialize()?)?;
+        assert_eq!(xbe.library_versions.len(), count + 1);
+        let lib = get_library_version(&xbe, b"MYLIB").ok_or("Added library is missing")?;
+        assert_eq!(
+            (lib.major_version, lib.minor_version, lib.build_version),
+            (1, 2, 3)
+        );
+
+        assert!(remove_library_version(&mut xbe, b"MYLIB")?.is_some());
+        assert!(remove_library_version(&mut xbe, b"MYLIB")?.is_none());
+        let xbe = Xbe::new(&xbe.serialize()?)?;
+        assert_eq!(xbe.library_versions.len(), count);
+        assert!(get_library_version(&xbe, b"MYLIB").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn set_version() -> TestError {
+        let mut xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let count = xbe.library_versions.len();
+
+        assert!(set_library_version(&mut xbe, "MYLIB", 1, 0, 0, 0)?.is_none());
+        let replaced = set_library_version(&mut xbe, "MYLIB", 1, 0, 5849, 0)?
+            .ok_or("Library version wasn't replaced")?;
+        assert_eq!(replaced.build_version, 0);
+        assert_eq!(xbe.library_versions.len(), count + 1);
+        let lib = get_library_version(&xbe, b"MYLIB").ok_or("Library is missing")?;
+        assert_eq!(lib.build_version, 5849);
+
+        assert!(set_library_version(&mut xbe, "TOOLONGNAME", 1, 0, 0, 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn remove_required() -> TestError {
+        let mut xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let count = xbe.library_versions.len();
+
+        for name in ["XBOXKRNL", "XAPILIB"] {
+            let err = LibraryChange::Remove(name.to_string())
+                .apply(&mut xbe)
+                .expect_err("Removed a library the header points to");
+            assert!(matches!(
+                err.downcast_ref::<LibraryError>(),
+                Some(LibraryError::RequiredLibrary(lib)) if lib == name
+            ));
+        }
+        assert_eq!(xbe.library_versions.len(), count);
+        Ok(())
+    }
+}
@@ -0,0 +1,333 @@
+use crate::config::Configuration;
+use anyhow::{Context, Result};
+use std::{collections::HashMap, fmt::Display, path::Path};
+
+/// A check for configurations that can be injected, but probably don't do what was intended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Lint {
+    /// A patch start symbol doesn't begin with an underscore, as symbols of C functions do
+    StartSymbolUnderscore,
+    /// A modfile doesn't have the `.o` extension of an object file
+    ModfileExtension,
+    /// A fixed patch or data section address isn't 4-byte aligned
+    UnalignedAddress,
+    /// A patchfile is also listed in modfiles, so its code is injected as well as patched in
+    PatchfileInModfiles,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 4] = [
+        Lint::StartSymbolUnderscore,
+        Lint::ModfileExtension,
+        Lint::UnalignedAddress,
+        Lint::PatchfileInModfiles,
+    ];
+
+    /// Name used for this lint in lint configs and diagnostics
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lint::StartSymbolUnderscore => "start_symbol_underscore",
+            Lint::ModfileExtension => "modfile_extension",
+            Lint::UnalignedAddress => "unaligned_address",
+            Lint::PatchfileInModfiles => "patchfile_in_modfiles",
+        }
+    }
+}
+
+/// How a [`Lint`] is reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LintLevel {
+    /// The lint is not checked
+    Allow,
+    /// The lint is reported, but doesn't stop injection
+    Warning,
+    /// The lint is reported and stops injection
+    Error,
+}
+
+/// The level each [`Lint`] is reported at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LintConfig {
+    levels: HashMap<Lint, LintLevel>,
+}
+
+impl Default for LintConfig {
+    /// Patching x86 code doesn't require alignment, so unaligned addresses are only linted on
+    /// request. Patching with a file that is also a modfile is valid, if unusual, so it only warns.
+    fn default() -> Self {
+        Self {
+            levels: HashMap::from([
+                (Lint::StartSymbolUnderscore, LintLevel::Warning),
+                (Lint::ModfileExtension, LintLevel::Warning),
+                (Lint::UnalignedAddress, LintLevel::Allow),
+                (Lint::PatchfileInModfiles, LintLevel::Warning),
+            ]),
+        }
+    }
+}
+
+impl LintConfig {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let conf = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read file '{path:?}'"))?;
+
+        Self::from_toml(&conf)
+    }
+
+    /// Parses `conf` as a toml table of lint names to levels, e.g.
+    /// `unaligned_address = "error"`. Lints that aren't listed keep their default level.
+    pub fn from_toml(conf: &str) -> Result<Self> {
+        let levels: HashMap<Lint, LintLevel> = toml::from_str(conf)?;
+
+        let mut config = Self::default();
+        config.levels.extend(levels);
+        Ok(config)
+    }
+
+    pub fn level(&self, lint: Lint) -> LintLevel {
+        self.levels.get(&lint).copied().unwrap_or(LintLevel::Allow)
+    }
+
+    pub fn set_level(&mut self, lint: Lint, level: LintLevel) {
+        self.levels.insert(lint, level);
+    }
+}
+
+/// A [`Lint`] found in a [`Configuration`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LintDiagnostic {
+    pub lint: Lint,
+    pub level: LintLevel,
+    pub message: String,
+}
+
+impl Display for LintDiagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:?}: {} [{}]",
+            self.level,
+            self.message,
+            self.lint.name()
+        )
+    }
+}
+
+impl Configuration {
+    /// Checks this configuration for each lint that `lint_config` doesn't allow
+    pub fn lint(&self, lint_config: &LintConfig) -> Vec<LintDiagnostic> {
+        let mut found = Vec::new();
+        let mut push = |lint, message: String| found.push((lint, message));
+
+        for site in self.patches.iter().flat_map(|p| p.sites.iter()) {
+            if !site.start_symbol_name.starts_with('_') {
+                push(
+                    Lint::StartSymbolUnderscore,
+                    format!(
+                        "Patch start symbol '{}' does not begin with an underscore",
+                        site.start_symbol_name
+                    ),
+                );
+            }
+            if site.signature.is_none() && site.virtual_address % 4 != 0 {
+                push(
+                    Lint::UnalignedAddress,
+                    format!(
+                        "Patch '{}' virtual address {:#x} is not 4-byte aligned",
+                        site.start_symbol_name, site.virtual_address
+                    ),
+                );
+            }
+        }
+        for raw in self
+            .raw_patches
+            .iter()
+            .filter(|r| r.virtual_address % 4 != 0)
+        {
+            push(
+                Lint::UnalignedAddress,
+                format!(
+                    "Raw patch virtual address {:#x} is not 4-byte aligned",
+                    raw.virtual_address
+                ),
+            );
+        }
+        for section in self.data_sections.iter() {
+            if let Some(va) = section.virtual_address.filter(|va| va % 4 != 0) {
+                push(
+                    Lint::UnalignedAddress,
+                    format!(
+                        "Data section '{}' virtual address {va:#x} is not 4-byte aligned",
+                        section.name
+                    ),
+                );
+            }
+        }
+
+        for modfile in self.modfiles.iter() {
+            if modfile.path.extension().is_none_or(|ext| ext != "o") {
+                push(
+                    Lint::ModfileExtension,
+                    format!(
+                        "Modfile '{:?}' does not have the .o extension",
+                        modfile.path
+                    ),
+                );
+            }
+        }
+        for patch in self.patches.iter() {
+            if self.modfiles.iter().any(|m| m.path == patch.patchfile.path) {
+                push(
+                    Lint::PatchfileInModfiles,
+                    format!("Patchfile '{:?}' is also in modfiles", patch.patchfile.path),
+                );
+            }
+        }
+
+        found
+            .into_iter()
+            .map(|(lint, message)| LintDiagnostic {
+                lint,
+                level: lint_config.level(lint),
+                message,
+            })
+            .filter(|d| d.level != LintLevel::Allow)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type TestError = std::result::Result<(), Box<dyn std::error::Error>>;
+
+    /// Lints of the config `toml` with every lint at [`LintLevel::Warning`]
+    fn lints(toml: &str) -> Result<Vec<Lint>> {
+        let mut lint_config = LintConfig::default();
+        for lint in Lint::ALL {
+            lint_config.set_level(lint, LintLevel::Warning);
+        }
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        Ok(config
+            .lint(&lint_config)
+            .into_iter()
+            .map(|d| d.lint)
+            .collect())
+    }
+
+    #[test]
+    fn clean_config() -> TestError {
+        let toml = r#"
+            modfiles = ["loader.o"]
+
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396160"#;
+        assert_eq!(lints(toml)?, []);
+        Ok(())
+    }
+
+    #[test]
+    fn start_symbol_underscore() -> TestError {
+        let toml = r#"
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396160"#;
+        assert_eq!(lints(toml)?, [Lint::StartSymbolUnderscore]);
+        Ok(())
+    }
+
+    #[test]
+    fn modfile_extension() -> TestError {
+        let toml = r#"modfiles = ["loader.obj"]"#;
+        assert_eq!(lints(toml)?, [Lint::ModfileExtension]);
+        Ok(())
+    }
+
+    #[test]
+    fn unaligned_address() -> TestError {
+        let toml = r#"
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396158
+
+            [[raw_patch]]
+            virtual_address = 396162
+            bytes = "90"
+
+            [[data_section]]
+            name = ".blob"
+            file = "blob.bin"
+            virtual_address = 0x80000"#;
+        assert_eq!(
+            lints(toml)?,
+            [Lint::UnalignedAddress, Lint::UnalignedAddress]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn patchfile_in_modfiles() -> TestError {
+        let toml = r#"
+            modfiles = ["framehook_patch.o"]
+
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396160"#;
+        assert_eq!(lints(toml)?, [Lint::PatchfileInModfiles]);
+
+        // Only a warning by default
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let diagnostics = config.lint(&LintConfig::default());
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].level, LintLevel::Warning);
+        Ok(())
+    }
+
+    #[test]
+    fn lint_levels() -> TestError {
+        let toml = r#"
+            modfiles = ["loader.obj"]
+
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            start_symbol = "_framehook_patch"
+            end_symbol = "_framehook_patch_end"
+            virtual_address = 396158"#;
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+
+        // Unaligned addresses are allowed by default
+        let diagnostics = config.lint(&LintConfig::default());
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].lint, Lint::ModfileExtension);
+        assert_eq!(diagnostics[0].level, LintLevel::Warning);
+
+        let lint_config = LintConfig::from_toml(
+            r#"
+            modfile_extension = "allow"
+            unaligned_address = "error""#,
+        )?;
+        let diagnostics = config.lint(&lint_config);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].lint, Lint::UnalignedAddress);
+        assert_eq!(diagnostics[0].level, LintLevel::Error);
+        assert!(diagnostics[0]
+            .to_string()
+            .starts_with("Error: Patch '_framehook_patch' virtual address 0x60b7e"));
+
+        assert!(LintConfig::from_toml(r#"unknown_lint = "error""#).is_err());
+        assert!(LintConfig::from_toml(r#"modfile_extension = "fatal""#).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+use anyhow::{bail, Result};
+use thiserror::Error;
+
+/// Width in pixels of the logo shown by the dashboard
+pub const LOGO_WIDTH: usize = 100;
+/// Height in pixels of the logo shown by the dashboard
+pub const LOGO_HEIGHT: usize = 17;
+/// Longest run a one byte entry holds
+const MAX_SHORT_RUN: usize = 0x7;
+/// Longest run a two byte entry holds
+const MAX_LONG_RUN: usize = 0x3FF;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum LogoError {
+    #[error("Logo entry at offset {0:#x} is neither a one nor two byte run")]
+    InvalidEntry(usize),
+    #[error("Logo entry at offset {0:#x} ends past the end of the logo")]
+    TruncatedEntry(usize),
+    #[error("Logo runs cover {0} pixels, but the logo only has {LOGO_WIDTH}x{LOGO_HEIGHT}")]
+    TooManyPixels(usize),
+    #[error("Logo is {0}x{1} pixels, but must be {LOGO_WIDTH}x{LOGO_HEIGHT}")]
+    WrongDimensions(usize, usize),
+    #[error("{0} pixels given for a {1}x{2} logo")]
+    PixelCount(usize, usize, usize),
+    #[error("Encoded logo is {0} bytes, but only {1} fit in the headers")]
+    TooLarge(usize, usize),
+}
+
+/// Decodes the run length encoded logo `raw` into 8-bit grayscale pixels, row by row. The logo
+/// stores 4-bit shades, so the low bits of each pixel are zero. Pixels the runs don't reach are
+/// black.
+///
+/// Each run is either one byte, with bit 0 set, a 3-bit length in bits 1-3 and the shade in
+/// bits 4-7, or two little endian bytes, with bit 0 clear, bit 1 set, a 10-bit length in bits
+/// 2-11 and the shade in bits 12-15.
+pub fn decode_logo(raw: &[u8]) -> Result<Vec<u8>> {
+    let mut pixels = Vec::with_capacity(LOGO_WIDTH * LOGO_HEIGHT);
+    let mut offset = 0;
+    while let Some(&first) = raw.get(offset) {
+        let (len, shade) = if first & 0x1 != 0 {
+            offset += 1;
+            ((first as usize >> 1) & MAX_SHORT_RUN, first >> 4)
+        } else if first & 0x2 != 0 {
+            let Some(&second) = raw.get(offset + 1) else {
+                bail!(LogoError::TruncatedEntry(offset));
+            };
+            offset += 2;
+            let entry = u16::from_le_bytes([first, second]);
+            ((entry as usize >> 2) & MAX_LONG_RUN, (entry >> 12) as u8)
+        } else {
+            bail!(LogoError::InvalidEntry(offset));
+        };
+
+        if pixels.len() + len > LOGO_WIDTH * LOGO_HEIGHT {
+            bail!(LogoError::TooManyPixels(pixels.len() + len));
+        }
+        pixels.resize(pixels.len() + len, shade << 4);
+    }
+    pixels.resize(LOGO_WIDTH * LOGO_HEIGHT, 0);
+    Ok(pixels)
+}
+
+/// Encodes the 8-bit grayscale `pixels` of a `width` by `height` logo, row by row, keeping the
+/// high 4 bits of each. The dashboard only shows logos of [`LOGO_WIDTH`] by [`LOGO_HEIGHT`].
+///
+/// Runs are encoded greedily, so encoding a decoded logo gives the same pixels but not
+/// necessarily the same bytes as the original encoder.
+pub fn encode_logo(pixels: &[u8], width: usize, height: usize) -> Result<Vec<u8>> {
+    if (width, height) != (LOGO_WIDTH, LOGO_HEIGHT) {
+        bail!(LogoError::WrongDimensions(width, height));
+    }
+    if pixels.len() != width * height {
+        bail!(LogoError::PixelCount(pixels.len(), width, height));
+    }
+
+    let mut raw = Vec::new();
+    let mut rest = pixels;
+    while let Some(&pixel) = rest.first() {
+        let shade = pixel >> 4;
+        let run = rest.iter().take_while(|&&p| p >> 4 == shade).count();
+        let len = run.min(MAX_LONG_RUN);
+        if len <= MAX_SHORT_RUN {
+            raw.push(0x1 | (len as u8) << 1 | shade << 4);
+        } else {
+            let entry = 0x2 | (len as u16) << 2 | (shade as u16) << 12;
+            raw.extend(entry.to_le_bytes());
+        }
+        rest = &rest[len..];
+    }
+    Ok(raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type TestError = std::result::Result<(), Box<dyn std::error::Error>>;
+
+    #[test]
+    fn round_trip() -> TestError {
+        // A gradient of short runs over a long black run
+        let mut pixels = vec![0; LOGO_WIDTH * LOGO_HEIGHT];
+        for (i, pixel) in pixels.iter_mut().take(LOGO_WIDTH).enumerate() {
+            *pixel = ((i / 5) as u8 % 16) << 4 | 0xF;
+        }
+
+        let raw = encode_logo(&pixels, LOGO_WIDTH, LOGO_HEIGHT)?;
+        let decoded = decode_logo(&raw)?;
+        let quantized: Vec<u8> = pixels.iter().map(|p| p & 0xF0).collect();
+        assert_eq!(decoded, quantized);
+        // Runs of 5 take one byte, and the 1600 black pixels take two entries of two bytes
+        assert_eq!(raw.len(), 20 + 4);
+        Ok(())
+    }
+
+    #[test]
+    fn entries() -> TestError {
+        // One byte: length 3 of shade 0xA
+        assert_eq!(
+            decode_logo(&[0x1 | 3 << 1 | 0xA0])?[..4],
+            [0xA0, 0xA0, 0xA0, 0]
+        );
+        // Two bytes: length 0x100 of shade 0x5
+        let entry = (0x2u16 | 0x100 << 2 | 0x5 << 12).to_le_bytes();
+        let decoded = decode_logo(&entry)?;
+        assert!(decoded[..0x100].iter().all(|&p| p == 0x50));
+        assert_eq!(decoded[0x100], 0);
+        // Zero length runs decode to nothing
+        assert_eq!(decode_logo(&[0x1, 0x1])?, vec![0; LOGO_WIDTH * LOGO_HEIGHT]);
+
+        let err = |raw: &[u8]| decode_logo(raw).expect_err("Decoded an invalid logo");
+        assert_eq!(
+            err(&[0x1, 0x0]).downcast_ref(),
+            Some(&LogoError::InvalidEntry(1))
+        );
+        assert_eq!(
+            err(&[0x2]).downcast_ref(),
+            Some(&LogoError::TruncatedEntry(0))
+        );
+        let long = (0x2u16 | 0x3FF << 2).to_le_bytes();
+        assert_eq!(
+            err(&[long, long].concat()).downcast_ref(),
+            Some(&LogoError::TooManyPixels(0x7FE))
+        );
+
+        let pixels = vec![0; LOGO_WIDTH * LOGO_HEIGHT];
+        assert!(encode_logo(&pixels, 50, 34).is_err());
+        assert!(encode_logo(&pixels[1..], LOGO_WIDTH, LOGO_HEIGHT).is_err());
+        Ok(())
+    }
+}
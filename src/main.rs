@@ -1,31 +1,148 @@
-use std::path::PathBuf;
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::{Duration, Instant, SystemTime},
+};
 
-use anyhow::{Context, Result};
-use clap::Parser;
-use log::LevelFilter;
-use xbld::config::Configuration;
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
+use log::{error, warn, LevelFilter};
+use notify::{RecursiveMode, Watcher};
+use xbld::{
+    config::Configuration,
+    error::ErrorMode,
+    image::{DigestStatus, XbeImage},
+    lint::{LintConfig, LintLevel},
+    obj::ObjectFile,
+    pack::PatchPack,
+    report::{InjectionReport, PatchStatus},
+    util::hexdump,
+    validate::Severity,
+    InjectOptions,
+};
 
 #[derive(Debug, Parser)]
 #[clap(about, author, version)]
+#[clap(subcommand_negates_reqs = true)]
 struct Cli {
-    #[clap(value_parser)]
+    #[clap(subcommand)]
+    command: Option<Command>,
+    #[clap(value_parser, required = true)]
     /// Config file specifying code to be injected
-    config: PathBuf,
-    #[clap(value_parser)]
+    config: Option<PathBuf>,
+    #[clap(value_parser, required = true)]
     /// XBE Binary to inject into
-    input: PathBuf,
-    #[clap(value_parser)]
+    input: Option<PathBuf>,
+    #[clap(value_parser, required = true)]
     /// File path to write output to
-    output: PathBuf,
-    #[clap(short, long)]
+    output: Option<PathBuf>,
+    #[clap(long)]
+    /// Strip sections left by a previous injection instead of refusing to inject
+    allow_repatch: bool,
+    #[clap(long)]
+    /// Only inject the sections listed in the config's extra_sections
+    no_default_sections: bool,
+    #[clap(long)]
+    /// Warn instead of failing when the injected image doesn't fit in its memory budget
+    force: bool,
+    #[clap(long)]
+    /// Stop injecting at the first patch that fails, even if --collect-errors is given. This is
+    /// the default
+    fail_fast: bool,
+    #[clap(long)]
+    /// Apply every patch even after one fails, reporting each failure
+    collect_errors: bool,
+    #[clap(long, value_parser, value_delimiter = ',')]
+    /// Comma separated names of patch sites to apply first, in order, replacing the config's
+    /// patch_order
+    patch_order: Option<Vec<String>>,
+    #[clap(long, value_parser)]
+    /// Write a manifest of the applied patches, for use with the verify command
+    manifest: Option<PathBuf>,
+    #[clap(long)]
+    /// Print a hex dump of the bytes of each patched region after injecting
+    dump_patches: bool,
+    #[clap(long, value_parser)]
+    /// Write the header fields of the output XBE to a file as JSON
+    dump_header_json: Option<PathBuf>,
+    #[clap(long)]
+    /// Re-inject whenever the config, an object file, or the input XBE changes
+    watch: bool,
+    #[clap(long, value_parser, global = true)]
+    /// Lint levels to check the config with, as a toml table of lint names to levels
+    lint_config: Option<PathBuf>,
+    #[clap(short, long, global = true)]
     /// Silence all output
     quiet: bool,
-    #[clap(short, long)]
+    #[clap(short, long, global = true)]
     #[clap(action = clap::ArgAction::Count)]
     /// Increase message verbosity
     verbosity: u8,
 }
 
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// List the symbols defined and referenced by an object file
+    Symbols {
+        #[clap(value_parser)]
+        /// COFF object file to inspect
+        object: PathBuf,
+    },
+    /// Check that an XBE still contains the patches recorded in a manifest
+    Verify {
+        #[clap(value_parser)]
+        /// Patched XBE to check
+        xbe: PathBuf,
+        #[clap(value_parser)]
+        /// Manifest written during injection
+        manifest: PathBuf,
+    },
+    /// Undo an injection, recreating the original XBE from a patched XBE and its manifest
+    Restore {
+        #[clap(value_parser)]
+        /// Patched XBE to restore
+        input: PathBuf,
+        #[clap(value_parser)]
+        /// Manifest written during injection
+        manifest: PathBuf,
+        #[clap(value_parser)]
+        /// File path to write the restored XBE to
+        output: PathBuf,
+    },
+    /// Check a config and the object files it uses for problems, without an XBE
+    Validate {
+        #[clap(value_parser)]
+        /// Config file to check
+        config: PathBuf,
+    },
+    /// Perform an injection and save the result as a patch file that can be applied without the
+    /// config or object files
+    Pack {
+        #[clap(value_parser)]
+        /// Config file specifying code to be injected
+        config: PathBuf,
+        #[clap(value_parser)]
+        /// XBE Binary to inject into
+        input: PathBuf,
+        #[clap(value_parser)]
+        /// File path to write the .xbldpatch file to
+        output: PathBuf,
+    },
+    /// Apply a patch file created by the pack command
+    ApplyPack {
+        #[clap(value_parser)]
+        /// .xbldpatch file to apply
+        patch: PathBuf,
+        #[clap(value_parser)]
+        /// XBE the patch file was created from
+        input: PathBuf,
+        #[clap(value_parser)]
+        /// File path to write the patched XBE to
+        output: PathBuf,
+    },
+}
+
 fn main() -> Result<()> {
     let cli = Cli::parse();
     env_logger::Builder::new()
@@ -42,14 +159,728 @@ fn main() -> Result<()> {
         .format_timestamp(None)
         .init();
 
-    do_injection(&cli)
+    let mut out: Box<dyn Write> = if cli.quiet {
+        Box::new(std::io::sink())
+    } else {
+        Box::new(std::io::stdout())
+    };
+
+    match &cli.command {
+        Some(Command::Symbols { object }) => list_symbols(object, &mut out),
+        Some(Command::Verify { xbe, manifest }) => verify(xbe, manifest, &mut out),
+        Some(Command::Restore {
+            input,
+            manifest,
+            output,
+        }) => restore(input, manifest, output),
+        Some(Command::Validate { config }) => validate(config, &lint_config(&cli)?, &mut out),
+        Some(Command::Pack {
+            config,
+            input,
+            output,
+        }) => pack(config, input, output),
+        Some(Command::ApplyPack {
+            patch,
+            input,
+            output,
+        }) => apply_pack(patch, input, output),
+        None if cli.watch => watch(&cli, &mut out),
+        None => do_injection(&cli, &mut out),
+    }
+}
+
+/// How long to wait for further changes before re-injecting, so a burst of writes only triggers
+/// a single rebuild
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(100);
+
+enum WatchEvent {
+    Changed,
+    Interrupted,
+}
+
+/// Performs the injection described by `cli` and repeats it whenever one of its input files
+/// changes, until interrupted with Ctrl+C. Failed injections are reported without stopping.
+fn watch(cli: &Cli, out: &mut impl Write) -> Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let interrupt = tx.clone();
+    ctrlc::set_handler(move || {
+        let _ = interrupt.send(WatchEvent::Interrupted);
+    })?;
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if event.is_ok_and(|e| !e.kind.is_access()) {
+            let _ = tx.send(WatchEvent::Changed);
+        }
+    })?;
+
+    let mut watched = Vec::new();
+    loop {
+        let start = Instant::now();
+        match do_injection(cli, out) {
+            Ok(()) => writeln!(
+                out,
+                "[{}] Rebuilt in {}ms",
+                timestamp(),
+                start.elapsed().as_millis()
+            )?,
+            Err(e) => writeln!(out, "[{}] Injection failed: {e:?}", timestamp())?,
+        }
+
+        // The config may have changed which object files are used
+        for path in watched.drain(..) {
+            let _ = watcher.unwatch(&path);
+        }
+        for path in watched_paths(cli) {
+            match watcher.watch(&path, RecursiveMode::NonRecursive) {
+                Ok(()) => watched.push(path),
+                Err(e) => warn!("Couldn't watch '{path:?}': {e}"),
+            }
+        }
+
+        if let WatchEvent::Interrupted = rx.recv()? {
+            return Ok(());
+        }
+        loop {
+            match rx.recv_timeout(WATCH_DEBOUNCE) {
+                Ok(WatchEvent::Changed) => continue,
+                Ok(WatchEvent::Interrupted) => return Ok(()),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+/// Files that an injection described by `cli` reads
+fn watched_paths(cli: &Cli) -> Vec<PathBuf> {
+    let mut paths: Vec<_> = cli.config.iter().chain(cli.input.iter()).cloned().collect();
+    if let Some(config) = cli
+        .config
+        .as_deref()
+        .and_then(|path| Configuration::from_file(path).ok())
+    {
+        paths.extend(config.object_paths().map(Path::to_path_buf));
+    }
+    paths
+}
+
+/// Current UTC time of day as `HH:MM:SS`
+fn timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    format!(
+        "{:02}:{:02}:{:02}",
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Writes the external symbols defined and referenced by `object` to `out`
+fn list_symbols(object: &Path, out: &mut impl Write) -> Result<()> {
+    let obj = ObjectFile::new(object.to_path_buf())?;
+
+    writeln!(out, "{} symbols in '{:?}'", obj.symbol_count(), obj.path)?;
+    writeln!(out, "Defined:")?;
+    for name in obj.external_symbol_names() {
+        writeln!(out, "    {name}")?;
+    }
+    writeln!(out, "Undefined:")?;
+    for name in obj.undefined_symbol_names() {
+        writeln!(out, "    {name}")?;
+    }
+    Ok(())
+}
+
+/// Writes `image` to `output`, with its PE checksum recomputed from the serialized bytes
+fn write_xbe(image: &XbeImage, output: &Path) -> Result<()> {
+    let file = std::fs::File::create(output)
+        .with_context(|| format!("Failed to create XBE '{output:?}'"))?;
+    image
+        .write_to(std::io::BufWriter::new(file))
+        .with_context(|| format!("Failed to write XBE '{output:?}'"))
+}
+
+/// Reads the XBE at `path`
+fn read_xbe(path: &Path) -> Result<XbeImage> {
+    let file =
+        std::fs::File::open(path).with_context(|| format!("Failed to open XBE '{path:?}'"))?;
+    XbeImage::read_from(std::io::BufReader::new(file))
+        .with_context(|| format!("Failed to read XBE '{path:?}'"))
+}
+
+/// Reads the manifest at `path`
+fn read_manifest(path: &Path) -> Result<InjectionReport> {
+    InjectionReport::from_manifest(
+        &std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read manifest '{path:?}'"))?,
+    )
+}
+
+/// Writes the XBE at `input` to `output` with the injection recorded in `manifest` undone
+fn restore(input: &Path, manifest: &Path, output: &Path) -> Result<()> {
+    let manifest = read_manifest(manifest)?;
+    let mut image = read_xbe(input)?;
+    image.xbe = xbld::restore(image.xbe, &manifest)?;
+    write_xbe(&image, output)?;
+    Ok(())
+}
+
+/// Lint levels given by `--lint-config`, or the defaults
+fn lint_config(cli: &Cli) -> Result<LintConfig> {
+    match &cli.lint_config {
+        Some(path) => LintConfig::from_file(path)
+            .with_context(|| format!("Failed to parse lint config '{path:?}'")),
+        None => Ok(LintConfig::default()),
+    }
+}
+
+/// Logs the lints found in `config`, failing if any are errors
+fn lint(config: &Configuration, lint_config: &LintConfig, config_path: &Path) -> Result<()> {
+    let lints = config.lint(lint_config);
+    for lint in lints.iter() {
+        warn!("{lint}");
+    }
+
+    let errors = lints.iter().filter(|l| l.level == LintLevel::Error).count();
+    if errors > 0 {
+        bail!("Found {errors} lint errors in '{config_path:?}'");
+    }
+    Ok(())
+}
+
+/// Writes the diagnostics and lints of the config at `config_path` to `out`, failing if there are
+/// any errors. Checks that need the input XBE are listed but don't fail validation.
+fn validate(config_path: &Path, lint_config: &LintConfig, out: &mut impl Write) -> Result<()> {
+    let config = Configuration::from_file(config_path)
+        .with_context(|| format!("Failed to parse config file '{config_path:?}'"))?;
+
+    let diagnostics = config.validate();
+    for diagnostic in diagnostics.iter() {
+        writeln!(out, "{diagnostic}")?;
+    }
+    let lints = config.lint(lint_config);
+    for lint in lints.iter() {
+        writeln!(out, "{lint}")?;
+    }
+
+    let errors = diagnostics
+        .iter()
+        .filter(|d| d.severity == Severity::Error)
+        .count()
+        + lints.iter().filter(|l| l.level == LintLevel::Error).count();
+    if errors > 0 {
+        bail!("Found {errors} errors in '{config_path:?}'");
+    }
+    Ok(())
+}
+
+/// Injects into the XBE at `input` as described by the config at `config_path`, writing the
+/// result to `output` as a patch pack
+fn pack(config_path: &Path, input: &Path, output: &Path) -> Result<()> {
+    let config = Configuration::from_file(config_path)
+        .with_context(|| format!("Failed to parse config file '{config_path:?}'"))?;
+    let (xbe, report) = xbld::inject_with_report(config, read_xbe(input)?.xbe)?;
+
+    let mut buf = Vec::new();
+    PatchPack::from_injection(&xbe, &report)?.write(&mut buf)?;
+    std::fs::write(output, buf)?;
+    Ok(())
+}
+
+/// Writes the XBE at `input` to `output` with the patch pack at `patch` applied
+fn apply_pack(patch: &Path, input: &Path, output: &Path) -> Result<()> {
+    let pack = PatchPack::read(&mut std::fs::read(patch)?.as_slice())
+        .with_context(|| format!("Failed to read patch file '{patch:?}'"))?;
+    let mut image = read_xbe(input)?;
+    image.xbe = pack.apply(image.xbe)?;
+    write_xbe(&image, output)?;
+    Ok(())
+}
+
+/// Writes the status of each patch recorded in `manifest` within the XBE at `xbe_path` to `out`,
+/// failing if any are not intact, followed by whether each section's stored digest matches its
+/// data. Patched sections keep the digests they were loaded with, so digests don't fail it.
+fn verify(xbe_path: &Path, manifest: &Path, out: &mut impl Write) -> Result<()> {
+    let report = read_manifest(manifest)?;
+    let image = read_xbe(xbe_path)?;
+
+    let statuses = report.verify(&image.xbe);
+    for (patch, status) in statuses.iter() {
+        writeln!(
+            out,
+            "{:?}: '{}' at {:#x} ({} bytes)",
+            status, patch.name, patch.virtual_address, patch.length
+        )?;
+    }
+    let hex = |digest: [u8; 20]| {
+        digest
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>()
+    };
+    for (name, status) in image.verify_digests() {
+        match status {
+            DigestStatus::Match => writeln!(out, "Match: digest of section '{name}'")?,
+            DigestStatus::Mismatch { stored, computed } => writeln!(
+                out,
+                "Mismatch: digest of section '{name}' is {} but its data hashes to {}",
+                hex(stored),
+                hex(computed)
+            )?,
+            DigestStatus::Absent => writeln!(out, "Absent: digest of section '{name}'")?,
+        }
+    }
+
+    let damaged = statuses
+        .iter()
+        .filter(|(_, status)| *status != PatchStatus::Intact)
+        .count();
+    if damaged > 0 {
+        bail!("{damaged} of {} patches are not intact", statuses.len());
+    }
+    Ok(())
 }
 
-fn do_injection(cli: &Cli) -> Result<()> {
-    let config = Configuration::from_file(&cli.config)
-        .with_context(|| format!("Failed to parse config file '{:?}'", &cli.config))?;
-    let xbe: xbe::Xbe = xbld::inject(config, xbe::Xbe::new(&std::fs::read(&cli.input)?)?)?;
-    std::fs::write(&cli.output, xbe.serialize()?)?;
+/// How to inject with the flags given by `cli`. Unlike the library, the CLI stops at the first
+/// failure unless `--collect-errors` is given, as most of its runs are unattended builds.
+/// `--fail-fast` overrides `--collect-errors`, so a build script can force it. The input is only
+/// checksummed when a manifest is written.
+fn inject_options(cli: &Cli) -> InjectOptions {
+    InjectOptions {
+        error_mode: ErrorMode {
+            fail_fast: cli.fail_fast || !cli.collect_errors,
+        },
+        checksum_input: cli.manifest.is_some(),
+    }
+}
+
+/// Performs the injection described by `cli`, writing a summary of the result to `out`
+fn do_injection(cli: &Cli, out: &mut impl Write) -> Result<()> {
+    // clap requires these arguments when no subcommand is given
+    let (config_path, input, output) = match (&cli.config, &cli.input, &cli.output) {
+        (Some(config), Some(input), Some(output)) => (config, input, output),
+        _ => unreachable!("Missing injection arguments"),
+    };
 
+    let mut config = Configuration::from_file(config_path)
+        .with_context(|| format!("Failed to parse config file '{config_path:?}'"))?;
+    lint(&config, &lint_config(cli)?, config_path)?;
+    if cli.allow_repatch {
+        config.set_allow_repatch(true);
+    }
+    if cli.no_default_sections {
+        config.set_no_default_sections(true);
+    }
+    if cli.force {
+        config.set_allow_over_budget(true);
+    }
+    if let Some(order) = &cli.patch_order {
+        config.set_patch_order(order.clone());
+    }
+    let mut image = read_xbe(input)?;
+    let (xbe, report) =
+        xbld::inject_with_options(config, image.xbe, inject_options(cli)).map_err(|e| {
+            for other in e.errors().skip(1) {
+                error!("{other:#}");
+            }
+            e
+        })?;
+    image.xbe = xbe;
+    write_xbe(&image, output)?;
+    if let Some(path) = &cli.dump_header_json {
+        std::fs::write(path, serde_json::to_string_pretty(&image.header_json()?)?)
+            .with_context(|| format!("Failed to write header JSON '{path:?}'"))?;
+    }
+    if let Some(manifest) = &cli.manifest {
+        std::fs::write(manifest, report.to_manifest()?)
+            .with_context(|| format!("Failed to write manifest '{manifest:?}'"))?;
+    }
+    if cli.dump_patches {
+        // the bytes as each patch wrote them, before later patches overwrote any of them
+        for patch in report.patches.iter() {
+            if patch.written_bytes.len() != patch.length as usize {
+                bail!(
+                    "No bytes were recorded for patch '{}' at {:#x}",
+                    patch.name,
+                    patch.virtual_address
+                );
+            }
+            writeln!(
+                out,
+                "{} at {:#x}:\n{}",
+                patch.name,
+                patch.virtual_address,
+                hexdump(patch.virtual_address, &patch.written_bytes)
+            )?;
+        }
+    }
+
+    writeln!(out, "{report}")?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn injection_summary() -> Result<()> {
+        let output = std::env::temp_dir().join("xbld_injection_summary.xbe");
+        let cli = Cli::parse_from([
+            "xbld",
+            "test/conf.toml",
+            "test/bin/default.xbe",
+            output.to_str().context("Non UTF-8 temp directory")?,
+        ]);
+
+        let mut out = Vec::new();
+        do_injection(&cli, &mut out)?;
+        let _ = std::fs::remove_file(output);
+
+        let summary = String::from_utf8(out)?;
+        assert!(summary.starts_with("Injected 1 sections"));
+        assert!(summary.contains("bytes .mtext"));
+        assert!(summary.contains("applied 1 patches"));
+        Ok(())
+    }
+
+    #[test]
+    fn error_mode_flags() {
+        let args = |flags: &[&'static str]| {
+            ["xbld", "test/conf.toml", "test/bin/default.xbe", "out.xbe"]
+                .into_iter()
+                .chain(flags.iter().copied())
+                .collect::<Vec<_>>()
+        };
+        let fail_fast = |flags: &[&'static str]| {
+            inject_options(&Cli::parse_from(args(flags)))
+                .error_mode
+                .fail_fast
+        };
+
+        assert!(fail_fast(&[]));
+        assert!(fail_fast(&["--fail-fast"]));
+        assert!(!fail_fast(&["--collect-errors"]));
+        assert!(fail_fast(&["--fail-fast", "--collect-errors"]));
+        assert!(fail_fast(&["--collect-errors", "--fail-fast"]));
+    }
+
+    #[test]
+    fn checksum_input_flag() {
+        let args = ["xbld", "test/conf.toml", "test/bin/default.xbe", "out.xbe"];
+        assert!(!inject_options(&Cli::parse_from(args)).checksum_input);
+        let cli = Cli::parse_from(args.into_iter().chain(["--manifest", "out.toml"]));
+        assert!(inject_options(&cli).checksum_input);
+    }
+
+    #[test]
+    fn output_checksum() -> Result<()> {
+        let output = std::env::temp_dir().join("xbld_output_checksum.xbe");
+        let cli = Cli::parse_from([
+            "xbld",
+            "test/conf.toml",
+            "test/bin/default.xbe",
+            output.to_str().context("Non UTF-8 temp directory")?,
+        ]);
+        do_injection(&cli, &mut std::io::sink())?;
+        let bytes = std::fs::read(&output)?;
+        let _ = std::fs::remove_file(output);
+
+        // Sum the 32-bit little endian words with the checksum field zeroed, folding the carries
+        // down to 16 bits at the end, then add the file length
+        let mut padded = bytes.clone();
+        padded[0x144..0x148].fill(0);
+        padded.resize((padded.len() + 3) & !3, 0);
+        let sum: u64 = padded
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()) as u64)
+            .sum();
+        let mut sum = (sum & 0xFFFF_FFFF) + (sum >> 32);
+        sum = (sum & 0xFFFF) + (sum >> 16);
+        sum = (sum & 0xFFFF) + (sum >> 16);
+        sum = (sum & 0xFFFF) + (sum >> 16);
+        let expected = sum as u32 + bytes.len() as u32;
+
+        assert_eq!(bytes[0x144..0x148], expected.to_le_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn dump_patches_flag() -> Result<()> {
+        let output = std::env::temp_dir().join("xbld_dump_patches_flag.xbe");
+        let cli = Cli::parse_from([
+            "xbld",
+            "test/conf.toml",
+            "test/bin/default.xbe",
+            output.to_str().context("Non UTF-8 temp directory")?,
+            "--dump-patches",
+        ]);
+
+        let mut out = Vec::new();
+        do_injection(&cli, &mut out)?;
+        let _ = std::fs::remove_file(output);
+
+        // The framehook patch is written at 0x60b7e
+        let dump = String::from_utf8(out)?;
+        assert!(dump.contains("at 0x60b7e:\n00060b7e  "), "{dump}");
+        Ok(())
+    }
+
+    #[test]
+    fn dump_header_json_flag() -> Result<()> {
+        let output = std::env::temp_dir().join("xbld_dump_header_json_flag.xbe");
+        let json = std::env::temp_dir().join("xbld_dump_header_json_flag.json");
+        let cli = Cli::parse_from([
+            "xbld",
+            "test/conf.toml",
+            "test/bin/default.xbe",
+            output.to_str().context("Non UTF-8 temp directory")?,
+            "--dump-header-json",
+            json.to_str().context("Non UTF-8 temp directory")?,
+        ]);
+        do_injection(&cli, &mut std::io::sink())?;
+        let header: xbld::image::HeaderJson =
+            serde_json::from_str(&std::fs::read_to_string(&json)?)?;
+        let written = read_xbe(&output)?;
+        let _ = std::fs::remove_file(output);
+        let _ = std::fs::remove_file(json);
+
+        // The checksum is only written to the file, after serializing
+        let mut expected = written.header_json()?;
+        expected.pe_checksum = header.pe_checksum;
+        assert_eq!(header, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn header_patch_kept() -> Result<()> {
+        let config = std::env::temp_dir().join("xbld_header_patch_kept.toml");
+        let output = std::env::temp_dir().join("xbld_header_patch_kept.xbe");
+        let vanilla = std::fs::read("test/bin/default.xbe")?;
+        let certificate = u32::from_le_bytes(vanilla[0x118..0x11C].try_into()?);
+        let region = read_xbe(Path::new("test/bin/default.xbe"))?.game_region().0 ^ 0x2;
+
+        // Patch the certificate's game region, which XbeImage also writes
+        let bytes = region.to_le_bytes().map(|b| format!("{b:02X}")).join(" ");
+        std::fs::write(
+            &config,
+            format!(
+                "allow_header_patches = true\n\n[[raw_patch]]\nvirtual_address = {}\nbytes = \"{bytes}\"\n",
+                certificate + 0xA0
+            ),
+        )?;
+        let cli = Cli::parse_from([
+            "xbld",
+            config.to_str().context("Non UTF-8 temp directory")?,
+            "test/bin/default.xbe",
+            output.to_str().context("Non UTF-8 temp directory")?,
+        ]);
+        let injected = do_injection(&cli, &mut std::io::sink());
+        let written = read_xbe(&output);
+        let _ = std::fs::remove_file(config);
+        let _ = std::fs::remove_file(output);
+
+        injected?;
+        assert_eq!(written?.game_region().0, region);
+        Ok(())
+    }
+
+    #[test]
+    fn verify_subcommand() -> Result<()> {
+        let output = std::env::temp_dir().join("xbld_verify_subcommand.xbe");
+        let manifest = std::env::temp_dir().join("xbld_verify_subcommand.toml");
+        let cli = Cli::parse_from([
+            "xbld",
+            "test/conf.toml",
+            "test/bin/default.xbe",
+            output.to_str().context("Non UTF-8 temp directory")?,
+            "--manifest",
+            manifest.to_str().context("Non UTF-8 temp directory")?,
+        ]);
+        do_injection(&cli, &mut std::io::sink())?;
+
+        let mut out = Vec::new();
+        let patched = verify(&output, &manifest, &mut out);
+        let vanilla = verify(
+            Path::new("test/bin/default.xbe"),
+            &manifest,
+            &mut Vec::new(),
+        );
+        let _ = std::fs::remove_file(output);
+        let _ = std::fs::remove_file(manifest);
+
+        patched?;
+        let out = String::from_utf8(out)?;
+        let mut lines = out.lines();
+        assert_eq!(
+            lines.next(),
+            Some("Intact: '_framehook_patch' at 0x60b7e (5 bytes)")
+        );
+        // The patched section keeps its loaded digest, and added sections have none
+        assert!(lines.any(|l| l.starts_with("Mismatch: digest of section '.text' is ")));
+        assert!(out.contains("Absent: digest of section '.mtext'\n"));
+        assert!(vanilla.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn restore_subcommand() -> Result<()> {
+        let patched = std::env::temp_dir().join("xbld_restore_subcommand.xbe");
+        let manifest = std::env::temp_dir().join("xbld_restore_subcommand.toml");
+        let restored = std::env::temp_dir().join("xbld_restore_subcommand_restored.xbe");
+        let cli = Cli::parse_from([
+            "xbld",
+            "test/conf.toml",
+            "test/bin/default.xbe",
+            patched.to_str().context("Non UTF-8 temp directory")?,
+            "--manifest",
+            manifest.to_str().context("Non UTF-8 temp directory")?,
+        ]);
+        do_injection(&cli, &mut std::io::sink())?;
+
+        let result = restore(&patched, &manifest, &restored);
+        let output = std::fs::read(&restored);
+        let _ = std::fs::remove_file(patched);
+        let _ = std::fs::remove_file(manifest);
+        let _ = std::fs::remove_file(restored);
+
+        result?;
+        assert!(output? == std::fs::read("test/bin/default.xbe")?);
+        Ok(())
+    }
+
+    #[test]
+    fn pack_subcommands() -> Result<()> {
+        let patch = std::env::temp_dir().join("xbld_pack_subcommands.xbldpatch");
+        let applied = std::env::temp_dir().join("xbld_pack_subcommands.xbe");
+        let injected = std::env::temp_dir().join("xbld_pack_subcommands_injected.xbe");
+        let paths = [&patch, &applied, &injected]
+            .map(|p| p.to_str().context("Non UTF-8 temp directory"))
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+
+        let result = (|| -> Result<bool> {
+            for args in [
+                vec![
+                    "xbld",
+                    "pack",
+                    "test/conf.toml",
+                    "test/bin/default.xbe",
+                    paths[0],
+                ],
+                vec![
+                    "xbld",
+                    "apply-pack",
+                    paths[0],
+                    "test/bin/default.xbe",
+                    paths[1],
+                ],
+            ] {
+                match Cli::parse_from(args).command {
+                    Some(Command::Pack {
+                        config,
+                        input,
+                        output,
+                    }) => pack(&config, &input, &output)?,
+                    Some(Command::ApplyPack {
+                        patch,
+                        input,
+                        output,
+                    }) => apply_pack(&patch, &input, &output)?,
+                    _ => panic!("Expected pack subcommand"),
+                }
+            }
+            let cli = Cli::parse_from(["xbld", "test/conf.toml", "test/bin/default.xbe", paths[2]]);
+            do_injection(&cli, &mut std::io::sink())?;
+            Ok(std::fs::read(&applied)? == std::fs::read(&injected)?)
+        })();
+        let _ = std::fs::remove_file(&patch);
+        let _ = std::fs::remove_file(&applied);
+        let _ = std::fs::remove_file(&injected);
+
+        assert!(result?);
+        Ok(())
+    }
+
+    #[test]
+    fn pack_without_xapilib() -> Result<()> {
+        let input = std::env::temp_dir().join("xbld_pack_without_xapilib.xbe");
+        let patch = std::env::temp_dir().join("xbld_pack_without_xapilib.xbldpatch");
+        let field = |bytes: &[u8], offset: usize| -> Result<usize> {
+            Ok(u32::from_le_bytes(bytes[offset..offset + 4].try_into()?) as usize)
+        };
+
+        // Rename the XAPILIB library version and clear the header's pointer to it
+        let mut bytes = std::fs::read("test/bin/default.xbe")?;
+        let records = field(&bytes, 0x164)? - field(&bytes, 0x104)?;
+        let xapilib = (0..field(&bytes, 0x160)?)
+            .map(|i| records + i * 0x10)
+            .find(|&offset| bytes[offset..offset + 8] == *b"XAPILIB\0")
+            .context("No XAPILIB library version")?;
+        bytes[xapilib..xapilib + 8].copy_from_slice(b"XAPILIX\0");
+        bytes[0x16C..0x170].fill(0);
+        std::fs::write(&input, bytes)?;
+
+        // Loaded like the input of an injection, rather than refused
+        let packed = pack(Path::new("test/conf.toml"), &input, &patch);
+        let _ = std::fs::remove_file(input);
+        let _ = std::fs::remove_file(patch);
+
+        packed?;
+        Ok(())
+    }
+
+    #[test]
+    fn validate_subcommand() -> Result<()> {
+        let cli = Cli::parse_from(["xbld", "validate", "test/conf.toml"]);
+        let config = match &cli.command {
+            Some(Command::Validate { config }) => config,
+            _ => panic!("Expected validate subcommand"),
+        };
+
+        let mut out = Vec::new();
+        validate(config, &lint_config(&cli)?, &mut out)?;
+        assert!(!String::from_utf8(out)?.contains("Error"));
+        Ok(())
+    }
+
+    #[test]
+    fn lint_config_flag() -> Result<()> {
+        let path = std::env::temp_dir().join("xbld_lint_config_flag.toml");
+        std::fs::write(&path, r#"unaligned_address = "error""#)?;
+        let cli = Cli::parse_from([
+            "xbld",
+            "validate",
+            "test/conf.toml",
+            "--lint-config",
+            path.to_str().context("Non UTF-8 temp directory")?,
+        ]);
+        let lint_config = lint_config(&cli);
+        let _ = std::fs::remove_file(path);
+
+        // The test config patches an unaligned address
+        let mut out = Vec::new();
+        assert!(validate(Path::new("test/conf.toml"), &lint_config?, &mut out).is_err());
+        assert!(String::from_utf8(out)?.contains("[unaligned_address]"));
+        Ok(())
+    }
+
+    #[test]
+    fn symbols_subcommand() -> Result<()> {
+        let cli = Cli::parse_from(["xbld", "symbols", "test/bin/loader.o"]);
+        let object = match cli.command {
+            Some(Command::Symbols { object }) => object,
+            _ => panic!("Expected symbols subcommand"),
+        };
+
+        let mut out = Vec::new();
+        list_symbols(&object, &mut out)?;
+
+        let listing = String::from_utf8(out)?;
+        assert!(listing.starts_with("7 symbols"));
+        assert!(listing.contains("Defined:\n    _framehook_shim\n    _framehook_c\n"));
+        assert!(listing.contains("Undefined:\n    _framehook_patch\n"));
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+use crate::{
+    patch::XBE_BASE_ADDRESS,
+    section::{SectionExt, XbeExt},
+};
+use itertools::Itertools;
+use std::{borrow::Cow, ops::Range};
+use thiserror::Error;
+use xbe::Xbe;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ReadError {
+    #[error("No part of {0:#x}..{1:#x} is mapped by a section")]
+    Unmapped(u32, u32),
+    #[error("{0:#x}..{1:#x} is only partially mapped, {2:#x} is not mapped by a section")]
+    PartiallyUnmapped(u32, u32, u32),
+}
+
+/// RAM of a retail Xbox
+pub const RETAIL_MEMORY_BUDGET: u32 = 64 << 20;
+/// RAM of a development kit
+pub const DEVKIT_MEMORY_BUDGET: u32 = 128 << 20;
+/// Rough estimate of the memory kept by the kernel for its own image, page tables, and the
+/// framebuffer, which an image can never be loaded into. The real amount varies with the kernel
+/// version and video mode, so this is a conservative allowance rather than a measured figure.
+pub const KERNEL_RESERVED_SIZE: u32 = 4 << 20;
+/// Number of sections named when an image doesn't fit its budget
+const LARGEST_SECTION_COUNT: usize = 3;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum BudgetError {
+    #[error(
+        "Image needs {0:#x} bytes more than the memory budget of {1:#x} bytes. Largest sections: \
+        {2}"
+    )]
+    OverBudget(u64, u32, String),
+}
+
+/// Checks that the image of `xbe` fits in `budget` bytes of RAM alongside the kernel's
+/// reservations. Returns the number of bytes used. Injected sections are only counted once they
+/// have been added to `xbe`, and inserted files, which aren't loaded, never are.
+pub fn check_memory_budget(xbe: &Xbe, budget: u32) -> Result<u64, BudgetError> {
+    let sections: Vec<_> = xbe
+        .sections
+        .iter()
+        .filter(|s| !s.is_inserted_file())
+        .map(|s| (crate::reloc::strip_null(&s.name), s.virtual_range()))
+        .collect();
+
+    let end = sections
+        .iter()
+        .map(|(_, range)| range.end)
+        .max()
+        .unwrap_or(XBE_BASE_ADDRESS);
+    let footprint =
+        u64::from(end.saturating_sub(XBE_BASE_ADDRESS)) + u64::from(KERNEL_RESERVED_SIZE);
+    if footprint <= u64::from(budget) {
+        return Ok(footprint);
+    }
+
+    let largest = sections
+        .iter()
+        .sorted_by(|a, b| b.1.len().cmp(&a.1.len()).then_with(|| a.0.cmp(b.0)))
+        .take(LARGEST_SECTION_COUNT)
+        .map(|(name, range)| format!("'{name}' ({:#x} bytes)", range.len()))
+        .join(", ");
+    Err(BudgetError::OverBudget(
+        footprint - u64::from(budget),
+        budget,
+        largest,
+    ))
+}
+
+/// Reads `range` from the virtual address space of `xbe` as it would be loaded. Unlike
+/// [`XbeExt::bytes_at`], the range may span consecutive sections. Space in a section beyond its
+/// data and gaps of up to `max_gap` bytes between sections read as zeros.
+///
+/// The XBE header is not part of any section, so reading it is an error.
+pub fn read_virtual(
+    xbe: &Xbe,
+    range: Range<u32>,
+    max_gap: u32,
+) -> Result<Cow<'_, [u8]>, ReadError> {
+    if let Some(bytes) = xbe.bytes_at(range.clone()) {
+        return Ok(Cow::Borrowed(bytes));
+    }
+
+    let mut bytes = Vec::with_capacity(range.len());
+    let mut address = range.start;
+    let mut mapped = false;
+    for section in xbe
+        .sections
+        .iter()
+        .sorted_by_key(|s| s.virtual_address)
+        .filter(|s| s.virtual_range().end > range.start)
+    {
+        if address >= range.end {
+            break;
+        }
+        if section.virtual_address > address {
+            // bytes before the first section are not a gap between sections
+            let gap = section.virtual_address.min(range.end) - address;
+            if !mapped || gap > max_gap {
+                break;
+            }
+            bytes.resize(bytes.len() + gap as usize, 0);
+            address += gap;
+            if address >= range.end {
+                break;
+            }
+        }
+
+        let end = range.end.min(section.virtual_range().end);
+        let offset = (address - section.virtual_address) as usize;
+        let len = (end - address) as usize;
+        let data = section.data.get(offset..).unwrap_or_default();
+        bytes.extend(data.iter().take(len));
+        bytes.resize(bytes.len() + len - data.len().min(len), 0);
+        address = end;
+        mapped = true;
+    }
+
+    if address < range.end {
+        let intersects = xbe
+            .sections
+            .iter()
+            .any(|s| s.virtual_address < range.end && range.start < s.virtual_range().end);
+        return Err(if intersects {
+            ReadError::PartiallyUnmapped(range.start, range.end, address)
+        } else {
+            ReadError::Unmapped(range.start, range.end)
+        });
+    }
+    Ok(Cow::Owned(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    type TestError = std::result::Result<(), Box<dyn std::error::Error>>;
+
+    #[test]
+    fn read_across_sections() -> TestError {
+        let xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let sections: Vec<_> = xbe
+            .sections
+            .iter()
+            .sorted_by_key(|s| s.virtual_address)
+            .collect();
+        let (first, second) = (sections[0], sections[1]);
+
+        // Within a section, the bytes are borrowed
+        let start = first.virtual_address;
+        assert!(matches!(
+            read_virtual(&xbe, start..start + 4, 0)?,
+            Cow::Borrowed(_)
+        ));
+
+        // Across the end of the first section, including any gap before the second
+        let end = first.virtual_address + first.virtual_size;
+        let gap = second.virtual_address - end;
+        let bytes = read_virtual(&xbe, end - 4..second.virtual_address + 4, gap)?;
+        assert_eq!(bytes.len() as u32, 8 + gap);
+        assert_eq!(
+            bytes[bytes.len() - 4..],
+            second.data[..4],
+            "Second section's bytes follow the gap"
+        );
+
+        // The header isn't mapped
+        assert_eq!(
+            read_virtual(&xbe, 0x10000..0x10004, 0),
+            Err(ReadError::Unmapped(0x10000, 0x10004))
+        );
+        let start = first.virtual_address;
+        assert_eq!(
+            read_virtual(&xbe, start - 4..start + 4, 0x1000),
+            Err(ReadError::PartiallyUnmapped(
+                start - 4,
+                start + 4,
+                start - 4
+            ))
+        );
+        let last = sections[sections.len() - 1];
+        let end = last.virtual_address + last.virtual_size;
+        assert_eq!(
+            read_virtual(&xbe, end - 4..end + 4, 0x1000),
+            Err(ReadError::PartiallyUnmapped(end - 4, end + 4, end))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn memory_budget() -> TestError {
+        let mut xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let footprint = check_memory_budget(&xbe, RETAIL_MEMORY_BUDGET)?;
+        assert!(footprint > u64::from(KERNEL_RESERVED_SIZE));
+
+        // A section ending at the top of retail memory doesn't fit beside the kernel
+        let start = xbe.get_next_virtual_address();
+        xbe.add_section(
+            ".mbss\0".to_string(),
+            xbe::SectionFlags::PRELOAD | xbe::SectionFlags::WRITABLE,
+            Vec::new(),
+            start,
+            RETAIL_MEMORY_BUDGET - start,
+        );
+        let footprint = u64::from(RETAIL_MEMORY_BUDGET - XBE_BASE_ADDRESS + KERNEL_RESERVED_SIZE);
+        let over = footprint - u64::from(RETAIL_MEMORY_BUDGET);
+        match check_memory_budget(&xbe, RETAIL_MEMORY_BUDGET) {
+            Err(BudgetError::OverBudget(bytes, budget, largest)) => {
+                assert_eq!(bytes, over);
+                assert_eq!(budget, RETAIL_MEMORY_BUDGET);
+                assert!(largest.starts_with("'.mbss'"), "{largest}");
+                assert_eq!(largest.matches('\'').count(), 2 * LARGEST_SECTION_COUNT);
+            }
+            result => panic!("Expected the image to be over budget, got {result:?}"),
+        }
+
+        // but fits on a devkit
+        assert_eq!(
+            check_memory_budget(&xbe, DEVKIT_MEMORY_BUDGET),
+            Ok(footprint)
+        );
+        Ok(())
+    }
+}
@@ -1,9 +1,27 @@
-use anyhow::Context;
-use goblin::pe::Coff;
+use anyhow::{bail, Context};
+use goblin::pe::{
+    header::COFF_MACHINE_X86,
+    section_table::IMAGE_SCN_CNT_UNINITIALIZED_DATA,
+    symbol::{Symbol, IMAGE_SYM_CLASS_EXTERNAL},
+    Coff,
+};
 use log::info;
 use std::{fmt::Debug, fs, ops::Deref, path::PathBuf};
+use thiserror::Error;
 use yoke::{Yoke, Yokeable};
 
+#[derive(Debug, Error)]
+pub enum ObjectError {
+    #[error("Object file targets machine type {0:#06x}, expected i386 ({COFF_MACHINE_X86:#06x})")]
+    UnsupportedMachine(u16),
+    #[error(
+        "Data of section '{0}' at {1:#x}..{2:#x} extends past the end of the file ({3:#x} bytes)"
+    )]
+    SectionOutOfBounds(String, u32, u64, usize),
+    #[error("Symbol '{0}' references section {1}, but the file only has {2} sections")]
+    InvalidSectionNumber(String, i16, usize),
+}
+
 /// A parsed coff file paird with it's backing-data and filepath
 pub struct ObjectFile {
     pub path: PathBuf,
@@ -25,11 +43,61 @@ impl ObjectFile {
             .with_context(|| format!("Failed to read object file '{path:?}'"))?
             .into_boxed_slice();
 
+        Self::from_bytes(path, bytes)
+    }
+
+    /// Parses `bytes` as a COFF file read from `path`
+    pub(crate) fn from_bytes(path: PathBuf, bytes: Box<[u8]>) -> anyhow::Result<Self> {
         info!("Parsing ObjectFile '{path:?}'");
         let coff = Yoke::try_attach_to_cart(bytes, |b| Coff::parse(b).map(|coff| coff.into()))
             .with_context(|| format!("Failed to parse object file '{path:?}'"))?;
 
-        Ok(Self { path, coff })
+        let obj = Self { path, coff };
+        obj.validate()
+            .with_context(|| format!("Malformed object file '{:?}'", obj.path))?;
+        Ok(obj)
+    }
+
+    /// Checks the COFF header values that later processing trusts: the target machine, that the
+    /// raw data of each section is within the file, and that symbols only reference sections that
+    /// exist. Goblin already fails to parse a file whose section count runs past its end.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let coff = self.coff();
+        // Relocations are processed as i386 relocation types
+        if coff.header.machine != COFF_MACHINE_X86 {
+            bail!(ObjectError::UnsupportedMachine(coff.header.machine));
+        }
+
+        let len = self.bytes().len();
+        // Uninitialized sections have no raw data, whatever their size
+        for section in coff
+            .sections
+            .iter()
+            .filter(|s| s.characteristics & IMAGE_SCN_CNT_UNINITIALIZED_DATA == 0)
+        {
+            let start = section.pointer_to_raw_data;
+            let end = start as u64 + section.size_of_raw_data as u64;
+            if end > len as u64 {
+                bail!(ObjectError::SectionOutOfBounds(
+                    section.name().unwrap_or_default().to_string(),
+                    start,
+                    end,
+                    len
+                ));
+            }
+        }
+
+        // Zero and negative section numbers mark undefined, absolute, and debug symbols
+        if let Some((name, sym)) = self.named_symbols().find(|(_, sym)| {
+            sym.section_number > 0 && sym.section_number as usize > coff.sections.len()
+        }) {
+            bail!(ObjectError::InvalidSectionNumber(
+                name.to_string(),
+                sym.section_number,
+                coff.sections.len()
+            ));
+        }
+        Ok(())
     }
 
     #[inline]
@@ -41,6 +109,51 @@ impl ObjectFile {
     pub fn bytes(&self) -> &[u8] {
         self.coff.backing_cart()
     }
+
+    /// Number of symbols in the symbol table, not including auxiliary records
+    pub fn symbol_count(&self) -> usize {
+        self.coff().symbols.iter().count()
+    }
+
+    /// Names of the external symbols defined by this file
+    pub fn external_symbol_names(&self) -> Vec<&str> {
+        self.named_symbols()
+            .filter(|(_, sym)| sym.storage_class == IMAGE_SYM_CLASS_EXTERNAL)
+            .filter(|(_, sym)| sym.section_number > 0)
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    /// Names of all symbols defined in a section of this file, external or not
+    pub(crate) fn defined_symbol_names(&self) -> Vec<&str> {
+        self.named_symbols()
+            .filter(|(_, sym)| sym.section_number > 0)
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    /// Names of the external symbols referenced, but not defined, by this file
+    pub fn undefined_symbol_names(&self) -> Vec<&str> {
+        self.named_symbols()
+            .filter(|(_, sym)| sym.storage_class == IMAGE_SYM_CLASS_EXTERNAL)
+            .filter(|(_, sym)| sym.section_number == 0)
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    /// Iterates over each symbol in the symbol table alongside its name
+    pub(crate) fn named_symbols(&self) -> impl Iterator<Item = (&str, Symbol)> {
+        let coff = self.coff();
+        coff.symbols.iter().map(move |(_, name, sym)| {
+            let name = name
+                .or_else(|| {
+                    sym.name_offset()
+                        .and_then(|offset| coff.strings.get_at(offset as usize))
+                })
+                .unwrap_or_default();
+            (name, sym)
+        })
+    }
 }
 
 #[derive(Yokeable)]
@@ -59,3 +172,81 @@ impl<'a> From<Coff<'a>> for YokeableCoff<'a> {
         Self(v)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symbol_names() -> anyhow::Result<()> {
+        let obj = ObjectFile::new("test/bin/loader.o".into())?;
+        assert_eq!(obj.symbol_count(), 7);
+        assert_eq!(
+            obj.external_symbol_names(),
+            ["_framehook_shim", "_framehook_c"]
+        );
+        assert_eq!(obj.undefined_symbol_names(), ["_framehook_patch"]);
+
+        let obj = ObjectFile::new("test/bin/mod.o".into())?;
+        assert_eq!(obj.symbol_count(), 11);
+        assert_eq!(
+            obj.external_symbol_names(),
+            ["_test", "_test2", "_mod_size", "_anotherone", "_uninit"]
+        );
+        assert!(obj.undefined_symbol_names().is_empty());
+        Ok(())
+    }
+
+    /// `loader.o` with `corrupt` applied to its bytes
+    fn corrupted_loader(corrupt: impl FnOnce(&mut [u8])) -> anyhow::Result<ObjectFile> {
+        let mut bytes = fs::read("test/bin/loader.o")?;
+        corrupt(&mut bytes);
+        ObjectFile::from_bytes("loader.o".into(), bytes.into_boxed_slice())
+    }
+
+    #[test]
+    fn wrong_machine() {
+        // x86-64
+        let err = corrupted_loader(|b| b[0..2].copy_from_slice(&0x8664u16.to_le_bytes()))
+            .expect_err("Loaded an x86-64 object file");
+        assert!(matches!(
+            err.downcast_ref::<ObjectError>(),
+            Some(ObjectError::UnsupportedMachine(0x8664))
+        ));
+        assert_eq!(
+            err.root_cause().to_string(),
+            "Object file targets machine type 0x8664, expected i386 (0x014c)"
+        );
+    }
+
+    #[test]
+    fn malformed_section_count() {
+        // 255 section headers run far past the end of the file, which goblin refuses to parse
+        let err = corrupted_loader(|b| b[2..4].copy_from_slice(&255u16.to_le_bytes()))
+            .expect_err("Parsed more section headers than the file holds");
+        assert!(err.root_cause().is::<goblin::error::Error>());
+        assert!(err.to_string().starts_with("Failed to parse object file"));
+    }
+
+    #[test]
+    fn malformed_section_data() {
+        // size_of_raw_data of .text, whose data starts at 0x8c
+        let err = corrupted_loader(|b| b[36..40].copy_from_slice(&0x1000u32.to_le_bytes()))
+            .expect_err("Section data past the end of the file");
+        assert!(matches!(
+            err.downcast_ref::<ObjectError>(),
+            Some(ObjectError::SectionOutOfBounds(name, 0x8c, 0x108c, 436)) if name == ".text"
+        ));
+    }
+
+    #[test]
+    fn malformed_symbol_section() {
+        // section_number of _framehook_c
+        let err = corrupted_loader(|b| b[254..256].copy_from_slice(&9i16.to_le_bytes()))
+            .expect_err("Symbol in a section that doesn't exist");
+        assert!(matches!(
+            err.downcast_ref::<ObjectError>(),
+            Some(ObjectError::InvalidSectionNumber(name, 9, 3)) if name == "_framehook_c"
+        ));
+    }
+}
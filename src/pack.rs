@@ -0,0 +1,445 @@
+use crate::{
+    header,
+    patch::{byte_range, overwrite, read, HeaderWrites},
+    reloc::{crc32, strip_null},
+    report::{InjectionReport, LibraryVersionReport},
+    section::XbeExt,
+};
+use anyhow::{bail, Result};
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use itertools::Itertools;
+use std::io::{Read, Write};
+use thiserror::Error;
+use xbe::Xbe;
+
+/// Identifies an `.xbldpatch` file
+const MAGIC: &[u8; 8] = b"XBLDPACK";
+/// Version of the pack format written by this build
+const VERSION: u16 = 2;
+/// Version of the pack format before header fields and library versions were stored
+const VERSION_WITHOUT_HEADER: u16 = 1;
+
+#[derive(Debug, Error)]
+pub enum PackError {
+    #[error("Not an xbld patch pack")]
+    BadMagic,
+    #[error("Unsupported patch pack version {0}, expected {VERSION}")]
+    UnsupportedVersion(u16),
+    #[error("Patch at {0:#x} is corrupt, its checksum doesn't match its bytes")]
+    CorruptPatch(u32),
+    #[error("Section '{0}' has invalid flags {1:#x}")]
+    InvalidFlags(String, u32),
+    #[error("Injected section '{0}' at {1:#x} is missing from the XBE")]
+    MissingSection(String, u32),
+    #[error("Input XBE has checksum {1:#010x} but the pack was built for {0:#010x}")]
+    WrongBaseXbe(u32, u32),
+}
+
+/// The result of an injection as plain bytes, so it can be distributed and applied without the
+/// object files and config it was built from.
+///
+/// A pack is stored as the magic `XBLDPACK` followed by little endian fields:
+/// - `u16` format version
+/// - `u32` CRC-32 of the XBE the pack applies to
+/// - the encoded entry point and the TLS address, each a `u8` that is 1 if it is replaced followed
+///   by the `u32` value, or 0 otherwise
+/// - a `u8` that is 1 if the library versions are replaced, followed by the `u32` count and for
+///   each its 8 byte name and `u16` major, minor, and build versions and flags
+/// - `u32` section count, then for each section its name (`u16` length and UTF-8 bytes),
+///   `u32` virtual address, `u32` virtual size, `u32` flags, and data (`u32` length and bytes)
+/// - `u32` patch count, then for each patch its `u32` virtual address, bytes (`u32` length and
+///   bytes), and the `u32` CRC-32 of those bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchPack {
+    /// CRC-32 of the serialized XBE the pack applies to
+    pub original_checksum: u32,
+    /// Encoded entry point of the injected XBE, if the injection replaced it
+    pub entry_point: Option<u32>,
+    /// TLS address of the injected XBE, if the injection replaced it
+    pub tls_address: Option<u32>,
+    /// Library versions of the injected XBE, if the injection changed them
+    pub library_versions: Option<Vec<LibraryVersionReport>>,
+    pub sections: Vec<PackedSection>,
+    /// Bytes written over the XBE, in the order they are applied
+    pub patches: Vec<PackedPatch>,
+}
+
+/// A section added to the XBE
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackedSection {
+    pub name: String,
+    pub virtual_address: u32,
+    pub virtual_size: u32,
+    pub flags: u32,
+    pub data: Vec<u8>,
+}
+
+/// Bytes written over the XBE at a virtual address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackedPatch {
+    pub virtual_address: u32,
+    pub bytes: Vec<u8>,
+}
+
+impl PatchPack {
+    /// Captures the sections and patched bytes of `xbe`, the output of the injection described by
+    /// `report`
+    pub fn from_injection(xbe: &Xbe, report: &InjectionReport) -> Result<Self> {
+        // sections are added in address order, as they were during injection
+        let sections = report
+            .sections
+            .iter()
+            .sorted_by_key(|section| section.virtual_address)
+            .map(|section| {
+                let sec = xbe
+                    .sections
+                    .iter()
+                    .find(|s| {
+                        strip_null(&s.name) == section.name
+                            && s.virtual_address == section.virtual_address
+                    })
+                    .ok_or_else(|| {
+                        PackError::MissingSection(section.name.clone(), section.virtual_address)
+                    })?;
+                Ok(PackedSection {
+                    name: section.name.clone(),
+                    virtual_address: sec.virtual_address,
+                    virtual_size: sec.virtual_size,
+                    flags: sec.flags.bits(),
+                    data: sec.data.clone(),
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        let patches = report
+            .patches
+            .iter()
+            .map(|patch| {
+                let bytes = read(
+                    xbe,
+                    byte_range(patch.virtual_address, patch.length as usize)?,
+                )?;
+                Ok(PackedPatch {
+                    virtual_address: patch.virtual_address,
+                    bytes: bytes.into_owned(),
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            original_checksum: report.original_checksum,
+            entry_point: report.original_entry_point.map(|_| xbe.header.entry_point),
+            tls_address: report.original_tls_address.map(|_| xbe.header.tls_address),
+            library_versions: report
+                .original_library_versions
+                .as_ref()
+                .map(|_| xbe.library_versions.iter().map(Into::into).collect()),
+            sections,
+            patches,
+        })
+    }
+
+    /// Adds the packed sections to `xbe` and writes the packed patches over it. `xbe` must be the
+    /// XBE the pack was built from.
+    pub fn apply(&self, mut xbe: Xbe) -> Result<Xbe> {
+        let checksum = crc32(&header::serialize(&xbe)?);
+        if checksum != self.original_checksum {
+            bail!(PackError::WrongBaseXbe(self.original_checksum, checksum));
+        }
+
+        for section in self.sections.iter() {
+            let flags = xbe::SectionFlags::from_bits(section.flags)
+                .ok_or_else(|| PackError::InvalidFlags(section.name.clone(), section.flags))?;
+            xbe.try_add_section(
+                &section.name,
+                flags,
+                section.data.clone(),
+                section.virtual_address,
+                section.virtual_size,
+            )?;
+        }
+
+        let mut header = HeaderWrites::new(true);
+        for patch in self.patches.iter() {
+            overwrite(&mut xbe, &mut header, patch.virtual_address, &patch.bytes)?;
+        }
+        header.apply(&mut xbe)?;
+
+        if let Some(entry_point) = self.entry_point {
+            xbe.header.entry_point = entry_point;
+        }
+        if let Some(tls_address) = self.tls_address {
+            xbe.header.tls_address = tls_address;
+        }
+        if let Some(libraries) = self.library_versions.as_ref() {
+            xbe.library_versions = libraries.iter().map(Into::into).collect();
+        }
+        Ok(xbe)
+    }
+
+    /// Writes this pack in the `.xbldpatch` format
+    pub fn write(&self, w: &mut impl Write) -> Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_u16::<LE>(VERSION)?;
+        w.write_u32::<LE>(self.original_checksum)?;
+
+        for value in [self.entry_point, self.tls_address] {
+            write_option(w, value.as_ref(), |w, value| Ok(w.write_u32::<LE>(*value)?))?;
+        }
+        write_option(w, self.library_versions.as_ref(), |w, libraries| {
+            w.write_u32::<LE>(libraries.len() as u32)?;
+            for library in libraries.iter() {
+                w.write_all(&library.name)?;
+                for value in [library.major, library.minor, library.build, library.flags] {
+                    w.write_u16::<LE>(value)?;
+                }
+            }
+            Ok(())
+        })?;
+
+        w.write_u32::<LE>(self.sections.len() as u32)?;
+        for section in self.sections.iter() {
+            w.write_u16::<LE>(section.name.len() as u16)?;
+            w.write_all(section.name.as_bytes())?;
+            w.write_u32::<LE>(section.virtual_address)?;
+            w.write_u32::<LE>(section.virtual_size)?;
+            w.write_u32::<LE>(section.flags)?;
+            write_bytes(w, &section.data)?;
+        }
+
+        w.write_u32::<LE>(self.patches.len() as u32)?;
+        for patch in self.patches.iter() {
+            w.write_u32::<LE>(patch.virtual_address)?;
+            write_bytes(w, &patch.bytes)?;
+            w.write_u32::<LE>(crc32(&patch.bytes))?;
+        }
+        Ok(())
+    }
+
+    /// Reads a pack in the `.xbldpatch` format, checking the integrity of each patch
+    pub fn read(r: &mut impl Read) -> Result<Self> {
+        let mut magic = [0; MAGIC.len()];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            bail!(PackError::BadMagic);
+        }
+        let version = r.read_u16::<LE>()?;
+        if version != VERSION && version != VERSION_WITHOUT_HEADER {
+            bail!(PackError::UnsupportedVersion(version));
+        }
+        let original_checksum = r.read_u32::<LE>()?;
+
+        let (mut entry_point, mut tls_address, mut library_versions) = (None, None, None);
+        if version != VERSION_WITHOUT_HEADER {
+            entry_point = read_option(r, |r| Ok(r.read_u32::<LE>()?))?;
+            tls_address = read_option(r, |r| Ok(r.read_u32::<LE>()?))?;
+            library_versions = read_option(r, |r| {
+                (0..r.read_u32::<LE>()?)
+                    .map(|_| {
+                        let mut name = [0; 8];
+                        r.read_exact(&mut name)?;
+                        Ok(LibraryVersionReport {
+                            name,
+                            major: r.read_u16::<LE>()?,
+                            minor: r.read_u16::<LE>()?,
+                            build: r.read_u16::<LE>()?,
+                            flags: r.read_u16::<LE>()?,
+                        })
+                    })
+                    .collect()
+            })?;
+        }
+
+        let sections = (0..r.read_u32::<LE>()?)
+            .map(|_| {
+                let mut name = vec![0; r.read_u16::<LE>()? as usize];
+                r.read_exact(&mut name)?;
+                Ok(PackedSection {
+                    name: String::from_utf8(name)?,
+                    virtual_address: r.read_u32::<LE>()?,
+                    virtual_size: r.read_u32::<LE>()?,
+                    flags: r.read_u32::<LE>()?,
+                    data: read_bytes(r)?,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        let patches = (0..r.read_u32::<LE>()?)
+            .map(|_| {
+                let virtual_address = r.read_u32::<LE>()?;
+                let bytes = read_bytes(r)?;
+                if r.read_u32::<LE>()? != crc32(&bytes) {
+                    bail!(PackError::CorruptPatch(virtual_address));
+                }
+                Ok(PackedPatch {
+                    virtual_address,
+                    bytes,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            original_checksum,
+            entry_point,
+            tls_address,
+            library_versions,
+            sections,
+            patches,
+        })
+    }
+}
+
+/// Writes `bytes` prefixed by their `u32` length
+fn write_bytes(w: &mut impl Write, bytes: &[u8]) -> Result<()> {
+    w.write_u32::<LE>(bytes.len() as u32)?;
+    w.write_all(bytes)?;
+    Ok(())
+}
+
+/// Writes a `u8` that is 1 if `value` is present followed by `value` written with `write`, or 0
+fn write_option<W: Write, T>(
+    w: &mut W,
+    value: Option<&T>,
+    write: impl FnOnce(&mut W, &T) -> Result<()>,
+) -> Result<()> {
+    match value {
+        Some(value) => {
+            w.write_u8(1)?;
+            write(w, value)
+        }
+        None => Ok(w.write_u8(0)?),
+    }
+}
+
+/// Reads a value written by [`write_option`] with `read`
+fn read_option<R: Read, T>(r: &mut R, read: impl FnOnce(&mut R) -> Result<T>) -> Result<Option<T>> {
+    match r.read_u8()? {
+        0 => Ok(None),
+        _ => read(r).map(Some),
+    }
+}
+
+/// Reads bytes prefixed by their `u32` length
+fn read_bytes(r: &mut impl Read) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let len = r.read_u32::<LE>()? as u64;
+    r.by_ref().take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        bail!(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Configuration, inject_with_report};
+    use std::{fs, path::Path};
+
+    fn example_pack() -> PatchPack {
+        PatchPack {
+            original_checksum: 0xDEAD_BEEF,
+            entry_point: Some(0xA8FC_57AB ^ 0x3000),
+            tls_address: None,
+            library_versions: Some(vec![LibraryVersionReport {
+                name: *b"MYLIB\0\0\0",
+                major: 1,
+                minor: 0,
+                build: 5849,
+                flags: 0x4000,
+            }]),
+            sections: vec![PackedSection {
+                name: ".mtext".to_string(),
+                virtual_address: 0x3000,
+                virtual_size: 8,
+                flags: 0x6,
+                data: vec![0x90, 0xC3],
+            }],
+            patches: vec![PackedPatch {
+                virtual_address: 396158,
+                bytes: vec![0xE9, 0x01, 0x02, 0x03, 0x04],
+            }],
+        }
+    }
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let pack = example_pack();
+        let mut buf = Vec::new();
+        pack.write(&mut buf)?;
+        assert!(buf.starts_with(MAGIC));
+
+        assert_eq!(PatchPack::read(&mut buf.as_slice())?, pack);
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_pack() -> Result<()> {
+        let mut buf = Vec::new();
+        example_pack().write(&mut buf)?;
+
+        // Flip a byte of the patch, just before its checksum
+        let mut corrupt = buf.clone();
+        let index = corrupt.len() - 5;
+        corrupt[index] ^= 0xFF;
+        let err = PatchPack::read(&mut corrupt.as_slice()).expect_err("Read a corrupt patch");
+        assert!(matches!(
+            err.downcast_ref::<PackError>(),
+            Some(PackError::CorruptPatch(396158))
+        ));
+
+        // Truncated
+        assert!(PatchPack::read(&mut &buf[..buf.len() - 1]).is_err());
+
+        let err = PatchPack::read(&mut &b"NOTAPACK"[..]).expect_err("Read without magic");
+        assert!(matches!(
+            err.downcast_ref::<PackError>(),
+            Some(PackError::BadMagic)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn apply_pack() -> Result<()> {
+        let config = Configuration::from_file(Path::new("test/conf.toml"))?;
+        let (injected, report) =
+            inject_with_report(config, Xbe::new(&fs::read("test/bin/default.xbe")?)?)?;
+
+        let mut buf = Vec::new();
+        PatchPack::from_injection(&injected, &report)?.write(&mut buf)?;
+        let pack = PatchPack::read(&mut buf.as_slice())?;
+
+        let applied = pack.apply(Xbe::new(&fs::read("test/bin/default.xbe")?)?)?;
+        assert!(applied.serialize()? == injected.serialize()?);
+
+        // A pack only applies to the XBE it was built from
+        assert!(pack.apply(applied).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn apply_pack_header() -> Result<()> {
+        let toml = r#"
+            modfiles = ["loader_stub.o", "mod.o"]
+            entry_point_symbol = "_test"
+            tls_address_symbol = "_test"
+
+            [[library]]
+            name = "MYLIB"
+            major = 1
+            minor = 0
+            build = 5849"#;
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let (injected, report) =
+            inject_with_report(config, Xbe::new(&fs::read("test/bin/default.xbe")?)?)?;
+
+        let pack = PatchPack::from_injection(&injected, &report)?;
+        assert_eq!(pack.entry_point, Some(injected.header.entry_point));
+        assert_eq!(pack.tls_address, Some(injected.header.tls_address));
+        assert!(pack.library_versions.is_some());
+
+        let applied = pack.apply(Xbe::new(&fs::read("test/bin/default.xbe")?)?)?;
+        assert!(applied.serialize()? == injected.serialize()?);
+        Ok(())
+    }
+}
@@ -1,86 +1,825 @@
-use crate::{obj::ObjectFile, reloc::SymbolTable, SectionMap, Xbe};
-use anyhow::{bail, Result};
-use goblin::pe::symbol::Symbol;
-use std::{
-    io::{Cursor, Write},
-    path::PathBuf,
+use crate::{
+    demangle, header,
+    obj::ObjectFile,
+    reloc::{strip_null, suggest_names, RelocationError, SymbolTable},
+    section::{SectionExt, XbeExt},
+    signature::{Signature, SignatureMatch},
+    SectionMap, Xbe,
 };
+use anyhow::{bail, Result};
+use goblin::pe::symbol::{Symbol, IMAGE_SYM_CLASS_EXTERNAL};
+use itertools::Itertools;
+use log::{debug, info, warn};
+use std::{borrow::Cow, fmt::Display, ops::Range, path::PathBuf};
 use thiserror::Error;
 
-#[derive(Debug, Error)]
+/// x86 single-byte no-op instruction
+pub(crate) const NOP: u8 = 0x90;
+/// Virtual address the XBE header is loaded at
+pub(crate) const XBE_BASE_ADDRESS: u32 = 0x10000;
+/// Prefix of start symbols that name the address they're patched at, see
+/// [`address_from_symbol_name`]
+const PATCH_SITE_PREFIX: &str = "_patch_site_";
+
+#[derive(Debug, Error, PartialEq, Eq)]
 pub enum PatchError {
-    #[error("Symbol '{0}' undefined.")]
-    UndefinedSymbol(String),
+    #[error("Symbol '{0}' undefined among {1} symbols.{2}")]
+    UndefinedSymbol(String, usize, String),
     #[error("Section Mismatch: Start/End symbol sections differ")]
     SectionMismatch(),
     #[error("Could not locate section '{0}'")]
     MissingSection(String),
     #[error("Virtual address {0} is unused by input XBE")]
     InvalidAddress(u32),
+    #[error(
+        "Virtual address {0:#x} is within the XBE header, which isn't part of any section. Set \
+        allow_header_patches = true in the config to patch header fields"
+    )]
+    HeaderAddress(u32),
+    #[error(
+        "Header bytes at {0:#x} are recomputed when the XBE is serialized, so patching them has no \
+        effect"
+    )]
+    DerivedHeaderField(u32),
+    #[error("Virtual address {0:#x} is not mapped by any section, it is {1}")]
+    UnmappedAddress(u32, NearestSection),
+    #[error(
+        "Range {0:#x}..{1:#x} extends past the end of section '{2}'. Patched bytes must lie within \
+        a single section"
+    )]
+    SpansSections(u32, u32, String),
+    #[error("Cannot patch into compressed section '{0}' at {1:#x}")]
+    CompressedSection(String, u32),
+    #[error("Code patch targets virtual address {0:#x} in non-executable section '{1}'")]
+    NonExecutableTarget(u32, String),
+    #[error("Patch is {0} bytes but only replaces {1} bytes")]
+    PatchTooLong(u32, u32),
+    #[error("Patch order references unknown patch site '{0}'")]
+    UnknownOrderName(String),
+    #[error("Expected bytes [{1}] at virtual address {0:#x} but found [{2}]")]
+    UnexpectedBytes(u32, String, String),
+    #[error(
+        "End symbol '{end_symbol}' at offset {end_va:#x} precedes start symbol '{start_symbol}' at \
+        offset {start_va:#x}"
+    )]
+    InvalidSymbolRange {
+        start_symbol: String,
+        start_va: u32,
+        end_symbol: String,
+        end_va: u32,
+    },
+    #[error(
+        "Symbol '{symbol}' at offset {offset:#x} is past the end of section '{section}' \
+        ({section_size:#x} bytes)"
+    )]
+    SymbolOutsideSection {
+        symbol: String,
+        offset: u32,
+        section: String,
+        section_size: u32,
+    },
+    #[error(
+        "Start symbol '{0}' is at offset {1:#x} of its section, so placing it at {2:#x} would put \
+        the section below address 0"
+    )]
+    SectionBelowZero(String, u32, u32),
+    #[error("Symbol '{0}' does not name its address, such as '{PATCH_SITE_PREFIX}00060A3E_start'")]
+    NoAddressInSymbol(String),
+    #[error("Symbol '{0}' is already defined at {1:#x}, so it can't be defined at {2:#x}")]
+    DuplicateSymbol(String, u32, u32),
+    #[error("{1} bytes at virtual address {0:#x} run past the end of the address space")]
+    AddressOverflow(u32, usize),
+}
+
+/// Determines the order patch sites are applied in. Sites named in `order` are applied first, in
+/// the order given, followed by all remaining sites in declaration order. Names are matched like
+/// symbol names, so a site named after a mangled start symbol can be ordered by its demangled
+/// name. Returns the index of each site's patch alongside the site.
+pub(crate) fn application_order<'a>(
+    patches: &'a [Patch],
+    order: &[String],
+) -> Result<Vec<(usize, &'a PatchSite)>> {
+    let mut sites: Vec<_> = patches
+        .iter()
+        .enumerate()
+        .flat_map(|(i, p)| p.sites.iter().map(move |s| (i, s)))
+        .collect();
+
+    let mut ordered = Vec::with_capacity(sites.len());
+    for name in order {
+        let site_name = demangle::resolve_name(name, sites.iter().map(|(_, s)| s.name()))?
+            .ok_or_else(|| PatchError::UnknownOrderName(name.clone()))?;
+        let pos = sites
+            .iter()
+            .position(|(_, s)| s.name() == site_name)
+            .expect("Resolved name of a remaining site");
+        ordered.push(sites.remove(pos));
+    }
+    ordered.append(&mut sites);
+
+    Ok(ordered)
+}
+
+/// Pairs of indices into `regions` whose address ranges overlap
+pub(crate) fn overlapping_regions(regions: &[Range<u32>]) -> Vec<(usize, usize)> {
+    regions
+        .iter()
+        .enumerate()
+        .tuple_combinations()
+        .filter(|((_, a), (_, b))| a.start < b.end && b.start < a.end)
+        .map(|((i, _), (j, _))| (i, j))
+        .collect()
+}
+
+/// Whether the patch sites in `order` can be built independently of each other, and so in
+/// parallel. This requires that no site overlaps another or any of the already `applied` regions,
+/// and that no patch refers to a symbol defined within a site. Sites whose regions can't be
+/// determined are never independent, leaving the error to be reported when they're applied.
+pub(crate) fn independent_sites(
+    patches: &[Patch],
+    order: &[(usize, &PatchSite)],
+    applied: &[Range<u32>],
+) -> bool {
+    let mut regions = applied.to_vec();
+    let mut site_symbols = Vec::new();
+    for &(i, site) in order {
+        let (Ok(region), Ok(symbols)) = (
+            patches[i]
+                .write_length(site)
+                .and_then(|length| byte_range(site.virtual_address, length as usize)),
+            patches[i].site_symbols(site),
+        ) else {
+            return false;
+        };
+        regions.push(region);
+        site_symbols.extend(symbols.into_iter().map(|(name, _)| name));
+    }
+
+    overlapping_regions(&regions).is_empty()
+        && !patches.iter().any(|p| {
+            p.patchfile
+                .undefined_symbol_names()
+                .into_iter()
+                .any(|name| site_symbols.iter().any(|s| s == name))
+        })
+}
+
+/// Where an unmapped address lies relative to the sections of an XBE
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressPosition {
+    BeforeSections,
+    BetweenSections,
+    AfterSections,
+}
+
+/// The section closest to an address that no section maps
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NearestSection {
+    pub name: String,
+    pub virtual_range: Range<u32>,
+    pub position: AddressPosition,
+    /// Whether the address precedes the section rather than following it
+    pub precedes: bool,
+    /// Bytes from the address to the start of the section, or from the end of the section to
+    /// the address
+    pub distance: u32,
+}
+
+impl Display for NearestSection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let position = match self.position {
+            AddressPosition::BeforeSections => "before all sections",
+            AddressPosition::BetweenSections => "between sections",
+            AddressPosition::AfterSections => "after all sections",
+        };
+        let relation = if self.precedes {
+            "before"
+        } else {
+            "past the end of"
+        };
+        write!(
+            f,
+            "{position}, {:#x} bytes {relation} section '{}' ({:#x}..{:#x})",
+            self.distance, self.name, self.virtual_range.start, self.virtual_range.end
+        )
+    }
+}
+
+/// The section of `xbe` closest to `address`, which must not be mapped by any section
+fn nearest_section(xbe: &Xbe, address: u32) -> Option<NearestSection> {
+    let distance = |range: &Range<u32>| {
+        if address < range.start {
+            range.start - address
+        } else {
+            address - range.end
+        }
+    };
+    let nearest = xbe
+        .sections
+        .iter()
+        .min_by_key(|s| distance(&s.virtual_range()))?;
+
+    let position = if xbe.sections.iter().all(|s| address < s.virtual_address) {
+        AddressPosition::BeforeSections
+    } else if xbe
+        .sections
+        .iter()
+        .all(|s| address >= s.virtual_range().end)
+    {
+        AddressPosition::AfterSections
+    } else {
+        AddressPosition::BetweenSections
+    };
+    Some(NearestSection {
+        name: strip_null(&nearest.name).to_string(),
+        virtual_range: nearest.virtual_range(),
+        position,
+        precedes: address < nearest.virtual_address,
+        distance: distance(&nearest.virtual_range()),
+    })
+}
+
+/// Error for a patch targeting `range` when it isn't mapped by a single section of `xbe`,
+/// distinguishing ranges starting within the XBE header, outside of every section, or running
+/// into another section
+pub(crate) fn unmapped_range(xbe: &Xbe, range: Range<u32>) -> PatchError {
+    let start = range.start;
+    if let Some(section) = xbe.section_containing(start) {
+        return PatchError::SpansSections(start, range.end, strip_null(&section.name).to_string());
+    }
+    match xbe.sections.iter().map(|s| s.virtual_address).min() {
+        Some(first) if (XBE_BASE_ADDRESS..first).contains(&start) => {
+            PatchError::HeaderAddress(start)
+        }
+        _ => match nearest_section(xbe, start) {
+            Some(nearest) => PatchError::UnmappedAddress(start, nearest),
+            None => PatchError::InvalidAddress(start),
+        },
+    }
+}
+
+/// Writes `bytes` over `xbe` at `virtual_address`, returning the bytes they replaced. Every write
+/// to the existing sections of an XBE goes through this, so patches, detours and packs are all
+/// checked the same way.
+///
+/// Writes overlapping any compressed section are refused, as their data is not what is loaded at
+/// their virtual addresses. Writes to the header are refused unless `header` allows them, and are
+/// otherwise made to its copy of the header until [`HeaderWrites::apply`] is called.
+pub(crate) fn overwrite(
+    xbe: &mut Xbe,
+    header: &mut HeaderWrites,
+    virtual_address: u32,
+    bytes: &[u8],
+) -> Result<Vec<u8>> {
+    let range = byte_range(virtual_address, bytes.len())?;
+    if let Some(section) = xbe.sections.iter().find(|s| {
+        let section_range = s.virtual_range();
+        s.is_compressed() && section_range.start < range.end && range.start < section_range.end
+    }) {
+        bail!(PatchError::CompressedSection(
+            strip_null(&section.name).to_string(),
+            virtual_address.max(section.virtual_address)
+        ));
+    }
+
+    if let Some(original) = xbe.write_virtual(virtual_address, bytes) {
+        return Ok(original);
+    }
+
+    if !header.allowed {
+        bail!(unmapped_range(xbe, range));
+    }
+    let image = header.image(xbe)?;
+    let Some(offsets) = header_offsets(image, range.clone()) else {
+        bail!(unmapped_range(xbe, range));
+    };
+    let original = image[offsets.clone()].to_vec();
+    image[offsets.clone()].copy_from_slice(bytes);
+    header.written.push(offsets);
+    Ok(original)
+}
+
+/// Writes to the header of an XBE, which isn't part of any section. They're made to one copy of
+/// the serialized XBE, which is only parsed again once every write is made, rather than
+/// serializing and parsing the XBE for each write.
+#[derive(Debug, Default)]
+pub(crate) struct HeaderWrites {
+    /// Whether writes to the header are allowed at all
+    allowed: bool,
+    /// The serialized XBE, once the header has been written to
+    image: Option<Vec<u8>>,
+    /// File offsets written, in order
+    written: Vec<Range<usize>>,
+}
+
+impl HeaderWrites {
+    /// Header writes to an XBE, refused unless `allowed`
+    pub(crate) fn new(allowed: bool) -> Self {
+        Self {
+            allowed,
+            ..Default::default()
+        }
+    }
+
+    /// The serialized `xbe`, serializing it on first use
+    fn image(&mut self, xbe: &Xbe) -> Result<&mut Vec<u8>> {
+        let image = match self.image.take() {
+            Some(image) => image,
+            None => header::serialize(xbe)?,
+        };
+        Ok(self.image.insert(image))
+    }
+
+    /// Reads `range` of `xbe`, including any writes to its header not yet applied
+    pub(crate) fn read<'a>(&'a self, xbe: &'a Xbe, range: Range<u32>) -> Result<Cow<'a, [u8]>> {
+        if let Some(bytes) = xbe.bytes_at(range.clone()) {
+            return Ok(Cow::Borrowed(bytes));
+        }
+        match &self.image {
+            Some(image) => match header_offsets(image, range.clone()) {
+                Some(offsets) => Ok(Cow::Borrowed(&image[offsets])),
+                None => bail!(unmapped_range(xbe, range)),
+            },
+            None => read(xbe, range),
+        }
+    }
+
+    /// Applies the header writes to `xbe`, which is serialized with them and parsed again. Fails
+    /// if any written bytes are recomputed by the xbe crate when serializing, leaving `xbe`
+    /// unchanged.
+    pub(crate) fn apply(self, xbe: &mut Xbe) -> Result<()> {
+        let Some(written) = self.image.filter(|_| !self.written.is_empty()) else {
+            return Ok(());
+        };
+
+        // sections may have been written to since the header was copied
+        let mut image = header::serialize(xbe)?;
+        for offsets in self.written.iter() {
+            image[offsets.clone()].copy_from_slice(&written[offsets.clone()]);
+        }
+        let patched = Xbe::new(&image)?;
+        let reserialized = header::serialize(&patched)?;
+        if let Some(offsets) = self
+            .written
+            .iter()
+            .find(|&offsets| reserialized.get(offsets.clone()) != written.get(offsets.clone()))
+        {
+            bail!(PatchError::DerivedHeaderField(
+                XBE_BASE_ADDRESS + offsets.start as u32
+            ));
+        }
+        *xbe = patched;
+        Ok(())
+    }
+}
+
+/// Virtual addresses occupied by `len` bytes starting at `virtual_address`
+pub(crate) fn byte_range(virtual_address: u32, len: usize) -> Result<Range<u32>> {
+    match u32::try_from(len)
+        .ok()
+        .and_then(|len| virtual_address.checked_add(len))
+    {
+        Some(end) => Ok(virtual_address..end),
+        None => bail!(PatchError::AddressOverflow(virtual_address, len)),
+    }
 }
 
+/// Reads `range` of `xbe`. The header isn't part of any section, so addresses within it are read
+/// from the serialized XBE.
+pub(crate) fn read(xbe: &Xbe, range: Range<u32>) -> Result<Cow<'_, [u8]>> {
+    if let Some(bytes) = xbe.bytes_at(range.clone()) {
+        return Ok(Cow::Borrowed(bytes));
+    }
+
+    let image = header::serialize(xbe)?;
+    match header_offsets(&image, range.clone()) {
+        Some(offsets) => Ok(Cow::Owned(image[offsets].to_vec())),
+        None => bail!(unmapped_range(xbe, range)),
+    }
+}
+
+/// File offsets of `range` within the serialized XBE `image`, if the range lies within the header.
+/// The header is loaded at the base address, up to its size at offset 0x108.
+fn header_offsets(image: &[u8], range: Range<u32>) -> Option<Range<usize>> {
+    let size_of_headers = u32::from_le_bytes(image.get(0x108..0x10C)?.try_into().ok()?);
+    let header = XBE_BASE_ADDRESS..XBE_BASE_ADDRESS.checked_add(size_of_headers)?;
+    if range.start < header.start || range.end > header.end {
+        return None;
+    }
+    Some((range.start - header.start) as usize..(range.end - header.start) as usize)
+}
+
+/// Verifies the XBE contains `expected` at `virtual_address`
+fn check_bytes(
+    xbe: &Xbe,
+    header: &HeaderWrites,
+    virtual_address: u32,
+    expected: &[u8],
+) -> Result<()> {
+    let actual = header.read(xbe, byte_range(virtual_address, expected.len())?)?;
+    if *actual != *expected {
+        bail!(PatchError::UnexpectedBytes(
+            virtual_address,
+            to_hex(expected),
+            to_hex(&actual)
+        ));
+    }
+    Ok(())
+}
+
+/// Reads the virtual address a start symbol named by convention is patched at, such as
+/// `0x60A3E` from `_patch_site_00060A3E_start`. The address is up to 8 hex digits, optionally
+/// followed by an underscore and any suffix.
+pub(crate) fn address_from_symbol_name(name: &str) -> Result<u32, PatchError> {
+    let error = || PatchError::NoAddressInSymbol(name.to_string());
+    let digits = name
+        .strip_prefix(PATCH_SITE_PREFIX)
+        .and_then(|rest| rest.split('_').next())
+        .filter(|digits| (1..=8).contains(&digits.len()))
+        .ok_or_else(error)?;
+    if !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(error());
+    }
+    u32::from_str_radix(digits, 16).map_err(|_| error())
+}
+
+/// Formats `bytes` as space separated hex, such as `8B 44 24 08`
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02X}")).join(" ")
+}
+
+/// An object file whose code is written over the XBE at one or more sites
 #[derive(Debug)]
-pub(crate) struct Patch {
-    pub(crate) patchfile: ObjectFile,
-    pub(crate) start_symbol_name: String,
-    pub(crate) end_symbol_name: String,
-    pub(crate) virtual_address: u32,
+pub struct Patch {
+    pub patchfile: ObjectFile,
+    pub sites: Vec<PatchSite>,
 }
 
-impl Patch {
+/// A region of a patchfile, delimited by a start and end symbol, that is written to the XBE
+#[derive(Debug, Clone)]
+pub struct PatchSite {
+    /// Name used to refer to this site in the patch order and report, defaults to the start symbol
+    pub(crate) name: Option<String>,
+    pub start_symbol_name: String,
+    pub end_symbol_name: String,
+    /// Address the site is written to. For sites located by signature this is only known once
+    /// the signature is resolved during injection.
+    pub virtual_address: u32,
+    /// Pattern locating this site in the XBE, replacing `virtual_address` once resolved
+    pub(crate) signature: Option<Signature>,
+    /// Distance from the start of the signature match to the site
+    pub(crate) signature_offset: i32,
+    /// Whether the signature must be unique or the site is applied at every occurrence
+    pub(crate) signature_match: SignatureMatch,
+    /// Maximum number of occurrences allowed when matching every occurrence of the signature
+    pub(crate) max_matches: Option<usize>,
+    /// Skip this site with a warning, rather than overwriting, if it overlaps an earlier patch
+    pub(crate) skip_overlapping: bool,
+    /// Name of the site this one was split from when matching every occurrence of a signature
+    pub(crate) matched_from: Option<String>,
+    /// Length of the original code/data being replaced by this patch, if known
+    pub(crate) replaces_length: Option<u32>,
+    /// Fill the remainder of the replaced region with NOPs when the patch is shorter than it
+    pub(crate) nop_pad: bool,
+    /// Bytes that must be present at the virtual address before patching
+    pub(crate) expected_bytes: Option<Vec<u8>>,
+}
+
+impl PatchSite {
     pub(crate) fn new(
-        path: PathBuf,
         start_symbol_name: String,
         end_symbol_name: String,
         virtual_address: u32,
-    ) -> Result<Self> {
-        let patchfile = ObjectFile::new(path)?;
-        Ok(Self {
-            patchfile,
+    ) -> Self {
+        Self {
+            name: None,
             start_symbol_name,
             end_symbol_name,
             virtual_address,
-        })
+            signature: None,
+            signature_offset: 0,
+            signature_match: SignatureMatch::One,
+            max_matches: None,
+            skip_overlapping: false,
+            matched_from: None,
+            replaces_length: None,
+            nop_pad: true,
+            expected_bytes: None,
+        }
     }
 
-    pub(crate) fn apply(&self, xbe: &mut Xbe, symbol_table: &SymbolTable) -> Result<()> {
-        // find patch symbols
-        let start_symbol = self.find_symbol(self.start_symbol_name.as_str())?;
-        let end_symbol = self.find_symbol(self.end_symbol_name.as_str())?;
-        if start_symbol.section_number != end_symbol.section_number {
-            bail!(PatchError::SectionMismatch(),);
+    /// Name used to refer to this site in the patch order and report
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.start_symbol_name)
+    }
+
+    /// Scans `xbe` for this site's signature, if it has one, and sets the virtual address to the
+    /// location it was found at. Sites matching every occurrence of their signature are split into
+    /// one site per occurrence, named after the address they are applied at.
+    pub(crate) fn resolve_signature(mut self, xbe: &Xbe) -> Result<Vec<PatchSite>> {
+        let signature = match self.signature.take() {
+            Some(signature) => signature,
+            None => return Ok(vec![self]),
+        };
+
+        match self.signature_match {
+            SignatureMatch::One => {
+                let found = signature.resolve(xbe)?;
+                self.virtual_address = found.wrapping_add(self.signature_offset as u32);
+                info!(
+                    "Resolved patch site '{}' to virtual address {:#x}",
+                    self.name(),
+                    self.virtual_address
+                );
+                Ok(vec![self])
+            }
+            SignatureMatch::All => {
+                let found = signature.resolve_all(xbe, self.max_matches)?;
+                info!(
+                    "Resolved patch site '{}' to {} virtual addresses",
+                    self.name(),
+                    found.len()
+                );
+                Ok(found
+                    .into_iter()
+                    .map(|address| {
+                        let virtual_address = address.wrapping_add(self.signature_offset as u32);
+                        let mut site = self.clone();
+                        site.name = Some(format!("{}@{virtual_address:#x}", self.name()));
+                        site.virtual_address = virtual_address;
+                        site.skip_overlapping = true;
+                        site.matched_from = Some(self.name().to_string());
+                        site
+                    })
+                    .collect())
+            }
+        }
+    }
+
+    /// Number of bytes of the XBE this site overwrites when its code is `patch_len` bytes long
+    fn write_length(&self, patch_len: u32) -> Result<u32> {
+        match self.replaces_length {
+            Some(len) if patch_len > len => bail!(PatchError::PatchTooLong(patch_len, len)),
+            Some(len) if self.nop_pad => Ok(len),
+            _ => Ok(patch_len),
+        }
+    }
+
+    /// Writes `bytes` over the XBE at this site, returning the bytes they replaced
+    pub(crate) fn write(
+        &self,
+        xbe: &mut Xbe,
+        header: &mut HeaderWrites,
+        bytes: &[u8],
+    ) -> Result<Vec<u8>> {
+        overwrite(xbe, header, self.virtual_address, bytes)
+    }
+
+    /// Verifies the XBE contains the expected bytes at this site, confirming the patch is being
+    /// applied to the intended game version.
+    fn check_expected_bytes(&self, xbe: &Xbe, header: &HeaderWrites) -> Result<()> {
+        match &self.expected_bytes {
+            Some(expected) => check_bytes(xbe, header, self.virtual_address, expected),
+            None => Ok(()),
+        }
+    }
+
+    /// Compares the flags of the XBE section containing the target address against the kind of
+    /// data this patch writes. Code written into a non-executable section is almost certainly a
+    /// mistake in the configured address.
+    fn check_target_flags(&self, xbe: &Xbe, sec_name: &str, deny_warnings: bool) -> Result<()> {
+        let section = match xbe.section_containing(self.virtual_address) {
+            Some(s) => s,
+            // Unmapped addresses are reported when the patch bytes are written
+            None => return Ok(()),
+        };
+        let target_name = strip_null(&section.name);
+
+        let executable = section.flags.contains(xbe::SectionFlags::EXECUTABLE);
+        if strip_null(sec_name) == ".text" && !executable {
+            if deny_warnings {
+                bail!(PatchError::NonExecutableTarget(
+                    self.virtual_address,
+                    target_name.to_string()
+                ));
+            }
+            warn!(
+                "Patch '{}' writes code to virtual address {:#x} in non-executable section '{}'. \
+                Is the virtual address correct?",
+                self.start_symbol_name, self.virtual_address, target_name
+            );
+        } else if executable && !section.flags.contains(xbe::SectionFlags::WRITABLE) {
+            debug!(
+                "Patch '{}' writes to read-only executable section '{}'.",
+                self.start_symbol_name, target_name
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Bytes written directly to the XBE, for patches too trivial to warrant an object file
+#[derive(Debug)]
+pub(crate) struct RawPatch {
+    pub(crate) virtual_address: u32,
+    pub(crate) bytes: Vec<u8>,
+    /// Bytes that must be present at the virtual address before patching
+    pub(crate) expected_bytes: Option<Vec<u8>>,
+}
+
+impl RawPatch {
+    /// Writes the patch bytes into the XBE, returning the bytes they replaced
+    pub(crate) fn apply(&self, xbe: &mut Xbe, header: &mut HeaderWrites) -> Result<Vec<u8>> {
+        if let Some(expected) = &self.expected_bytes {
+            check_bytes(xbe, header, self.virtual_address, expected)?;
         }
 
-        let sec_name = self
+        overwrite(xbe, header, self.virtual_address, &self.bytes)
+    }
+}
+
+impl Display for Patch {
+    /// The patchfile's name followed by the symbols and address of each site
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let file = self
+            .patchfile
+            .path
+            .file_name()
+            .unwrap_or(self.patchfile.path.as_os_str());
+        let sites = self
+            .sites
+            .iter()
+            .map(|site| {
+                format!(
+                    "{}..{} at {:#x}",
+                    site.start_symbol_name, site.end_symbol_name, site.virtual_address
+                )
+            })
+            .join(", ");
+        write!(f, "{}: {sites}", file.to_string_lossy())
+    }
+}
+
+impl Patch {
+    pub(crate) fn new(path: PathBuf, sites: Vec<PatchSite>) -> Result<Self> {
+        let patchfile = ObjectFile::new(path)?;
+        Ok(Self { patchfile, sites })
+    }
+
+    /// Replaces the start and end symbol names of each site with the MSVC mangled symbol they
+    /// refer to, when they only match a symbol once demangled.
+    pub(crate) fn resolve_symbol_names(&mut self) -> Result<()> {
+        let defined = self.patchfile.defined_symbol_names();
+
+        for site in self.sites.iter_mut() {
+            for name in [&mut site.start_symbol_name, &mut site.end_symbol_name] {
+                if let Some(mangled) =
+                    demangle::resolve_name(name.as_str(), defined.iter().copied())?
+                {
+                    if mangled != name.as_str() {
+                        info!("Resolved symbol '{name}' to '{mangled}'");
+                        *name = mangled.to_string();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the start and end symbols of `site` delimit a region of a single section of the
+    /// patchfile, returning its length in bytes.
+    pub(crate) fn site_length(&self, site: &PatchSite) -> Result<u32> {
+        let (_, region) = self.site_region(site)?;
+        Ok(region.end - region.start)
+    }
+
+    /// Finds the start and end symbols of `site`, checking they delimit a region of a single
+    /// section of the patchfile. Returns the name of that section and the region's offsets within
+    /// it.
+    fn site_region(&self, site: &PatchSite) -> Result<(&str, Range<u32>)> {
+        let defined = self.patchfile.defined_symbol_names();
+        let find = |name: &str| -> Result<Symbol> {
+            let name = demangle::resolve_name(name, defined.iter().copied())?.unwrap_or(name);
+            self.find_symbol(name)
+        };
+
+        let start_symbol = find(&site.start_symbol_name)?;
+        let end_symbol = find(&site.end_symbol_name)?;
+        if start_symbol.section_number != end_symbol.section_number {
+            bail!(PatchError::SectionMismatch());
+        }
+        if start_symbol.section_number < 1 {
+            bail!(self.undefined_symbol(&site.start_symbol_name));
+        }
+        let section = self
             .patchfile
             .coff()
             .sections
             .get(start_symbol.section_number as usize - 1)
-            .unwrap()
-            .name()?;
+            .ok_or_else(|| {
+                RelocationError::MissingSection(
+                    self.patchfile.path.clone(),
+                    start_symbol.section_number,
+                )
+            })?;
+
+        if end_symbol.value < start_symbol.value {
+            bail!(PatchError::InvalidSymbolRange {
+                start_symbol: site.start_symbol_name.clone(),
+                start_va: start_symbol.value,
+                end_symbol: site.end_symbol_name.clone(),
+                end_va: end_symbol.value,
+            });
+        }
+        if end_symbol.value > section.size_of_raw_data {
+            bail!(PatchError::SymbolOutsideSection {
+                symbol: site.end_symbol_name.clone(),
+                offset: end_symbol.value,
+                section: strip_null(section.name()?).to_string(),
+                section_size: section.size_of_raw_data,
+            });
+        }
+        Ok((section.name()?, start_symbol.value..end_symbol.value))
+    }
+
+    /// Combines the sections of the patchfile. Each site relocates its own copy of these, since
+    /// relocations depend on where the site is placed.
+    pub(crate) fn section_map(&self) -> Result<SectionMap<'_>> {
+        SectionMap::from_data(std::slice::from_ref(&self.patchfile))
+    }
+
+    /// Relocates `site` using a fresh copy of this patch's `section_map` after checking it against
+    /// the XBE, returning the bytes to write at the site including any NOP padding. This doesn't
+    /// modify the XBE, so independent sites can be built in parallel.
+    pub(crate) fn build_site(
+        &self,
+        site: &PatchSite,
+        mut section_map: SectionMap<'_>,
+        xbe: &Xbe,
+        header: &HeaderWrites,
+        symbol_table: &SymbolTable,
+        deny_warnings: bool,
+    ) -> Result<Vec<u8>> {
+        // find patch symbols
+        let (sec_name, region) = self.site_region(site)?;
+
+        // Place the section such that the start symbol lands on the site's virtual address
+        let section_address = site
+            .virtual_address
+            .checked_sub(region.start)
+            .ok_or_else(|| {
+                PatchError::SectionBelowZero(
+                    site.start_symbol_name.clone(),
+                    region.start,
+                    site.virtual_address,
+                )
+            })?;
+
+        site.check_target_flags(xbe, sec_name, deny_warnings)?;
+        site.check_expected_bytes(xbe, header)?;
 
-        // Process Patch Coff (symbols have already been read)
-        let mut section_map = SectionMap::from_data(std::slice::from_ref(&self.patchfile));
         section_map
             .get_mut(sec_name)
             .ok_or_else(|| PatchError::MissingSection(sec_name.to_string()))?
-            .virtual_address = self.virtual_address;
+            .virtual_address = section_address;
 
         section_map.process_relocations(symbol_table, std::slice::from_ref(&self.patchfile))?;
 
-        let xbe_bytes = xbe
-            .get_bytes_mut(self.virtual_address..self.virtual_address + 5)
-            .ok_or(PatchError::InvalidAddress(self.virtual_address))?;
-
-        let patch_bytes = &section_map
+        let patch_bytes = section_map
             .get(sec_name)
             .ok_or_else(|| PatchError::MissingSection(sec_name.to_string()))?
-            .bytes[start_symbol.value as usize..end_symbol.value as usize];
+            .bytes
+            .get(region.start as usize..region.end as usize)
+            .ok_or_else(|| PatchError::MissingSection(sec_name.to_string()))?;
 
-        let mut c = Cursor::new(xbe_bytes);
-        c.write_all(patch_bytes).expect("Failed to apply patch");
+        let mut bytes = patch_bytes.to_vec();
+        bytes.resize(site.write_length(bytes.len() as u32)? as usize, NOP);
+        Ok(bytes)
+    }
 
-        Ok(())
+    /// Number of bytes of the XBE `site` overwrites, including any NOP padding
+    pub(crate) fn write_length(&self, site: &PatchSite) -> Result<u32> {
+        site.write_length(self.site_length(site)?)
+    }
+
+    /// External symbols defined within `site`, at the virtual addresses they occupy once the site
+    /// is applied. These are added to the symbol table so later patches can refer to them.
+    pub(crate) fn site_symbols(&self, site: &PatchSite) -> Result<Vec<(String, u32)>> {
+        let start_symbol = self.find_symbol(site.start_symbol_name.as_str())?;
+        let end_symbol = self.find_symbol(site.end_symbol_name.as_str())?;
+
+        self.patchfile
+            .named_symbols()
+            .filter(|(_, sym)| sym.storage_class == IMAGE_SYM_CLASS_EXTERNAL)
+            .filter(|(_, sym)| sym.section_number == start_symbol.section_number)
+            .filter(|(_, sym)| (start_symbol.value..end_symbol.value).contains(&sym.value))
+            .map(|(name, sym)| -> Result<(String, u32)> {
+                let offset = (sym.value - start_symbol.value) as usize;
+                let range = byte_range(site.virtual_address, offset)?;
+                Ok((name.to_string(), range.end))
+            })
+            .collect()
     }
 
     fn find_symbol(&self, name: &str) -> Result<Symbol> {
@@ -94,7 +833,418 @@ impl Patch {
                     == name
             })
             .map(|(_, _, sym)| sym)
-            .ok_or_else(|| PatchError::UndefinedSymbol(name.to_string()))?;
+            .ok_or_else(|| self.undefined_symbol(name))?;
         Ok(sym)
     }
+
+    /// Error for `name` missing from the patchfile, suggesting similar names it does define
+    fn undefined_symbol(&self, name: &str) -> PatchError {
+        PatchError::UndefinedSymbol(
+            name.to_string(),
+            self.patchfile.symbol_count(),
+            suggest_names(name, self.patchfile.defined_symbol_names()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::section::{RawLayout, COMPRESSED_FLAG};
+
+    #[test]
+    fn hex_format() {
+        assert_eq!(to_hex(&[]), "");
+        assert_eq!(to_hex(&[0x8B, 0x44, 0x24, 0x08, 0x09]), "8B 44 24 08 09");
+    }
+
+    #[test]
+    fn display() -> Result<()> {
+        let patch = Patch::new(
+            PathBuf::from("test/bin/framehook_patch.o"),
+            vec![PatchSite::new(
+                "_framehook_patch".to_string(),
+                "_framehook_patch_end".to_string(),
+                396158,
+            )],
+        )?;
+        assert_eq!(
+            patch.to_string(),
+            "framehook_patch.o: _framehook_patch.._framehook_patch_end at 0x60b7e"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn symbol_ranges() -> Result<()> {
+        let patch = Patch::new(PathBuf::from("test/bin/bad_ranges.o"), Vec::new())?;
+        let length = |start: &str, end: &str| {
+            patch.site_length(&PatchSite::new(start.to_string(), end.to_string(), 0))
+        };
+
+        let err = length("_reversed", "_reversed_end").expect_err("End precedes start");
+        assert!(matches!(
+            err.downcast_ref::<PatchError>(),
+            Some(PatchError::InvalidSymbolRange {
+                start_va: 1,
+                end_va: 0,
+                ..
+            })
+        ));
+
+        let err = length("_reversed", "_data_label").expect_err("Symbols in different sections");
+        assert!(matches!(
+            err.downcast_ref::<PatchError>(),
+            Some(PatchError::SectionMismatch())
+        ));
+
+        let err = length("_reversed", "_far_end").expect_err("End past the section");
+        assert!(matches!(
+            err.downcast_ref::<PatchError>(),
+            Some(PatchError::SymbolOutsideSection {
+                offset: 0x101,
+                section_size: 3,
+                ..
+            })
+        ));
+
+        assert_eq!(length("_reversed_end", "_reversed")?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn unmapped_addresses() -> Result<()> {
+        let mut xbe = Xbe::new(&std::fs::read("test/bin/default.xbe")?)?;
+        let sections: Vec<_> = xbe
+            .sections
+            .iter()
+            .map(|s| (strip_null(&s.name).to_string(), s.virtual_range()))
+            .sorted_by_key(|(_, range)| range.start)
+            .collect();
+        let write = |xbe: &mut Xbe, virtual_address| {
+            PatchSite::new("_a".to_string(), "_b".to_string(), virtual_address)
+                .write(xbe, &mut HeaderWrites::default(), &[NOP; 4])
+                .expect_err("Wrote to an unmapped address")
+        };
+
+        let (last, range) = sections.last().expect("XBE has sections");
+        let err = write(&mut xbe, range.end + 0x10);
+        match err.downcast_ref::<PatchError>() {
+            Some(PatchError::UnmappedAddress(_, nearest)) => {
+                assert_eq!(nearest.name, *last);
+                assert_eq!(nearest.position, AddressPosition::AfterSections);
+                assert_eq!(nearest.distance, 0x10);
+            }
+            _ => panic!("Expected an unmapped address, found {err}"),
+        }
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Virtual address {:#x} is not mapped by any section, it is after all sections, \
+                0x10 bytes past the end of section '{last}' ({:#x}..{:#x})",
+                range.end + 0x10,
+                range.start,
+                range.end
+            )
+        );
+
+        let (first, range) = &sections[0];
+        let err = write(&mut xbe, 0x100);
+        assert!(err.to_string().contains(&format!(
+            "before all sections, {:#x} bytes before section '{first}'",
+            range.start - 0x100
+        )));
+
+        let err = write(&mut xbe, range.end - 2);
+        assert!(err
+            .to_string()
+            .contains(&format!("extends past the end of section '{first}'")));
+
+        // Leave a gap after the last section by adding one beyond it
+        let last_end = sections.last().expect("XBE has sections").1.end;
+        xbe.add_section(
+            ".gap\0".to_string(),
+            xbe::SectionFlags::PRELOAD,
+            vec![0; 4],
+            last_end + 0x1000,
+            4,
+        );
+        let err = write(&mut xbe, last_end + 0x10);
+        assert!(matches!(
+            err.downcast_ref::<PatchError>(),
+            Some(PatchError::UnmappedAddress(_, nearest))
+                if nearest.position == AddressPosition::BetweenSections
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn buffered_header_writes() -> Result<()> {
+        let mut xbe = Xbe::new(&std::fs::read("test/bin/default.xbe")?)?;
+        let timestamp = XBE_BASE_ADDRESS + 0x114;
+
+        let mut header = HeaderWrites::new(true);
+        let original = overwrite(&mut xbe, &mut header, timestamp, &[0x78, 0x56])?;
+        overwrite(&mut xbe, &mut header, timestamp + 2, &[0x34, 0x12])?;
+
+        // Writes are read back from the copy, but only reach the XBE once applied
+        assert_eq!(
+            *header.read(&xbe, timestamp..timestamp + 4)?,
+            [0x78, 0x56, 0x34, 0x12]
+        );
+        assert_eq!(*read(&xbe, timestamp..timestamp + 2)?, original[..]);
+        header.apply(&mut xbe)?;
+        assert_eq!(
+            *read(&xbe, timestamp..timestamp + 4)?,
+            [0x78, 0x56, 0x34, 0x12]
+        );
+
+        // The image size is recomputed when serializing, so the write is refused
+        let size_of_image = XBE_BASE_ADDRESS + 0x10C;
+        let mut header = HeaderWrites::new(true);
+        overwrite(&mut xbe, &mut header, size_of_image, &[0xFF])?;
+        let err = header
+            .apply(&mut xbe)
+            .expect_err("Patched a derived header field");
+        assert_eq!(
+            err.downcast_ref::<PatchError>(),
+            Some(&PatchError::DerivedHeaderField(size_of_image))
+        );
+        assert_eq!(
+            *read(&xbe, timestamp..timestamp + 4)?,
+            [0x78, 0x56, 0x34, 0x12]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn section_boundaries() -> Result<()> {
+        let mut xbe = Xbe::new(&std::fs::read("test/bin/default.xbe")?)?;
+        let range = xbe
+            .sections
+            .iter()
+            .find(|s| !s.data.is_empty() && s.data.len() == s.virtual_size as usize)
+            .expect("XBE has a section without uninitialized data")
+            .virtual_range();
+
+        // Starting at the section's start and ending at its end are both within it
+        for start in [range.start, range.end - 4] {
+            assert_eq!(read(&xbe, start..start + 4)?.len(), 4);
+            let original = overwrite(&mut xbe, &mut HeaderWrites::default(), start, &[NOP; 4])?;
+            assert_eq!(*read(&xbe, start..start + 4)?, [NOP; 4]);
+            overwrite(&mut xbe, &mut HeaderWrites::default(), start, &original)?;
+        }
+
+        // One byte before the start or past the end leaves the section
+        for start in [range.start - 1, range.end - 3] {
+            assert!(read(&xbe, start..start + 4).is_err(), "{start:#x}");
+            assert!(
+                overwrite(&mut xbe, &mut HeaderWrites::default(), start, &[NOP; 4]).is_err(),
+                "{start:#x}"
+            );
+        }
+
+        let err = overwrite(
+            &mut xbe,
+            &mut HeaderWrites::default(),
+            u32::MAX - 1,
+            &[NOP; 4],
+        )
+        .expect_err("Wrote past the end of the address space");
+        assert_eq!(
+            err.downcast_ref::<PatchError>(),
+            Some(&PatchError::AddressOverflow(u32::MAX - 1, 4))
+        );
+        Ok(())
+    }
+
+    /// The test XBE with the compressed flag set in the raw header of the section containing
+    /// `virtual_address`, as a loader that compresses sections would leave it
+    fn compressed_xbe(virtual_address: u32) -> Result<Xbe> {
+        let mut bytes = Xbe::new(&std::fs::read("test/bin/default.xbe")?)?.serialize()?;
+        let header = RawLayout::parse(&bytes)?
+            .sections
+            .into_iter()
+            .find(|s| {
+                (s.virtual_address..s.virtual_address + s.virtual_size).contains(&virtual_address)
+            })
+            .expect("Address is mapped");
+        let flags = header.flags | COMPRESSED_FLAG;
+        bytes[header.offset..header.offset + 4].copy_from_slice(&flags.to_le_bytes());
+
+        Ok(Xbe::new(&bytes)?)
+    }
+
+    #[test]
+    fn compressed_section() -> Result<()> {
+        let mut xbe = compressed_xbe(396158)?;
+        let section = xbe
+            .section_containing(396158)
+            .expect("Patch address is mapped");
+        assert!(section.is_compressed(), "Compressed flag lost when parsing");
+        let name = strip_null(&section.name).to_string();
+        let start = section.virtual_address;
+
+        let err = PatchSite::new("_a".to_string(), "_b".to_string(), 396158)
+            .write(&mut xbe, &mut HeaderWrites::default(), &[NOP; 4])
+            .expect_err("Patched a compressed section");
+        assert_eq!(
+            err.to_string(),
+            format!("Cannot patch into compressed section '{name}' at 0x60b7e")
+        );
+
+        // A write that only ends within the section is refused too
+        let err = overwrite(&mut xbe, &mut HeaderWrites::default(), start - 2, &[NOP; 4])
+            .expect_err("Wrote into compressed data");
+        assert_eq!(
+            err.downcast_ref::<PatchError>(),
+            Some(&PatchError::CompressedSection(name, start))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn symbol_name_addresses() {
+        assert_eq!(
+            address_from_symbol_name("_patch_site_00060A3E_start"),
+            Ok(0x60A3E)
+        );
+        assert_eq!(address_from_symbol_name("_patch_site_60a3e"), Ok(0x60A3E));
+        for name in [
+            "_framehook_patch",
+            "_patch_site_",
+            "_patch_site__start",
+            "_patch_site_0006XA3E_start",
+            "_patch_site_+60A3E",
+            "_patch_site_000060A3E_start",
+            "patch_site_00060A3E_start",
+        ] {
+            assert_eq!(
+                address_from_symbol_name(name),
+                Err(PatchError::NoAddressInSymbol(name.to_string())),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn overlaps() {
+        assert_eq!(
+            overlapping_regions(&[0..5, 5..10, 8..9, 20..21]),
+            vec![(1, 2)]
+        );
+        assert!(overlapping_regions(&[0..1, 2..3]).is_empty());
+    }
+
+    #[test]
+    fn order_demangled_name() -> Result<()> {
+        let mut patches = [
+            Patch::new(
+                PathBuf::from("test/bin/framehook_patch.o"),
+                vec![PatchSite::new(
+                    "_framehook_patch".to_string(),
+                    "_framehook_patch_end".to_string(),
+                    396158,
+                )],
+            )?,
+            Patch::new(
+                PathBuf::from("test/bin/mangled.o"),
+                vec![PatchSite::new(
+                    "hook".to_string(),
+                    "_hook_end".to_string(),
+                    396200,
+                )],
+            )?,
+        ];
+        for patch in patches.iter_mut() {
+            patch.resolve_symbol_names()?;
+        }
+
+        let order = application_order(&patches, &["hook".to_string()])?;
+        assert_eq!(order[0].1.name(), "?hook@@YAXXZ");
+        assert!(matches!(
+            application_order(&patches, &["unhook".to_string()])
+                .unwrap_err()
+                .downcast_ref::<PatchError>(),
+            Some(PatchError::UnknownOrderName(name)) if name == "unhook"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn independence() -> Result<()> {
+        let framehook = |addresses: &[u32]| {
+            Patch::new(
+                PathBuf::from("test/bin/framehook_patch.o"),
+                addresses
+                    .iter()
+                    .map(|&va| {
+                        PatchSite::new(
+                            "_framehook_patch".to_string(),
+                            "_framehook_patch_end".to_string(),
+                            va,
+                        )
+                    })
+                    .collect(),
+            )
+        };
+        let independent = |patches: &[Patch], applied: &[Range<u32>]| -> Result<bool> {
+            let order = application_order(patches, &[])?;
+            Ok(independent_sites(patches, &order, applied))
+        };
+
+        let patches = [framehook(&[396158, 396163])?];
+        assert!(independent(&patches, &[])?);
+        // overlapping a detour
+        assert!(!independent(&patches, &[396160..396165])?);
+
+        let patches = [framehook(&[396158, 396160])?];
+        assert!(!independent(&patches, &[])?);
+
+        // chain_b calls a symbol defined within chain_a's site
+        let patches = [
+            Patch::new(
+                PathBuf::from("test/bin/chain_a.o"),
+                vec![PatchSite::new(
+                    "_dispatcher_patch".to_string(),
+                    "_dispatcher_patch_end".to_string(),
+                    396158,
+                )],
+            )?,
+            Patch::new(
+                PathBuf::from("test/bin/chain_b.o"),
+                vec![PatchSite::new(
+                    "_call_patch".to_string(),
+                    "_call_patch_end".to_string(),
+                    396200,
+                )],
+            )?,
+        ];
+        assert!(!independent(&patches, &[])?);
+        Ok(())
+    }
+
+    #[test]
+    fn site_at_address_space_end() -> Result<()> {
+        let patches = [Patch::new(
+            PathBuf::from("test/bin/chain_a.o"),
+            vec![PatchSite::new(
+                "_dispatcher_patch".to_string(),
+                "_dispatcher_patch_end".to_string(),
+                u32::MAX,
+            )],
+        )?];
+        let order = application_order(&patches, &[])?;
+
+        // _dispatcher is defined one byte into the site, past the end of the address space
+        let err = patches[0]
+            .site_symbols(order[0].1)
+            .expect_err("Placed a symbol past the end of the address space");
+        assert_eq!(
+            err.downcast_ref::<PatchError>(),
+            Some(&PatchError::AddressOverflow(u32::MAX, 1))
+        );
+        assert!(!independent_sites(&patches, &order, &[]));
+        Ok(())
+    }
 }
@@ -1,15 +1,22 @@
-use crate::{obj::ObjectFile, Configuration};
+use crate::{
+    error::{InjectStep, RestoreError},
+    obj::ObjectFile,
+    patch::PatchError,
+    section::{check_section_name, SectionExt, XbeExt},
+    Configuration,
+};
 use anyhow::{bail, Context, Result};
 use byteorder::{ReadBytesExt, WriteBytesExt, LE};
 use goblin::pe;
 use itertools::Itertools;
 use log::{info, warn};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    fmt::Display,
     io::Cursor,
     iter::IntoIterator,
-    ops::{Deref, DerefMut},
-    path::Path,
+    ops::{Deref, DerefMut, Range},
+    path::{Path, PathBuf},
 };
 use thiserror::Error;
 
@@ -21,15 +28,145 @@ pub enum RelocationError {
     SymbolIndex(u32),
     #[error("Could not find the virtual address of symbol '{0}'.")]
     SymbolAddress(String),
+    #[error("Data of section '{0}' changed after relocations were processed")]
+    ChecksumMismatch(String),
+    #[error("Section '{1}' of '{0:?}' ends at offset {2:#x}, but the file is only {3:#x} bytes")]
+    TruncatedSection(PathBuf, String, usize, usize),
+    #[error("No section with number {1} in '{0:?}'")]
+    MissingSection(PathBuf, i16),
+    #[error("Relocation at offset {offset:#x} is outside of section '{section}' ({section_size:#x} bytes)")]
+    OutOfBoundsWrite {
+        section: String,
+        offset: u64,
+        section_size: usize,
+    },
+    #[error("Unrecognized storage class {0}")]
+    UnsupportedStorageClass(u8),
+    #[error("Virtual size {1:#x} of section '{0}' is smaller than its contents ({2:#x} bytes)")]
+    VirtualSizeTooSmall(String, u32, u32),
+    #[error("Data section '{0}' at {1:#x} overlaps section '{2}'")]
+    OverlappingSection(String, u32, String),
+    #[error("Data section '{0}' at {1:#x} is {2:#x} bytes, which extends past the address space")]
+    AddressOverflow(String, u32, usize),
+    #[error("XBE already has a section named '{0}'")]
+    DuplicateSection(String),
+    #[error("File '{0:?}' contributes to section '{1}' of both merged section maps")]
+    DuplicateFile(PathBuf, String),
+    #[error("Can't merge into section '{0}' after space has been reserved at its end")]
+    ReservedSpace(String),
+}
+
+/// Strips any trailing null terminators from a section name
+pub(crate) fn strip_null(s: &str) -> &str {
+    s.trim_end_matches('\0')
+}
+
+/// Shortest run of read-only data that is merged with an identical one
+const MIN_DEDUPLICATED_LEN: usize = 8;
+
+/// Maximum edit distance for a symbol name to be suggested in place of a missing one
+const MAX_SUGGESTION_DISTANCE: usize = 2;
+
+/// Formats up to three of `candidates` that closely resemble the missing symbol `name` as a hint
+/// for an error message, or an empty string if none do. Names differing only by case or leading
+/// underscores are the closest matches.
+pub(crate) fn suggest_names<'a>(
+    name: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> String {
+    let normalize = |s: &str| s.trim_start_matches('_').to_lowercase();
+    let target = normalize(name);
+
+    let suggestions = candidates
+        .into_iter()
+        .filter(|candidate| *candidate != name)
+        .map(|candidate| {
+            let normalized = normalize(candidate);
+            let distance = if normalized == target {
+                0
+            } else {
+                edit_distance(&normalized, &target).max(1)
+            };
+            (distance, candidate)
+        })
+        .filter(|(distance, _)| *distance <= MAX_SUGGESTION_DISTANCE)
+        .sorted()
+        .dedup()
+        .take(3)
+        .map(|(_, candidate)| format!("'{candidate}'"))
+        .join(", ");
+
+    if suggestions.is_empty() {
+        suggestions
+    } else {
+        format!(" Did you mean {suggestions}?")
+    }
+}
+
+/// Levenshtein distance between `a` and `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Names of the combined sections injected into the XBE
+pub(crate) const COMBINED_SECTION_NAMES: [&str; 4] = [".mtext", ".mdata", ".mbss", ".mrdata"];
+
+/// Maps the name of a COFF section to the name of the combined section it is injected into
+fn combined_section_name(name: &str) -> Option<&'static str> {
+    match strip_null(name) {
+        ".text" => Some(".mtext"),
+        ".data" => Some(".mdata"),
+        ".bss" => Some(".mbss"),
+        ".rdata" => Some(".mrdata"),
+        _ => None,
+    }
+}
+
+/// Whether `sym` is a COFF common symbol, an uninitialized global with its size as its value
+fn is_common_symbol(sym: &pe::symbol::Symbol) -> bool {
+    sym.section_number == 0
+        && sym.storage_class == pe::symbol::IMAGE_SYM_CLASS_EXTERNAL
+        && sym.value > 0
+}
+
+/// Computes the CRC-32 (IEEE) checksum of `bytes`
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, &b| {
+        (0..8).fold(crc ^ b as u32, |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
 }
 
 // TODO: Restructure things to avoid this needing to be exposed for patch
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct SectionBuilder<'a> {
     name: String,
     pub(crate) bytes: Vec<u8>,
     file_offset_start: HashMap<&'a Path, u32>,
+    /// Where the data of a file is stored after parts of it were merged with identical data, as
+    /// the offsets within the file's data where each stored piece begins and the offsets within
+    /// the section it's stored at. The data before the first piece is stored at the file's start.
+    moved: HashMap<&'a Path, Vec<(u32, u32)>>,
     pub(crate) virtual_address: u32,
+    /// Zero-initialized space following `bytes` that is reserved in memory but not stored in the
+    /// XBE
+    bss_size: u32,
 }
 
 impl<'a> SectionBuilder<'a> {
@@ -38,7 +175,9 @@ impl<'a> SectionBuilder<'a> {
             name,
             bytes: Vec::new(),
             file_offset_start: HashMap::new(),
+            moved: HashMap::new(),
             virtual_address: 0,
+            bss_size: 0,
         }
     }
 
@@ -52,11 +191,178 @@ impl<'a> SectionBuilder<'a> {
                 self.name
             );
         }
+        self.fill_bss();
         self.file_offset_start
             .insert(filename, self.bytes.len() as u32);
         self.bytes.append(&mut bytes.to_owned());
     }
 
+    /// Turns the reserved zero-initialized space into zeroed bytes, so bytes added afterwards are
+    /// placed after it instead of within it
+    fn fill_bss(&mut self) {
+        let len = self.bytes.len() + self.bss_size as usize;
+        self.bytes.resize(len, 0);
+        self.bss_size = 0;
+    }
+
+    /// Appends the bytes of `other` to this section, offsetting the start of each of its files.
+    /// Any space reserved by `other` replaces the space reserved by this section, so this section
+    /// shouldn't have any. Returns the offset `other` was placed at.
+    fn merge(&mut self, other: SectionBuilder<'a>) -> u32 {
+        let offset = self.bytes.len() as u32;
+        for (filename, start) in other.file_offset_start {
+            self.file_offset_start.insert(filename, start + offset);
+        }
+        for (filename, pieces) in other.moved {
+            let pieces = pieces.into_iter().map(|(from, to)| (from, to + offset));
+            self.moved.insert(filename, pieces.collect());
+        }
+        self.bytes.extend(other.bytes);
+        self.bss_size = other.bss_size;
+        offset
+    }
+
+    /// Offset within the section of the byte at `offset` within the data `filename` added, if it
+    /// added any
+    pub(crate) fn offset_of(&self, filename: &Path, offset: u32) -> Option<u32> {
+        let start = *self.file_offset_start.get(filename)?;
+        let pieces = self.moved.get(filename).map_or(&[][..], Vec::as_slice);
+        Some(piece_offset(start, pieces, offset))
+    }
+
+    /// Removes each run of at least [`MIN_DEDUPLICATED_LEN`] bytes, a multiple of 4 long and 4
+    /// aligned, identical to data kept before it, pointing that part of the file's data at the
+    /// kept copy instead. The runs are removed whole, so later data stays aligned. Files in
+    /// `pinned` are neither removed from nor shared, as relocating them would change the data of
+    /// every file sharing it. No run is cut within one of the `extents` of a file, the data of a
+    /// symbol other files may reach with an offset from it. Returns the number of bytes removed.
+    fn deduplicate_runs(
+        &mut self,
+        pinned: &HashSet<&Path>,
+        extents: &HashMap<&Path, Vec<Range<u32>>>,
+    ) -> usize {
+        let old = std::mem::take(&mut self.bytes);
+        let starts: Vec<_> = self
+            .file_offset_start
+            .iter()
+            .map(|(file, start)| (*file, *start as usize))
+            .sorted_by_key(|(_, start)| *start)
+            .collect();
+        let ends = starts
+            .iter()
+            .skip(1)
+            .map(|(_, start)| *start)
+            .chain(std::iter::once(old.len()));
+
+        // The first kept copy of each aligned run, with the end of the data kept along with it
+        let mut canonical = HashMap::new();
+        for ((file, start), end) in starts.into_iter().zip(ends) {
+            let data = &old[start..end];
+            let mergeable = !pinned.contains(file);
+            let cut_allowed = |offset: usize| {
+                !extents.get(file).is_some_and(|extents| {
+                    extents
+                        .iter()
+                        .any(|e| (e.start as usize) < offset && offset < e.end as usize)
+                })
+            };
+
+            let mut start = self.bytes.len() as u32;
+            let mut pieces: Vec<(u32, u32)> = Vec::new();
+            let mut kept = self.bytes.len();
+            let mut offset = 0;
+            while offset < data.len() {
+                let out = self.bytes.len();
+                let found = if mergeable && out % 4 == 0 && cut_allowed(offset) {
+                    longest_run(&self.bytes, &canonical, &data[offset..], |len| {
+                        cut_allowed(offset + len)
+                    })
+                } else {
+                    None
+                };
+                let Some((target, len)) = found else {
+                    self.bytes.push(data[offset]);
+                    offset += 1;
+                    continue;
+                };
+
+                info!("Merging {len} bytes of read-only data of '{file:?}' with an identical copy");
+                index_runs(&mut canonical, &self.bytes, kept..out);
+                if pieces
+                    .last()
+                    .is_some_and(|&(from, _)| from as usize == offset)
+                {
+                    pieces.pop();
+                }
+                match offset {
+                    0 => start = target as u32,
+                    _ => pieces.push((offset as u32, target as u32)),
+                }
+                offset += len;
+                if offset < data.len() {
+                    pieces.push((offset as u32, out as u32));
+                }
+                kept = out;
+            }
+            if mergeable {
+                index_runs(&mut canonical, &self.bytes, kept..self.bytes.len());
+            }
+
+            self.file_offset_start.insert(file, start);
+            if pieces.is_empty() {
+                self.moved.remove(file);
+            } else {
+                self.moved.insert(file, pieces);
+            }
+        }
+        old.len() - self.bytes.len()
+    }
+
+    /// Reserves `size` bytes of zero-initialized space at the end of the section. Unlike bytes
+    /// added to the section, this space only contributes to the virtual size, unless more bytes
+    /// are added after it. The space is then filled with zeroes so the new bytes follow it.
+    pub(crate) fn reserve_bss(&mut self, size: usize) {
+        self.bss_size += size as u32;
+    }
+
+    /// Size of the section once loaded into memory
+    pub(crate) fn virtual_size(&self) -> u32 {
+        self.bytes.len() as u32 + self.bss_size
+    }
+
+    /// CRC-32 of the section's current bytes
+    pub(crate) fn checksum(&self) -> u32 {
+        crc32(&self.bytes)
+    }
+
+    /// Offset of the `u32` located at `file_section_address` (plus the `file_start_offset` of
+    /// `filename`), checked to be within the section
+    fn u32_offset(&self, filename: &Path, file_section_address: u32) -> Result<u64> {
+        // find the offset of the data, in 64 bits so it can't wrap around into the section
+        let d_start = *self
+            .file_offset_start
+            .get(filename)
+            .ok_or_else(|| RelocationError::SectionOffset(self.name.clone()))?
+            as u64
+            + file_section_address as u64;
+        if d_start + std::mem::size_of::<u32>() as u64 > self.bytes.len() as u64 {
+            bail!(RelocationError::OutOfBoundsWrite {
+                section: self.name.clone(),
+                offset: d_start,
+                section_size: self.bytes.len(),
+            });
+        }
+        Ok(d_start)
+    }
+
+    /// Read the value located at `file_section_address` (plus the `file_start_offset` of `filename`)
+    fn read_u32(&self, filename: &Path, file_section_address: u32) -> Result<u32> {
+        let d_start = self.u32_offset(filename, file_section_address)?;
+        let mut cur = Cursor::new(&self.bytes);
+        cur.set_position(d_start);
+        Ok(cur.read_u32::<LE>()?)
+    }
+
     /// Read the value located at `file_section_address` (plus the `file_start_offset` of `filename`),
     /// add `value`, and overwrite the original value with the result.
     fn relative_update_u32(
@@ -65,19 +371,13 @@ impl<'a> SectionBuilder<'a> {
         file_section_address: u32,
         value: u32,
     ) -> Result<()> {
+        let d_start = self.u32_offset(filename, file_section_address)?;
         let mut cur = Cursor::new(&mut self.bytes);
 
-        // find the offset of the data to update
-        let d_start = self
-            .file_offset_start
-            .get(filename)
-            .ok_or_else(|| RelocationError::SectionOffset(self.name.clone()))?
-            + file_section_address;
-
         // read the current value, so we can add it to the new value
-        cur.set_position(d_start as u64);
+        cur.set_position(d_start);
         let offset = cur.read_u32::<LE>()?;
-        cur.set_position(d_start as u64);
+        cur.set_position(d_start);
 
         // update data
         cur.write_u32::<LE>(value.wrapping_add(offset))?;
@@ -96,12 +396,30 @@ impl<'a> SectionBuilder<'a> {
     }
 }
 
+/// Where the data a file added to a combined section is stored once parts of it were merged with
+/// identical data, see [`SectionBuilder::offset_of`]
+#[derive(Debug, Clone)]
+struct MovedData {
+    virtual_address: u32,
+    start: u32,
+    pieces: Vec<(u32, u32)>,
+}
+
+impl MovedData {
+    /// Virtual address of the byte at `offset` within the file's data
+    fn address_of(&self, offset: u32) -> u32 {
+        self.virtual_address
+            .wrapping_add(piece_offset(self.start, &self.pieces, offset))
+    }
+}
+
 trait RelocExt {
     fn perform(
         &self,
         file: &ObjectFile,
         symbol_table: &SymbolTable,
         section_data: &mut SectionBuilder<'_>,
+        moved: &HashMap<i16, MovedData>,
     ) -> Result<()>;
 }
 
@@ -111,6 +429,7 @@ impl RelocExt for pe::relocation::Relocation {
         file: &ObjectFile,
         symbol_table: &SymbolTable,
         section_data: &mut SectionBuilder<'_>,
+        moved: &HashMap<i16, MovedData>,
     ) -> Result<()> {
         // Find target symbol and name
         let (symbol_name, symbol) = file
@@ -129,11 +448,23 @@ impl RelocExt for pe::relocation::Relocation {
         // We are targeting Xbox so we use x86 relocations
         use pe::relocation::*;
         match self.typ {
-            IMAGE_REL_I386_DIR32 => section_data.relative_update_u32(
-                &file.path,
-                self.virtual_address,
-                target_address,
-            )?,
+            IMAGE_REL_I386_DIR32 => {
+                // The offset stored in the data may reach a different piece of merged data than
+                // the symbol is in, so the target is found from both when the file's data moved
+                let target_address = match moved.get(&symbol.section_number) {
+                    Some(data) => {
+                        let addend = section_data.read_u32(&file.path, self.virtual_address)?;
+                        data.address_of(symbol.value.wrapping_add(addend))
+                            .wrapping_sub(addend)
+                    }
+                    None => target_address,
+                };
+                section_data.relative_update_u32(
+                    &file.path,
+                    self.virtual_address,
+                    target_address,
+                )?
+            }
             IMAGE_REL_I386_REL32 => {
                 let sec_address = section_data
                     .file_offset_start
@@ -164,21 +495,137 @@ impl RelocExt for pe::relocation::Relocation {
     }
 }
 
-/// Maps from a given section name to it's section data
+/// The contents of a file injected as a section as-is, without being parsed as an object file
 #[derive(Debug)]
-pub(crate) struct SectionMap<'a>(HashMap<&'a str, SectionBuilder<'a>>);
+pub(crate) struct DataSection {
+    pub(crate) name: String,
+    pub(crate) path: PathBuf,
+    pub(crate) data: Vec<u8>,
+    /// Address to place the section at, or the next free address if `None`
+    pub(crate) virtual_address: Option<u32>,
+    pub(crate) flags: xbe::SectionFlags,
+}
+
+impl DataSection {
+    /// Adds this section to `xbe`, returning the virtual address it was placed at
+    pub(crate) fn add_to(&self, xbe: &mut xbe::Xbe) -> Result<u32> {
+        check_section_name(&self.name)?;
+        if xbe.section_by_name(&self.name).is_some() {
+            bail!(RelocationError::DuplicateSection(self.name.clone()));
+        }
+
+        let virtual_address = self
+            .virtual_address
+            .unwrap_or_else(|| xbe.get_next_virtual_address());
+        let overflow = || {
+            RelocationError::AddressOverflow(self.name.clone(), virtual_address, self.data.len())
+        };
+        let size = u32::try_from(self.data.len()).map_err(|_| overflow())?;
+        let end = virtual_address.checked_add(size).ok_or_else(overflow)?;
+
+        // sections placed at the next free address can't overlap
+        if self.virtual_address.is_some() {
+            if let Some(sec) = xbe
+                .sections
+                .iter()
+                .find(|s| s.virtual_address < end && virtual_address < s.virtual_range().end)
+            {
+                bail!(RelocationError::OverlappingSection(
+                    self.name.clone(),
+                    virtual_address,
+                    strip_null(&sec.name).to_string()
+                ));
+            }
+        }
+
+        info!(
+            "Adding data section '{}' from file '{:?}' at {virtual_address:#x}; {size} bytes.",
+            self.name, self.path
+        );
+        xbe.try_add_section(
+            &self.name,
+            self.flags,
+            self.data.clone(),
+            virtual_address,
+            size,
+        )?;
+        Ok(virtual_address)
+    }
+}
+
+/// Offset within a section of the byte at `offset` within the data of a file stored at `start`,
+/// with the `pieces` of it stored elsewhere, see [`SectionBuilder::offset_of`]
+fn piece_offset(start: u32, pieces: &[(u32, u32)], offset: u32) -> u32 {
+    match pieces.iter().rev().find(|(from, _)| *from <= offset) {
+        Some(&(from, to)) => to.wrapping_add(offset - from),
+        None => start.wrapping_add(offset),
+    }
+}
+
+/// Adds the 4 aligned runs of [`MIN_DEDUPLICATED_LEN`] bytes within `range` of `bytes` to
+/// `canonical`, unless an identical run is already there
+fn index_runs(
+    canonical: &mut HashMap<[u8; MIN_DEDUPLICATED_LEN], (usize, usize)>,
+    bytes: &[u8],
+    range: Range<usize>,
+) {
+    let mut start = range.start.next_multiple_of(4);
+    while start + MIN_DEDUPLICATED_LEN <= range.end {
+        if let Ok(run) = bytes[start..start + MIN_DEDUPLICATED_LEN].try_into() {
+            canonical.entry(run).or_insert((start, range.end));
+        }
+        start += 4;
+    }
+}
+
+/// The offset within `bytes` and length of the longest run at the start of `data` identical to
+/// one in `canonical`, at least [`MIN_DEDUPLICATED_LEN`] bytes and a multiple of 4 long, that
+/// `cut_allowed` allows to end at
+fn longest_run(
+    bytes: &[u8],
+    canonical: &HashMap<[u8; MIN_DEDUPLICATED_LEN], (usize, usize)>,
+    data: &[u8],
+    cut_allowed: impl Fn(usize) -> bool,
+) -> Option<(usize, usize)> {
+    let run: [u8; MIN_DEDUPLICATED_LEN] = data.get(..MIN_DEDUPLICATED_LEN)?.try_into().ok()?;
+    let &(target, end) = canonical.get(&run)?;
+    let mut len = MIN_DEDUPLICATED_LEN;
+    while len + 4 <= data.len()
+        && target + len + 4 <= end
+        && bytes[target + len..target + len + 4] == data[len..len + 4]
+    {
+        len += 4;
+    }
+    while len >= MIN_DEDUPLICATED_LEN && !cut_allowed(len) {
+        len -= 4;
+    }
+    (len >= MIN_DEDUPLICATED_LEN).then_some((target, len))
+}
+
+/// Maps from a given section name to it's section data
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SectionMap<'a> {
+    sections: HashMap<&'a str, SectionBuilder<'a>>,
+    /// Names of COFF sections to combine in addition to the built-in ones, mapped to the name of
+    /// the section they are combined into
+    extra_sections: HashMap<&'a str, &'a str>,
+    /// Offsets within `.mbss` allocated to common symbols
+    common_symbols: HashMap<&'a str, u32>,
+    /// Combine `.text`, `.data`, `.bss`, and `.rdata` into the default `.m*` sections
+    default_sections: bool,
+}
 
 impl<'a> Deref for SectionMap<'a> {
     type Target = HashMap<&'a str, SectionBuilder<'a>>;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.sections
     }
 }
 
 impl<'a> DerefMut for SectionMap<'a> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+        &mut self.sections
     }
 }
 
@@ -187,13 +634,48 @@ impl<'a> IntoIterator for SectionMap<'a> {
     type IntoIter = <HashMap<&'a str, SectionBuilder<'a>> as IntoIterator>::IntoIter;
 
     fn into_iter(self) -> Self::IntoIter {
-        self.0.into_iter()
+        self.sections.into_iter()
+    }
+}
+
+impl Display for SectionMap<'_> {
+    /// One line per section, in address order, with its virtual address and size
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let lines = self
+            .sections
+            .iter()
+            .sorted_by_key(|(name, sec)| (sec.virtual_address, **name))
+            .map(|(name, sec)| {
+                format!(
+                    "{:<8} {:#010x} {:>8} bytes",
+                    name,
+                    sec.virtual_address,
+                    sec.virtual_size()
+                )
+            })
+            .join("\n");
+        f.write_str(&lines)
     }
 }
 
 impl<'a> SectionMap<'a> {
-    pub(crate) fn from_data(files: &'a [ObjectFile]) -> Self {
-        let mut section_map = HashMap::new();
+    pub(crate) fn from_data(files: &'a [ObjectFile]) -> Result<Self> {
+        Self::from_data_with_extra_sections(files, HashMap::new(), true)
+    }
+
+    /// Combines the sections of `files` named in `extra_sections`, along with the built-in
+    /// `.text`, `.data`, `.bss`, and `.rdata` if `default_sections` is set
+    pub(crate) fn from_data_with_extra_sections(
+        files: &'a [ObjectFile],
+        extra_sections: HashMap<&'a str, &'a str>,
+        default_sections: bool,
+    ) -> Result<Self> {
+        let mut section_map = Self {
+            sections: HashMap::new(),
+            extra_sections,
+            common_symbols: HashMap::new(),
+            default_sections,
+        };
         for file in files.iter() {
             let mut combined_bytes = HashMap::new();
             for sec in file
@@ -202,22 +684,42 @@ impl<'a> SectionMap<'a> {
                 .iter()
                 .filter(|s| s.size_of_raw_data != 0)
             {
-                let sec_name = match &sec.name {
-                    b".text\0\0\0" => ".mtext",
-                    b".data\0\0\0" => ".mdata",
-                    b".bss\0\0\0\0" => ".mbss",
-                    b".rdata\0\0" => ".mrdata",
-                    _ => continue,
+                let coff_name = match sec.name() {
+                    Ok(name) => name,
+                    Err(_) => continue,
+                };
+                let sec_name = match section_map.combined_name(coff_name) {
+                    Some(name) => name,
+                    None => continue,
                 };
 
-                let start = sec.pointer_to_raw_data as usize;
-                let end = start + sec.size_of_raw_data as usize;
-                let data = &file.bytes()[start..end];
+                // Uninitialized sections have a size but no data within the file
+                let mut data = if sec.characteristics
+                    & pe::section_table::IMAGE_SCN_CNT_UNINITIALIZED_DATA
+                    != 0
+                {
+                    vec![0; sec.size_of_raw_data as usize]
+                } else {
+                    // `ObjectFile::validate` already checks this, but don't panic if it didn't
+                    let start = sec.pointer_to_raw_data as usize;
+                    let end = start + sec.size_of_raw_data as usize;
+                    file.bytes()
+                        .get(start..end)
+                        .ok_or_else(|| {
+                            RelocationError::TruncatedSection(
+                                file.path.clone(),
+                                coff_name.to_string(),
+                                end,
+                                file.bytes().len(),
+                            )
+                        })?
+                        .to_owned()
+                };
 
                 combined_bytes
                     .entry(sec_name)
                     .or_insert_with(Vec::default)
-                    .append(&mut data.to_owned());
+                    .append(&mut data);
             }
 
             for (sec_name, bytes) in combined_bytes.into_iter() {
@@ -229,13 +731,63 @@ impl<'a> SectionMap<'a> {
                 );
 
                 section_map
+                    .sections
                     .entry(sec_name)
                     .or_insert_with(|| SectionBuilder::new(sec_name.to_string()))
                     .add_bytes(&bytes, &file.path);
             }
         }
 
-        Self(section_map)
+        Ok(section_map)
+    }
+
+    /// Merges identical runs of read-only data, within the data of one file or of different
+    /// files, returning the number of bytes saved. Symbols and relocations into the merged data
+    /// are pointed at the copy that's kept. Files with relocations in their read-only data keep
+    /// their own copy, and the data of an external symbol is never split, as other files may
+    /// reach into it with an offset from the symbol.
+    pub(crate) fn deduplicate_constants(&mut self, files: &'a [ObjectFile]) -> usize {
+        let mut pinned = HashSet::new();
+        let mut extents = HashMap::new();
+        for file in files.iter() {
+            let numbers: Vec<_> = file
+                .coff()
+                .sections
+                .iter()
+                .enumerate()
+                .filter(|(_, sec)| {
+                    sec.name()
+                        .is_ok_and(|name| self.combined_name(name) == Some(".mrdata"))
+                })
+                .map(|(i, sec)| (i as i16 + 1, sec.number_of_relocations))
+                .collect();
+            if numbers.iter().any(|&(_, relocations)| relocations > 0) {
+                pinned.insert(file.path.as_path());
+            }
+
+            let symbols: Vec<_> = file
+                .coff()
+                .symbols
+                .iter()
+                .map(|(_, _, sym)| sym)
+                .filter(|sym| numbers.iter().any(|&(n, _)| n == sym.section_number))
+                .collect();
+            let starts: Vec<_> = symbols.iter().map(|sym| sym.value).sorted().collect();
+            let file_extents = symbols
+                .iter()
+                .filter(|sym| sym.storage_class == pe::symbol::IMAGE_SYM_CLASS_EXTERNAL)
+                .map(|sym| {
+                    let end = starts.iter().find(|&&start| start > sym.value);
+                    sym.value..end.copied().unwrap_or(u32::MAX)
+                })
+                .collect();
+            extents.insert(file.path.as_path(), file_extents);
+        }
+
+        match self.sections.get_mut(".mrdata") {
+            Some(rdata) => rdata.deduplicate_runs(&pinned, &extents),
+            None => 0,
+        }
     }
 
     pub(crate) fn assign_addresses(&mut self, xbe: &xbe::Xbe) {
@@ -244,51 +796,204 @@ impl<'a> SectionMap<'a> {
         for (_, sec) in self.iter_mut().sorted_by(|a, b| a.0.cmp(b.0)) {
             sec.virtual_address = last_virtual_address;
             last_virtual_address =
-                xbe.get_next_virtual_address_after(last_virtual_address + sec.bytes.len() as u32);
+                xbe.get_next_virtual_address_after(last_virtual_address + sec.virtual_size());
         }
     }
 
-    pub(crate) fn finalize(self, xbe: &mut xbe::Xbe) {
+    /// Adds the combined sections to `xbe`. Sections without an entry in `section_flags` use
+    /// flags based on their name.
+    pub(crate) fn finalize(
+        self,
+        xbe: &mut xbe::Xbe,
+        section_flags: &HashMap<String, xbe::SectionFlags>,
+    ) -> Result<()> {
         for sec in self
             .into_iter()
             .map(|(_, sec)| sec)
             .sorted_by(|a, b| a.virtual_address.cmp(&b.virtual_address))
         {
-            let flags = xbe::SectionFlags::PRELOAD
-                | match sec.name.as_str() {
-                    ".mtext" => xbe::SectionFlags::EXECUTABLE,
-                    ".mdata" | ".mbss" => xbe::SectionFlags::WRITABLE,
-                    _ => xbe::SectionFlags::PRELOAD, //No "zero" value
-                };
-            let virtual_size = sec.bytes.len() as u32;
-            xbe.add_section(
-                sec.name + "\0",
-                flags,
-                sec.bytes,
-                sec.virtual_address,
-                virtual_size,
-            )
+            let flags = match section_flags.get(strip_null(&sec.name)) {
+                Some(flags) => *flags,
+                None => {
+                    xbe::SectionFlags::PRELOAD
+                        | match strip_null(&sec.name) {
+                            ".mtext" => xbe::SectionFlags::EXECUTABLE,
+                            ".mdata" | ".mbss" => xbe::SectionFlags::WRITABLE,
+                            _ => xbe::SectionFlags::PRELOAD, //No "zero" value
+                        }
+                }
+            };
+            let virtual_size = sec.virtual_size();
+            // uninitialized data only needs address space, the loader zero-fills it
+            let data = if strip_null(&sec.name) == ".mbss" && sec.bytes.iter().all(|&b| b == 0) {
+                Vec::new()
+            } else {
+                sec.bytes
+            };
+            xbe.try_add_section(&sec.name, flags, data, sec.virtual_address, virtual_size)?;
+        }
+        Ok(())
+    }
+
+    /// Total size in bytes of all combined sections
+    pub(crate) fn total_size(&self) -> usize {
+        self.values().map(|sec| sec.bytes.len()).sum()
+    }
+
+    /// Number of combined sections
+    pub(crate) fn section_count(&self) -> usize {
+        self.len()
+    }
+
+    /// Size in bytes of a section, given either its combined name (`.mtext`) or the name of the
+    /// COFF sections combined into it (`.text`)
+    pub(crate) fn size_of(&self, section_name: &str) -> Option<usize> {
+        self.sections
+            .get(strip_null(section_name))
+            .or_else(|| self.get(section_name))
+            .map(|sec| sec.bytes.len())
+    }
+
+    /// Appends `len` zeroed bytes to the combined section `name`, creating it if needed, and
+    /// returns the offset of the reserved space within the section.
+    pub(crate) fn reserve(&mut self, name: &'a str, len: u32) -> u32 {
+        let sec = self.get_or_insert(name);
+        sec.fill_bss();
+        let offset = sec.bytes.len() as u32;
+        sec.bytes.resize(sec.bytes.len() + len as usize, 0);
+        offset
+    }
+
+    /// Gets the combined section `name`, creating an empty one if it does not exist
+    pub(crate) fn get_or_insert(&mut self, name: &'a str) -> &mut SectionBuilder<'a> {
+        self.sections
+            .entry(name)
+            .or_insert_with(|| SectionBuilder::new(name.to_string()))
+    }
+
+    /// Pads the section `name` with zero-initialized space so that its virtual size is `size`,
+    /// adding the section if it doesn't exist
+    pub(crate) fn set_virtual_size(&mut self, name: &'a str, size: u32) -> Result<()> {
+        let section = self.get_or_insert(name);
+        let current = section.virtual_size();
+        if size < current {
+            bail!(RelocationError::VirtualSizeTooSmall(
+                name.to_string(),
+                size,
+                current
+            ));
+        }
+        section.reserve_bss((size - current) as usize);
+        Ok(())
+    }
+
+    /// Allocates space in `.mbss` for the common symbols of `files`. These are uninitialized
+    /// globals whose symbol value is their size rather than an offset. Each name is allocated once
+    /// using the largest size declared for it, with every allocation aligned to 4 bytes.
+    pub(crate) fn allocate_common_symbols(&mut self, files: &'a [ObjectFile]) -> Result<()> {
+        let mut sizes: Vec<(&'a str, u32)> = Vec::new();
+        for file in files.iter() {
+            for (_, _, sym) in file.coff().symbols.iter() {
+                if !is_common_symbol(&sym) {
+                    continue;
+                }
+                let name = sym.name(&file.coff().strings)?;
+                match sizes.iter_mut().find(|(n, _)| *n == name) {
+                    Some((_, size)) => *size = (*size).max(sym.value),
+                    None => sizes.push((name, sym.value)),
+                }
+            }
+        }
+        if sizes.is_empty() {
+            return Ok(());
+        }
+        if !self.default_sections {
+            warn!("Skipping common symbols {sizes:?} as default sections are disabled");
+            return Ok(());
         }
+
+        let mbss = self
+            .sections
+            .entry(".mbss")
+            .or_insert_with(|| SectionBuilder::new(".mbss".to_string()));
+        for (name, size) in sizes {
+            let offset = mbss.virtual_size().next_multiple_of(4);
+            mbss.reserve_bss((offset - mbss.virtual_size() + size.next_multiple_of(4)) as usize);
+            info!("Allocating common symbol '{name}' at .mbss+{offset:#x}; {size} bytes.");
+            self.common_symbols.insert(name, offset);
+        }
+        Ok(())
+    }
+
+    /// Combines the sections of `other` into this map. Sections present in both have the bytes of
+    /// `other` appended, so must not have had space reserved in this map. A file may only
+    /// contribute to one of the maps.
+    // Not yet used by injection, which builds a single map from every file
+    #[allow(dead_code)]
+    pub(crate) fn merge(&mut self, other: SectionMap<'a>) -> Result<()> {
+        let files: HashSet<_> = self
+            .sections
+            .values()
+            .flat_map(|sec| sec.file_offset_start.keys())
+            .collect();
+        for (name, sec) in other.sections.iter() {
+            if self.sections.get(name).is_some_and(|s| s.bss_size != 0) {
+                bail!(RelocationError::ReservedSpace(name.to_string()));
+            }
+            if let Some(filename) = sec
+                .file_offset_start
+                .keys()
+                .find(|filename| files.contains(filename))
+            {
+                bail!(RelocationError::DuplicateFile(
+                    filename.to_path_buf(),
+                    sec.name.clone()
+                ));
+            }
+        }
+
+        let mut offsets = HashMap::new();
+        for (name, sec) in other.sections {
+            let offset = match self.sections.get_mut(name) {
+                Some(existing) => existing.merge(sec),
+                None => {
+                    self.sections.insert(name, sec);
+                    0
+                }
+            };
+            offsets.insert(name, offset);
+        }
+
+        let mbss_offset = offsets.get(".mbss").copied().unwrap_or_default();
+        for (name, offset) in other.common_symbols {
+            self.common_symbols.insert(name, offset + mbss_offset);
+        }
+        self.extra_sections.extend(other.extra_sections);
+        Ok(())
+    }
+
+    /// Maps each section name to its virtual address and the checksum of its current bytes
+    pub(crate) fn checksums(&self) -> HashMap<String, (u32, u32)> {
+        self.values()
+            .map(|sec| (sec.name.clone(), (sec.virtual_address, sec.checksum())))
+            .collect()
     }
 
     pub(crate) fn get(&self, section: &str) -> Option<&SectionBuilder<'_>> {
-        self.0.get(match section {
-            ".text" => ".mtext",
-            ".data" => ".mdata",
-            ".bss" => ".mbss",
-            ".rdata" => ".mrdata",
-            _ => return None,
-        })
+        self.sections.get(self.combined_name(section)?)
     }
 
     pub(crate) fn get_mut(&mut self, section: &str) -> Option<&mut SectionBuilder<'a>> {
-        self.0.get_mut(match section {
-            ".text" => ".mtext",
-            ".data" => ".mdata",
-            ".bss" => ".mbss",
-            ".rdata" => ".mrdata",
-            _ => return None,
-        })
+        let name = self.combined_name(section)?;
+        self.sections.get_mut(name)
+    }
+
+    /// Maps the name of a COFF section to the name of the section it is combined into
+    fn combined_name(&self, section: &str) -> Option<&'a str> {
+        self.default_sections
+            .then(|| combined_section_name(section))
+            .flatten()
+            .or_else(|| self.extra_sections.get(strip_null(section)).copied())
     }
 
     pub(crate) fn process_relocations(
@@ -297,6 +1002,22 @@ impl<'a> SectionMap<'a> {
         files: &[ObjectFile],
     ) -> Result<()> {
         for file in files.iter() {
+            let moved: HashMap<_, _> = file
+                .coff()
+                .sections
+                .iter()
+                .enumerate()
+                .filter_map(|(i, section)| {
+                    let data = self.get(section.name().ok()?)?;
+                    let moved = MovedData {
+                        virtual_address: data.virtual_address,
+                        start: *data.file_offset_start.get(file.path.as_path())?,
+                        pieces: data.moved.get(file.path.as_path())?.clone(),
+                    };
+                    Some((i as i16 + 1, moved))
+                })
+                .collect();
+
             for section in file.coff().sections.iter() {
                 // find data to update
                 // TODO: This is assuming 32 bit relocations
@@ -311,11 +1032,15 @@ impl<'a> SectionMap<'a> {
 
                 info!("Beginning relocation processing for section '{section_name}.'");
 
-                for reloc in section.relocations(file.bytes()).unwrap_or_default() {
+                let relocations = section.relocations(file.bytes()).with_context(|| {
+                    format!("Failed to read relocations of section '{section_name}'")
+                })?;
+                for reloc in relocations {
                     reloc
-                        .perform(file, symbol_table, section_data)
-                        .with_context(|| {
-                            format!("Failed to perform a relocation in section '{section_name}'.")
+                        .perform(file, symbol_table, section_data, &moved)
+                        .with_context(|| InjectStep::Relocation {
+                            file: file.path.clone(),
+                            section: section_name.to_string(),
                         })?;
                 }
             }
@@ -325,10 +1050,44 @@ impl<'a> SectionMap<'a> {
     }
 }
 
+/// Recompute the checksum of each section in `checksums` from the data stored in `xbe` and verify
+/// it matches the recorded value.
+pub(crate) fn verify_checksums(
+    xbe: &xbe::Xbe,
+    checksums: &HashMap<String, (u32, u32)>,
+) -> Result<()> {
+    for (name, (virtual_address, checksum)) in checksums.iter() {
+        let section = xbe
+            .section_by_name(name)
+            .ok_or_else(|| RestoreError::MissingSection(name.clone(), *virtual_address))?;
+        if crc32(&section.data) != *checksum {
+            bail!(RelocationError::ChecksumMismatch(name.clone()));
+        }
+    }
+    Ok(())
+}
+
 /// Maps from a given symbol name to its virtual address
 // TODO: Remove heap allocation (String)
 #[derive(Debug, Clone)]
-pub(crate) struct SymbolTable(HashMap<String, u32>);
+pub struct SymbolTable(HashMap<String, u32>);
+
+/// Number of entries shown when displaying a [`SymbolTable`]
+const DISPLAYED_SYMBOLS: usize = 5;
+
+impl Display for SymbolTable {
+    /// The symbol count followed by the first few symbols by name
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} symbols", self.len())?;
+        for (name, address) in self.iter().sorted().take(DISPLAYED_SYMBOLS) {
+            write!(f, "\n    {name} = {address:#x}")?;
+        }
+        if self.len() > DISPLAYED_SYMBOLS {
+            write!(f, "\n    ...")?;
+        }
+        Ok(())
+    }
+}
 
 impl SymbolTable {
     pub(crate) fn new(
@@ -342,133 +1101,212 @@ impl SymbolTable {
             .map(|p| &p.patchfile)
             .chain(config.modfiles.iter())
         {
-            map.extract_symbols(section_map, obj, config)
-                .with_context(|| format!("Couldn't extract symbols from file '{:?}'", obj.path))?;
+            map.extract_symbols(section_map, obj, config)?;
+        }
+
+        // Common symbols are only used if no file defines the symbol
+        if let Some(mbss) = section_map.sections.get(".mbss") {
+            for (name, offset) in section_map.common_symbols.iter() {
+                map.0
+                    .entry(name.to_string())
+                    .or_insert(mbss.virtual_address + offset);
+            }
         }
         Ok(map)
     }
 
+    /// Virtual address of the symbol `name`
+    pub fn get(&self, name: &str) -> Option<u32> {
+        self.0.get(name).copied()
+    }
+
+    /// Whether the symbol `name` has been assigned a virtual address
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.contains_key(name)
+    }
+
+    /// Number of symbols in the table
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether the table has no symbols
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub(crate) fn insert(&mut self, name: String, address: u32) {
+        self.0.insert(name, address);
+    }
+
+    /// Error for `name` missing from this table, suggesting similar names it does contain
+    pub(crate) fn undefined_symbol(&self, name: &str) -> PatchError {
+        PatchError::UndefinedSymbol(
+            name.to_string(),
+            self.len(),
+            suggest_names(name, self.0.keys().map(String::as_str)),
+        )
+    }
+
+    /// Each symbol name alongside its virtual address, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u32)> {
+        self.0
+            .iter()
+            .map(|(name, address)| (name.as_str(), *address))
+    }
+
+    /// Each symbol name alongside its virtual address, sorted by address and then by name
+    pub fn into_sorted_iter(self) -> Vec<(String, u32)> {
+        self.0
+            .into_iter()
+            .sorted_by(|(a_name, a), (b_name, b)| a.cmp(b).then_with(|| a_name.cmp(b_name)))
+            .collect()
+    }
+
     fn extract_symbols(
         &mut self,
         section_map: &SectionMap<'_>,
         obj: &ObjectFile,
         config: &Configuration,
     ) -> Result<()> {
-        for (_, _, sym) in obj.coff().symbols.iter() {
-            match sym.section_number {
-                0 => {
-                    // TODO: Probably track these external symbols and produce error/warnings if
-                    // unresolved
-                    info!(
-                        "Skipping external symbol '{}' in file '{:?}'.",
-                        sym.name(&obj.coff().strings).unwrap_or(""),
+        for (_, name, sym) in obj.coff().symbols.iter() {
+            self.extract_symbol(section_map, obj, config, &sym)
+                .with_context(|| {
+                    let name = name.map_or_else(|| sym.name(&obj.coff().strings), Ok);
+                    format!(
+                        "Symbol '{}' in file '{:?}'",
+                        name.unwrap_or("<unnamed>"),
                         obj.path
-                    );
-                    continue;
-                }
-                -2 | -1 => {
-                    // TODO: Determine if these symbols are important at all
-                    warn!(
-                        "Skipping symbol '{}' in file '{:?}' with section number {}.",
-                        sym.name(&obj.coff().strings).unwrap_or(""),
-                        obj.path,
-                        sym.section_number
-                    );
-                    continue;
-                }
-                _ => (),
+                    )
+                })?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds `sym` of `obj` to the table if it's defined in a combined section or patch
+    fn extract_symbol(
+        &mut self,
+        section_map: &SectionMap<'_>,
+        obj: &ObjectFile,
+        config: &Configuration,
+        sym: &pe::symbol::Symbol,
+    ) -> Result<()> {
+        match sym.section_number {
+            0 if is_common_symbol(sym) => {
+                // Allocated by `SectionMap::allocate_common_symbols`
+                return Ok(());
             }
+            0 => {
+                // TODO: Probably track these external symbols and produce error/warnings if
+                // unresolved
+                info!(
+                    "Skipping external symbol '{}' in file '{:?}'.",
+                    sym.name(&obj.coff().strings).unwrap_or(""),
+                    obj.path
+                );
+                return Ok(());
+            }
+            -2 | -1 => {
+                // TODO: Determine if these symbols are important at all
+                warn!(
+                    "Skipping symbol '{}' in file '{:?}' with section number {}.",
+                    sym.name(&obj.coff().strings).unwrap_or(""),
+                    obj.path,
+                    sym.section_number
+                );
+                return Ok(());
+            }
+            _ => (),
+        }
 
-            // Get section data from table
-            let sec_data = match section_map.get(
-                obj.coff()
-                    .sections
-                    .get(sym.section_number as usize - 1)
-                    .unwrap_or_else(|| {
-                        panic!(
-                            "No section for section number {} in file {:?}",
-                            sym.section_number, obj.path
-                        )
-                    })
-                    .name()?,
-            ) {
-                Some(data) => data,
-                None => continue,
-            };
+        // Get section data from table
+        let sec_data = match section_map.get(
+            obj.coff()
+                .sections
+                .get(sym.section_number as usize - 1)
+                .ok_or_else(|| {
+                    RelocationError::MissingSection(obj.path.clone(), sym.section_number)
+                })?
+                .name()?,
+        ) {
+            Some(data) => data,
+            None => return Ok(()),
+        };
 
-            use pe::symbol::*;
-            match sym.storage_class {
-                IMAGE_SYM_CLASS_EXTERNAL if sym.typ == 0x20 => {
-                    let sym_name = sym.name(&obj.coff().strings)?;
-                    self.0.insert(
-                        sym_name.to_owned(),
-                        match sec_data.file_offset_start.get(&*obj.path) {
-                            Some(addr) => *addr + sym.value + sec_data.virtual_address,
-                            None => {
-                                if let Some(patch) = config
-                                    .patches
-                                    .iter()
-                                    .find(|p| p.start_symbol_name == sym_name)
-                                {
-                                    patch.virtual_address
-                                } else {
-                                    continue;
-                                }
+        use pe::symbol::*;
+        match sym.storage_class {
+            IMAGE_SYM_CLASS_EXTERNAL if sym.typ == 0x20 => {
+                let sym_name = sym.name(&obj.coff().strings)?;
+                self.0.insert(
+                    sym_name.to_owned(),
+                    match sec_data.offset_of(&obj.path, sym.value) {
+                        Some(offset) => offset + sec_data.virtual_address,
+                        None => {
+                            if let Some(site) = config
+                                .patches
+                                .iter()
+                                .flat_map(|p| p.sites.iter())
+                                .find(|s| s.start_symbol_name == sym_name)
+                            {
+                                site.virtual_address
+                            } else {
+                                return Ok(());
                             }
-                        },
-                    );
-                }
-                IMAGE_SYM_CLASS_FUNCTION => {
-                    let sym_name = sym.name(&obj.coff().strings)?;
-                    self.0.insert(
-                        sym_name.to_owned(),
-                        match sec_data.file_offset_start.get(&*obj.path) {
-                            Some(addr) => *addr + sym.value + sec_data.virtual_address,
-                            None => {
-                                if let Some(patch) = config
-                                    .patches
-                                    .iter()
-                                    .find(|p| p.start_symbol_name == sym_name)
-                                {
-                                    patch.virtual_address
-                                } else {
-                                    continue;
-                                }
+                        }
+                    },
+                );
+            }
+            IMAGE_SYM_CLASS_FUNCTION => {
+                let sym_name = sym.name(&obj.coff().strings)?;
+                self.0.insert(
+                    sym_name.to_owned(),
+                    match sec_data.offset_of(&obj.path, sym.value) {
+                        Some(offset) => offset + sec_data.virtual_address,
+                        None => {
+                            if let Some(site) = config
+                                .patches
+                                .iter()
+                                .flat_map(|p| p.sites.iter())
+                                .find(|s| s.start_symbol_name == sym_name)
+                            {
+                                site.virtual_address
+                            } else {
+                                return Ok(());
                             }
-                        },
-                    );
-                }
-                IMAGE_SYM_CLASS_EXTERNAL if sym.section_number > 0 => {
-                    self.0.insert(
-                        sym.name(&obj.coff().strings)?.to_owned(),
-                        match sec_data.file_offset_start.get(&*obj.path) {
-                            Some(addr) => *addr + sym.value + sec_data.virtual_address,
-                            None => continue,
-                        },
-                    );
-                }
-                IMAGE_SYM_CLASS_EXTERNAL => {
-                    // TODO: Check if this is a link-time symbol necessary for modloader
-                    // functionality.
+                        }
+                    },
+                );
+            }
+            IMAGE_SYM_CLASS_EXTERNAL if sym.section_number > 0 => {
+                self.0.insert(
+                    sym.name(&obj.coff().strings)?.to_owned(),
+                    match sec_data.offset_of(&obj.path, sym.value) {
+                        Some(offset) => offset + sec_data.virtual_address,
+                        None => return Ok(()),
+                    },
+                );
+            }
+            IMAGE_SYM_CLASS_EXTERNAL => {
+                // TODO: Check if this is a link-time symbol necessary for modloader
+                // functionality.
 
-                    // External symbol should be declared in a future file
-                    // TODO: Keep up with unresolved externals for errors?
-                    continue;
-                }
-                IMAGE_SYM_CLASS_STATIC => {
-                    self.0.insert(
-                        sym.name(&obj.coff().strings)?.to_owned(),
-                        match sec_data.file_offset_start.get(&*obj.path) {
-                            Some(addr) => *addr + sec_data.virtual_address,
-                            None => continue,
-                        },
-                    );
-                }
-                IMAGE_SYM_CLASS_FILE => continue,
-                _ => bail!("storage_class {} not implemented", sym.storage_class),
+                // External symbol should be declared in a future file
+                // TODO: Keep up with unresolved externals for errors?
+                return Ok(());
+            }
+            IMAGE_SYM_CLASS_STATIC => {
+                self.0.insert(
+                    sym.name(&obj.coff().strings)?.to_owned(),
+                    match sec_data.file_offset_start.get(&*obj.path) {
+                        Some(addr) => *addr + sec_data.virtual_address,
+                        None => return Ok(()),
+                    },
+                );
             }
+            IMAGE_SYM_CLASS_FILE => return Ok(()),
+            _ => bail!(RelocationError::UnsupportedStorageClass(sym.storage_class)),
         }
-
         Ok(())
     }
 }
@@ -478,6 +1316,7 @@ mod tests {
     use std::path::PathBuf;
 
     use super::*;
+    use crate::obj::ObjectError;
     use itertools::Itertools;
 
     #[test]
@@ -507,6 +1346,479 @@ mod tests {
         assert_eq!(section.bytes, (0..12).chain(0..8).collect_vec());
     }
 
+    #[test]
+    fn merge() {
+        fn map<'a>(sections: Vec<(&'a str, Vec<u8>, &'a Path)>) -> SectionMap<'a> {
+            let mut map = SectionMap::default();
+            for (name, bytes, path) in sections {
+                map.entry(name)
+                    .or_insert_with(|| SectionBuilder::new(name.to_string()))
+                    .add_bytes(&bytes, path);
+            }
+            map
+        }
+        let (path_a, path_b, path_c, path_d) = (
+            Path::new("bytesA"),
+            Path::new("bytesB"),
+            Path::new("bytesC"),
+            Path::new("bytesD"),
+        );
+
+        let mut merged = map(vec![
+            (".mtext", vec![1, 2, 3], path_a),
+            (".mdata", vec![4], path_a),
+        ]);
+        let mut other = map(vec![
+            (".mtext", vec![5, 6], path_b),
+            (".mtext", vec![7], path_c),
+            (".mbss", vec![0; 4], path_b),
+        ]);
+        other.sections.get_mut(".mbss").unwrap().reserve_bss(8);
+        merged.merge(other).unwrap();
+
+        let mtext = &merged.sections[".mtext"];
+        assert_eq!(mtext.bytes, [1, 2, 3, 5, 6, 7]);
+        assert_eq!(mtext.file_offset_start.get(path_a), Some(&0));
+        assert_eq!(mtext.file_offset_start.get(path_b), Some(&3));
+        assert_eq!(mtext.file_offset_start.get(path_c), Some(&5));
+        assert_eq!(merged.sections[".mdata"].bytes, [4]);
+        assert_eq!(merged.sections[".mbss"].virtual_size(), 12);
+
+        // A file can't contribute to both maps
+        let err = merged
+            .merge(map(vec![(".mdata", vec![8], path_b)]))
+            .expect_err("Merged a file twice");
+        assert!(matches!(
+            err.downcast_ref::<RelocationError>(),
+            Some(RelocationError::DuplicateFile(..))
+        ));
+
+        // Bytes can't be appended after reserved space
+        let err = merged
+            .merge(map(vec![(".mbss", vec![0; 4], path_d)]))
+            .expect_err("Merged into reserved space");
+        assert!(matches!(
+            err.downcast_ref::<RelocationError>(),
+            Some(RelocationError::ReservedSpace(_))
+        ));
+        assert_eq!(merged.sections[".mbss"].virtual_size(), 12);
+    }
+
+    #[test]
+    fn checksum() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+
+        let mut section = SectionBuilder::new("test".to_string());
+        let path: PathBuf = "bytes".into();
+        section.add_bytes(b"123456789", &path);
+        assert_eq!(section.checksum(), 0xCBF43926);
+    }
+
+    #[test]
+    fn verify_checksums_mismatch() -> Result<()> {
+        let mut xbe = xbe::Xbe::new(&std::fs::read("test/bin/default.xbe")?)?;
+        let virtual_address = xbe.get_next_virtual_address();
+        xbe.add_section(
+            ".mtext\0".to_string(),
+            xbe::SectionFlags::PRELOAD | xbe::SectionFlags::EXECUTABLE,
+            vec![0x90; 16],
+            virtual_address,
+            16,
+        );
+
+        let mut checksums = HashMap::new();
+        checksums.insert(".mtext".to_string(), (virtual_address, crc32(&[0x90; 16])));
+        assert!(verify_checksums(&xbe, &checksums).is_ok());
+
+        checksums.insert(".mtext".to_string(), (virtual_address, crc32(&[0xCC; 16])));
+        let err = verify_checksums(&xbe, &checksums).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<RelocationError>(),
+            Some(RelocationError::ChecksumMismatch(name)) if name == ".mtext"
+        ));
+
+        // A section missing entirely isn't reported as a changed checksum
+        checksums.insert(".mdata".to_string(), (virtual_address + 16, 0));
+        checksums.remove(".mtext");
+        let err = verify_checksums(&xbe, &checksums).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<RestoreError>(),
+            Some(RestoreError::MissingSection(name, address))
+                if name == ".mdata" && *address == virtual_address + 16
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn strip_null_terminators() {
+        assert_eq!(strip_null(".mtext\0"), ".mtext");
+        assert_eq!(strip_null(".mtext\0\0\0"), ".mtext");
+        assert_eq!(strip_null(".mtext"), ".mtext");
+        assert_eq!(strip_null(""), "");
+    }
+
+    #[test]
+    fn section_lookup_with_null() {
+        let mut map = SectionMap::default();
+        map.insert(".mtext", SectionBuilder::new(".mtext".to_string()));
+
+        assert!(map.get(".text").is_some());
+        assert!(map.get(".text\0").is_some());
+        assert!(map.get(".text\0\0\0").is_some());
+        assert!(map.get_mut(".text\0").is_some());
+        assert!(map.get(".data\0").is_none());
+        assert!(map.get(".mtext").is_none());
+    }
+
+    #[test]
+    fn section_sizes() {
+        let path_a: PathBuf = "bytesA".into();
+        let path_b: PathBuf = "bytesB".into();
+        let mut text = SectionBuilder::new(".mtext".to_string());
+        text.add_bytes(&(0..12).collect_vec(), &path_a);
+        text.add_bytes(&(0..8).collect_vec(), &path_b);
+        let mut data = SectionBuilder::new(".mdata".to_string());
+        data.add_bytes(&(0..4).collect_vec(), &path_a);
+
+        let mut map = SectionMap::default();
+        assert_eq!(map.total_size(), 0);
+        assert_eq!(map.section_count(), 0);
+        assert_eq!(map.size_of(".mtext"), None);
+
+        map.insert(".mtext", text);
+        map.insert(".mdata", data);
+        assert_eq!(map.total_size(), 24);
+        assert_eq!(map.section_count(), 2);
+        assert_eq!(map.size_of(".mtext"), Some(20));
+        assert_eq!(map.size_of(".text"), Some(20));
+        assert_eq!(map.size_of(".mdata\0"), Some(4));
+        assert_eq!(map.size_of(".mbss"), None);
+    }
+
+    #[test]
+    fn deduplicate_files() {
+        let (a, b, c, d, e): (PathBuf, PathBuf, PathBuf, PathBuf, PathBuf) =
+            ("a".into(), "b".into(), "c".into(), "d".into(), "e".into());
+        let constant = [1, 2, 3, 4, 5, 6, 7, 8];
+        let mut section = SectionBuilder::new(".mrdata".to_string());
+        section.add_bytes(&constant, &a);
+        section.add_bytes(&[9; 4], &b);
+        section.add_bytes(&constant, &c);
+        section.add_bytes(&constant, &d);
+        section.add_bytes(&[9; 4], &e);
+
+        // `d` is relocated, so keeps its own copy, and `e` is too short to merge
+        let pinned = HashSet::from([d.as_path()]);
+        let removed = section.deduplicate_runs(&pinned, &HashMap::new());
+        assert_eq!(removed, 8);
+        assert_eq!(section.bytes.len(), 8 + 4 + 8 + 4);
+        assert_eq!(section.file_offset_start[a.as_path()], 0);
+        assert_eq!(section.file_offset_start[b.as_path()], 8);
+        assert_eq!(section.file_offset_start[c.as_path()], 0);
+        assert_eq!(section.file_offset_start[d.as_path()], 12);
+        assert_eq!(section.file_offset_start[e.as_path()], 20);
+        assert_eq!(section.bytes[12..20], constant);
+
+        // Once unpinned, `d` is merged as well
+        assert_eq!(
+            section.deduplicate_runs(&HashSet::new(), &HashMap::new()),
+            8
+        );
+        assert_eq!(section.bytes.len(), 8 + 4 + 4);
+        assert_eq!(section.file_offset_start[a.as_path()], 0);
+        assert_eq!(section.file_offset_start[d.as_path()], 0);
+        assert_eq!(section.file_offset_start[e.as_path()], 12);
+    }
+
+    #[test]
+    fn deduplicate_runs() {
+        let (a, b): (PathBuf, PathBuf) = ("a".into(), "b".into());
+        let constant = [1, 2, 3, 4, 5, 6, 7, 8];
+        let section = || {
+            let mut section = SectionBuilder::new(".mrdata".to_string());
+            section.add_bytes(&[[0xA; 4], constant, [0xB; 4]].concat(), &a);
+            section.add_bytes(&[[0xC; 4], constant, [0xD; 4]].concat(), &b);
+            section
+        };
+
+        // The constant `b` shares with `a` is dropped from between the rest of its data
+        let mut merged = section();
+        assert_eq!(merged.deduplicate_runs(&HashSet::new(), &HashMap::new()), 8);
+        assert_eq!(merged.bytes.len(), 16 + 8);
+        assert_eq!(merged.offset_of(&b, 0), Some(16));
+        assert_eq!(merged.offset_of(&b, 4), Some(4));
+        assert_eq!(merged.offset_of(&b, 11), Some(11));
+        assert_eq!(merged.offset_of(&b, 12), Some(20));
+        assert_eq!(merged.bytes[16..], [0xC, 0xC, 0xC, 0xC, 0xD, 0xD, 0xD, 0xD]);
+
+        // Unless that would split the data of a symbol
+        let mut kept = section();
+        let extents = HashMap::from([(b.as_path(), vec![0..16])]);
+        assert_eq!(kept.deduplicate_runs(&HashSet::new(), &extents), 0);
+        assert_eq!(kept.bytes.len(), 32);
+        assert_eq!(kept.offset_of(&b, 4), Some(20));
+    }
+
+    #[test]
+    fn reserve_bss() {
+        let path: PathBuf = "bytes".into();
+        let mut map = SectionMap::default();
+
+        let bss = map.get_or_insert(".mbss");
+        bss.reserve_bss(1024);
+        assert!(bss.bytes.is_empty());
+        assert_eq!(bss.virtual_size(), 1024);
+
+        // Bytes added later are placed after the reserved space, not within it
+        bss.add_bytes(&[0xFF; 4], &path);
+        bss.reserve_bss(16);
+        assert_eq!(bss.file_offset_start[path.as_path()], 1024);
+        assert_eq!(bss.bytes.len(), 1028);
+        assert!(bss.bytes[..1024].iter().all(|&b| b == 0));
+        assert_eq!(bss.virtual_size(), 1044);
+        assert_eq!(map.section_count(), 1);
+
+        // Reserving space for a trampoline doesn't overlap the reserved space either
+        let offset = map.reserve(".mbss", 8);
+        assert_eq!(offset, 1044);
+        assert_eq!(map.sections[".mbss"].virtual_size(), 1052);
+    }
+
+    #[test]
+    fn extra_sections() {
+        let files = vec![
+            ObjectFile::new(PathBuf::from("test/bin/loader.o")).unwrap(),
+            ObjectFile::new(PathBuf::from("test/bin/hook_section.o")).unwrap(),
+        ];
+
+        let map = SectionMap::from_data(&files).unwrap();
+        assert!(map.get(".hook").is_none());
+        assert_eq!(map.section_count(), 1);
+
+        let map =
+            SectionMap::from_data_with_extra_sections(&files, HashMap::from([(".hook", ".mhook")]))
+                .unwrap();
+        assert_eq!(map.section_count(), 2);
+        assert_eq!(map.size_of(".mhook"), Some(6));
+        assert_eq!(map.size_of(".hook"), Some(6));
+        assert_eq!(map.size_of(".text"), Some(0x14));
+
+        // Extra sections may also be merged into a built-in section
+        let map =
+            SectionMap::from_data_with_extra_sections(&files, HashMap::from([(".hook", ".mtext")]))
+                .unwrap();
+        assert_eq!(map.section_count(), 1);
+        assert_eq!(map.size_of(".mtext"), Some(0x14 + 6));
+
+        // Only extra sections are combined without the defaults
+        let map = SectionMap::from_data_with_extra_sections(
+            &files,
+            HashMap::from([(".hook", ".mhook")]),
+            false,
+        )
+        .unwrap();
+        assert_eq!(map.section_count(), 1);
+        assert_eq!(map.size_of(".mhook"), Some(6));
+        assert!(map.get(".text").is_none());
+    }
+
+    #[test]
+    fn truncated_section() {
+        // Claim loader.o's .text (the first section header) extends far past the end of the file
+        let mut bytes = std::fs::read("test/bin/loader.o").unwrap();
+        bytes[36..40].copy_from_slice(&0x10000u32.to_le_bytes());
+        let path = std::env::temp_dir().join("xbld_truncated_section.o");
+        std::fs::write(&path, bytes).unwrap();
+
+        // The file is rejected when loaded, before its sections are combined
+        let err = ObjectFile::new(path.clone()).unwrap_err();
+        let _ = std::fs::remove_file(path);
+        assert!(matches!(
+            err.downcast_ref::<ObjectError>(),
+            Some(ObjectError::SectionOutOfBounds(name, ..)) if name == ".text"
+        ));
+    }
+
+    #[test]
+    fn relocation_out_of_bounds() {
+        let path: PathBuf = "bytes".into();
+        let mut section = SectionBuilder::new(".mtext".to_string());
+        section.add_bytes(&[0; 8], &path);
+
+        // The last four bytes can be updated, but not a u32 starting one byte later
+        assert!(section.relative_update_u32(&path, 4, 1).is_ok());
+        let err = section.relative_update_u32(&path, 5, 1).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<RelocationError>(),
+            Some(RelocationError::OutOfBoundsWrite {
+                offset: 5,
+                section_size: 8,
+                ..
+            })
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Relocation at offset 0x5 is outside of section '.mtext' (0x8 bytes)"
+        );
+
+        // Offsets of later files are added without overflowing
+        let other: PathBuf = "other".into();
+        section.add_bytes(&[0; 8], &other);
+        let err = section
+            .relative_update_u32(&other, u32::MAX - 4, 1)
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<RelocationError>(),
+            Some(RelocationError::OutOfBoundsWrite { offset, section_size: 16, .. })
+                if *offset == u32::MAX as u64 + 4
+        ));
+        assert_eq!(
+            section.bytes,
+            [0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn common_symbols() {
+        let files = vec![
+            ObjectFile::new(PathBuf::from("test/bin/mod.o")).unwrap(),
+            ObjectFile::new(PathBuf::from("test/bin/common.o")).unwrap(),
+        ];
+        let mut map = SectionMap::from_data(&files).unwrap();
+        map.allocate_common_symbols(&files).unwrap();
+
+        // Allocated after mod.o's 4 byte .bss, with the 10 byte `_buffer` padded to 12
+        assert_eq!(map.common_symbols.get("_counter"), Some(&4));
+        assert_eq!(map.common_symbols.get("_buffer"), Some(&8));
+        let mbss = map.get(".bss").unwrap();
+        assert_eq!(mbss.bytes.len(), 4);
+        assert_eq!(mbss.virtual_size(), 20);
+    }
+
+    #[test]
+    fn uninitialized_data() {
+        let files = vec![ObjectFile::new(PathBuf::from("test/bin/mod.o")).unwrap()];
+        let map = SectionMap::from_data(&files).unwrap();
+
+        // mod.o's .bss has no data in the file and must not be read from offset 0
+        assert_eq!(map.get(".bss").unwrap().bytes, vec![0; 4]);
+    }
+
+    #[test]
+    fn symbol_suggestions() {
+        let names = [
+            "_framehook_patch",
+            "_framehook_patch_end",
+            "_Framehook_Shim",
+            "_unrelated",
+        ];
+        assert_eq!(
+            suggest_names("framehook_patch", names),
+            " Did you mean '_framehook_patch'?"
+        );
+        assert_eq!(
+            suggest_names("_framehook_shim", names),
+            " Did you mean '_Framehook_Shim'?"
+        );
+        assert_eq!(
+            suggest_names("_framehook_pach_end", names),
+            " Did you mean '_framehook_patch_end'?"
+        );
+        assert_eq!(suggest_names("_missing", names), "");
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn set_virtual_size() {
+        let files = vec![ObjectFile::new(PathBuf::from("test/bin/loader.o")).unwrap()];
+        let mut map = SectionMap::from_data(&files).unwrap();
+        let text_len = map.get(".text").unwrap().bytes.len() as u32;
+
+        map.set_virtual_size(".mtext", 0x1000).unwrap();
+        let mtext = map.get(".text").unwrap();
+        assert_eq!(mtext.virtual_size(), 0x1000);
+        assert_eq!(mtext.bytes.len() as u32, text_len);
+
+        assert!(map.set_virtual_size(".mtext", text_len - 1).is_err());
+        map.set_virtual_size(".mnew", 0x10).unwrap();
+        assert_eq!(map.sections.get(".mnew").unwrap().virtual_size(), 0x10);
+    }
+
+    #[test]
+    fn display() {
+        let files = vec![ObjectFile::new(PathBuf::from("test/bin/loader.o")).unwrap()];
+        let mut map = SectionMap::from_data(&files).unwrap();
+        map.get_mut(".text").unwrap().virtual_address = 0x3000;
+        let layout = map.to_string();
+        assert!(layout.contains(".mtext   0x00003000"), "{layout}");
+        assert_eq!(layout.lines().count(), map.section_count());
+
+        let mut table = SymbolTable(HashMap::new());
+        for i in 0..8 {
+            table.insert(format!("_sym{i}"), 0x1000 + i);
+        }
+        let listing = table.to_string();
+        assert!(listing.starts_with("8 symbols\n    _sym0 = 0x1000\n"));
+        assert!(!listing.contains("_sym5"));
+        assert!(listing.ends_with("..."));
+    }
+
+    #[test]
+    fn symbol_table() {
+        let mut table = SymbolTable(HashMap::new());
+        assert!(table.is_empty());
+        assert_eq!(table.len(), 0);
+
+        table.insert("_a".to_string(), 0x1000);
+        table.insert("_b".to_string(), 0x2000);
+        table.insert("_a".to_string(), 0x3000);
+        assert!(!table.is_empty());
+        assert_eq!(table.len(), 2);
+        assert!(table.contains("_a"));
+        assert!(!table.contains("_c"));
+        assert_eq!(table.get("_a"), Some(0x3000));
+        assert_eq!(table.get("_b"), Some(0x2000));
+        assert_eq!(table.get("_c"), None);
+
+        table.insert("_c".to_string(), 0x2000);
+        assert_eq!(
+            table.iter().sorted().collect::<Vec<_>>(),
+            [("_a", 0x3000), ("_b", 0x2000), ("_c", 0x2000)]
+        );
+        assert_eq!(
+            table.into_sorted_iter(),
+            [
+                ("_b".to_string(), 0x2000),
+                ("_c".to_string(), 0x2000),
+                ("_a".to_string(), 0x3000)
+            ]
+        );
+    }
+
+    #[test]
+    fn unsupported_storage_class() {
+        // Give loader.o's `_framehook_c` (symbol 4, after the 170 byte symbol table offset) an
+        // unknown storage class
+        let mut bytes = std::fs::read("test/bin/loader.o").unwrap();
+        bytes[170 + 4 * 18 + 16] = 0x42;
+        let path = std::env::temp_dir().join("xbld_storage_class.o");
+        std::fs::write(&path, bytes).unwrap();
+
+        let toml = format!("modfiles = [{:?}]", path.to_str().unwrap());
+        let config = Configuration::from_toml(&toml, Path::new("test/bin/fakefile.toml")).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let section_map = SectionMap::from_data(&config.modfiles).unwrap();
+        let err = SymbolTable::new(&section_map, &config).unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<RelocationError>(),
+            Some(RelocationError::UnsupportedStorageClass(0x42))
+        ));
+        let message = format!("{err:#}");
+        assert!(message.contains("_framehook_c"), "{message}");
+        assert!(message.contains("xbld_storage_class.o"), "{message}");
+    }
+
     #[test]
     fn relative_update() {
         let mut section = SectionBuilder::new("test".to_string());
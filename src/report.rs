@@ -0,0 +1,233 @@
+use crate::{patch, reloc::crc32};
+use anyhow::Result;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use xbe::{raw::LibraryVersion, Xbe};
+
+/// Summary of the changes an injection made to an XBE. This can be saved as a manifest and later
+/// used to [verify](InjectionReport::verify) an XBE still contains the applied patches, or to
+/// [restore](crate::restore) the original XBE.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct InjectionReport {
+    /// CRC-32 of the serialized XBE before injection, or 0 unless
+    /// [`checksum_input`](crate::InjectOptions::checksum_input) was set
+    pub original_checksum: u32,
+    /// Encoded entry point of the XBE before injection, if it was replaced
+    pub original_entry_point: Option<u32>,
+    /// TLS address of the XBE before injection, if it was replaced
+    pub original_tls_address: Option<u32>,
+    /// Library version records of the XBE before injection, if any were added or removed
+    pub original_library_versions: Option<Vec<LibraryVersionReport>>,
+    /// Sections added to the XBE, sorted by name
+    pub sections: Vec<SectionReport>,
+    /// Regions of the XBE overwritten by patches and detours, in the order they were applied
+    pub patches: Vec<PatchReport>,
+}
+
+/// A section added to the XBE
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SectionReport {
+    pub name: String,
+    pub virtual_address: u32,
+    /// Size in bytes once loaded
+    pub size: usize,
+}
+
+/// A library version record of the XBE
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LibraryVersionReport {
+    /// Name padded with nulls to 8 bytes
+    pub name: [u8; 8],
+    pub major: u16,
+    pub minor: u16,
+    pub build: u16,
+    pub flags: u16,
+}
+
+impl From<&LibraryVersion> for LibraryVersionReport {
+    fn from(library: &LibraryVersion) -> Self {
+        Self {
+            name: library.library_name,
+            major: library.major_version,
+            minor: library.minor_version,
+            build: library.build_version,
+            flags: library.library_flags,
+        }
+    }
+}
+
+impl From<&LibraryVersionReport> for LibraryVersion {
+    fn from(library: &LibraryVersionReport) -> Self {
+        Self {
+            library_name: library.name,
+            major_version: library.major,
+            minor_version: library.minor,
+            build_version: library.build,
+            library_flags: library.flags,
+        }
+    }
+}
+
+/// A single patch site written to the XBE
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PatchReport {
+    /// Position of this patch in the application order, starting at 0
+    pub sequence: usize,
+    pub name: String,
+    pub virtual_address: u32,
+    /// Number of bytes overwritten
+    pub length: u32,
+    /// CRC-32 of the overwritten region once all patches were applied
+    pub checksum: u32,
+    /// Bytes of the region before this patch was applied
+    pub original_bytes: Vec<u8>,
+    /// Bytes of the region just after this patch was applied, before any later patch overwrote
+    /// them. Only known during the injection, so not written to the manifest.
+    #[serde(skip)]
+    pub written_bytes: Vec<u8>,
+}
+
+/// Whether a patched region of an XBE still matches the bytes written by the injection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchStatus {
+    Intact,
+    Modified,
+    /// The patched address is not mapped by the XBE
+    Missing,
+}
+
+impl PatchReport {
+    /// Checks the region of `xbe` this patch wrote to against its recorded checksum
+    pub fn status(&self, xbe: &Xbe) -> PatchStatus {
+        match patch::byte_range(self.virtual_address, self.length as usize)
+            .and_then(|range| patch::read(xbe, range))
+        {
+            Ok(bytes) if crc32(&bytes) == self.checksum => PatchStatus::Intact,
+            Ok(_) => PatchStatus::Modified,
+            Err(_) => PatchStatus::Missing,
+        }
+    }
+}
+
+impl InjectionReport {
+    /// Parses a manifest written by [`to_manifest`](InjectionReport::to_manifest)
+    pub fn from_manifest(manifest: &str) -> Result<Self> {
+        Ok(toml::from_str(manifest)?)
+    }
+
+    /// Serializes this report as a toml formatted manifest
+    pub fn to_manifest(&self) -> Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+
+    /// Checks every patch in this report against `xbe`, in the order they were applied
+    pub fn verify(&self, xbe: &Xbe) -> Vec<(&PatchReport, PatchStatus)> {
+        self.patches.iter().map(|p| (p, p.status(xbe))).collect()
+    }
+}
+
+impl Display for InjectionReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sections = self
+            .sections
+            .iter()
+            .map(|sec| format!("{} bytes {}", sec.size, sec.name))
+            .join(", ");
+        write!(
+            f,
+            "Injected {} sections ({sections}) and applied {} patches.",
+            self.sections.len(),
+            self.patches.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn section(name: &str, virtual_address: u32, size: usize) -> SectionReport {
+        SectionReport {
+            name: name.to_string(),
+            virtual_address,
+            size,
+        }
+    }
+
+    #[test]
+    fn summary() {
+        let report = InjectionReport {
+            original_checksum: 0,
+            original_entry_point: None,
+            original_tls_address: None,
+            original_library_versions: None,
+            sections: vec![
+                section(".mtext", 0x3000, 1024),
+                section(".mdata", 0x4000, 256),
+            ],
+            patches: vec![
+                PatchReport {
+                    sequence: 0,
+                    name: "_a".to_string(),
+                    virtual_address: 0x1000,
+                    length: 5,
+                    checksum: 0,
+                    original_bytes: vec![0; 5],
+                    written_bytes: Vec::new(),
+                },
+                PatchReport {
+                    sequence: 1,
+                    name: "_b".to_string(),
+                    virtual_address: 0x2000,
+                    length: 5,
+                    checksum: 0,
+                    original_bytes: vec![0; 5],
+                    written_bytes: Vec::new(),
+                },
+            ],
+        };
+        assert_eq!(
+            report.to_string(),
+            "Injected 2 sections (1024 bytes .mtext, 256 bytes .mdata) and applied 2 patches."
+        );
+    }
+
+    #[test]
+    fn manifest_round_trip() -> Result<()> {
+        let report = InjectionReport {
+            original_checksum: 0xDEAD_BEEF,
+            original_entry_point: Some(0xA8FC_57AB ^ 0x11000),
+            original_tls_address: Some(0x2_0000),
+            original_library_versions: Some(vec![LibraryVersionReport {
+                name: *b"XAPILIB\0",
+                major: 1,
+                minor: 0,
+                build: 5849,
+                flags: 0x4000,
+            }]),
+            sections: vec![section(".mdata", 0x4000, 8), section(".mtext", 0x3000, 20)],
+            patches: vec![PatchReport {
+                sequence: 0,
+                name: "_framehook_patch".to_string(),
+                virtual_address: 396158,
+                length: 5,
+                checksum: 0x1234_5678,
+                original_bytes: vec![0xA1, 0xC0, 0x2A, 0x37, 0x00],
+                written_bytes: Vec::new(),
+            }],
+        };
+
+        let parsed = InjectionReport::from_manifest(&report.to_manifest()?)?;
+        assert_eq!(parsed.original_checksum, report.original_checksum);
+        assert_eq!(parsed.original_entry_point, report.original_entry_point);
+        assert_eq!(parsed.original_tls_address, report.original_tls_address);
+        assert_eq!(
+            parsed.original_library_versions,
+            report.original_library_versions
+        );
+        assert_eq!(parsed.sections, report.sections);
+        assert_eq!(parsed.patches, report.patches);
+        Ok(())
+    }
+}
@@ -0,0 +1,1542 @@
+use crate::{
+    header,
+    image::{digest_statuses, DigestStatus},
+    reloc::strip_null,
+    validate::{validate_xbe, ValidationIssue},
+};
+use anyhow::{bail, Result};
+use log::debug;
+use std::ops::Range;
+use thiserror::Error;
+use xbe::{Section, SectionFlags, Xbe};
+
+#[derive(Debug, Error)]
+pub enum SectionError {
+    #[error("Can't remove section '{0}', the {1} at {2:#x} points into it")]
+    Referenced(String, &'static str, u32),
+    #[error("Serialized XBE is truncated, it ends before offset {0:#x}")]
+    Truncated(usize),
+    #[error("Section headers at {0:#x} are below the base address {1:#x}")]
+    HeadersBelowBase(u32, u32),
+    #[error("No section is named '{0}'")]
+    NotFound(String),
+    #[error("{1} bytes of data don't fit in section '{0}' of virtual size {2:#x}")]
+    DataExceedsVirtualSize(String, usize, u32),
+    #[error("Section '{0}' would overlap section '{1}' once loaded")]
+    VirtualOverlap(String, String),
+    #[error("No section is at {0:#x}, the virtual address of a serialized section header")]
+    UnmatchedHeader(u32),
+    #[error("Section name '{0}' is {1} bytes, but at most {MAX_SECTION_NAME_LEN} are allowed")]
+    NameTooLong(String, usize),
+    #[error("Section name {0:?} contains a null byte")]
+    NameContainsNull(String),
+    #[error("Section name at file offset {0:#x} isn't valid UTF-8")]
+    NonUtf8Name(usize),
+    #[error("Section name is empty")]
+    EmptyName,
+    #[error("XBE already has a section named '{0}'")]
+    DuplicateName(String),
+    #[error(
+        "Can't write {2} bytes at offset {1:#x} of section '{0}', it has {3:#x} bytes of data"
+    )]
+    WriteOutOfBounds(String, u32, usize, usize),
+}
+
+/// Sections to remove with [`XbeExt::strip_sections`]
+#[derive(Debug, Clone, Copy)]
+pub enum StripSections<'a> {
+    /// Every section whose name starts with the prefix, such as `.m` for injected sections
+    Prefix(&'a str),
+    /// The sections with these names, which may or may not be null terminated
+    Names(&'a [&'a str]),
+}
+
+impl StripSections<'_> {
+    fn matches(&self, section: &Section) -> bool {
+        let name = strip_null(&section.name);
+        match self {
+            StripSections::Prefix(prefix) => name.starts_with(prefix),
+            StripSections::Names(names) => names.iter().any(|n| strip_null(n) == name),
+        }
+    }
+}
+
+/// Most bytes a section name may have, not counting its null terminator
+pub const MAX_SECTION_NAME_LEN: usize = 255;
+
+/// Checks `name` can be stored as a section name: at most [`MAX_SECTION_NAME_LEN`] bytes,
+/// without any null bytes besides an optional terminator
+pub fn check_section_name(name: &str) -> Result<()> {
+    let name = name.strip_suffix('\0').unwrap_or(name);
+    if name.contains('\0') {
+        bail!(SectionError::NameContainsNull(name.to_string()));
+    }
+    if name.len() > MAX_SECTION_NAME_LEN {
+        bail!(SectionError::NameTooLong(name.to_string(), name.len()));
+    }
+    Ok(())
+}
+
+/// Size of a section header in a serialized XBE
+pub(crate) const SECTION_HEADER_SIZE: usize = 0x38;
+/// Alignment of section data in a serialized XBE
+pub(crate) const RAW_ALIGNMENT: usize = 0x1000;
+
+/// A section header of a serialized XBE
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawSectionHeader {
+    /// File offset of the header itself
+    pub offset: usize,
+    pub flags: u32,
+    pub virtual_address: u32,
+    pub virtual_size: u32,
+    /// File offset of the section's data
+    pub raw_address: u32,
+    pub raw_size: u32,
+    /// Virtual address of the section's null terminated name, within the headers
+    pub name_address: u32,
+    /// Virtual address of the reference count of the page the section starts on
+    pub head_page_ref_address: u32,
+    /// Virtual address of the reference count of the page the section ends on
+    pub tail_page_ref_address: u32,
+}
+
+impl RawSectionHeader {
+    /// File offsets of the section's data
+    pub fn raw_range(&self) -> Range<u32> {
+        self.raw_address..self.raw_address.saturating_add(self.raw_size)
+    }
+}
+
+/// The image header fields and section headers of a serialized XBE, including the file offsets
+/// of its sections, which are only assigned when it's serialized
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawLayout {
+    /// Virtual address the image is loaded at
+    pub base_address: u32,
+    /// Size of the headers loaded at the base address
+    pub size_of_headers: u32,
+    /// Size of the image once loaded, from the base address to the end of the last section
+    pub size_of_image: u32,
+    /// Section headers, in the order they're stored
+    pub sections: Vec<RawSectionHeader>,
+}
+
+impl RawLayout {
+    /// Reads the layout of the serialized XBE `image`
+    pub fn parse(image: &[u8]) -> Result<Self> {
+        let base_address = read_u32(image, 0x104)?;
+        let headers_address = read_u32(image, 0x120)?;
+        let headers = match headers_address.checked_sub(base_address) {
+            Some(offset) => offset as usize,
+            None => bail!(SectionError::HeadersBelowBase(
+                headers_address,
+                base_address
+            )),
+        };
+        let sections = (0..read_u32(image, 0x11C)? as usize)
+            .map(|i| {
+                let offset = headers + i * SECTION_HEADER_SIZE;
+                Ok(RawSectionHeader {
+                    offset,
+                    flags: read_u32(image, offset)?,
+                    virtual_address: read_u32(image, offset + 0x4)?,
+                    virtual_size: read_u32(image, offset + 0x8)?,
+                    raw_address: read_u32(image, offset + 0xC)?,
+                    raw_size: read_u32(image, offset + 0x10)?,
+                    name_address: read_u32(image, offset + 0x14)?,
+                    head_page_ref_address: read_u32(image, offset + 0x1C)?,
+                    tail_page_ref_address: read_u32(image, offset + 0x20)?,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            base_address,
+            size_of_headers: read_u32(image, 0x108)?,
+            size_of_image: read_u32(image, 0x10C)?,
+            sections,
+        })
+    }
+
+    /// Serializes `xbe` and reads the layout it's given
+    pub fn of(xbe: &Xbe) -> Result<Self> {
+        Self::parse(&header::serialize(xbe)?)
+    }
+
+    /// The header of the section whose raw data contains the file offset `raw_address`
+    pub fn section_at(&self, raw_address: u32) -> Option<&RawSectionHeader> {
+        self.sections
+            .iter()
+            .find(|s| s.raw_range().contains(&raw_address))
+    }
+
+    /// File offset of the end of the last section's data, or of the headers if there are no
+    /// sections
+    pub fn raw_end(&self) -> u32 {
+        self.sections
+            .iter()
+            .map(|s| s.raw_range().end)
+            .fold(self.size_of_headers, u32::max)
+    }
+
+    /// File offset the data of a section added after the others would start at, the end of the
+    /// last section's data rounded up to a page. The end is found from each section's raw size,
+    /// the length of its data, which may differ from its virtual size.
+    pub fn next_raw_address(&self) -> u32 {
+        self.raw_end()
+            .checked_next_multiple_of(RAW_ALIGNMENT as u32)
+            .unwrap_or(u32::MAX)
+    }
+
+    /// The page aligned gaps in the file between the end of the headers and the data of the last
+    /// section that no section's data is stored in, in file order
+    pub fn raw_gaps(&self) -> Vec<Range<u32>> {
+        let mut ranges: Vec<_> = self
+            .sections
+            .iter()
+            .map(RawSectionHeader::raw_range)
+            .filter(|r| !r.is_empty())
+            .collect();
+        ranges.sort_by_key(|r| r.start);
+
+        let mut gaps = Vec::new();
+        let mut end = self.size_of_headers;
+        for range in ranges {
+            let Some(start) = end.checked_next_multiple_of(RAW_ALIGNMENT as u32) else {
+                break;
+            };
+            if range.start > start {
+                gaps.push(start..range.start);
+            }
+            end = end.max(range.end);
+        }
+        gaps
+    }
+
+    /// Whether the raw data of any two sections overlaps
+    pub fn raw_overlap(&self) -> bool {
+        let mut ranges: Vec<_> = self
+            .sections
+            .iter()
+            .map(RawSectionHeader::raw_range)
+            .filter(|r| !r.is_empty())
+            .collect();
+        ranges.sort_by_key(|r| r.start);
+        ranges.windows(2).any(|w| w[1].start < w[0].end)
+    }
+}
+
+/// The little endian `u32` at `offset` of the serialized XBE `image`
+pub(crate) fn read_u32(image: &[u8], offset: usize) -> Result<u32> {
+    match image.get(offset..offset.saturating_add(4)) {
+        Some(bytes) => Ok(u32::from_le_bytes(bytes.try_into()?)),
+        None => bail!(SectionError::Truncated(offset.saturating_add(4))),
+    }
+}
+
+/// Replaces the little endian `u32` at `offset` of the serialized XBE `image`
+pub(crate) fn write_u32(image: &mut [u8], offset: usize, value: u32) -> Result<()> {
+    match image.get_mut(offset..offset.saturating_add(4)) {
+        Some(bytes) => bytes.copy_from_slice(&value.to_le_bytes()),
+        None => bail!(SectionError::Truncated(offset.saturating_add(4))),
+    }
+    Ok(())
+}
+
+/// Lookup of the sections of an XBE by name or address, and changes to them that keep the XBE
+/// consistent. The `_mut` lookups give unchecked access to a section's fields, which can leave it
+/// in a state that only fails once serialized. Prefer [`SectionExt::write_at`],
+/// [`XbeExt::write_virtual`] and [`XbeExt::set_section_virtual_size`], which check their bounds.
+pub trait XbeExt {
+    /// Each section of the XBE, in the order they're stored
+    fn sections(&self) -> impl Iterator<Item = &Section>;
+
+    /// Each section of the XBE, in the order they're stored
+    fn sections_mut(&mut self) -> impl Iterator<Item = &mut Section>;
+
+    /// The section named `name`. Stored names are null terminated, `name` may or may not be.
+    fn section_by_name(&self, name: &str) -> Option<&Section>;
+
+    /// The section named `name`. Stored names are null terminated, `name` may or may not be.
+    fn section_by_name_mut(&mut self, name: &str) -> Option<&mut Section>;
+
+    /// The section whose virtual address range contains `virtual_address`
+    fn section_containing(&self, virtual_address: u32) -> Option<&Section>;
+
+    /// The section whose virtual address range contains `virtual_address`
+    fn section_containing_mut(&mut self, virtual_address: u32) -> Option<&mut Section>;
+
+    /// Alias of [`XbeExt::section_containing`]
+    fn section_at(&self, virtual_address: u32) -> Option<&Section> {
+        self.section_containing(virtual_address)
+    }
+
+    /// The data of the section containing the start of `range`, if that data holds every byte of
+    /// it. The range is checked here as the half-open `range` within the section's data, so no
+    /// read depends on how [`Xbe::get_bytes`] treats a range ending at the end of a section.
+    fn bytes_at(&self, range: Range<u32>) -> Option<&[u8]>;
+
+    /// Writes `bytes` over the data of the section containing `virtual_address` with
+    /// [`SectionExt::write_at`], returning the bytes they replaced. Returns `None`, writing
+    /// nothing, unless the data of that one section holds every byte.
+    fn write_virtual(&mut self, virtual_address: u32, bytes: &[u8]) -> Option<Vec<u8>>;
+
+    /// Replaces the virtual size of the section named `name`, which must still hold its data
+    /// without overlapping another section once loaded
+    fn set_section_virtual_size(&mut self, name: &str, virtual_size: u32) -> Result<()>;
+
+    /// The section whose raw data contains the file offset `raw_address` in `layout`, the
+    /// [`RawLayout`] read from this XBE once serialized. Reading the layout once lets many raw
+    /// addresses be looked up without serializing the XBE for each.
+    fn section_at_raw(&self, layout: &RawLayout, raw_address: u32) -> Option<&Section>;
+
+    /// Problems that may stop the XBE from loading, see [`validate_xbe`], followed by a
+    /// [`ValidationIssue::DigestMismatch`] for each section whose data doesn't match its stored
+    /// digest, see [`XbeExt::verify_digests`]
+    fn validate(&self) -> Vec<ValidationIssue>;
+
+    /// Whether the SHA-1 digest stored for each section matches its data, by section name in the
+    /// order they're stored. Vanilla XBEs match, while sections changed since their digest was
+    /// written, by patches or otherwise, mismatch. The stored digests are read from the XBE
+    /// serialized, see [`XbeImage::verify_digests`](crate::image::XbeImage::verify_digests) to
+    /// check the digests of a loaded image without serializing it.
+    fn verify_digests(&self) -> Result<Vec<(String, DigestStatus)>>;
+
+    /// Adds a section like [`Xbe::add_section`], adding the null terminator to `name` if it's
+    /// missing. Unlike [`Xbe::add_section`], which adds anything, the section is refused if its
+    /// name is empty, fails [`check_section_name`], or is already used by another section, or if
+    /// it would overlap another section once loaded. If the headers would grow into the data of
+    /// the first section, the data of every section is moved up by whole pages to make room.
+    fn try_add_section(
+        &mut self,
+        name: &str,
+        flags: SectionFlags,
+        data: Vec<u8>,
+        virtual_address: u32,
+        virtual_size: u32,
+    ) -> Result<()>;
+
+    /// Removes the sections matching `sections`, returning them in their original order. The data
+    /// of later sections is moved up to fill the space they took in the file, and the image size
+    /// is recomputed when the XBE is serialized.
+    ///
+    /// Sections that the entry point, TLS address, or kernel thunk address point into are
+    /// refused, leaving the XBE unchanged. Bytes of other sections overwritten by patches are not
+    /// restored, see [`restore`](crate::restore) for undoing an injection completely.
+    fn strip_sections(&mut self, sections: StripSections<'_>) -> Result<Vec<Section>>;
+
+    /// Removes the section named `name` and returns it. Stored names are null terminated, `name`
+    /// may or may not be. The data of later sections is moved up to fill the space it took in the
+    /// file, and the image size is recomputed when the XBE is serialized.
+    ///
+    /// Like [`XbeExt::strip_sections`], the section is refused if the entry point, TLS address, or
+    /// kernel thunk address points into it.
+    fn remove_section(&mut self, name: &str) -> Result<Section>;
+
+    /// Replaces the data of the section named `name`, returning the data it replaced. The section's
+    /// virtual size is replaced too if `virtual_size` is given, and must still hold the data
+    /// without overlapping another section.
+    ///
+    /// The data of later sections is moved out of the way when the XBE is serialized with
+    /// [`header::serialize`] if the new data overlaps it.
+    fn replace_section_data(
+        &mut self,
+        name: &str,
+        data: Vec<u8>,
+        virtual_size: Option<u32>,
+    ) -> Result<Vec<u8>>;
+
+    /// Moves the data of each section named in `names`, in order, into the first gap in the file
+    /// that holds it, see [`RawLayout::raw_gaps`], returning the file offset each was moved to.
+    /// [`Xbe::add_section`] always stores data after every other section, leaving the gaps the
+    /// input XBE has between its sections empty. The sections keep their virtual addresses, and
+    /// the data of later sections is moved up to fill the space theirs took. A section is left
+    /// where it is, with `None` returned for it, if no gap before its data is large enough. The
+    /// gaps are found once, so the space a moved section leaves is never reused.
+    fn move_to_raw_gaps(&mut self, names: &[&str]) -> Result<Vec<Option<u32>>>;
+
+    /// Adds `data` as a file inserted into the XBE, the way title images are stored: a section
+    /// flagged [`SectionFlags::INSERTED_FILE`] alone, which the kernel neither loads nor runs. It's
+    /// given the next free virtual address, so it overlaps no loaded section, but isn't counted in
+    /// the size of image by [`header::serialize`] unless loaded sections are added after it. The
+    /// name is checked like [`XbeExt::try_add_section`] checks it. Returns the virtual address of
+    /// the section.
+    fn add_inserted_file(&mut self, name: &str, data: Vec<u8>) -> Result<u32>;
+}
+
+impl XbeExt for Xbe {
+    fn sections(&self) -> impl Iterator<Item = &Section> {
+        self.sections.iter()
+    }
+
+    fn sections_mut(&mut self) -> impl Iterator<Item = &mut Section> {
+        self.sections.iter_mut()
+    }
+
+    fn section_by_name(&self, name: &str) -> Option<&Section> {
+        self.sections
+            .iter()
+            .find(|s| strip_null(&s.name) == strip_null(name))
+    }
+
+    fn section_by_name_mut(&mut self, name: &str) -> Option<&mut Section> {
+        self.sections
+            .iter_mut()
+            .find(|s| strip_null(&s.name) == strip_null(name))
+    }
+
+    fn section_containing(&self, virtual_address: u32) -> Option<&Section> {
+        self.sections
+            .iter()
+            .find(|s| s.virtual_range().contains(&virtual_address))
+    }
+
+    fn section_containing_mut(&mut self, virtual_address: u32) -> Option<&mut Section> {
+        self.sections
+            .iter_mut()
+            .find(|s| s.virtual_range().contains(&virtual_address))
+    }
+
+    fn section_at_raw(&self, layout: &RawLayout, raw_address: u32) -> Option<&Section> {
+        let header = layout.section_at(raw_address)?;
+        self.sections
+            .iter()
+            .find(|s| s.virtual_address == header.virtual_address)
+    }
+
+    fn bytes_at(&self, range: Range<u32>) -> Option<&[u8]> {
+        let section = self.section_containing(range.start)?;
+        let start = range.start - section.virtual_address;
+        let end = range.end.checked_sub(section.virtual_address)?;
+        section.data.get(start as usize..end as usize)
+    }
+
+    fn write_virtual(&mut self, virtual_address: u32, bytes: &[u8]) -> Option<Vec<u8>> {
+        let section = self.section_containing_mut(virtual_address)?;
+        let offset = virtual_address - section.virtual_address;
+        section.write_at(offset, bytes).ok()
+    }
+
+    fn set_section_virtual_size(&mut self, name: &str, virtual_size: u32) -> Result<()> {
+        let index = section_index(self, name)?;
+        check_virtual_size(self, index, self.sections[index].data.len(), virtual_size)?;
+        self.sections[index].virtual_size = virtual_size;
+        Ok(())
+    }
+
+    fn validate(&self) -> Vec<ValidationIssue> {
+        let mut issues = validate_xbe(self);
+        // an XBE that can't be serialized has no stored digests to check
+        let statuses = self.verify_digests().unwrap_or_default();
+        issues.extend(statuses.into_iter().filter_map(|(name, status)| {
+            matches!(status, DigestStatus::Mismatch { .. })
+                .then_some(ValidationIssue::DigestMismatch(name))
+        }));
+        issues
+    }
+
+    fn verify_digests(&self) -> Result<Vec<(String, DigestStatus)>> {
+        digest_statuses(&header::serialize(self)?)?
+            .into_iter()
+            .map(|(virtual_address, status)| {
+                let Some(section) = self
+                    .sections
+                    .iter()
+                    .find(|s| s.virtual_address == virtual_address)
+                else {
+                    bail!(SectionError::UnmatchedHeader(virtual_address));
+                };
+                Ok((strip_null(&section.name).to_string(), status))
+            })
+            .collect()
+    }
+
+    fn try_add_section(
+        &mut self,
+        name: &str,
+        flags: SectionFlags,
+        data: Vec<u8>,
+        virtual_address: u32,
+        virtual_size: u32,
+    ) -> Result<()> {
+        check_section_name(name)?;
+        let name = strip_null(name);
+        if name.is_empty() {
+            bail!(SectionError::EmptyName);
+        }
+        if self.section_by_name(name).is_some() {
+            bail!(SectionError::DuplicateName(name.to_string()));
+        }
+        let end = virtual_address.saturating_add(virtual_size);
+        let overlapped = self.sections.iter().find(|s| {
+            let range = s.virtual_range();
+            range.start < end && virtual_address < range.end
+        });
+        if let Some(other) = overlapped {
+            bail!(SectionError::VirtualOverlap(
+                name.to_string(),
+                strip_null(&other.name).to_string()
+            ));
+        }
+        // A section header, the null terminated name, and the reference counts of the pages the
+        // section starts and ends on
+        make_header_room(self, (SECTION_HEADER_SIZE + name.len() + 1 + 4) as u32)?;
+
+        self.add_section(
+            format!("{name}\0"),
+            flags,
+            data,
+            virtual_address,
+            virtual_size,
+        );
+        Ok(())
+    }
+
+    fn strip_sections(&mut self, sections: StripSections<'_>) -> Result<Vec<Section>> {
+        for section in self.sections.iter().filter(|s| sections.matches(s)) {
+            check_unreferenced(self, section)?;
+        }
+
+        remove_sections(self, |s| sections.matches(s))
+    }
+
+    fn remove_section(&mut self, name: &str) -> Result<Section> {
+        let index = section_index(self, name)?;
+        check_unreferenced(self, &self.sections[index])?;
+        let address = self.sections[index].virtual_address;
+        let mut removed = remove_sections(self, |s| s.virtual_address == address)?;
+        Ok(removed.remove(0))
+    }
+
+    fn replace_section_data(
+        &mut self,
+        name: &str,
+        data: Vec<u8>,
+        virtual_size: Option<u32>,
+    ) -> Result<Vec<u8>> {
+        let index = section_index(self, name)?;
+        let virtual_size = virtual_size.unwrap_or(self.sections[index].virtual_size);
+        check_virtual_size(self, index, data.len(), virtual_size)?;
+
+        let section = &mut self.sections[index];
+        section.virtual_size = virtual_size;
+        Ok(std::mem::replace(&mut section.data, data))
+    }
+
+    fn move_to_raw_gaps(&mut self, names: &[&str]) -> Result<Vec<Option<u32>>> {
+        let addresses = names
+            .iter()
+            .map(|&name| Ok(self.sections[section_index(self, name)?].virtual_address))
+            .collect::<Result<Vec<_>>>()?;
+        let mut image = header::serialize(self)?;
+        let layout = RawLayout::parse(&image)?;
+        let mut gaps = layout.raw_gaps();
+
+        let mut moved = Vec::with_capacity(addresses.len());
+        let mut vacated = Vec::new();
+        for address in addresses {
+            let Some(header) = layout
+                .sections
+                .iter()
+                .find(|s| s.virtual_address == address)
+            else {
+                bail!(SectionError::UnmatchedHeader(address));
+            };
+            let size = header
+                .raw_size
+                .checked_next_multiple_of(RAW_ALIGNMENT as u32)
+                .filter(|&size| size > 0);
+            let gap = size.and_then(|size| {
+                gaps.iter_mut()
+                    .find(|g| g.start < header.raw_address && g.end.saturating_sub(g.start) >= size)
+            });
+            let (Some(size), Some(gap)) = (size, gap) else {
+                moved.push(None);
+                continue;
+            };
+
+            let old = header.raw_range();
+            if old.end as usize > image.len() {
+                bail!(SectionError::Truncated(old.end as usize));
+            }
+            image.copy_within(old.start as usize..old.end as usize, gap.start as usize);
+            write_u32(&mut image, header.offset + 0xC, gap.start)?;
+            moved.push(Some(gap.start));
+            vacated.push(old);
+            gap.start += size;
+        }
+
+        if !vacated.is_empty() {
+            *self = Xbe::new(&close_raw_gaps(&image, &vacated)?)?;
+        }
+        Ok(moved)
+    }
+
+    fn add_inserted_file(&mut self, name: &str, data: Vec<u8>) -> Result<u32> {
+        let virtual_address = self.get_next_virtual_address();
+        let virtual_size = u32::try_from(data.len())?;
+        self.try_add_section(
+            name,
+            SectionFlags::INSERTED_FILE,
+            data,
+            virtual_address,
+            virtual_size,
+        )?;
+        Ok(virtual_address)
+    }
+}
+
+/// Moves the data of the sections of `xbe` up by whole pages if its headers, grown by `growth`
+/// bytes, would reach the data of its first section, see [`header::serialize`]. Section headers
+/// and virtual addresses are untouched.
+fn make_header_room(xbe: &mut Xbe, growth: u32) -> Result<()> {
+    let mut image = header::serialize(xbe)?;
+    let layout = RawLayout::parse(&image)?;
+    let Some(first) = layout
+        .sections
+        .iter()
+        .filter(|s| s.raw_size > 0)
+        .map(|s| s.raw_address)
+        .min()
+    else {
+        return Ok(());
+    };
+    let end = layout.size_of_headers.saturating_add(growth);
+    if end <= first {
+        return Ok(());
+    }
+
+    let shift = (end - first).next_multiple_of(RAW_ALIGNMENT as u32);
+    debug!("Moving section data up {shift:#x} bytes to make room for the headers");
+    for header in layout.sections.iter().filter(|s| s.raw_address >= first) {
+        let raw_address = header.raw_address.saturating_add(shift);
+        write_u32(&mut image, header.offset + 0xC, raw_address)?;
+    }
+    let first = first as usize;
+    image.splice(first..first, std::iter::repeat(0).take(shift as usize));
+    *xbe = Xbe::new(&image)?;
+    Ok(())
+}
+
+/// Index of the section of `xbe` named `name`, which may or may not be null terminated
+fn section_index(xbe: &Xbe, name: &str) -> Result<usize> {
+    match xbe
+        .sections
+        .iter()
+        .position(|s| strip_null(&s.name) == strip_null(name))
+    {
+        Some(index) => Ok(index),
+        None => bail!(SectionError::NotFound(strip_null(name).to_string())),
+    }
+}
+
+/// Checks the section at `index` of `xbe` can hold `data_len` bytes of data in `virtual_size`
+/// bytes without overlapping another section once loaded
+fn check_virtual_size(xbe: &Xbe, index: usize, data_len: usize, virtual_size: u32) -> Result<()> {
+    let section = &xbe.sections[index];
+    let name = strip_null(&section.name);
+    if data_len > virtual_size as usize {
+        bail!(SectionError::DataExceedsVirtualSize(
+            name.to_string(),
+            data_len,
+            virtual_size
+        ));
+    }
+    let start = section.virtual_address;
+    let end = start.saturating_add(virtual_size);
+    let overlapped = xbe.sections.iter().enumerate().find(|&(i, s)| {
+        let range = s.virtual_range();
+        i != index && range.start < end && start < range.end
+    });
+    if let Some((_, other)) = overlapped {
+        bail!(SectionError::VirtualOverlap(
+            name.to_string(),
+            strip_null(&other.name).to_string()
+        ));
+    }
+    Ok(())
+}
+
+/// Removes the sections of `xbe` that `filter` matches, returning them in their original order.
+/// The XBE is serialized once with the removed sections left without data, the data of the others
+/// is moved up over the space theirs took, and the result is parsed again.
+fn remove_sections(xbe: &mut Xbe, filter: impl Fn(&Section) -> bool) -> Result<Vec<Section>> {
+    let addresses: Vec<_> = xbe
+        .sections
+        .iter()
+        .filter(|s| filter(s))
+        .map(|s| s.virtual_address)
+        .collect();
+    if addresses.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut image = header::serialize(xbe)?;
+    let mut removed = Vec::new();
+    for header in RawLayout::parse(&image)?
+        .sections
+        .iter()
+        .filter(|h| addresses.contains(&h.virtual_address))
+    {
+        removed.push(header.raw_range());
+        write_u32(&mut image, header.offset + 0xC, 0)?;
+        write_u32(&mut image, header.offset + 0x10, 0)?;
+    }
+    let mut closed = Xbe::new(&close_raw_gaps(&image, &removed)?)?;
+    closed
+        .sections
+        .retain(|s| !addresses.contains(&s.virtual_address));
+
+    Ok(std::mem::replace(xbe, closed)
+        .sections
+        .into_iter()
+        .filter(|s| addresses.contains(&s.virtual_address))
+        .collect())
+}
+
+/// Copies the serialized XBE `image` with the data of its sections moved up over the `removed`
+/// file ranges, which no section's data is stored in any more. Whatever is between the other
+/// sections' data is kept.
+fn close_raw_gaps(image: &[u8], removed: &[Range<u32>]) -> Result<Vec<u8>> {
+    let layout = RawLayout::parse(image)?;
+    let mut headers: Vec<_> = layout.sections.iter().filter(|s| s.raw_size > 0).collect();
+    headers.sort_by_key(|s| s.raw_address);
+
+    // The space before the first removed range is kept
+    let first_removed = |end: u32, before: u32| {
+        removed
+            .iter()
+            .filter(|r| r.start >= end && r.end <= before)
+            .map(|r| r.start)
+            .min()
+            .unwrap_or(before)
+            .max(end)
+    };
+    let mut closed = Vec::with_capacity(image.len());
+    let mut end = 0;
+    for header in headers {
+        let range = header.raw_range();
+        let Some(data) = image.get(range.start as usize..range.end as usize) else {
+            bail!(SectionError::Truncated(range.end as usize));
+        };
+
+        let gap = first_removed(end, header.raw_address);
+        let len = closed.len() + (gap - end) as usize;
+        closed.extend_from_slice(image.get(end as usize..gap as usize).unwrap_or_default());
+        closed.resize(len, 0);
+        write_u32(&mut closed, header.offset + 0xC, closed.len() as u32)?;
+        closed.extend_from_slice(data);
+        end = range.end;
+    }
+    let tail = first_removed(end, image.len() as u32) as usize;
+    closed.extend_from_slice(image.get(end as usize..tail).unwrap_or_default());
+    Ok(closed)
+}
+
+/// Fails with [`SectionError::Referenced`] if the entry point, TLS address, or kernel thunk
+/// address of `xbe` points into `section`, which can't be removed without breaking the XBE
+fn check_unreferenced(xbe: &Xbe, section: &Section) -> Result<()> {
+    let references = [
+        ("entry point", header::entry_point(xbe).ok()),
+        ("TLS address", Some(xbe.header.tls_address)),
+        (
+            "kernel thunk address",
+            header::kernel_thunk_address(xbe).ok(),
+        ),
+    ];
+    let referenced = references.iter().find_map(|&(field, address)| {
+        address
+            .filter(|a| section.virtual_range().contains(a))
+            .map(|a| (field, a))
+    });
+    if let Some((field, address)) = referenced {
+        bail!(SectionError::Referenced(
+            strip_null(&section.name).to_string(),
+            field,
+            address
+        ));
+    }
+    Ok(())
+}
+
+/// Section flag bit used by custom loaders to mark LZ compressed sections, unused by the XBE
+/// format itself
+pub const COMPRESSED_FLAG: u32 = 1 << 30;
+
+pub trait SectionExt {
+    /// Virtual addresses occupied by the section once loaded, ending at the top of the address
+    /// space for malformed sections that would run past it
+    fn virtual_range(&self) -> Range<u32>;
+
+    /// Whether the section's data is compressed, and so can't be patched in place
+    fn is_compressed(&self) -> bool;
+
+    /// Whether the section is a file inserted into the XBE, which the kernel doesn't load
+    fn is_inserted_file(&self) -> bool;
+
+    /// Writes `bytes` over the section's data at `offset`, returning the bytes they replaced.
+    /// Writes running past the end of the data are refused with
+    /// [`SectionError::WriteOutOfBounds`], as the data would have to grow without its virtual
+    /// size being checked.
+    fn write_at(&mut self, offset: u32, bytes: &[u8]) -> Result<Vec<u8>>;
+}
+
+impl SectionExt for Section {
+    fn virtual_range(&self) -> Range<u32> {
+        self.virtual_address..self.virtual_address.saturating_add(self.virtual_size)
+    }
+
+    fn is_compressed(&self) -> bool {
+        self.flags.bits() & COMPRESSED_FLAG != 0
+    }
+
+    fn is_inserted_file(&self) -> bool {
+        self.flags.contains(SectionFlags::INSERTED_FILE)
+    }
+
+    fn write_at(&mut self, offset: u32, bytes: &[u8]) -> Result<Vec<u8>> {
+        let start = offset as usize;
+        let Some(data) = self.data.get_mut(start..start.saturating_add(bytes.len())) else {
+            bail!(SectionError::WriteOutOfBounds(
+                strip_null(&self.name).to_string(),
+                offset,
+                bytes.len(),
+                self.data.len()
+            ));
+        };
+        let original = data.to_vec();
+        data.copy_from_slice(bytes);
+        Ok(original)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    type TestError = std::result::Result<(), Box<dyn std::error::Error>>;
+
+    #[test]
+    fn lookup() -> TestError {
+        let mut xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+
+        let text = xbe.section_by_name(".text").ok_or("No .text section")?;
+        assert_eq!(text.name, ".text\0");
+        let range = text.virtual_range();
+        assert!(range.contains(&396158));
+        assert_eq!(
+            xbe.section_by_name(".text\0").map(|s| s.virtual_address),
+            Some(range.start)
+        );
+        assert!(xbe.section_by_name(".txt").is_none());
+
+        // Sections are found by any address within them, and only those
+        let containing = |xbe: &Xbe, va| xbe.section_containing(va).map(|s| s.name.clone());
+        assert_eq!(containing(&xbe, 396158).as_deref(), Some(".text\0"));
+        assert_eq!(containing(&xbe, range.start).as_deref(), Some(".text\0"));
+        assert_ne!(containing(&xbe, range.end).as_deref(), Some(".text\0"));
+        assert_eq!(containing(&xbe, 0x10000), None);
+
+        xbe.section_containing_mut(396158)
+            .ok_or("No section containing patch address")?
+            .data[0] ^= 0xFF;
+        xbe.section_by_name_mut(".text")
+            .ok_or("No .text section")?
+            .data[0] ^= 0xFF;
+        assert_eq!(
+            xbe.section_by_name(".text").map(|s| s.data[0]),
+            Xbe::new(&fs::read("test/bin/default.xbe")?)?
+                .section_by_name(".text")
+                .map(|s| s.data[0])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn iteration() -> TestError {
+        let mut xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        assert_eq!(XbeExt::sections(&xbe).count(), xbe.sections.len());
+        assert!(XbeExt::sections(&xbe).any(|s| s.name == ".text\0"));
+        assert!(!XbeExt::sections(&xbe).any(|s| s.name == ".mtext\0"));
+
+        for section in xbe.sections_mut() {
+            section.data.clear();
+        }
+        assert!(XbeExt::sections(&xbe).all(|s| s.data.is_empty()));
+
+        assert_eq!(
+            xbe.section_at(396158).map(|s| s.name.as_str()),
+            Some(".text\0")
+        );
+        assert!(xbe.section_at(0x10000).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn virtual_range_overflow() -> TestError {
+        let mut xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        xbe.add_section(
+            ".top\0".to_string(),
+            xbe::SectionFlags::PRELOAD,
+            Vec::new(),
+            0xFFFF_F000,
+            0x2000,
+        );
+        let top = xbe.section_by_name(".top").ok_or("No .top section")?;
+        assert_eq!(top.virtual_range(), 0xFFFF_F000..u32::MAX);
+        Ok(())
+    }
+
+    #[test]
+    fn raw_lookup() -> TestError {
+        let xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let image = xbe.serialize()?;
+        let layout = RawLayout::parse(&image)?;
+        assert_eq!(layout.sections.len(), xbe.sections.len());
+
+        // Each section is found at the first and last byte of its raw data, which is its data
+        for header in layout.sections.iter().filter(|h| h.raw_size > 0) {
+            let range = header.raw_range();
+            let section = xbe
+                .section_at_raw(&layout, range.start)
+                .ok_or("Section start not found")?;
+            assert_eq!(section.virtual_address, header.virtual_address);
+            assert_eq!(
+                xbe.section_at_raw(&layout, range.end - 1).map(|s| &s.name),
+                Some(&section.name)
+            );
+            let data = image
+                .get(range.start as usize..range.start as usize + section.data.len())
+                .ok_or("Section data past the end of the image")?;
+            assert_eq!(section.data, data);
+        }
+
+        // The header isn't raw data of any section, nor is anything past the end of the file
+        assert!(xbe.section_at_raw(&layout, 0).is_none());
+        assert!(xbe.section_at_raw(&layout, image.len() as u32).is_none());
+
+        let err = RawLayout::parse(&image[..0x110]).expect_err("Parsed a truncated image");
+        assert!(matches!(
+            err.downcast_ref::<SectionError>(),
+            Some(SectionError::Truncated(0x124))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn replace_data() -> TestError {
+        let bytes = fs::read("test/bin/default.xbe")?;
+        let mut xbe = Xbe::new(&bytes)?;
+        let original = Xbe::new(&bytes)?;
+        let layout = RawLayout::parse(&header::serialize(&xbe)?)?;
+        let last_raw = layout.sections.iter().map(|s| s.raw_address).max();
+        let data_of = |xbe: &Xbe, name: &str| {
+            xbe.sections
+                .iter()
+                .find(|s| s.name == name)
+                .map(|s| s.data.clone())
+        };
+
+        // A section whose data is followed by another's, with room to grow once loaded
+        let header = layout
+            .sections
+            .iter()
+            .find(|s| Some(s.raw_address) != last_raw && s.virtual_size >= s.raw_size + 0x1000)
+            .ok_or("No section with room to grow")?;
+        let name = xbe
+            .sections
+            .iter()
+            .find(|s| s.virtual_address == header.virtual_address)
+            .ok_or("Section header without a section")?
+            .name
+            .clone();
+
+        let check = |xbe: &Xbe, data: &[u8]| -> TestError {
+            let image = header::serialize(xbe)?;
+            assert!(!RawLayout::parse(&image)?.raw_overlap());
+            let reloaded = Xbe::new(&image)?;
+            for section in original.sections.iter() {
+                let reloaded = data_of(&reloaded, &section.name).ok_or("Section lost")?;
+                if section.name == name {
+                    assert_eq!(reloaded, data);
+                } else {
+                    assert_eq!(reloaded, section.data);
+                }
+            }
+            Ok(())
+        };
+
+        let grown = vec![0x5A; header.raw_size as usize + 0x1000];
+        let replaced = xbe.replace_section_data(&name, grown.clone(), None)?;
+        assert_eq!(Some(replaced), data_of(&original, &name));
+        check(&xbe, &grown)?;
+
+        let shrunk = vec![0xA5; 0x10];
+        xbe.replace_section_data(&name, shrunk.clone(), Some(0x10))?;
+        assert_eq!(
+            xbe.sections
+                .iter()
+                .find(|s| s.name == name)
+                .map(|s| s.virtual_size),
+            Some(0x10)
+        );
+        check(&xbe, &shrunk)?;
+
+        let err = xbe
+            .replace_section_data(&name, vec![0; 0x11], None)
+            .expect_err("Data larger than the virtual size");
+        assert!(matches!(
+            err.downcast_ref::<SectionError>(),
+            Some(SectionError::DataExceedsVirtualSize(_, 0x11, 0x10))
+        ));
+        let err = xbe
+            .replace_section_data(&name, Vec::new(), Some(u32::MAX))
+            .expect_err("Virtual size overlapping a later section");
+        assert!(matches!(
+            err.downcast_ref::<SectionError>(),
+            Some(SectionError::VirtualOverlap(_, _))
+        ));
+        let err = xbe
+            .replace_section_data(".nothing", Vec::new(), None)
+            .expect_err("Missing section");
+        assert!(matches!(
+            err.downcast_ref::<SectionError>(),
+            Some(SectionError::NotFound(n)) if n == ".nothing"
+        ));
+        Ok(())
+    }
+    #[test]
+    fn next_raw_address() -> TestError {
+        let mut xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let layout = RawLayout::parse(&header::serialize(&xbe)?)?;
+        assert_eq!(layout.next_raw_address() % 0x1000, 0);
+        assert!(layout.next_raw_address() >= layout.raw_end());
+
+        // Sections whose data is shorter than their virtual size only take up their data
+        let address = xbe.get_next_virtual_address();
+        xbe.add_section(
+            ".mbss\0".to_string(),
+            xbe::SectionFlags::PRELOAD | xbe::SectionFlags::WRITABLE,
+            vec![1; 4],
+            address,
+            0x3000,
+        );
+        let layout = RawLayout::parse(&header::serialize(&xbe)?)?;
+        let added = layout
+            .sections
+            .iter()
+            .find(|s| s.virtual_address == address)
+            .ok_or("Added section is missing")?;
+        assert_eq!(added.raw_size, 4);
+        assert_eq!(layout.raw_end(), added.raw_address + 4);
+        assert_eq!(
+            layout.next_raw_address(),
+            (added.raw_address + 4).next_multiple_of(0x1000)
+        );
+
+        let empty = RawLayout {
+            base_address: 0x10000,
+            size_of_headers: 0x800,
+            size_of_image: 0x800,
+            sections: Vec::new(),
+        };
+        assert_eq!(empty.raw_end(), 0x800);
+        assert_eq!(empty.next_raw_address(), 0x1000);
+        Ok(())
+    }
+    #[test]
+    fn remove() -> TestError {
+        let mut xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let original = header::serialize(&xbe)?;
+
+        let address = xbe.get_next_virtual_address();
+        xbe.add_section(
+            ".mtext\0".to_string(),
+            xbe::SectionFlags::PRELOAD | xbe::SectionFlags::EXECUTABLE,
+            vec![0xCC; 0x1800],
+            address,
+            0x1800,
+        );
+        assert_ne!(header::serialize(&xbe)?, original);
+        let removed = xbe.remove_section(".mtext")?;
+        assert_eq!(removed.virtual_address, address);
+        assert_eq!(removed.data, vec![0xCC; 0x1800]);
+        assert_eq!(header::serialize(&xbe)?, original);
+
+        let err = xbe
+            .remove_section(".mtext")
+            .expect_err("Removed a missing section");
+        assert!(matches!(
+            err.downcast_ref::<SectionError>(),
+            Some(SectionError::NotFound(n)) if n == ".mtext"
+        ));
+
+        // The section the entry point is in is kept
+        let entry_point = header::entry_point(&xbe)?;
+        let name = xbe
+            .section_containing(entry_point)
+            .map(|s| s.name.clone())
+            .ok_or("No section containing the entry point")?;
+        let err = xbe
+            .remove_section(&name)
+            .expect_err("Removed the entry point's section");
+        assert!(matches!(
+            err.downcast_ref::<SectionError>(),
+            Some(SectionError::Referenced(_, "entry point", a)) if *a == entry_point
+        ));
+        assert_eq!(header::serialize(&xbe)?, original);
+        Ok(())
+    }
+    #[test]
+    fn remove_middle() -> TestError {
+        let bytes = fs::read("test/bin/default.xbe")?;
+        let original = Xbe::new(&bytes)?;
+        let before = RawLayout::of(&original)?;
+        let mut raw: Vec<_> = before.sections.iter().filter(|s| s.raw_size > 0).collect();
+        raw.sort_by_key(|s| s.raw_address);
+
+        // A section with data before and after it in the file, that nothing points into
+        let (removed_header, next) = raw
+            .windows(2)
+            .skip(1)
+            .map(|w| (w[0], w[1]))
+            .find(|(h, _)| {
+                original
+                    .sections
+                    .iter()
+                    .find(|s| s.virtual_address == h.virtual_address)
+                    .is_some_and(|s| check_unreferenced(&original, s).is_ok())
+            })
+            .ok_or("No removable section between two others")?;
+        let name = original
+            .sections
+            .iter()
+            .find(|s| s.virtual_address == removed_header.virtual_address)
+            .map(|s| s.name.clone())
+            .ok_or("Section header has no section")?;
+        let mut xbe = Xbe::new(&bytes)?;
+        xbe.remove_section(&name)?;
+
+        let image = header::serialize(&xbe)?;
+        let after = RawLayout::parse(&image)?;
+        let reloaded = Xbe::new(&image)?;
+        assert_eq!(reloaded.sections.len(), original.sections.len() - 1);
+        assert_eq!(after.size_of_image, before.size_of_image);
+        assert!(image.len() < header::serialize(&original)?.len());
+
+        // The other sections keep their virtual addresses and data
+        for section in original.sections.iter().filter(|s| s.name != name) {
+            let kept = reloaded
+                .sections
+                .iter()
+                .find(|s| s.name == section.name)
+                .ok_or("Section lost")?;
+            assert_eq!(kept.virtual_address, section.virtual_address);
+            assert_eq!(kept.virtual_size, section.virtual_size);
+            assert_eq!(kept.data, section.data);
+        }
+
+        // The section after the removed one in the file takes its place
+        let moved = after
+            .sections
+            .iter()
+            .find(|s| s.virtual_address == next.virtual_address)
+            .ok_or("Section lost")?;
+        assert_eq!(moved.raw_address, removed_header.raw_address);
+        Ok(())
+    }
+    #[test]
+    fn strip_several() -> TestError {
+        let mut xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let raw_address = RawLayout::of(&xbe)?.next_raw_address();
+        let address = xbe.get_next_virtual_address();
+        for (i, name) in [".ma", ".mb", ".mc"].into_iter().enumerate() {
+            let flags = xbe::SectionFlags::PRELOAD;
+            let start = address + i as u32 * 0x2000;
+            xbe.try_add_section(name, flags, vec![i as u8; 0x1800], start, 0x1800)?;
+        }
+
+        let stripped = xbe.strip_sections(StripSections::Names(&[".ma", ".mc"]))?;
+        let names: Vec<_> = stripped.iter().map(|s| strip_null(&s.name)).collect();
+        assert_eq!(names, [".ma", ".mc"]);
+
+        // The kept section's data takes the place of the first stripped section's
+        let image = header::serialize(&xbe)?;
+        let layout = RawLayout::parse(&image)?;
+        let kept = layout
+            .sections
+            .iter()
+            .find(|s| s.virtual_address == address + 0x2000)
+            .ok_or("Section lost")?;
+        assert_eq!(kept.raw_address, raw_address);
+        assert_eq!(layout.raw_end(), raw_address + 0x1800);
+        let reloaded = Xbe::new(&image)?;
+        assert_eq!(
+            reloaded.section_by_name(".mb").ok_or("Section lost")?.data,
+            vec![1; 0x1800]
+        );
+        Ok(())
+    }
+    #[test]
+    fn section_names() {
+        assert!(check_section_name(".mtext").is_ok());
+        assert!(check_section_name(".mtext\0").is_ok());
+        assert!(check_section_name(&"x".repeat(MAX_SECTION_NAME_LEN)).is_ok());
+
+        let err = check_section_name(&"x".repeat(MAX_SECTION_NAME_LEN + 1))
+            .expect_err("Overlong section name");
+        assert!(matches!(
+            err.downcast_ref::<SectionError>(),
+            Some(SectionError::NameTooLong(_, 256))
+        ));
+        for name in [".m\0text", ".mtext\0\0"] {
+            let err = check_section_name(name).expect_err("Section name with a null");
+            assert!(matches!(
+                err.downcast_ref::<SectionError>(),
+                Some(SectionError::NameContainsNull(_))
+            ));
+        }
+    }
+    #[test]
+    fn shared_pages() -> TestError {
+        let mut xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        assert_eq!(header::serialize(&xbe)?, xbe.serialize()?);
+
+        // Two sections on one page, stored out of address order
+        let address = xbe.get_next_virtual_address().next_multiple_of(0x1000);
+        for (name, offset) in [(".mhigh\0", 0x800), (".mlow\0", 0)] {
+            xbe.add_section(
+                name.to_string(),
+                xbe::SectionFlags::PRELOAD,
+                vec![0; 0x10],
+                address + offset,
+                0x10,
+            );
+        }
+        let layout = RawLayout::parse(&header::serialize(&xbe)?)?;
+        let section = |address: u32| {
+            layout
+                .sections
+                .iter()
+                .find(|s| s.virtual_address == address)
+                .ok_or("Added section is missing")
+        };
+        let (low, high) = (section(address)?, section(address + 0x800)?);
+        assert_eq!(low.head_page_ref_address, low.tail_page_ref_address);
+        assert_eq!(high.head_page_ref_address, low.tail_page_ref_address);
+        assert_eq!(high.tail_page_ref_address, low.tail_page_ref_address);
+
+        // Sections on pages of their own keep their own counts
+        let vanilla = RawLayout::parse(&xbe.serialize()?)?;
+        for (header, original) in layout.sections.iter().zip(vanilla.sections.iter()) {
+            if header.virtual_address < address {
+                assert_eq!(header, original);
+            }
+        }
+        Ok(())
+    }
+    #[test]
+    fn move_to_raw_gaps() -> TestError {
+        // Open a two page gap before the last section's data
+        let mut bytes = fs::read("test/bin/default.xbe")?;
+        let layout = RawLayout::parse(&bytes)?;
+        let last = layout
+            .sections
+            .iter()
+            .filter(|s| s.raw_size > 0)
+            .max_by_key(|s| s.raw_address)
+            .ok_or("No section with data")?;
+        let gap = last.raw_address as usize;
+        bytes.splice(gap..gap, vec![0; 0x2000]);
+        write_u32(&mut bytes, last.offset + 0xC, last.raw_address + 0x2000)?;
+        let mut xbe = Xbe::new(&bytes)?;
+        let raw_end = RawLayout::parse(&header::serialize(&xbe)?)?.raw_end();
+
+        let address = xbe.get_next_virtual_address();
+        let flags = xbe::SectionFlags::PRELOAD;
+        xbe.try_add_section(".mdata", flags, vec![1; 0x800], address, 0x800)?;
+        assert!(RawLayout::parse(&header::serialize(&xbe)?)?.raw_end() > raw_end);
+        assert_eq!(xbe.move_to_raw_gaps(&[".mdata"])?, [Some(gap as u32)]);
+
+        let image = header::serialize(&xbe)?;
+        let layout = RawLayout::parse(&image)?;
+        let moved = layout
+            .sections
+            .iter()
+            .find(|s| s.virtual_address == address)
+            .ok_or("Moved section is missing")?;
+        assert_eq!(moved.raw_address, gap as u32);
+        assert_eq!(layout.raw_end(), raw_end);
+        assert_eq!(image[gap..gap + 0x800], [1; 0x800]);
+        let reloaded = Xbe::new(&image)?;
+        let section = reloaded
+            .section_by_name(".mdata")
+            .ok_or("Moved section is missing")?;
+        assert_eq!(section.virtual_address, address);
+        assert_eq!(section.data, vec![1; 0x800]);
+
+        // One page of the gap is left, too small for this section
+        let address = xbe.get_next_virtual_address();
+        xbe.try_add_section(".mrdata", flags, vec![2; 0x1800], address, 0x1800)?;
+        let before = header::serialize(&xbe)?;
+        assert_eq!(xbe.move_to_raw_gaps(&[".mrdata"])?, [None]);
+        assert_eq!(header::serialize(&xbe)?, before);
+
+        let err = xbe
+            .move_to_raw_gaps(&[".mrdata", ".mtext"])
+            .expect_err("Moved a missing section");
+        assert!(matches!(
+            err.downcast_ref::<SectionError>(),
+            Some(SectionError::NotFound(n)) if n == ".mtext"
+        ));
+        Ok(())
+    }
+    #[test]
+    fn move_several_to_raw_gaps() -> TestError {
+        // Open a three page gap before the last section's data
+        let mut bytes = fs::read("test/bin/default.xbe")?;
+        let layout = RawLayout::parse(&bytes)?;
+        let last = layout
+            .sections
+            .iter()
+            .filter(|s| s.raw_size > 0)
+            .max_by_key(|s| s.raw_address)
+            .ok_or("No section with data")?;
+        let gap = last.raw_address;
+        let at = gap as usize;
+        bytes.splice(at..at, vec![0; 0x3000]);
+        write_u32(&mut bytes, last.offset + 0xC, gap + 0x3000)?;
+        let mut xbe = Xbe::new(&bytes)?;
+        assert_eq!(
+            RawLayout::parse(&bytes)?.raw_gaps().last(),
+            Some(&(gap..gap + 0x3000))
+        );
+
+        let flags = xbe::SectionFlags::PRELOAD;
+        let sizes = [(".ma", 0x800), (".mb", 0x1800), (".mc", 0x800)];
+        for (i, &(name, size)) in sizes.iter().enumerate() {
+            let address = xbe.get_next_virtual_address();
+            xbe.try_add_section(name, flags, vec![i as u8; size], address, size as u32)?;
+        }
+
+        // The first two fill the gap, which leaves no room for the third
+        let moved = xbe.move_to_raw_gaps(&[".ma", ".mb", ".mc"])?;
+        assert_eq!(moved, [Some(gap), Some(gap + 0x1000), None]);
+        let image = header::serialize(&xbe)?;
+        assert!(!RawLayout::parse(&image)?.raw_overlap());
+        let reloaded = Xbe::new(&image)?;
+        for (i, &(name, size)) in sizes.iter().enumerate() {
+            let section = reloaded.section_by_name(name).ok_or("Section lost")?;
+            assert_eq!(section.data, vec![i as u8; size]);
+        }
+        Ok(())
+    }
+    #[test]
+    fn inserted_file() -> TestError {
+        let mut xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let data: Vec<u8> = (0..=255).cycle().take(0x1234).collect();
+        let address = xbe.add_inserted_file("$$XTCONF", data.clone())?;
+
+        // The file is stored but not counted in the size of image
+        let image = header::serialize(&xbe)?;
+        let layout = RawLayout::parse(&image)?;
+        assert!(layout.base_address + layout.size_of_image <= address);
+        let header = layout
+            .sections
+            .iter()
+            .find(|s| s.virtual_address == address)
+            .ok_or("Inserted file is missing")?;
+        assert_eq!(header.flags, SectionFlags::INSERTED_FILE.bits());
+        assert_eq!(
+            image[header.raw_range().start as usize..][..data.len()],
+            data
+        );
+
+        let reloaded = Xbe::new(&image)?;
+        let section = reloaded
+            .section_by_name("$$XTCONF")
+            .ok_or("Inserted file is missing")?;
+        assert!(section.is_inserted_file());
+        assert_eq!(section.virtual_address, address);
+        assert_eq!(section.data, data);
+        assert_eq!(header::serialize(&reloaded)?, image);
+        Ok(())
+    }
+    #[test]
+    fn checked_writes() -> TestError {
+        let mut xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let address = xbe.get_next_virtual_address();
+        let flags = xbe::SectionFlags::PRELOAD;
+        xbe.try_add_section(".mdata", flags, vec![1; 0x10], address, 0x20)?;
+
+        assert_eq!(xbe.write_virtual(address + 4, &[2; 4]), Some(vec![1; 4]));
+        assert_eq!(xbe.write_virtual(address + 0xE, &[3; 4]), None);
+        assert_eq!(xbe.write_virtual(address + 0x18, &[3; 4]), None);
+        let section = xbe.section_by_name_mut(".mdata").ok_or("No .mdata")?;
+        assert_eq!(section.data[..0xC], [1, 1, 1, 1, 2, 2, 2, 2, 1, 1, 1, 1]);
+        let err = section
+            .write_at(0xE, &[3; 4])
+            .expect_err("Wrote past the end of the data");
+        assert!(matches!(
+            err.downcast_ref::<SectionError>(),
+            Some(SectionError::WriteOutOfBounds(n, 0xE, 4, 0x10)) if n == ".mdata"
+        ));
+        assert_eq!(section.data[0xC..], [1; 4]);
+
+        xbe.set_section_virtual_size(".mdata", 0x10)?;
+        assert_eq!(
+            xbe.section_by_name(".mdata").map(|s| s.virtual_size),
+            Some(0x10)
+        );
+        let err = xbe
+            .set_section_virtual_size(".mdata", 0x8)
+            .expect_err("Shrank a section below its data");
+        assert!(matches!(
+            err.downcast_ref::<SectionError>(),
+            Some(SectionError::DataExceedsVirtualSize(n, 0x10, 0x8)) if n == ".mdata"
+        ));
+        xbe.try_add_section(".mbss", flags, Vec::new(), address + 0x1000, 0x10)?;
+        let err = xbe
+            .set_section_virtual_size(".mdata", 0x1008)
+            .expect_err("Grew a section over another");
+        assert!(matches!(
+            err.downcast_ref::<SectionError>(),
+            Some(SectionError::VirtualOverlap(n, other)) if n == ".mdata" && other == ".mbss"
+        ));
+        assert_eq!(
+            xbe.section_by_name(".mdata").map(|s| s.virtual_size),
+            Some(0x10)
+        );
+        Ok(())
+    }
+    #[test]
+    fn read_boundaries() -> TestError {
+        let mut xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let address = xbe.get_next_virtual_address();
+        let flags = xbe::SectionFlags::PRELOAD;
+        let data: Vec<u8> = (0..0x10).collect();
+        xbe.try_add_section(".mdata", flags, data.clone(), address, 0x20)?;
+        xbe.try_add_section(".mbss", flags, vec![0xFF; 4], address + 0x20, 4)?;
+
+        // Ranges starting at the start of the data and ending at its end are within it
+        assert_eq!(xbe.bytes_at(address..address + 0x10), Some(&data[..]));
+        assert_eq!(xbe.bytes_at(address..address), Some(&[][..]));
+        assert_eq!(
+            xbe.bytes_at(address + 0xC..address + 0x10),
+            Some(&data[0xC..])
+        );
+        // One byte before the start or past the end isn't
+        assert_eq!(xbe.bytes_at(address - 1..address + 3), None);
+        assert_eq!(xbe.bytes_at(address + 0xD..address + 0x11), None);
+        // The next section is read from its own start, never through the end of the one before
+        assert_eq!(xbe.bytes_at(address + 0x1C..address + 0x24), None);
+        assert_eq!(
+            xbe.bytes_at(address + 0x20..address + 0x24),
+            Some(&[0xFF; 4][..])
+        );
+        assert_eq!(xbe.bytes_at(address + 4..address), None);
+
+        // Every read xbld makes goes through the same check
+        let read = |range: Range<u32>| crate::patch::read(&xbe, range).ok().map(|b| b.to_vec());
+        assert_eq!(
+            read(address + 0xC..address + 0x10),
+            Some(data[0xC..].to_vec())
+        );
+        assert_eq!(read(address + 0xD..address + 0x11), None);
+        assert_eq!(read(address + 0x1C..address + 0x24), None);
+        Ok(())
+    }
+    #[test]
+    fn try_add() -> TestError {
+        let mut xbe = Xbe::new(&fs::read("test/bin/default.xbe")?)?;
+        let address = xbe.get_next_virtual_address();
+        let flags = xbe::SectionFlags::PRELOAD;
+        xbe.try_add_section(".mdata", flags, vec![1; 0x10], address, 0x10)?;
+        assert_eq!(
+            xbe.section_by_name(".mdata").map(|s| s.name.as_str()),
+            Some(".mdata\0")
+        );
+
+        let err = xbe
+            .try_add_section("\0", flags, Vec::new(), address + 0x1000, 0x10)
+            .expect_err("Added a section without a name");
+        assert!(matches!(
+            err.downcast_ref::<SectionError>(),
+            Some(SectionError::EmptyName)
+        ));
+        let err = xbe
+            .try_add_section(".m\0data", flags, Vec::new(), address + 0x1000, 0x10)
+            .expect_err("Added a section name with a null");
+        assert!(matches!(
+            err.downcast_ref::<SectionError>(),
+            Some(SectionError::NameContainsNull(_))
+        ));
+        let err = xbe
+            .try_add_section(".mdata\0", flags, Vec::new(), address + 0x1000, 0x10)
+            .expect_err("Added a section twice");
+        assert!(matches!(
+            err.downcast_ref::<SectionError>(),
+            Some(SectionError::DuplicateName(n)) if n == ".mdata"
+        ));
+        let err = xbe
+            .try_add_section(".mbss", flags, Vec::new(), address + 0x8, 0x10)
+            .expect_err("Added an overlapping section");
+        assert!(matches!(
+            err.downcast_ref::<SectionError>(),
+            Some(SectionError::VirtualOverlap(n, other)) if n == ".mbss" && other == ".mdata"
+        ));
+
+        // Nothing is added when a section is refused
+        assert_eq!(
+            xbe.sections.len(),
+            Xbe::new(&fs::read("test/bin/default.xbe")?)?.sections.len() + 1
+        );
+        Ok(())
+    }
+    #[test]
+    fn digests() -> TestError {
+        let mut bytes = fs::read("test/bin/default.xbe")?;
+        let statuses = Xbe::new(&bytes)?.verify_digests()?;
+        assert_eq!(statuses.len(), Xbe::new(&bytes)?.sections.len());
+        assert!(statuses.iter().all(|(_, s)| *s == DigestStatus::Match));
+
+        // Only the section with a changed byte mismatches
+        let layout = RawLayout::parse(&bytes)?;
+        let tampered = layout
+            .sections
+            .iter()
+            .position(|s| s.raw_size > 0)
+            .ok_or("No section with data")?;
+        bytes[layout.sections[tampered].raw_address as usize] ^= 0xFF;
+        let statuses = Xbe::new(&bytes)?.verify_digests()?;
+        for (i, (name, status)) in statuses.iter().enumerate() {
+            if i == tampered {
+                let mismatched = matches!(
+                    status,
+                    DigestStatus::Mismatch { stored, computed } if stored != computed
+                );
+                assert!(mismatched, "{name}: {status:?}");
+            } else {
+                assert_eq!(*status, DigestStatus::Match, "{name}");
+            }
+        }
+        assert_eq!(
+            Xbe::new(&bytes)?.validate(),
+            vec![ValidationIssue::DigestMismatch(
+                statuses[tampered].0.clone()
+            )]
+        );
+
+        // Added sections have no digest
+        let mut xbe = Xbe::new(&bytes)?;
+        let address = xbe.get_next_virtual_address();
+        xbe.try_add_section(".mdata", xbe::SectionFlags::PRELOAD, vec![1; 4], address, 4)?;
+        assert_eq!(
+            xbe.verify_digests()?.last(),
+            Some(&(".mdata".to_string(), DigestStatus::Absent))
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+use crate::{patch::to_hex, reloc::strip_null};
+use anyhow::{bail, Context, Result};
+use itertools::Itertools;
+use std::{fmt::Display, str::FromStr};
+use thiserror::Error;
+use xbe::Xbe;
+
+#[derive(Debug, Error)]
+pub enum SignatureError {
+    #[error("Signature must contain at least one non-wildcard byte")]
+    AllWildcards,
+    #[error("Signature [{0}] was not found in any executable section")]
+    NotFound(String),
+    #[error("Signature [{0}] is ambiguous, it matched {1} times at [{2}]")]
+    Ambiguous(String, usize, String),
+    #[error("Signature [{0}] matched {1} times, more than the limit of {2}")]
+    TooManyMatches(String, usize, usize),
+    #[error("Patch site '{0}' matched {1} times but every match overlapped an earlier patch")]
+    NoneApplied(String, usize),
+}
+
+/// Which occurrences of a signature a patch site is applied at
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SignatureMatch {
+    /// The signature must occur exactly once
+    #[default]
+    One,
+    /// Every non-overlapping occurrence of the signature
+    All,
+}
+
+/// A byte pattern where `None` bytes are wildcards that match any value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Signature(Vec<Option<u8>>);
+
+impl FromStr for Signature {
+    type Err = anyhow::Error;
+
+    /// Parses whitespace separated hex bytes where `??` is a wildcard, such as `"56 8B ?? E8"`
+    fn from_str(s: &str) -> Result<Self> {
+        let bytes = s
+            .split_whitespace()
+            .map(|b| match b {
+                "??" => Ok(None),
+                _ => u8::from_str_radix(b, 16)
+                    .map(Some)
+                    .with_context(|| format!("Invalid signature byte '{b}'")),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        if bytes.iter().all(Option::is_none) {
+            bail!(SignatureError::AllWildcards);
+        }
+        Ok(Self(bytes))
+    }
+}
+
+impl Display for Signature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = self
+            .0
+            .iter()
+            .map(|b| b.map_or_else(|| "??".to_string(), |b| to_hex(&[b])))
+            .join(" ");
+        f.write_str(&s)
+    }
+}
+
+impl Signature {
+    /// Finds the offset of every occurrence of this signature within `haystack`
+    pub(crate) fn find_all(&self, haystack: &[u8]) -> Vec<usize> {
+        // Search for the first fixed byte and compare the rest of the pattern from there
+        let (anchor_offset, anchor) = self
+            .0
+            .iter()
+            .enumerate()
+            .find_map(|(i, b)| b.map(|b| (i, b)))
+            .expect("Signature without fixed bytes");
+
+        memchr::memchr_iter(anchor, haystack)
+            .filter_map(|pos| pos.checked_sub(anchor_offset))
+            .filter(|&start| {
+                haystack
+                    .get(start..start + self.0.len())
+                    .is_some_and(|window| self.matches(window))
+            })
+            .collect()
+    }
+
+    /// Finds the virtual address of every occurrence of this signature within the executable
+    /// sections of `xbe`.
+    fn scan(&self, xbe: &Xbe) -> Vec<u32> {
+        xbe.sections
+            .iter()
+            .filter(|s| s.flags.contains(xbe::SectionFlags::EXECUTABLE))
+            .flat_map(|s| {
+                log::trace!("Scanning section '{}' for [{}]", strip_null(&s.name), self);
+                self.find_all(&s.data)
+                    .into_iter()
+                    .map(move |offset| s.virtual_address + offset as u32)
+            })
+            .collect()
+    }
+
+    /// Resolves this signature to the virtual address of its single occurrence within the
+    /// executable sections of `xbe`.
+    pub(crate) fn resolve(&self, xbe: &Xbe) -> Result<u32> {
+        let matches = self.scan(xbe);
+        match matches.as_slice() {
+            [] => bail!(SignatureError::NotFound(self.to_string())),
+            [address] => Ok(*address),
+            _ => bail!(SignatureError::Ambiguous(
+                self.to_string(),
+                matches.len(),
+                matches.iter().map(|a| format!("{a:#x}")).join(", ")
+            )),
+        }
+    }
+
+    /// Resolves this signature to the virtual address of every non-overlapping occurrence within
+    /// the executable sections of `xbe`, failing if there are none or more than `max_matches`.
+    pub(crate) fn resolve_all(&self, xbe: &Xbe, max_matches: Option<usize>) -> Result<Vec<u32>> {
+        let mut matches = self.scan(xbe);
+        let mut end = 0;
+        matches.retain(|&address| {
+            let keep = address >= end;
+            if keep {
+                end = address + self.0.len() as u32;
+            }
+            keep
+        });
+
+        if matches.is_empty() {
+            bail!(SignatureError::NotFound(self.to_string()));
+        }
+        if let Some(max) = max_matches.filter(|&max| matches.len() > max) {
+            bail!(SignatureError::TooManyMatches(
+                self.to_string(),
+                matches.len(),
+                max
+            ));
+        }
+        Ok(matches)
+    }
+
+    fn matches(&self, window: &[u8]) -> bool {
+        self.0
+            .iter()
+            .zip(window)
+            .all(|(pattern, byte)| pattern.is_none_or(|p| p == *byte))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let sig: Signature = "56 8b ?? E8".parse().unwrap();
+        assert_eq!(
+            sig,
+            Signature(vec![Some(0x56), Some(0x8B), None, Some(0xE8)])
+        );
+        assert_eq!(sig.to_string(), "56 8B ?? E8");
+
+        assert!("?? ??".parse::<Signature>().is_err());
+        assert!("".parse::<Signature>().is_err());
+        assert!("56 8G".parse::<Signature>().is_err());
+    }
+
+    #[test]
+    fn find() {
+        let haystack = [
+            0x00, 0x56, 0x8B, 0x11, 0xE8, 0x56, 0x8B, 0x22, 0xE8, 0x56, 0x8B,
+        ];
+
+        let sig: Signature = "56 8B ?? E8".parse().unwrap();
+        assert_eq!(sig.find_all(&haystack), [1, 5]);
+
+        let sig: Signature = "56 8B 22 E8".parse().unwrap();
+        assert_eq!(sig.find_all(&haystack), [5]);
+
+        // Leading wildcards can't start before the haystack
+        let sig: Signature = "?? ?? 56".parse().unwrap();
+        assert_eq!(sig.find_all(&haystack), [3, 7]);
+
+        // Partial match at the end of the haystack
+        let sig: Signature = "56 8B ?? ??".parse().unwrap();
+        assert_eq!(sig.find_all(&haystack), [1, 5]);
+
+        let sig: Signature = "E9".parse().unwrap();
+        assert!(sig.find_all(&haystack).is_empty());
+    }
+}
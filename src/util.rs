@@ -0,0 +1,47 @@
+use itertools::Itertools;
+
+/// Bytes shown on each line of a [`hexdump`]
+const BYTES_PER_LINE: usize = 16;
+
+/// Formats `bytes` loaded at virtual address `va` like `hexdump -C`: each line has the address of
+/// its first byte, up to 16 bytes in hex split into two groups of 8, and the bytes as ASCII with
+/// unprintable bytes shown as `.`
+pub fn hexdump(va: u32, bytes: &[u8]) -> String {
+    bytes
+        .chunks(BYTES_PER_LINE)
+        .enumerate()
+        .map(|(i, line)| {
+            let hex = (0..BYTES_PER_LINE)
+                .map(|j| line.get(j).map_or("  ".to_string(), |b| format!("{b:02x}")))
+                .chunks(BYTES_PER_LINE / 2)
+                .into_iter()
+                .map(|mut group| group.join(" "))
+                .join("  ");
+            let ascii: String = line
+                .iter()
+                .map(|&b| match b {
+                    b' '..=b'~' => b as char,
+                    _ => '.',
+                })
+                .collect();
+            let address = va.wrapping_add((i * BYTES_PER_LINE) as u32);
+            format!("{address:08x}  {hex}  |{ascii}|")
+        })
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hexdump_format() {
+        let bytes = b"\xe9\x10\x00\x00\x00Hello, world!\n\x90\x90";
+        assert_eq!(
+            hexdump(0x60b7e, bytes),
+            "00060b7e  e9 10 00 00 00 48 65 6c  6c 6f 2c 20 77 6f 72 6c  |.....Hello, worl|\n\
+             00060b8e  64 21 0a 90 90                                    |d!...|"
+        );
+        assert_eq!(hexdump(0x10000, &[]), "");
+    }
+}
@@ -0,0 +1,455 @@
+use crate::{
+    config::Configuration,
+    demangle,
+    detour::DetourTarget,
+    header, patch,
+    reloc::{strip_null, SectionMap, SymbolTable},
+    section::{SectionExt, XbeExt},
+};
+use itertools::Itertools;
+use std::{collections::HashSet, fmt::Display};
+use xbe::{SectionFlags, Xbe};
+
+/// How a [`Diagnostic`] affects whether a configuration can be injected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// The injection will fail
+    Error,
+    /// The injection may not do what was intended
+    Warning,
+    /// The check depends on the contents of the input XBE
+    Unverifiable,
+}
+
+/// A problem found while validating a [`Configuration`] without an XBE
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.severity, self.message)
+    }
+}
+
+impl Diagnostic {
+    fn new(severity: Severity, message: impl Into<String>) -> Self {
+        Self {
+            severity,
+            message: message.into(),
+        }
+    }
+}
+
+/// A problem with an XBE that may stop hardware or emulators from loading it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationIssue {
+    /// The second section starts at a lower virtual address than the first, which precedes it
+    UnsortedSections(String, String),
+    OverlappingSections(String, String),
+    /// The encoded entry point doesn't decode to an address within an executable section
+    InvalidEntryPoint(u32),
+    UnmappedTlsAddress(u32),
+    /// The decoded kernel thunk address isn't within a writable section, so the loader can't
+    /// resolve the kernel imports in place
+    InvalidKernelThunk(u32),
+    UnterminatedName(String),
+    /// The section's data doesn't match the SHA-1 digest stored in its header, as for a section
+    /// patched since the digest was written
+    DigestMismatch(String),
+}
+
+impl ValidationIssue {
+    pub fn severity(&self) -> Severity {
+        match self {
+            ValidationIssue::UnsortedSections(..)
+            | ValidationIssue::UnterminatedName(_)
+            | ValidationIssue::DigestMismatch(_) => Severity::Warning,
+            ValidationIssue::OverlappingSections(..)
+            | ValidationIssue::InvalidEntryPoint(_)
+            | ValidationIssue::UnmappedTlsAddress(_)
+            | ValidationIssue::InvalidKernelThunk(_) => Severity::Error,
+        }
+    }
+}
+
+impl Display for ValidationIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: ", self.severity())?;
+        match self {
+            ValidationIssue::UnsortedSections(a, b) => write!(
+                f,
+                "Section '{b}' follows '{a}' but has a lower virtual address"
+            ),
+            ValidationIssue::OverlappingSections(a, b) => {
+                write!(f, "Sections '{a}' and '{b}' overlap in virtual memory")
+            }
+            ValidationIssue::InvalidEntryPoint(encoded) => write!(
+                f,
+                "Entry point {encoded:#x} does not decode to an address in an executable section"
+            ),
+            ValidationIssue::UnmappedTlsAddress(address) => {
+                write!(f, "TLS address {address:#x} is not mapped by any section")
+            }
+            ValidationIssue::InvalidKernelThunk(address) => write!(
+                f,
+                "Kernel thunk address {address:#x} is not within a writable section"
+            ),
+            ValidationIssue::UnterminatedName(name) => {
+                write!(f, "Section name '{name}' is not null terminated")
+            }
+            ValidationIssue::DigestMismatch(name) => {
+                write!(
+                    f,
+                    "Data of section '{name}' doesn't match its stored digest"
+                )
+            }
+        }
+    }
+}
+
+/// Checks that the sections of `xbe` are sorted, don't overlap, and have null terminated
+/// names, that its entry point and TLS directory are within sections, and that its kernel thunk
+/// is within a writable section. Only what is visible
+/// through the parsed XBE is checked; file offsets and header sizes are computed when the XBE
+/// is serialized.
+pub fn validate_xbe(xbe: &Xbe) -> Vec<ValidationIssue> {
+    let mut issues = Vec::new();
+    let name = |s: &xbe::Section| strip_null(&s.name).to_string();
+
+    for (a, b) in xbe.sections.iter().tuple_windows() {
+        if b.virtual_address < a.virtual_address {
+            issues.push(ValidationIssue::UnsortedSections(name(a), name(b)));
+        }
+    }
+    for (a, b) in xbe.sections.iter().tuple_combinations() {
+        let (a_range, b_range) = (a.virtual_range(), b.virtual_range());
+        if a_range.start < b_range.end && b_range.start < a_range.end {
+            issues.push(ValidationIssue::OverlappingSections(name(a), name(b)));
+        }
+    }
+
+    let entry_executable = header::entry_point(xbe).is_ok_and(|entry| {
+        xbe.section_containing(entry)
+            .is_some_and(|s| s.flags.contains(SectionFlags::EXECUTABLE))
+    });
+    if !entry_executable {
+        issues.push(ValidationIssue::InvalidEntryPoint(xbe.header.entry_point));
+    }
+    // XBEs without thread local storage have no TLS directory
+    let tls_address = xbe.header.tls_address;
+    if tls_address != 0 && xbe.section_containing(tls_address).is_none() {
+        issues.push(ValidationIssue::UnmappedTlsAddress(tls_address));
+    }
+    // without a known key the thunk can't be decoded, which is reported as an invalid entry point
+    if let Ok(thunk) = header::kernel_thunk_address(xbe) {
+        let writable = xbe
+            .section_containing(thunk)
+            .is_some_and(|s| s.flags.contains(SectionFlags::WRITABLE));
+        if !writable {
+            issues.push(ValidationIssue::InvalidKernelThunk(thunk));
+        }
+    }
+
+    for section in xbe.sections.iter().filter(|s| !s.name.ends_with('\0')) {
+        issues.push(ValidationIssue::UnterminatedName(section.name.clone()));
+    }
+    issues
+}
+
+impl Configuration {
+    /// Runs every check of this configuration that doesn't need the input XBE: patch symbols
+    /// exist and delimit a region of one section, fixed patch addresses don't overlap, the
+    /// sections and symbol tables can be built, and every symbol referenced by a relocation or
+    /// detour is defined. Checks that depend on the XBE are reported as
+    /// [`Severity::Unverifiable`].
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        self.validate_patches(&mut diagnostics);
+        self.validate_symbols(&mut diagnostics);
+
+        for raw in self.raw_patches.iter() {
+            diagnostics.push(Diagnostic::new(
+                Severity::Unverifiable,
+                format!(
+                    "Raw patch at {:#x} is not checked against the XBE",
+                    raw.virtual_address
+                ),
+            ));
+        }
+        diagnostics
+    }
+
+    fn validate_patches(&self, diagnostics: &mut Vec<Diagnostic>) {
+        if let Err(e) = patch::application_order(&self.patches, &self.patch_order) {
+            diagnostics.push(Diagnostic::new(Severity::Error, format!("{e:#}")));
+        }
+
+        let mut sites = Vec::new();
+        let mut regions = Vec::new();
+        for (patch, site) in self
+            .patches
+            .iter()
+            .flat_map(|p| p.sites.iter().map(move |s| (p, s)))
+        {
+            let write_len = match patch.write_length(site) {
+                Ok(length) => length,
+                Err(e) => {
+                    diagnostics.push(Diagnostic::new(
+                        Severity::Error,
+                        format!(
+                            "Patch site '{}' in {:?}: {e:#}",
+                            site.name(),
+                            patch.patchfile.path
+                        ),
+                    ));
+                    continue;
+                }
+            };
+
+            if site.signature.is_some() {
+                diagnostics.push(Diagnostic::new(
+                    Severity::Unverifiable,
+                    format!("Patch site '{}' is located by signature", site.name()),
+                ));
+                continue;
+            }
+            if site.expected_bytes.is_some() {
+                diagnostics.push(Diagnostic::new(
+                    Severity::Unverifiable,
+                    format!("Expected bytes of patch site '{}'", site.name()),
+                ));
+            }
+            sites.push(site);
+            regions.push(site.virtual_address..site.virtual_address + write_len);
+        }
+
+        for (a, b) in patch::overlapping_regions(&regions) {
+            diagnostics.push(Diagnostic::new(
+                Severity::Warning,
+                format!(
+                    "Patch sites '{}' at {:#x} and '{}' at {:#x} overlap",
+                    sites[a].name(),
+                    sites[a].virtual_address,
+                    sites[b].name(),
+                    sites[b].virtual_address
+                ),
+            ));
+        }
+    }
+
+    fn validate_symbols(&self, diagnostics: &mut Vec<Diagnostic>) {
+        // dry build the sections and symbol table, without assigning addresses
+        let mut section_map = match SectionMap::from_data_with_extra_sections(
+            &self.modfiles,
+            self.extra_sections
+                .iter()
+                .map(|(coff_name, combined_name)| (coff_name.as_str(), combined_name.as_str()))
+                .collect(),
+            !self.no_default_sections,
+        ) {
+            Ok(section_map) => section_map,
+            Err(e) => {
+                diagnostics.push(Diagnostic::new(Severity::Error, format!("{e:#}")));
+                return;
+            }
+        };
+        if let Err(e) = section_map.allocate_common_symbols(&self.modfiles) {
+            diagnostics.push(Diagnostic::new(Severity::Error, format!("{e:#}")));
+        }
+        let symbol_table = match SymbolTable::new(&section_map, self) {
+            Ok(symbol_table) => symbol_table,
+            Err(e) => {
+                diagnostics.push(Diagnostic::new(Severity::Error, format!("{e:#}")));
+                return;
+            }
+        };
+
+        // symbols defined by patches and trampolines become available during injection
+        let defined: HashSet<String> = symbol_table
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .chain(
+                self.patches
+                    .iter()
+                    .flat_map(|p| p.patchfile.external_symbol_names())
+                    .map(str::to_string),
+            )
+            .chain(
+                self.detours
+                    .iter()
+                    .filter(|d| d.with_original)
+                    .map(|d| d.original_symbol_name()),
+            )
+            .collect();
+        let is_defined = |name: &str| {
+            demangle::resolve_name(name, defined.iter().map(String::as_str))
+                .is_ok_and(|found| found.is_some())
+        };
+
+        for obj in self
+            .modfiles
+            .iter()
+            .chain(self.patches.iter().map(|p| &p.patchfile))
+        {
+            for name in obj.undefined_symbol_names() {
+                if !defined.contains(name) {
+                    diagnostics.push(Diagnostic::new(
+                        Severity::Error,
+                        format!(
+                            "Symbol '{name}' referenced by {:?} is never defined",
+                            obj.path
+                        ),
+                    ));
+                }
+            }
+        }
+
+        for detour in self.detours.iter() {
+            let target = match &detour.target {
+                DetourTarget::Symbol(name) => Some(name),
+                DetourTarget::Address(_) => {
+                    diagnostics.push(Diagnostic::new(
+                        Severity::Unverifiable,
+                        format!("Target of detour to '{}'", detour.symbol_name),
+                    ));
+                    None
+                }
+            };
+            for name in std::iter::once(&detour.symbol_name).chain(target) {
+                if !is_defined(name) {
+                    diagnostics.push(Diagnostic::new(
+                        Severity::Error,
+                        format!(
+                            "Symbol '{name}' used by detour to '{}' is never defined",
+                            detour.symbol_name
+                        ),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    type TestError = std::result::Result<(), Box<dyn std::error::Error>>;
+
+    #[test]
+    fn valid_config() -> TestError {
+        let config = Configuration::from_file(Path::new("test/conf.toml"))?;
+        let diagnostics = config.validate();
+        assert!(
+            diagnostics.iter().all(|d| d.severity != Severity::Error),
+            "{diagnostics:?}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn xbe_issues() -> TestError {
+        let bytes = std::fs::read("test/bin/default.xbe")?;
+        let mut xbe = Xbe::new(&bytes)?;
+        assert_eq!(validate_xbe(&xbe), []);
+        let names = (
+            strip_null(&xbe.sections[0].name).to_string(),
+            strip_null(&xbe.sections[1].name).to_string(),
+        );
+
+        xbe.sections.swap(0, 1);
+        assert!(
+            validate_xbe(&xbe).contains(&ValidationIssue::UnsortedSections(
+                names.1.clone(),
+                names.0.clone()
+            ))
+        );
+
+        let mut xbe = Xbe::new(&bytes)?;
+        xbe.sections[1].virtual_address = xbe.sections[0].virtual_address;
+        assert!(
+            validate_xbe(&xbe).contains(&ValidationIssue::OverlappingSections(
+                names.0.clone(),
+                names.1.clone()
+            ))
+        );
+
+        let mut xbe = Xbe::new(&bytes)?;
+        xbe.header.entry_point = 0;
+        assert_eq!(validate_xbe(&xbe), [ValidationIssue::InvalidEntryPoint(0)]);
+
+        let mut xbe = Xbe::new(&bytes)?;
+        xbe.header.tls_address = 0x10;
+        assert_eq!(
+            validate_xbe(&xbe),
+            [ValidationIssue::UnmappedTlsAddress(0x10)]
+        );
+
+        let mut xbe = Xbe::new(&bytes)?;
+        header::set_kernel_thunk_address(&mut xbe, 0x10)?;
+        assert_eq!(
+            validate_xbe(&xbe),
+            [ValidationIssue::InvalidKernelThunk(0x10)]
+        );
+
+        // The thunk is resolved in place, so can't be in a read-only section
+        let mut xbe = Xbe::new(&bytes)?;
+        let thunk = header::kernel_thunk_address(&xbe)?;
+        xbe.section_containing_mut(thunk)
+            .ok_or("Kernel thunk unmapped")?
+            .flags
+            .remove(SectionFlags::WRITABLE);
+        assert_eq!(
+            validate_xbe(&xbe),
+            [ValidationIssue::InvalidKernelThunk(thunk)]
+        );
+
+        let mut xbe = Xbe::new(&bytes)?;
+        xbe.sections[0].name = names.0.clone();
+        assert_eq!(
+            validate_xbe(&xbe),
+            [ValidationIssue::UnterminatedName(names.0)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_config() -> TestError {
+        let toml = r#"
+            modfiles = ["loader_stub.o"]
+
+            [[patch]]
+            patchfile = "framehook_patch.o"
+            sites = [
+                { name = "a", start_symbol = "_framehook_patch", end_symbol = "_framehook_patch_end", virtual_address = 396158 },
+                { name = "b", start_symbol = "_framehook_patch", end_symbol = "_framehook_patch_end", virtual_address = 396161 },
+                { name = "c", start_symbol = "_framehook_patch", end_symbol = "_framehook_patch_end", signature = "A1 ?? ?? ?? 00" },
+                { name = "d", start_symbol = "_missing", end_symbol = "_framehook_patch_end", virtual_address = 0x1000 },
+            ]
+
+            [[detour]]
+            target = 0x1000
+            symbol = "_undefined_hook""#;
+
+        let config = Configuration::from_toml(toml, Path::new("test/bin/fakefile.toml"))?;
+        let diagnostics = config.validate();
+        let count = |severity| {
+            diagnostics
+                .iter()
+                .filter(|d| d.severity == severity)
+                .count()
+        };
+
+        // '_missing' and '_undefined_hook'
+        assert_eq!(count(Severity::Error), 2, "{diagnostics:?}");
+        // "a" overlaps "b"
+        assert_eq!(count(Severity::Warning), 1, "{diagnostics:?}");
+        // "c" and the detour target
+        assert_eq!(count(Severity::Unverifiable), 2, "{diagnostics:?}");
+        Ok(())
+    }
+}